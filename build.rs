@@ -1,34 +1,94 @@
-use std::env;
-use std::fs;
-use std::io::Write;
-use std::path::Path;
+// Without a backend there is nothing to generate, lib.rs reports the missing feature instead
+#[cfg(not(any(feature = "protobuf", feature = "prost")))]
+fn main() {}
 
+#[cfg(any(feature = "protobuf", feature = "prost"))]
 fn main() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
     // Prepare OUT_DIR/proto directory
     let out_dir = Path::new(&env::var("OUT_DIR").unwrap()).join("proto");
     fs::create_dir_all(&out_dir).expect("Failed to create $OUT_DIR/proto directory");
 
-    protobuf_codegen::Codegen::new()
-        .out_dir(&out_dir)
-        .inputs(&[if cfg!(feature = "webrtc-extensions") {
-            "protos/MumbleWithWebRTC.proto"
-        } else {
-            "protos/Mumble.proto"
-        }])
-        .includes(&["protos"])
-        .customize(protobuf_codegen::Customize::default()
-            .generate_accessors(true)
-        )
-        .run()
-        .expect("protoc");
-
-    // Create mod.rs (see https://github.com/stepancheg/rust-protobuf/issues/324)
-    let content = if cfg!(feature = "webrtc-extensions") {
-        "mod MumbleWithWebRTC; pub use MumbleWithWebRTC::*;"
+    let input = if cfg!(feature = "webrtc-extensions") {
+        "protos/MumbleWithWebRTC.proto"
     } else {
-        "mod Mumble; pub use Mumble::*;"
+        "protos/Mumble.proto"
+    };
+
+    #[cfg(feature = "protobuf")]
+    let content = {
+        protobuf_codegen::Codegen::new()
+            .out_dir(&out_dir)
+            .inputs([input])
+            .includes(["protos"])
+            .pure()
+            .customize(protobuf_codegen::Customize::default()
+                .generate_accessors(true)
+            )
+            .run()
+            .expect("protobuf-codegen");
+
+        // Create mod.rs (see https://github.com/stepancheg/rust-protobuf/issues/324)
+        if cfg!(feature = "webrtc-extensions") {
+            "mod MumbleWithWebRTC; pub use MumbleWithWebRTC::*;"
+        } else {
+            "mod Mumble; pub use Mumble::*;"
+        }
+    };
+
+    // Unlike protobuf-codegen, prost-build always requires a protoc binary
+    #[cfg(feature = "prost")]
+    let content = {
+        prost_build::Config::new()
+            .out_dir(&out_dir)
+            .compile_protos(&[input], &["protos"])
+            .expect("protoc");
+
+        // prost converts names to UpperCamelCase, restore the ones used by the protocol
+        let mut content = String::from(
+            "mod mumble_proto; pub use mumble_proto::*; pub use Acl as ACL; \
+             pub mod acl { pub use super::mumble_proto::acl::*; pub use ChanAcl as ChanACL; }",
+        );
+        if cfg!(feature = "webrtc-extensions") {
+            content.push_str(" pub use WebRtc as WebRTC;");
+        }
+        content
     };
+
     let mut file = fs::File::create(out_dir.join("mod.rs")).unwrap();
     file.write_all(content.as_bytes())
-        .expect("Failed to write proto/mod.rs")
+        .expect("Failed to write proto/mod.rs");
+
+    // The protobuf based voice packets of Mumble 1.5, kept apart from the control messages
+    let udp_out_dir = Path::new(&env::var("OUT_DIR").unwrap()).join("proto_udp");
+    fs::create_dir_all(&udp_out_dir).expect("Failed to create $OUT_DIR/proto_udp directory");
+
+    #[cfg(feature = "protobuf")]
+    let udp_content = {
+        protobuf_codegen::Codegen::new()
+            .out_dir(&udp_out_dir)
+            .inputs(["protos/MumbleUDP.proto"])
+            .includes(["protos"])
+            .pure()
+            .run()
+            .expect("protobuf-codegen");
+        "mod MumbleUDP; pub use MumbleUDP::*;"
+    };
+
+    #[cfg(feature = "prost")]
+    let udp_content = {
+        prost_build::Config::new()
+            .out_dir(&udp_out_dir)
+            .compile_protos(&["protos/MumbleUDP.proto"], &["protos"])
+            .expect("protoc");
+        "mod mumble_udp; pub use mumble_udp::*;"
+    };
+
+    let mut file = fs::File::create(udp_out_dir.join("mod.rs")).unwrap();
+    file.write_all(udp_content.as_bytes())
+        .expect("Failed to write proto_udp/mod.rs")
 }
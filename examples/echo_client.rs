@@ -7,12 +7,13 @@ use futures::channel::oneshot;
 use futures::join;
 use futures::StreamExt;
 use futures::SinkExt;
-use mumble_protocol::control::msgs;
-use mumble_protocol::control::ClientControlCodec;
-use mumble_protocol::control::ControlPacket;
-use mumble_protocol::crypt::ClientCryptState;
-use mumble_protocol::voice::VoicePacket;
-use mumble_protocol::voice::VoicePacketPayload;
+use mumble_protocol_2x::control::msgs;
+use mumble_protocol_2x::control::sink::SendControlExt;
+use mumble_protocol_2x::control::ClientControlCodec;
+use mumble_protocol_2x::control::ControlPacket;
+use mumble_protocol_2x::crypt::ClientCryptState;
+use mumble_protocol_2x::voice::VoicePacket;
+use mumble_protocol_2x::voice::VoicePacketPayload;
 use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
@@ -60,7 +61,7 @@ async fn connect(
         msg.set_password(password);
     }
     msg.set_opus(true);
-    sink.send(msg.into()).await.unwrap();
+    sink.send_msg(msg).await.unwrap();
 
     println!("Logging in..");
     let mut crypt_state = None;
@@ -80,21 +81,14 @@ async fn connect(
                 let mut response = msgs::TextMessage::new();
                 response.mut_session().push(msg.actor());
                 response.set_message(msg.take_message());
-                sink.send(response.into()).await.unwrap();
+                sink.send_msg(response).await.unwrap();
             }
             ControlPacket::CryptSetup(msg) => {
                 // Wait until we're fully connected before initiating UDP voice
-                crypt_state = Some(ClientCryptState::new_from(
-                    msg.key()
-                        .try_into()
-                        .expect("Server sent private key with incorrect size"),
-                    msg.client_nonce()
-                        .try_into()
-                        .expect("Server sent client_nonce with incorrect size"),
-                    msg.server_nonce()
-                        .try_into()
-                        .expect("Server sent server_nonce with incorrect size"),
-                ));
+                crypt_state = Some(
+                    ClientCryptState::from_crypt_setup(&msg)
+                        .expect("Server sent invalid CryptSetup"),
+                );
             }
             ControlPacket::ServerSync(_) => {
                 println!("Logged in!");
@@ -0,0 +1,107 @@
+//! Counts the heap allocations made while relaying voice packets.
+//!
+//! Run with `cargo run --release --example voice_allocations`.
+
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use bytes::BytesMut;
+use mumble_protocol_2x::control::ClientControlCodec;
+use mumble_protocol_2x::control::ControlPacket;
+use mumble_protocol_2x::control::ServerControlCodec;
+use mumble_protocol_2x::voice::AudioBuilder;
+use mumble_protocol_2x::voice::ClientVoiceCodec;
+use mumble_protocol_2x::voice::Clientbound;
+use mumble_protocol_2x::voice::Position;
+use mumble_protocol_2x::voice::ServerVoiceCodec;
+use mumble_protocol_2x::voice::VoicePacket;
+use tokio_util::codec::Decoder;
+use tokio_util::codec::Encoder;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ROUNDS: usize = 100_000;
+
+/// Runs `f` [ROUNDS] times and prints the allocations and time per round.
+fn measure(name: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..ROUNDS {
+        f();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "{:<24} {:>6.2} allocations, {:>6} ns per packet",
+        name,
+        allocations as f64 / ROUNDS as f64,
+        start.elapsed().as_nanos() / ROUNDS as u128
+    );
+}
+
+fn main() {
+    let packet: VoicePacket<Clientbound> = AudioBuilder::opus(vec![0xfc; 120])
+        .session(42)
+        .seq(1234)
+        .position(Position::new(1.0, 2.0, 3.0))
+        .build()
+        .unwrap();
+
+    let mut datagram = BytesMut::new();
+    ServerVoiceCodec::new()
+        .encode(packet.clone(), &mut datagram)
+        .unwrap();
+    let mut stream = BytesMut::new();
+    ServerControlCodec::new()
+        .encode(packet.clone().into(), &mut stream)
+        .unwrap();
+
+    let mut buf = BytesMut::with_capacity(1024);
+    measure("udp decode", || {
+        buf.extend_from_slice(&datagram);
+        ClientVoiceCodec::new().decode(&mut buf).unwrap().unwrap();
+    });
+    measure("udp encode", || {
+        ServerVoiceCodec::new()
+            .encode(packet.clone(), &mut buf)
+            .unwrap();
+        buf.clear();
+    });
+
+    let mut codec = ClientControlCodec::new();
+    measure("tunnel decode", || {
+        buf.extend_from_slice(&stream);
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(decoded, ControlPacket::UDPTunnel(_)));
+    });
+    let mut codec = ServerControlCodec::new();
+    let tunneled = ControlPacket::from(packet);
+    measure("tunnel encode", || {
+        codec.encode_ref(&tunneled, &mut buf).unwrap();
+        buf.clear();
+    });
+}
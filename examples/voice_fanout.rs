@@ -0,0 +1,56 @@
+//! Compares encrypting one voice packet for many recipients with and without encoding it once.
+//!
+//! Run with `cargo run --release --example voice_fanout`.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::BytesMut;
+use mumble_protocol_2x::crypt::ServerCryptState;
+use mumble_protocol_2x::voice::AudioBuilder;
+use mumble_protocol_2x::voice::Clientbound;
+use mumble_protocol_2x::voice::Position;
+use mumble_protocol_2x::voice::VoicePacket;
+
+const RECIPIENTS: usize = 500;
+const ROUNDS: u32 = 200;
+
+/// Runs `f` [ROUNDS] times and returns the time per recipient.
+fn measure(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        f();
+    }
+    start.elapsed() / (ROUNDS * RECIPIENTS as u32)
+}
+
+fn main() {
+    let packet: VoicePacket<Clientbound> = AudioBuilder::opus(vec![0xfc; 120])
+        .session(42)
+        .seq(1234)
+        .position(Position::new(1.0, 2.0, 3.0))
+        .build()
+        .unwrap();
+    let mut recipients: Vec<_> = (0..RECIPIENTS)
+        .map(|_| ServerCryptState::generate_new())
+        .collect();
+    let mut buf = BytesMut::with_capacity(1024);
+
+    let naive = measure(|| {
+        for state in &mut recipients {
+            buf.clear();
+            state.encrypt(packet.clone(), &mut buf).unwrap();
+        }
+    });
+    let shared = measure(|| {
+        let encoded = packet.encode_shared().unwrap();
+        for state in &mut recipients {
+            buf.clear();
+            state.encrypt_prepared(&encoded, &mut buf);
+        }
+    });
+
+    println!("{} recipients, per recipient:", RECIPIENTS);
+    println!("encrypt          {:>6} ns", naive.as_nanos());
+    println!("encrypt_prepared {:>6} ns", shared.as_nanos());
+}
@@ -0,0 +1,248 @@
+//! `Arbitrary` implementations for generating random yet valid packets, e.g. for fuzzing
+
+use std::marker::PhantomData;
+
+use ::arbitrary::Arbitrary;
+use ::arbitrary::Result;
+use ::arbitrary::Unstructured;
+use bytes::Bytes;
+use protobuf::reflect::MessageDescriptor;
+use protobuf::reflect::ReflectValueBox;
+use protobuf::reflect::RuntimeFieldType;
+use protobuf::reflect::RuntimeType;
+use protobuf::MessageDyn;
+
+use crate::control::msgs;
+use crate::control::ControlPacket;
+use crate::control::PacketKind;
+use crate::control::RawControlPacket;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+use crate::voice::VoicePacketPayload;
+use crate::voice::MAX_FRAME_LEN;
+use crate::voice::MAX_OPUS_FRAME_LEN;
+use crate::voice::POSITION_LEN;
+
+/// Maximum amount of characters of generated string fields.
+const MAX_STRING_LEN: usize = 32;
+/// Maximum amount of bytes of generated bytes fields and raw packets.
+const MAX_BYTES_LEN: usize = 64;
+/// Maximum amount of elements of generated repeated fields.
+const MAX_REPEATED_LEN: usize = 4;
+/// Fields holding session ids, which are kept within a realistic range.
+const SESSION_FIELDS: &[&str] = &["session", "actor"];
+/// Maximum value of generated session ids.
+const MAX_SESSION: u32 = 1024;
+/// Lowest id of generated [ControlPacket::Other] packets, well above all known packet ids.
+const MIN_UNKNOWN_ID: u16 = 1000;
+/// Maximum amount of frames in generated CELT and Speex payloads.
+const MAX_FRAMES: usize = 4;
+
+fn arbitrary_bytes(u: &mut Unstructured<'_>, max_len: usize) -> Result<Bytes> {
+    let len = u.int_in_range(0..=max_len)?;
+    Ok(Bytes::copy_from_slice(u.bytes(len.min(u.len()))?))
+}
+
+fn arbitrary_string(u: &mut Unstructured<'_>) -> Result<String> {
+    let len = u.int_in_range(0..=MAX_STRING_LEN)?;
+    (0..len).map(|_| u.arbitrary::<char>()).collect()
+}
+
+/// Generates a finite float, since NaN would break equality of round-tripped packets.
+fn arbitrary_float(u: &mut Unstructured<'_>) -> Result<f64> {
+    Ok(f64::from(u.arbitrary::<i32>()?) / 256.0)
+}
+
+/// Generates a message with a random subset of its fields set.
+///
+/// Required fields are always set, so the message can always be serialized.
+fn arbitrary_message(
+    u: &mut Unstructured<'_>,
+    descriptor: &MessageDescriptor,
+) -> Result<Box<dyn MessageDyn>> {
+    let mut msg = descriptor.new_instance();
+    for field in descriptor.fields() {
+        match field.runtime_field_type() {
+            RuntimeFieldType::Singular(ty) => {
+                if field.is_required() || u.arbitrary()? {
+                    let value = arbitrary_value(u, &ty, field.name())?;
+                    field.set_singular_field(&mut *msg, value);
+                }
+            }
+            RuntimeFieldType::Repeated(ty) => {
+                for _ in 0..u.int_in_range(0..=MAX_REPEATED_LEN)? {
+                    let value = arbitrary_value(u, &ty, field.name())?;
+                    field.mut_repeated(&mut *msg).push(value);
+                }
+            }
+            RuntimeFieldType::Map(_, _) => {}
+        }
+    }
+    Ok(msg)
+}
+
+fn arbitrary_value(
+    u: &mut Unstructured<'_>,
+    ty: &RuntimeType,
+    name: &str,
+) -> Result<ReflectValueBox> {
+    Ok(match ty {
+        RuntimeType::I32 => ReflectValueBox::I32(u.arbitrary()?),
+        RuntimeType::I64 => ReflectValueBox::I64(u.arbitrary()?),
+        RuntimeType::U32 if SESSION_FIELDS.contains(&name) => {
+            ReflectValueBox::U32(u.int_in_range(0..=MAX_SESSION)?)
+        }
+        RuntimeType::U32 => ReflectValueBox::U32(u.arbitrary()?),
+        RuntimeType::U64 => ReflectValueBox::U64(u.arbitrary()?),
+        RuntimeType::F32 => ReflectValueBox::F32(arbitrary_float(u)? as f32),
+        RuntimeType::F64 => ReflectValueBox::F64(arbitrary_float(u)?),
+        RuntimeType::Bool => ReflectValueBox::Bool(u.arbitrary()?),
+        RuntimeType::String => ReflectValueBox::String(arbitrary_string(u)?),
+        RuntimeType::VecU8 => ReflectValueBox::Bytes(arbitrary_bytes(u, MAX_BYTES_LEN)?.to_vec()),
+        RuntimeType::Enum(descriptor) => {
+            let values = descriptor.values().collect::<Vec<_>>();
+            ReflectValueBox::Enum(descriptor.clone(), u.choose(&values)?.value())
+        }
+        RuntimeType::Message(descriptor) => {
+            ReflectValueBox::Message(arbitrary_message(u, descriptor)?)
+        }
+    })
+}
+
+impl<'a> Arbitrary<'a> for RawControlPacket {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(RawControlPacket {
+            id: u.arbitrary()?,
+            bytes: arbitrary_bytes(u, MAX_BYTES_LEN)?,
+        })
+    }
+}
+
+impl<'a, Dst: VoicePacketDst> Arbitrary<'a> for ControlPacket<Dst>
+where
+    Dst::SessionId: Arbitrary<'a>,
+{
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let kind = match PacketKind::ALL.get(u.choose_index(PacketKind::ALL.len() + 1)?) {
+            Some(&PacketKind::UDPTunnel) => return Ok(VoicePacket::arbitrary(u)?.into()),
+            Some(&kind) => kind,
+            None => {
+                return Ok(ControlPacket::Other(RawControlPacket {
+                    id: u.int_in_range(MIN_UNKNOWN_ID..=u16::MAX)?,
+                    bytes: arbitrary_bytes(u, MAX_BYTES_LEN)?,
+                }))
+            }
+        };
+        let descriptor = msgs::file_descriptor()
+            .message_by_package_relative_name(kind.name())
+            .expect("every packet kind has a message type of the same name");
+        let msg = arbitrary_message(u, &descriptor)?;
+        let raw = RawControlPacket {
+            id: kind.id(),
+            bytes: msg
+                .write_to_bytes_dyn()
+                .expect("all required fields are set")
+                .into(),
+        };
+        Ok(raw.try_into().expect("generated message is valid"))
+    }
+}
+
+impl<'a> Arbitrary<'a> for VoicePacketPayload {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        fn frames(u: &mut Unstructured<'_>) -> Result<Vec<Bytes>> {
+            (0..u.int_in_range(1..=MAX_FRAMES)?)
+                .map(|_| arbitrary_bytes(u, MAX_FRAME_LEN))
+                .collect()
+        }
+        Ok(match u.choose_index(4)? {
+            0 => VoicePacketPayload::CeltAlpha(frames(u)?),
+            1 => VoicePacketPayload::CeltBeta(frames(u)?),
+            2 => VoicePacketPayload::Speex(frames(u)?),
+            _ => VoicePacketPayload::Opus(arbitrary_bytes(u, MAX_OPUS_FRAME_LEN)?, u.arbitrary()?),
+        })
+    }
+}
+
+impl<'a, Dst: VoicePacketDst> Arbitrary<'a> for VoicePacket<Dst>
+where
+    Dst::SessionId: Arbitrary<'a>,
+{
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        if u.ratio(1, 8)? {
+            return Ok(VoicePacket::Ping {
+                timestamp: u.arbitrary()?,
+            });
+        }
+        Ok(VoicePacket::Audio {
+            _dst: PhantomData,
+            target: u.int_in_range(0..=0x1f)?,
+            session_id: u.arbitrary()?,
+            seq_num: u.arbitrary()?,
+            payload: u.arbitrary()?,
+            position_info: if u.arbitrary()? {
+                Some(Bytes::copy_from_slice(u.bytes(POSITION_LEN)?))
+            } else {
+                None
+            },
+        })
+    }
+}
+
+#[cfg(all(test, feature = "tokio-codec"))]
+mod test {
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+    use tokio_util::codec::Encoder;
+
+    use super::*;
+    use crate::control::ClientControlCodec;
+    use crate::control::ServerControlCodec;
+    use crate::voice::Clientbound;
+    use crate::voice::Serverbound;
+
+    /// Deterministic pseudo-random input data.
+    fn input(seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..4096)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn arbitrary_packets_round_trip() {
+        for seed in 0..200 {
+            let data = input(seed);
+            let mut u = Unstructured::new(&data);
+            while !u.is_empty() {
+                let Ok(packet) = ControlPacket::<Clientbound>::arbitrary(&mut u) else {
+                    break;
+                };
+                let mut buf = BytesMut::new();
+                ServerControlCodec::new()
+                    .encode(packet.clone(), &mut buf)
+                    .unwrap();
+                let decoded = ClientControlCodec::new().decode(&mut buf).unwrap();
+                assert_eq!(Some(packet), decoded);
+            }
+
+            let mut u = Unstructured::new(&data);
+            while !u.is_empty() {
+                let Ok(packet) = ControlPacket::<Serverbound>::arbitrary(&mut u) else {
+                    break;
+                };
+                let mut buf = BytesMut::new();
+                ClientControlCodec::new()
+                    .encode(packet.clone(), &mut buf)
+                    .unwrap();
+                let decoded = ServerControlCodec::new().decode(&mut buf).unwrap();
+                assert_eq!(Some(packet), decoded);
+            }
+        }
+    }
+}
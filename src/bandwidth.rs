@@ -0,0 +1,142 @@
+//! Mumble's accounting of the bandwidth used by voice
+//!
+//! Servers announce the maximum bandwidth per user in `ServerConfig` and may kick users
+//! exceeding it. The official client counts the headers of every packet against it and picks
+//! its Opus bitrate and the amount of frames per packet accordingly. The functions here
+//! replicate its calculations, `AudioInput::getNetworkBandwidth` and
+//! `AudioInput::adjustBandwidth`, including their integer arithmetic.
+//!
+//! Bitrates and bandwidths are in bits per second, frames are 10 ms of audio each.
+
+/// Bytes of IP and UDP headers per voice packet.
+pub const IP_UDP_OVERHEAD: u32 = 20 + 8;
+
+/// Bytes of the encryption header per voice packet.
+pub const CRYPT_OVERHEAD: u32 = 4;
+
+/// Bytes of the voice packet header and sequence number, as estimated by Mumble.
+pub const VOICE_HEADER_OVERHEAD: u32 = 1 + 2;
+
+/// Bytes additionally counted per voice packet tunneled through the control channel.
+pub const TUNNEL_OVERHEAD: u32 = 12;
+
+/// Bytes of positional audio data per voice packet.
+pub const POSITION_OVERHEAD: u32 = 12;
+
+/// The Opus bitrate the official client uses by default.
+pub const DEFAULT_BITRATE: u32 = 40_000;
+
+/// The amount of frames per packet the official client uses by default.
+pub const DEFAULT_FRAMES_PER_PACKET: u32 = 2;
+
+/// The official client never lowers the Opus bitrate below this.
+pub const MIN_BITRATE: u32 = 8_000;
+
+/// How voice packets reach the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// Encrypted UDP packets.
+    Udp,
+    /// Tunneled through the control channel.
+    Tcp,
+}
+
+/// Returns the bandwidth used by the headers of packets with `frames_per_packet` frames.
+///
+/// Positional audio adds [POSITION_OVERHEAD] bytes to every packet, which is not included.
+pub fn overhead(frames_per_packet: u32, transport: Transport) -> u32 {
+    let frames_per_packet = frames_per_packet.max(1);
+    let tunnel = match transport {
+        Transport::Udp => 0,
+        Transport::Tcp => TUNNEL_OVERHEAD,
+    };
+    // One length byte per frame
+    let bytes =
+        IP_UDP_OVERHEAD + CRYPT_OVERHEAD + VOICE_HEADER_OVERHEAD + tunnel + frames_per_packet;
+    // 100 frames per second, 8 bits per byte
+    bytes * (800 / frames_per_packet)
+}
+
+/// Returns the bandwidth used by audio of the given bitrate including all headers.
+pub fn total_bandwidth(bitrate: u32, frames_per_packet: u32, transport: Transport) -> u32 {
+    bitrate.saturating_add(overhead(frames_per_packet, transport))
+}
+
+/// Returns the highest bitrate whose [total_bandwidth] is within `limit`, 0 if the headers
+/// alone exceed it.
+pub fn max_bitrate_for(limit: u32, frames_per_packet: u32, transport: Transport) -> u32 {
+    limit.saturating_sub(overhead(frames_per_packet, transport))
+}
+
+/// Returns the bitrate and frames per packet the official client uses within `limit`, given
+/// the configured ones.
+///
+/// Like Mumble, this first sends more frames per packet and then lowers the bitrate in steps of
+/// 1000, but never below [MIN_BITRATE], even if the limit is still exceeded.
+pub fn adjust(
+    limit: u32,
+    bitrate: u32,
+    frames_per_packet: u32,
+    transport: Transport,
+) -> (u32, u32) {
+    let mut bitrate = bitrate;
+    let mut frames = frames_per_packet;
+    if total_bandwidth(bitrate, frames, transport) > limit {
+        if frames <= 4 && limit <= 32_000 {
+            frames = 4;
+        } else if frames == 1 && limit <= 64_000 {
+            frames = 2;
+        } else if frames == 2 && limit <= 48_000 {
+            frames = 4;
+        }
+        if total_bandwidth(bitrate, frames, transport) > limit {
+            loop {
+                bitrate = bitrate.saturating_sub(1000);
+                if bitrate <= MIN_BITRATE || total_bandwidth(bitrate, frames, transport) <= limit {
+                    break;
+                }
+            }
+        }
+    }
+    (bitrate.max(MIN_BITRATE), frames)
+}
+
+/// Returns the bitrate and frames per packet the official client uses within `limit` with its
+/// default settings.
+pub fn recommend(limit: u32, transport: Transport) -> (u32, u32) {
+    adjust(limit, DEFAULT_BITRATE, DEFAULT_FRAMES_PER_PACKET, transport)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn computes_bandwidth_like_mumble() {
+        // 37 bytes 50 times a second
+        assert_eq!(54_800, total_bandwidth(40_000, 2, Transport::Udp));
+        assert_eq!(59_600, total_bandwidth(40_000, 2, Transport::Tcp));
+        // 800 / 6 is rounded down
+        assert_eq!(41 * 133, overhead(6, Transport::Udp));
+        assert_eq!(57_200, max_bitrate_for(72_000, 2, Transport::Udp));
+        assert_eq!(0, max_bitrate_for(10_000, 1, Transport::Udp));
+        for frames in [1, 2, 4, 6] {
+            let bitrate = max_bitrate_for(72_000, frames, Transport::Tcp);
+            assert_eq!(72_000, total_bandwidth(bitrate, frames, Transport::Tcp));
+        }
+    }
+
+    #[test]
+    fn recommends_what_mumble_chooses() {
+        assert_eq!((40_000, 2), recommend(72_000, Transport::Udp));
+        // More frames per packet suffice
+        assert_eq!((40_000, 4), recommend(48_000, Transport::Udp));
+        assert_eq!((40_000, 2), adjust(64_000, 40_000, 1, Transport::Tcp));
+        // The bitrate has to be lowered as well
+        assert_eq!((24_000, 4), recommend(32_000, Transport::Udp));
+        assert_eq!((35_000, 2), adjust(55_000, 40_000, 2, Transport::Tcp));
+        // But not too far
+        assert_eq!((MIN_BITRATE, 4), recommend(10_000, Transport::Udp));
+        assert_eq!((MIN_BITRATE, 2), adjust(100_000, 5_000, 2, Transport::Udp));
+    }
+}
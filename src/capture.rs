@@ -0,0 +1,415 @@
+//! Recording and replaying of packet captures
+//!
+//! A capture file starts with the magic bytes `MUMBLECAP` followed by a one byte format version.
+//! It is followed by any amount of entries, each consisting of
+//!
+//! - a one byte record kind (`0` for control packets, `1` for voice datagrams),
+//! - a one byte [Direction] (`0` for [Direction::Serverbound], `1` for
+//!   [Direction::Clientbound]),
+//! - the time since the start of the capture in microseconds as a big-endian `u64` and
+//! - for control packets, the packet as it is sent over the control channel (see
+//!   [write_frame]), or
+//! - for voice datagrams, the length of the (unencrypted) datagram as a big-endian `u32`
+//!   followed by its contents.
+//!
+//! All numbers are big-endian.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use crate::control::read_frame;
+use crate::control::write_frame;
+use crate::control::RawControlPacket;
+use crate::control::DEFAULT_MAX_PAYLOAD;
+use crate::error::Error;
+use crate::voice::Direction;
+
+/// Magic bytes at the start of every capture file.
+const MAGIC: &[u8] = b"MUMBLECAP";
+/// Version of the capture file format written by [PacketRecorder].
+const VERSION: u8 = 1;
+
+const KIND_CONTROL: u8 = 0;
+const KIND_VOICE: u8 = 1;
+
+/// The packet contained in an [Entry].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    /// A packet sent over the control channel.
+    Control(RawControlPacket),
+    /// An unencrypted voice datagram.
+    Voice(Bytes),
+}
+
+/// A single packet in a capture.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// Time since the start of the capture.
+    pub timestamp: Duration,
+    /// Direction the packet was traveling in.
+    pub direction: Direction,
+    /// The packet itself.
+    pub record: Record,
+}
+
+/// Writes packets with timestamps to a capture file.
+///
+/// See the [module documentation](self) for the file format.
+#[derive(Debug)]
+pub struct PacketRecorder<W: Write> {
+    writer: W,
+    start: Instant,
+}
+
+impl<W: Write> PacketRecorder<W> {
+    /// Starts a new capture, writing the file header to `writer`.
+    ///
+    /// Timestamps of recorded packets are relative to the time this is called.
+    pub fn new(mut writer: W) -> Result<Self, Error> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        Ok(PacketRecorder {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Records a control packet with the current time.
+    pub fn record_control(
+        &mut self,
+        direction: Direction,
+        packet: &RawControlPacket,
+    ) -> Result<(), Error> {
+        let timestamp = self.start.elapsed();
+        self.write_header(KIND_CONTROL, direction, timestamp)?;
+        write_frame(&mut self.writer, packet)
+    }
+
+    /// Records an unencrypted voice datagram with the current time.
+    pub fn record_voice(&mut self, direction: Direction, datagram: &[u8]) -> Result<(), Error> {
+        let timestamp = self.start.elapsed();
+        self.write_header(KIND_VOICE, direction, timestamp)?;
+        self.write_voice(datagram)
+    }
+
+    /// Writes an entry with its own timestamp, e.g. when converting an existing capture.
+    pub fn write_entry(&mut self, entry: &Entry) -> Result<(), Error> {
+        match &entry.record {
+            Record::Control(packet) => {
+                self.write_header(KIND_CONTROL, entry.direction, entry.timestamp)?;
+                write_frame(&mut self.writer, packet)
+            }
+            Record::Voice(datagram) => {
+                self.write_header(KIND_VOICE, entry.direction, entry.timestamp)?;
+                self.write_voice(datagram)
+            }
+        }
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.writer.flush()?)
+    }
+
+    /// Unwraps the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_header(
+        &mut self,
+        kind: u8,
+        direction: Direction,
+        timestamp: Duration,
+    ) -> Result<(), Error> {
+        let direction = match direction {
+            Direction::Serverbound => 0,
+            Direction::Clientbound => 1,
+        };
+        self.writer.write_all(&[kind, direction])?;
+        self.writer
+            .write_all(&(timestamp.as_micros() as u64).to_be_bytes())?;
+        Ok(())
+    }
+
+    fn write_voice(&mut self, datagram: &[u8]) -> Result<(), Error> {
+        if datagram.len() > DEFAULT_MAX_PAYLOAD {
+            return Err(Error::PacketTooLong {
+                declared: datagram.len(),
+                limit: DEFAULT_MAX_PAYLOAD,
+            });
+        }
+        self.writer
+            .write_all(&(datagram.len() as u32).to_be_bytes())?;
+        self.writer.write_all(datagram)?;
+        Ok(())
+    }
+}
+
+/// How fast a [PacketReader] replays a capture as a `Stream`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timing {
+    /// Each entry is yielded at its timestamp relative to the start of the replay.
+    Original,
+    /// All entries are yielded immediately.
+    AsFastAsPossible,
+}
+
+/// Reads the entries of a capture file written by [PacketRecorder].
+///
+/// Iterating over it yields all entries in order and stops after the first error.
+#[derive(Debug)]
+pub struct PacketReader<R: Read> {
+    reader: R,
+    failed: bool,
+}
+
+impl<R: Read> PacketReader<R> {
+    /// Reads and validates the file header from `reader`.
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut header = [0; MAGIC.len() + 1];
+        reader.read_exact(&mut header)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(invalid_data("not a packet capture"));
+        }
+        if header[MAGIC.len()] != VERSION {
+            return Err(invalid_data("unsupported packet capture version"));
+        }
+        Ok(PacketReader {
+            reader,
+            failed: false,
+        })
+    }
+
+    /// Reads the next entry, returning `None` at the end of the capture.
+    pub fn read_entry(&mut self) -> Result<Option<Entry>, Error> {
+        let mut kind = [0];
+        loop {
+            match self.reader.read(&mut kind) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let mut header = [0; 9];
+        self.reader.read_exact(&mut header)?;
+        let direction = match header[0] {
+            0 => Direction::Serverbound,
+            1 => Direction::Clientbound,
+            _ => return Err(invalid_data("invalid direction in packet capture")),
+        };
+        let timestamp = u64::from_be_bytes(header[1..].try_into().expect("length is 8"));
+        let record = match kind[0] {
+            KIND_CONTROL => Record::Control(read_frame(&mut self.reader)?),
+            KIND_VOICE => {
+                let mut len = [0; 4];
+                self.reader.read_exact(&mut len)?;
+                let len = u32::from_be_bytes(len) as usize;
+                if len > DEFAULT_MAX_PAYLOAD {
+                    return Err(Error::PacketTooLong {
+                        declared: len,
+                        limit: DEFAULT_MAX_PAYLOAD,
+                    });
+                }
+                let mut datagram = vec![0; len];
+                self.reader.read_exact(&mut datagram)?;
+                Record::Voice(datagram.into())
+            }
+            _ => return Err(invalid_data("invalid record kind in packet capture")),
+        };
+        Ok(Some(Entry {
+            timestamp: Duration::from_micros(timestamp),
+            direction,
+            record,
+        }))
+    }
+
+    /// Unwraps the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Replays the capture as a `Stream`.
+    ///
+    /// Note that entries are read from the underlying reader synchronously, so it should not
+    /// block for long (e.g. a file or an in-memory buffer).
+    #[cfg(feature = "tokio-codec")]
+    pub fn into_stream(
+        self,
+        timing: Timing,
+    ) -> impl futures_util::Stream<Item = Result<Entry, Error>> {
+        let start = tokio::time::Instant::now();
+        futures_util::stream::unfold(self, move |mut reader| async move {
+            let entry = reader.next()?;
+            if let (Ok(entry), Timing::Original) = (&entry, timing) {
+                tokio::time::sleep_until(start + entry.timestamp).await;
+            }
+            Some((entry, reader))
+        })
+    }
+}
+
+impl<R: Read> Iterator for PacketReader<R> {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.read_entry().transpose();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
+
+fn invalid_data(msg: &'static str) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::msgs;
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry {
+                timestamp: Duration::from_millis(0),
+                direction: Direction::Serverbound,
+                record: Record::Control(RawControlPacket {
+                    id: msgs::id::Version,
+                    bytes: Bytes::from_static(b"version"),
+                }),
+            },
+            Entry {
+                timestamp: Duration::from_millis(20),
+                direction: Direction::Clientbound,
+                record: Record::Voice(Bytes::from_static(&[0x20, 0x05])),
+            },
+        ]
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let mut recorder = PacketRecorder::new(Vec::new()).unwrap();
+        for entry in entries() {
+            recorder.write_entry(&entry).unwrap();
+        }
+        let data = recorder.into_inner();
+
+        let reader = PacketReader::new(&data[..]).unwrap();
+        let read = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries(), read);
+
+        let mut reader = PacketReader::new(&data[..data.len() - 1]).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+
+        assert!(PacketReader::new(&b"NOTACAPTURE"[..]).is_err());
+    }
+
+    #[test]
+    fn retries_interrupted_reads() {
+        /// Reader which fails with `Interrupted` before every successful read.
+        struct Flaky<'a> {
+            data: &'a [u8],
+            interrupt: bool,
+        }
+
+        impl Read for Flaky<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.interrupt = !self.interrupt;
+                if self.interrupt {
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                self.data.read(buf)
+            }
+        }
+
+        let mut recorder = PacketRecorder::new(Vec::new()).unwrap();
+        for entry in entries() {
+            recorder.write_entry(&entry).unwrap();
+        }
+        let data = recorder.into_inner();
+
+        let reader = PacketReader::new(Flaky {
+            data: &data,
+            interrupt: false,
+        })
+        .unwrap();
+        assert_eq!(entries(), reader.collect::<Result<Vec<_>, _>>().unwrap());
+    }
+
+    #[test]
+    fn recorded_packets_are_timestamped() {
+        let mut recorder = PacketRecorder::new(Vec::new()).unwrap();
+        recorder
+            .record_voice(Direction::Serverbound, &[0x20, 0x01])
+            .unwrap();
+        let data = recorder.into_inner();
+
+        let mut reader = PacketReader::new(&data[..]).unwrap();
+        let entry = reader.read_entry().unwrap().unwrap();
+        assert_eq!(Direction::Serverbound, entry.direction);
+        assert_eq!(
+            Record::Voice(Bytes::from_static(&[0x20, 0x01])),
+            entry.record
+        );
+        assert!(entry.timestamp < Duration::from_secs(10));
+        assert!(reader.read_entry().unwrap().is_none());
+    }
+
+    #[cfg(feature = "tokio-codec")]
+    #[test]
+    fn replay_as_stream() {
+        use futures::StreamExt;
+
+        let mut recorder = PacketRecorder::new(Vec::new()).unwrap();
+        for entry in entries() {
+            recorder.write_entry(&entry).unwrap();
+        }
+        let data = recorder.into_inner();
+
+        let reader = PacketReader::new(&data[..]).unwrap();
+        let stream = reader.into_stream(Timing::AsFastAsPossible);
+        let read = futures::executor::block_on(stream.collect::<Vec<_>>());
+        assert_eq!(
+            entries(),
+            read.into_iter().collect::<Result<Vec<_>, _>>().unwrap()
+        );
+    }
+
+    #[cfg(feature = "tokio-codec")]
+    #[tokio::test(start_paused = true)]
+    async fn replays_with_original_timing() {
+        use futures::StreamExt;
+
+        let mut recorder = PacketRecorder::new(Vec::new()).unwrap();
+        for entry in entries() {
+            recorder.write_entry(&entry).unwrap();
+        }
+        let data = recorder.into_inner();
+
+        let start = tokio::time::Instant::now();
+        let reader = PacketReader::new(&data[..]).unwrap();
+        let offsets = reader
+            .into_stream(Timing::Original)
+            .map(|entry| (entry.unwrap().timestamp, start.elapsed()))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            vec![
+                (Duration::from_millis(0), Duration::from_millis(0)),
+                (Duration::from_millis(20), Duration::from_millis(20)),
+            ],
+            offsets
+        );
+    }
+}
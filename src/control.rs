@@ -1,21 +1,68 @@
 //! Control channel messages and codecs
 
+use std::fmt;
 use std::io;
-use std::io::Cursor;
 use std::marker::PhantomData;
 
 use bytes::Buf;
 use bytes::BufMut;
 use bytes::Bytes;
 use bytes::BytesMut;
-use protobuf::Error as ProtobufError;
-use protobuf::Message;
 
+use self::backend::Message;
+use crate::error::Error;
 use crate::voice::Clientbound;
+use crate::voice::Direction;
 use crate::voice::Serverbound;
 use crate::voice::VoiceCodec;
 use crate::voice::VoicePacket;
 use crate::voice::VoicePacketDst;
+use crate::voice_v2;
+use crate::voice_v2::VoiceCodecV2;
+use crate::voice_v2::VoicePacketV2;
+use limits::MessageLimits;
+use validate::Validate;
+use validate::ValidationIssue;
+
+pub mod acl;
+pub mod authenticate;
+pub(crate) mod backend;
+pub mod ban;
+pub mod channel_state;
+pub mod codec_version;
+pub mod context_action;
+pub mod crypt_setup;
+pub mod dispatch;
+mod display;
+#[cfg(feature = "webrtc-extensions")]
+pub mod ice_candidate;
+pub mod limits;
+pub mod permission_denied;
+pub mod permissions;
+pub mod ping_report;
+#[cfg(not(feature = "webrtc-extensions"))]
+pub mod plugin_data;
+pub mod priority;
+pub mod query_users;
+pub mod rate_limit;
+pub mod registration;
+pub mod reject;
+pub mod request_blob;
+pub mod server_config;
+#[cfg(any(feature = "tokio-codec", feature = "asynchronous-codec"))]
+pub mod sink;
+pub mod suggest_config;
+pub mod sync;
+pub mod text_message;
+pub mod tokens;
+pub mod user_list;
+pub mod user_remove;
+pub mod user_state;
+pub mod user_stats;
+pub mod validate;
+pub mod voice_target;
+#[cfg(feature = "webrtc-extensions")]
+pub mod webrtc;
 
 /// ProtoBuf message types for all Mumble messages.
 #[allow(renamed_and_removed_lints)] // protobuf is missing `clippy::` prefix
@@ -26,11 +73,28 @@ pub mod msgs {
         pub use super::super::generated_id::*;
     }
 
+    #[cfg(any(feature = "protobuf", feature = "prost"))]
     include!(concat!(env!("OUT_DIR"), "/proto/mod.rs"));
 }
 
+/// The protobuf definitions the messages in [msgs] were generated from.
+///
+/// With the `protobuf` feature, the parsed form is available via `file_descriptor`.
+#[cfg(not(feature = "webrtc-extensions"))]
+pub const MUMBLE_PROTO: &str = include_str!("../protos/Mumble.proto");
+/// The protobuf definitions the messages in [msgs] were generated from.
+///
+/// With the `protobuf` feature, the parsed form is available via `file_descriptor`.
+#[cfg(feature = "webrtc-extensions")]
+pub const MUMBLE_PROTO: &str = include_str!("../protos/MumbleWithWebRTC.proto");
+
+/// Returns the descriptor of all messages in [msgs], e.g. for parsing messages dynamically.
+#[cfg(feature = "protobuf")]
+pub use msgs::file_descriptor;
+
 /// Raw/not-yet-parsed Mumble control packet.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RawControlPacket {
     /// Packet ID
     ///
@@ -40,42 +104,270 @@ pub struct RawControlPacket {
     pub bytes: Bytes,
 }
 
+impl RawControlPacket {
+    /// Serializes an arbitrary protobuf message into a packet with the given ID.
+    ///
+    /// Useful for messages unknown to this crate, known ones can simply be converted via `From`.
+    pub fn from_message<M: Message>(id: u16, msg: &M) -> Result<Self, Error> {
+        Ok(RawControlPacket {
+            id,
+            bytes: backend::to_bytes(msg)?,
+        })
+    }
+
+    /// Parses the message bytes as an arbitrary protobuf message.
+    ///
+    /// Note that, unlike the `TryFrom` impls of known messages and [ControlPacketRef::parse_as],
+    /// this does not check the packet ID.
+    pub fn parse_message<M: Message + Default>(&self) -> Result<M, Error> {
+        backend::parse(&self.bytes)
+    }
+
+    /// Returns the type of this packet, or `None` if its ID is unknown.
+    pub fn kind(&self) -> Option<PacketKind> {
+        self.id.try_into().ok()
+    }
+
+    /// Returns a borrowed view of this packet which allows for inspecting it without parsing.
+    pub fn view(&self) -> ControlPacketRef<'_> {
+        ControlPacketRef { raw: self }
+    }
+
+    /// Returns a `UDPTunnel` packet carrying an already encoded voice packet, see
+    /// [VoicePacket::encode_shared].
+    ///
+    /// Encoded as [ControlPacket::Other], this is sent without running the [VoiceCodec] again.
+    pub fn udp_tunnel(voice: Bytes) -> Self {
+        RawControlPacket {
+            id: msgs::id::UDPTunnel,
+            bytes: voice,
+        }
+    }
+}
+
+/// Borrowed view of a [RawControlPacket] which only parses the message once asked to.
+///
+/// Useful for e.g. relays which only need to look at most packets' ids and forward their bytes
+/// unchanged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ControlPacketRef<'a> {
+    raw: &'a RawControlPacket,
+}
+
+impl<'a> ControlPacketRef<'a> {
+    /// Returns the packet ID.
+    ///
+    /// See [msgs::id].
+    pub fn id(&self) -> u16 {
+        self.raw.id
+    }
+
+    /// Returns the type of this packet, or `None` if its ID is unknown.
+    pub fn kind(&self) -> Option<PacketKind> {
+        self.raw.kind()
+    }
+
+    /// Returns the raw, unparsed message bytes.
+    pub fn bytes(&self) -> &'a Bytes {
+        &self.raw.bytes
+    }
+
+    /// Returns the underlying raw packet.
+    pub fn raw(&self) -> &'a RawControlPacket {
+        self.raw
+    }
+
+    /// Parses the message into a [ControlPacket] of whichever type its id indicates.
+    ///
+    /// The underlying bytes are shared, not copied.
+    pub fn parse<Dst: VoicePacketDst>(&self) -> Result<ControlPacket<Dst>, Error> {
+        self.raw.clone().try_into()
+    }
+
+    /// Parses the message as a specific type, e.g. [msgs::UserState].
+    ///
+    /// Fails if the packet id does not match the requested type.
+    pub fn parse_as<T: TryFrom<RawControlPacket>>(&self) -> Result<T, T::Error> {
+        self.raw.clone().try_into()
+    }
+}
+
+impl From<PacketKind> for u16 {
+    fn from(kind: PacketKind) -> Self {
+        kind.id()
+    }
+}
+
+impl fmt::Display for PacketKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Returns whether the packet with the given ID may be sent in the given direction.
+///
+/// Unknown packet IDs are always considered valid.
+pub fn is_valid_for_direction(id: u16, direction: Direction) -> bool {
+    PacketKind::try_from(id).map_or(true, |kind| kind.is_valid_for_direction(direction))
+}
+
+/// Returns the internal name of the packet with the given ID, if it is known.
+pub fn name_for_id(id: u16) -> Option<&'static str> {
+    PacketKind::try_from(id).ok().map(PacketKind::name)
+}
+
+/// Returns the ID of the packet with the given name, if it is known.
+///
+/// Names are matched case-insensitively.
+pub fn id_for_name(name: &str) -> Option<u16> {
+    PacketKind::ALL
+        .iter()
+        .find(|kind| kind.name().eq_ignore_ascii_case(name))
+        .map(|kind| kind.id())
+}
+
+/// Error returned when converting an unknown packet ID into a [PacketKind].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownPacketId(pub u16);
+
+impl fmt::Display for UnknownPacketId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown packet id {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownPacketId {}
+
+/// Default maximum payload length accepted by [RawControlCodec].
+///
+/// This is also the largest payload the codec is willing to encode, since that is the limit
+/// enforced by the reference implementation.
+pub const DEFAULT_MAX_PAYLOAD: usize = 0x7f_ffff;
+
+/// Outcome of decoding all complete packets in a buffer at once.
+///
+/// See [RawControlCodec::decode_all] and [ControlCodec::decode_all].
+#[derive(Debug)]
+pub struct DecodeAll<T> {
+    /// Packets which were successfully decoded, in order.
+    pub packets: Vec<T>,
+    /// Amount of bytes which belong to a trailing, incomplete packet.
+    pub remaining: usize,
+    /// The error which stopped decoding, if a malformed packet was encountered.
+    pub error: Option<Error>,
+}
+
 /// A `Codec` implementation that parses a stream of data into [RawControlPacket]s.
 #[derive(Debug)]
-pub struct RawControlCodec;
+pub struct RawControlCodec {
+    max_payload: usize,
+    /// Id and length of the packet whose header has already been consumed.
+    header: Option<(u16, usize)>,
+}
 
 impl RawControlCodec {
     /// Creates a new RawControlCodec.
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Creates a new RawControlCodec which rejects packets with payloads longer than
+    /// `max_payload` bytes.
+    pub fn with_max_payload(max_payload: usize) -> Self {
+        RawControlCodec {
+            max_payload,
+            header: None,
+        }
+    }
+
+    /// Returns the maximum accepted payload length in bytes.
+    pub fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    /// Changes the maximum accepted payload length in bytes.
+    ///
+    /// Takes effect starting with the next packet header which is read.
+    pub fn set_max_payload(&mut self, max_payload: usize) {
+        self.max_payload = max_payload;
+    }
 }
 
 impl Default for RawControlCodec {
     fn default() -> Self {
-        RawControlCodec
+        RawControlCodec::with_max_payload(DEFAULT_MAX_PAYLOAD)
     }
 }
 
 impl RawControlCodec {
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<RawControlPacket>, io::Error> {
-        let buf_len = buf.len();
-        if buf_len >= 6 {
-            let mut buf = Cursor::new(buf);
-            let id = buf.get_u16();
-            let len = buf.get_u32() as usize;
-            if len > 0x7f_ffff {
-                Err(io::Error::new(io::ErrorKind::Other, "packet too long"))
-            } else if buf_len >= 6 + len {
-                let mut bytes = buf.into_inner().split_to(6 + len);
-                bytes.advance(6);
-                let bytes = bytes.freeze();
-                Ok(Some(RawControlPacket { id, bytes }))
-            } else {
-                Ok(None)
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<RawControlPacket>, Error> {
+        let (id, len) = match self.header {
+            Some(header) => header,
+            None => {
+                if buf.len() < 6 {
+                    return Ok(None);
+                }
+                let (id, len) = parse_header(&buf[..6], self.max_payload)?;
+                buf.advance(6);
+                self.header = Some((id, len));
+                (id, len)
             }
-        } else {
-            Ok(None)
+        };
+        if buf.len() < len {
+            // Make room for the rest of the packet so it doesn't have to grow piecemeal
+            buf.reserve(len - buf.len());
+            return Ok(None);
+        }
+        self.header = None;
+        let bytes = buf.split_to(len).freeze();
+        Ok(Some(RawControlPacket { id, bytes }))
+    }
+
+    /// Decodes all complete packets contained in `buf`.
+    ///
+    /// Decoding stops at the end of the buffer or at the first malformed packet, whichever comes
+    /// first. Any trailing partial packet is left in the buffer (or, if its header has already
+    /// been read, in the codec) and is accounted for in [DecodeAll::remaining].
+    pub fn decode_all(&mut self, buf: &mut BytesMut) -> DecodeAll<RawControlPacket> {
+        let mut packets = Vec::new();
+        let error = loop {
+            match self.decode(buf) {
+                Ok(Some(packet)) => packets.push(packet),
+                Ok(None) => break None,
+                Err(err) => break Some(err),
+            }
+        };
+        DecodeAll {
+            packets,
+            remaining: self.pending_len(buf),
+            error,
+        }
+    }
+
+    /// Returns the amount of bytes of a partially received packet, including its header.
+    fn pending_len(&self, buf: &BytesMut) -> usize {
+        match self.header {
+            Some(_) => 6 + buf.len(),
+            None => buf.len(),
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<RawControlPacket>, Error> {
+        if let Some(packet) = self.decode(buf)? {
+            return Ok(Some(packet));
+        }
+        match self.header {
+            None if buf.is_empty() => Ok(None),
+            None => Err(Error::Truncated {
+                id: None,
+                received: buf.len(),
+                expected: 6,
+            }),
+            Some((id, len)) => Err(Error::Truncated {
+                id: Some(id),
+                received: buf.len(),
+                expected: len,
+            }),
         }
     }
 }
@@ -83,41 +375,140 @@ impl RawControlCodec {
 #[cfg(feature = "tokio-codec")]
 impl tokio_util::codec::Decoder for RawControlCodec {
     type Item = RawControlPacket;
-    type Error = io::Error;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         self.decode(src)
     }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode_eof(src)
+    }
 }
 
 #[cfg(feature = "asynchronous-codec")]
 impl asynchronous_codec::Decoder for RawControlCodec {
     type Item = RawControlPacket;
-    type Error = io::Error;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         self.decode(src)
     }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode_eof(src)
+    }
 }
 
 impl RawControlCodec {
-    fn encode(&mut self, item: RawControlPacket, dst: &mut BytesMut) -> Result<(), io::Error> {
-        let id = item.id;
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    fn encode(&mut self, item: RawControlPacket, dst: &mut BytesMut) -> Result<(), Error> {
+        self.encode_ref(&item, dst)
+    }
+
+    /// Encodes a packet without taking ownership of it.
+    pub fn encode_ref(&mut self, item: &RawControlPacket, dst: &mut BytesMut) -> Result<(), Error> {
         let bytes = &item.bytes;
         let len = bytes.len();
+        let header = encode_header(item.id, len)?;
         dst.reserve(6 + len);
-        dst.put_u16(id);
-        dst.put_u32(len as u32);
+        dst.put_slice(&header);
         dst.put_slice(bytes);
         Ok(())
     }
 }
 
+/// Parses a 6 byte packet header into packet id and payload length.
+fn parse_header(header: &[u8], max_payload: usize) -> Result<(u16, usize), Error> {
+    let id = u16::from_be_bytes([header[0], header[1]]);
+    let len = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+    if len > max_payload {
+        return Err(Error::PacketTooLong {
+            declared: len,
+            limit: max_payload,
+        });
+    }
+    Ok((id, len))
+}
+
+/// Builds the 6 byte header for a packet, rejecting payloads which are too long to be sent.
+fn encode_header(id: u16, len: usize) -> Result<[u8; 6], Error> {
+    if len > DEFAULT_MAX_PAYLOAD {
+        return Err(Error::PacketTooLong {
+            declared: len,
+            limit: DEFAULT_MAX_PAYLOAD,
+        });
+    }
+    let mut header = [0; 6];
+    header[..2].copy_from_slice(&id.to_be_bytes());
+    header[2..].copy_from_slice(&(len as u32).to_be_bytes());
+    Ok(header)
+}
+
+/// Writes a single packet, including its header, to `w`.
+///
+/// Packets with payloads longer than [DEFAULT_MAX_PAYLOAD] are rejected, just like
+/// [RawControlCodec] does.
+pub fn write_frame<W: io::Write>(mut w: W, packet: &RawControlPacket) -> Result<(), Error> {
+    let header = encode_header(packet.id, packet.bytes.len())?;
+    w.write_all(&header)?;
+    w.write_all(&packet.bytes)?;
+    Ok(())
+}
+
+/// Reads a single packet, including its header, from `r`.
+///
+/// Packets with payloads longer than [DEFAULT_MAX_PAYLOAD] are rejected, just like
+/// [RawControlCodec] does. If the reader reaches its end before the packet is complete (including
+/// when it is already at its end), [Error::Truncated] is returned.
+pub fn read_frame<R: io::Read>(mut r: R) -> Result<RawControlPacket, Error> {
+    let mut header = [0; 6];
+    let received = read_full(&mut r, &mut header)?;
+    if received < header.len() {
+        return Err(Error::Truncated {
+            id: None,
+            received,
+            expected: header.len(),
+        });
+    }
+    let (id, len) = parse_header(&header, DEFAULT_MAX_PAYLOAD)?;
+    let mut bytes = vec![0; len];
+    let received = read_full(&mut r, &mut bytes)?;
+    if received < len {
+        return Err(Error::Truncated {
+            id: Some(id),
+            received,
+            expected: len,
+        });
+    }
+    Ok(RawControlPacket {
+        id,
+        bytes: bytes.into(),
+    })
+}
+
+/// Fills `buf` from `r` unless the end is reached first, returning the amount of bytes read.
+fn read_full<R: io::Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
 #[cfg(feature = "tokio-codec")]
 impl tokio_util::codec::Encoder<RawControlPacket> for RawControlCodec {
-    type Error = io::Error;
+    type Error = Error;
 
-    fn encode(&mut self, item: RawControlPacket, dst: &mut BytesMut) -> Result<(), io::Error> {
+    fn encode(&mut self, item: RawControlPacket, dst: &mut BytesMut) -> Result<(), Error> {
         self.encode(item, dst)
     }
 }
@@ -125,13 +516,79 @@ impl tokio_util::codec::Encoder<RawControlPacket> for RawControlCodec {
 #[cfg(feature = "asynchronous-codec")]
 impl asynchronous_codec::Encoder for RawControlCodec {
     type Item = RawControlPacket;
-    type Error = io::Error;
+    type Error = Error;
 
-    fn encode(&mut self, item: RawControlPacket, dst: &mut BytesMut) -> Result<(), io::Error> {
+    fn encode(&mut self, item: RawControlPacket, dst: &mut BytesMut) -> Result<(), Error> {
         self.encode(item, dst)
     }
 }
 
+/// Per-[PacketKind] counters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PacketCounters {
+    counts: [u64; PacketKind::ALL.len()],
+    other: u64,
+}
+
+impl PacketCounters {
+    /// Returns the counter for packets of unknown kind.
+    pub fn other(&self) -> u64 {
+        self.other
+    }
+
+    /// Returns the sum of all counters, including [other](Self::other).
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum::<u64>() + self.other
+    }
+
+    /// Returns an iterator over the counters of all known packet kinds.
+    pub fn iter(&self) -> impl Iterator<Item = (PacketKind, u64)> + '_ {
+        PacketKind::ALL
+            .iter()
+            .copied()
+            .zip(self.counts.iter().copied())
+    }
+
+    fn add(&mut self, id: u16, amount: u64) {
+        match PacketKind::try_from(id) {
+            Ok(kind) => self.counts[kind.id() as usize] += amount,
+            Err(_) => self.other += amount,
+        }
+    }
+}
+
+impl Default for PacketCounters {
+    fn default() -> Self {
+        PacketCounters {
+            counts: [0; PacketKind::ALL.len()],
+            other: 0,
+        }
+    }
+}
+
+impl std::ops::Index<PacketKind> for PacketCounters {
+    type Output = u64;
+
+    fn index(&self, kind: PacketKind) -> &u64 {
+        &self.counts[kind.id() as usize]
+    }
+}
+
+/// Packet and byte counters collected by a [ControlCodec].
+///
+/// Byte counts include the 6 byte packet header.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ControlCodecStats {
+    /// Amount of decoded packets per kind.
+    pub decoded: PacketCounters,
+    /// Amount of encoded packets per kind.
+    pub encoded: PacketCounters,
+    /// Amount of decoded bytes per kind.
+    pub decoded_bytes: PacketCounters,
+    /// Amount of encoded bytes per kind.
+    pub encoded_bytes: PacketCounters,
+}
+
 /// A `Codec` implementation that parses a stream of data into [ControlPacket]s.
 ///
 /// Since [VoicePacket]s can be tunneled over the control channel and their encoding and decoding
@@ -140,6 +597,12 @@ impl asynchronous_codec::Encoder for RawControlCodec {
 #[derive(Debug)]
 pub struct ControlCodec<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> {
     inner: RawControlCodec,
+    lenient: bool,
+    strict_direction: bool,
+    raw_tunnel: bool,
+    protobuf_tunnel: bool,
+    limits: Option<MessageLimits>,
+    stats: Option<Box<ControlCodecStats>>,
     _encode_dst: PhantomData<EncodeDst>,
     _decode_dst: PhantomData<DecodeDst>,
 }
@@ -153,27 +616,234 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> ControlCodec<EncodeDs
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Creates a new control codec which rejects packets with payloads longer than
+    /// `max_payload` bytes.
+    ///
+    /// See [RawControlCodec::with_max_payload].
+    pub fn with_max_payload(max_payload: usize) -> Self {
+        ControlCodec {
+            inner: RawControlCodec::with_max_payload(max_payload),
+            lenient: false,
+            strict_direction: false,
+            raw_tunnel: false,
+            protobuf_tunnel: false,
+            limits: None,
+            stats: None,
+            _encode_dst: PhantomData,
+            _decode_dst: PhantomData,
+        }
+    }
+
+    /// Creates a new control codec in lenient mode.
+    ///
+    /// See [set_lenient](Self::set_lenient).
+    pub fn new_lenient() -> Self {
+        let mut codec = Self::new();
+        codec.set_lenient(true);
+        codec
+    }
+
+    /// Creates a new control codec which collects per-packet statistics.
+    ///
+    /// See [stats](Self::stats).
+    pub fn with_stats() -> Self {
+        let mut codec = Self::new();
+        codec.stats = Some(Default::default());
+        codec
+    }
+
+    /// Returns the statistics collected by this codec, if it was created with
+    /// [with_stats](Self::with_stats).
+    pub fn stats(&self) -> Option<&ControlCodecStats> {
+        self.stats.as_deref()
+    }
+
+    /// Returns the statistics collected by this codec so far and resets them.
+    pub fn take_stats(&mut self) -> Option<ControlCodecStats> {
+        self.stats.as_deref_mut().map(std::mem::take)
+    }
+
+    /// Returns whether this codec is in lenient mode.
+    pub fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+
+    /// Enables or disables lenient mode.
+    ///
+    /// By default, a packet whose message fails to parse results in a decoding error, which
+    /// usually terminates the stream.
+    /// In lenient mode, such packets are instead returned as [ControlPacket::Other] so the
+    /// application can decide what to do with them.
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Returns whether this codec rejects packets which are invalid for the decode direction.
+    pub fn is_strict_direction(&self) -> bool {
+        self.strict_direction
+    }
+
+    /// Enables or disables rejection of packets which are invalid for the decode direction.
+    ///
+    /// E.g. a [ServerControlCodec] will refuse to decode a `ServerSync` packet coming from a
+    /// client. See [is_valid_for_direction] for which packets are accepted.
+    /// If the codec is also in lenient mode, such packets are returned as [ControlPacket::Other]
+    /// instead of causing an error.
+    ///
+    /// The accepted packets are determined by the `DecodeDst` type parameter, only whether they
+    /// are checked at all is decided at runtime. That way, strict and permissive codecs share a
+    /// type and the choice can be made per connection, e.g. from configuration, at the cost of a
+    /// single branch per decoded packet when disabled.
+    pub fn set_strict_direction(&mut self, strict_direction: bool) {
+        self.strict_direction = strict_direction;
+    }
+
+    /// Returns whether this codec leaves tunneled voice packets unparsed.
+    pub fn is_raw_tunnel(&self) -> bool {
+        self.raw_tunnel
+    }
+
+    /// Enables or disables parsing of tunneled voice packets.
+    ///
+    /// If enabled, `UDPTunnel` packets are decoded as [ControlPacket::Other] without ever being
+    /// passed through the [VoiceCodec]. Since [ControlPacket::Other] is encoded verbatim, this
+    /// allows forwarding tunneled voice at minimal cost. All other packets are parsed as usual.
+    pub fn set_raw_tunnel(&mut self, raw_tunnel: bool) {
+        self.raw_tunnel = raw_tunnel;
+    }
+
+    /// Returns whether this codec tunnels voice packets in the protobuf format of Mumble 1.5.
+    pub fn is_protobuf_tunnel(&self) -> bool {
+        self.protobuf_tunnel
+    }
+
+    /// Switches tunneled voice packets between the legacy and the protobuf format.
+    ///
+    /// Mumble 1.5 peers use the protobuf format of [voice_v2](crate::voice_v2) in `UDPTunnel`
+    /// packets once both sides announced support for it. Tunneled packets are still represented
+    /// as [VoicePacket]s, so the extended information of pings and volume adjustments are lost,
+    /// and encoding non-Opus audio fails with [VoiceError::UnsupportedCodec]. Use
+    /// [set_raw_tunnel](Self::set_raw_tunnel) together with
+    /// [VoiceCodecV2](crate::voice_v2::VoiceCodecV2) to access them.
+    ///
+    /// [VoiceError::UnsupportedCodec]: crate::error::VoiceError::UnsupportedCodec
+    pub fn set_protobuf_tunnel(&mut self, protobuf_tunnel: bool) {
+        self.protobuf_tunnel = protobuf_tunnel;
+    }
+
+    /// Returns the text message limits enforced by this codec, if any.
+    pub fn message_limits(&self) -> Option<&MessageLimits> {
+        self.limits.as_ref()
+    }
+
+    /// Installs or removes text message limits, usually read from the server's
+    /// [msgs::ServerConfig].
+    ///
+    /// Encoding a `TextMessage` which exceeds the limits fails with
+    /// [Error::TextMessageTooLong] without writing anything. Codecs decoding [Serverbound] packets
+    /// also fail that way when receiving one, even in lenient mode. The packet is consumed either
+    /// way, so decoding can continue with the next one. Codecs decoding [Clientbound] packets do
+    /// not check received messages, as the server is the one announcing the limits.
+    pub fn set_message_limits(&mut self, limits: Option<MessageLimits>) {
+        self.limits = limits;
+    }
+
+    /// Returns the maximum accepted payload length in bytes.
+    pub fn max_payload(&self) -> usize {
+        self.inner.max_payload()
+    }
+
+    /// Changes the maximum accepted payload length in bytes.
+    ///
+    /// See [RawControlCodec::set_max_payload].
+    pub fn set_max_payload(&mut self, max_payload: usize) {
+        self.inner.set_max_payload(max_payload);
+    }
 }
 
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> Default
     for ControlCodec<EncodeDst, DecodeDst>
 {
     fn default() -> Self {
-        ControlCodec {
-            inner: RawControlCodec::default(),
-            _encode_dst: PhantomData,
-            _decode_dst: PhantomData,
-        }
+        ControlCodec::with_max_payload(DEFAULT_MAX_PAYLOAD)
     }
 }
 
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> ControlCodec<EncodeDst, DecodeDst> {
-    fn decode(
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ControlPacket<DecodeDst>>, Error> {
+        Ok(if let Some(raw_packet) = self.inner.decode(src)? {
+            Some(self.parse(raw_packet)?)
+        } else {
+            None
+        })
+    }
+
+    fn parse(&mut self, raw_packet: RawControlPacket) -> Result<ControlPacket<DecodeDst>, Error> {
+        if let Some(stats) = &mut self.stats {
+            stats.decoded.add(raw_packet.id, 1);
+            stats
+                .decoded_bytes
+                .add(raw_packet.id, 6 + raw_packet.bytes.len() as u64);
+        }
+        if self.strict_direction && !is_valid_for_direction(raw_packet.id, DecodeDst::DIRECTION) {
+            return if self.lenient {
+                Ok(ControlPacket::Other(raw_packet))
+            } else {
+                Err(Error::InvalidDirection {
+                    id: raw_packet.id,
+                    direction: DecodeDst::DIRECTION,
+                })
+            };
+        }
+        if self.raw_tunnel && raw_packet.id == msgs::id::UDPTunnel {
+            return Ok(ControlPacket::Other(raw_packet));
+        }
+        let result = if self.protobuf_tunnel && raw_packet.id == msgs::id::UDPTunnel {
+            voice_v2::parse(&raw_packet.bytes)
+                .and_then(|packet| Ok(VoicePacket::try_from(packet)?))
+                .map(|packet| ControlPacket::UDPTunnel(Box::new(packet)))
+                .map_err(|err| Error::parse(raw_packet.id, &raw_packet.bytes, err))
+        } else {
+            ControlPacket::try_from(raw_packet.clone())
+        };
+        let packet = match result {
+            Ok(packet) => packet,
+            Err(_) if self.lenient => return Ok(ControlPacket::Other(raw_packet)),
+            Err(err) => return Err(err),
+        };
+        // Limits are announced by the server, so only it enforces them on received packets
+        if let (Some(limits), Direction::Serverbound) = (&self.limits, DecodeDst::DIRECTION) {
+            limits.check(&packet)?;
+        }
+        Ok(packet)
+    }
+
+    /// Decodes all complete packets contained in `src`.
+    ///
+    /// See [RawControlCodec::decode_all].
+    pub fn decode_all(&mut self, src: &mut BytesMut) -> DecodeAll<ControlPacket<DecodeDst>> {
+        let mut packets = Vec::new();
+        let error = loop {
+            match self.decode(src) {
+                Ok(Some(packet)) => packets.push(packet),
+                Ok(None) => break None,
+                Err(err) => break Some(err),
+            }
+        };
+        DecodeAll {
+            packets,
+            remaining: self.inner.pending_len(src),
+            error,
+        }
+    }
+
+    fn decode_eof(
         &mut self,
         src: &mut BytesMut,
-    ) -> Result<Option<ControlPacket<DecodeDst>>, io::Error> {
-        Ok(if let Some(raw_packet) = self.inner.decode(src)? {
-            Some(raw_packet.try_into()?)
+    ) -> Result<Option<ControlPacket<DecodeDst>>, Error> {
+        Ok(if let Some(raw_packet) = self.inner.decode_eof(src)? {
+            Some(self.parse(raw_packet)?)
         } else {
             None
         })
@@ -185,11 +855,15 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> tokio_util::codec::De
     for ControlCodec<EncodeDst, DecodeDst>
 {
     type Item = ControlPacket<DecodeDst>;
-    type Error = io::Error;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         self.decode(src)
     }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode_eof(src)
+    }
 }
 
 #[cfg(feature = "asynchronous-codec")]
@@ -197,25 +871,78 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::D
     for ControlCodec<EncodeDst, DecodeDst>
 {
     type Item = ControlPacket<DecodeDst>;
-    type Error = io::Error;
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         self.decode(src)
     }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode_eof(src)
+    }
+}
+
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> ControlCodec<EncodeDst, DecodeDst> {
+    fn encode(&mut self, item: ControlPacket<EncodeDst>, dst: &mut BytesMut) -> Result<(), Error> {
+        self.encode_ref(&item, dst)
+    }
+
+    /// Encodes a packet without taking ownership of it.
+    ///
+    /// This allows sending the same packet to many recipients without cloning it for each one.
+    pub fn encode_ref(
+        &mut self,
+        item: &ControlPacket<EncodeDst>,
+        dst: &mut BytesMut,
+    ) -> Result<(), Error> {
+        if let Some(limits) = &self.limits {
+            limits.check(item)?;
+        }
+        if let (true, ControlPacket::UDPTunnel(voice)) = (self.protobuf_tunnel, item) {
+            let mut bytes = BytesMut::new();
+            VoiceCodecV2::<EncodeDst, EncodeDst>::new()
+                .encode_ref(&VoicePacketV2::try_from((**voice).clone())?, &mut bytes)?;
+            let raw = RawControlPacket {
+                id: msgs::id::UDPTunnel,
+                bytes: bytes.freeze(),
+            };
+            return self.encode_ref(&ControlPacket::Other(raw), dst);
+        }
+        let id = item.id();
+        let start = dst.len();
+        dst.reserve(item.encoded_len());
+        dst.put_slice(&[0; 6]);
+        let header = item
+            .write_body(dst)
+            .and_then(|()| encode_header(id, dst.len() - start - 6));
+        let header = match header {
+            Ok(header) => header,
+            Err(err) => {
+                dst.truncate(start);
+                return Err(err);
+            }
+        };
+        dst[start..start + 6].copy_from_slice(&header);
+        if let Some(stats) = &mut self.stats {
+            stats.encoded.add(id, 1);
+            stats.encoded_bytes.add(id, (dst.len() - start) as u64);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(feature = "tokio-codec")]
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>
     tokio_util::codec::Encoder<ControlPacket<EncodeDst>> for ControlCodec<EncodeDst, DecodeDst>
 {
-    type Error = io::Error;
+    type Error = Error;
 
     fn encode(
         &mut self,
         item: ControlPacket<EncodeDst>,
         dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
-        self.inner.encode(item.into(), dst)
+        self.encode(item, dst)
     }
 }
 
@@ -224,88 +951,164 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::E
     for ControlCodec<EncodeDst, DecodeDst>
 {
     type Item = ControlPacket<EncodeDst>;
-    type Error = io::Error;
+    type Error = Error;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        self.inner.encode(item.into(), dst)
+        self.encode(item, dst)
     }
 }
 
-/// Generates packet to ID mappings which will end up in [msgs::ids].
-macro_rules! define_packet_mappings {
-    ( @def $id:expr, $name:ident) => {
-        #[allow(dead_code)]
-        #[allow(non_upper_case_globals)]
-        pub const $name: u16 = $id;
-    };
-    ( @rec $id:expr, $(#[$attr:meta])* $head:ident ) => {
-        $(#[$attr])*
-        define_packet_mappings!(@def $id, $head);
-    };
-    ( @rec $id:expr, $(#[$attr:meta])* $head:ident, $( $(#[$attr_tail:meta])* $tail:ident ),* ) => {
-        $(#[$attr])*
-        define_packet_mappings!(@def $id, $head);
-        define_packet_mappings!(@rec $id + 1, $($(#[$attr_tail])* $tail),*);
-    };
-    ( $( $(#[$attrs:meta])* $names:ident ),* ) => {
-        define_packet_mappings!(@rec 0, $($(#[$attrs])* $names),*);
-    };
+/// A message which can be contained in a [ControlPacket].
+///
+/// Implemented for all protobuf messages in [msgs] as well as [VoicePacket].
+/// See [ControlPacket::downcast_ref].
+pub trait ControlPacketMessage<Dst: VoicePacketDst>: Sized {
+    /// The kind of packet containing messages of this type.
+    const KIND: PacketKind;
+
+    /// Returns a reference to the message if the packet contains one of this type.
+    fn from_packet_ref(packet: &ControlPacket<Dst>) -> Option<&Self>;
+    /// Returns a mutable reference to the message if the packet contains one of this type.
+    fn from_packet_mut(packet: &mut ControlPacket<Dst>) -> Option<&mut Self>;
 }
 
-/// Generates From impls for converting between RawCtrlPck <=> ProtoMsg => CtrlPck
-macro_rules! define_packet_from {
-    ( $Dst:ident UDPTunnel($type:ty) ) => {
-        impl<$Dst: VoicePacketDst> From<VoicePacket<Dst>> for RawControlPacket {
-            fn from(msg: VoicePacket<Dst>) -> Self {
-                let mut buf = BytesMut::new();
-
-                cfg_if::cfg_if! {
-                    if #[cfg(feature = "asynchronous-codec")] {
-                        use asynchronous_codec::Encoder as _;
-                    } else {
-                        use tokio_util::codec::Encoder as _;
-                    }
-                }
+impl<Dst: VoicePacketDst> ControlPacket<Dst> {
+    /// Returns a reference to the contained message if it is of type `M`.
+    pub fn downcast_ref<M: ControlPacketMessage<Dst>>(&self) -> Option<&M> {
+        M::from_packet_ref(self)
+    }
 
-                VoiceCodec::<Dst, Dst>::default()
-                    .encode(msg, &mut buf)
-                    .expect("VoiceEncoder is infallible");
+    /// Returns a mutable reference to the contained message if it is of type `M`.
+    pub fn downcast_mut<M: ControlPacketMessage<Dst>>(&mut self) -> Option<&mut M> {
+        M::from_packet_mut(self)
+    }
+}
 
-                Self {
-                    id: msgs::id::UDPTunnel,
-                    bytes: buf.freeze(),
-                }
-            }
-        }
-        impl<$Dst: VoicePacketDst> TryFrom<RawControlPacket> for VoicePacket<$Dst> {
-            type Error = io::Error;
+/// Serialized body of a control packet, without the header.
+trait PacketBody {
+    fn body_len(&self) -> usize;
+    fn write_body(&self, dst: &mut BytesMut) -> Result<(), Error>;
+}
 
-            fn try_from(packet: RawControlPacket) -> Result<Self, Self::Error> {
-                if packet.id == msgs::id::UDPTunnel {
+/// Human-readable representation of a control packet, see the `Display` impl of [ControlPacket].
+trait DisplayPacket {
+    fn fmt_packet(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+}
+
+/// Generates packet to ID mappings which will end up in [msgs::id].
+macro_rules! define_packet_mappings {
+    ( $( $(#[$attr:meta])* $name:ident ),* ) => {
+        $(
+            $(#[$attr])*
+            #[allow(dead_code)]
+            #[allow(non_upper_case_globals)]
+            pub const $name: u16 = super::PacketKind::$name as u16;
+        )*
+    };
+}
+
+/// Generates the PacketKind enum and its conversions.
+///
+/// Packet IDs are assigned in declaration order, so feature-gated packets must come last.
+macro_rules! define_packet_kind {
+    ( $( $(#[$attr:meta])* $name:ident [$($dir:ident),*] ),* ) => {
+        /// The type of a Mumble control packet, as identified by its packet ID.
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        #[repr(u16)]
+        #[non_exhaustive]
+        pub enum PacketKind {
+            $(
+                #[allow(missing_docs)]
+                $(#[$attr])*
+                $name,
+            )*
+        }
+        impl PacketKind {
+            /// All known packet kinds, ordered by packet ID.
+            pub const ALL: &'static [PacketKind] = &[
+                $(
+                    $(#[$attr])*
+                    PacketKind::$name,
+                )*
+            ];
+
+            /// Returns the packet ID of this kind.
+            pub fn id(self) -> u16 {
+                self as u16
+            }
+
+            /// Returns the internal name of this packet kind.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        PacketKind::$name => stringify!($name),
+                    )*
+                }
+            }
+
+            /// Returns whether packets of this kind may be sent in the given direction.
+            pub fn is_valid_for_direction(self, direction: Direction) -> bool {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        PacketKind::$name => matches!(direction, $(Direction::$dir)|*),
+                    )*
+                }
+            }
+        }
+        impl TryFrom<u16> for PacketKind {
+            type Error = UnknownPacketId;
+
+            fn try_from(id: u16) -> Result<Self, Self::Error> {
+                match id {
+                    $(
+                        $(#[$attr])*
+                        msgs::id::$name => Ok(PacketKind::$name),
+                    )*
+                    _ => Err(UnknownPacketId(id)),
+                }
+            }
+        }
+    };
+}
+
+/// Generates From impls for converting between RawCtrlPck <=> ProtoMsg => CtrlPck
+macro_rules! define_packet_from {
+    ( $Dst:ident UDPTunnel($type:ty) ) => {
+        /// Fails if the packet cannot be encoded, e.g. because an audio frame is too long for its
+        /// length header.
+        impl<$Dst: VoicePacketDst> TryFrom<VoicePacket<$Dst>> for RawControlPacket {
+            type Error = Error;
+
+            fn try_from(msg: VoicePacket<$Dst>) -> Result<Self, Self::Error> {
+                let mut buf = BytesMut::with_capacity(msg.encoded_len());
+                msg.write_body(&mut buf)?;
+                Ok(Self {
+                    id: msgs::id::UDPTunnel,
+                    bytes: buf.freeze(),
+                })
+            }
+        }
+        impl<$Dst: VoicePacketDst> TryFrom<RawControlPacket> for VoicePacket<$Dst> {
+            type Error = Error;
+
+            fn try_from(packet: RawControlPacket) -> Result<Self, Self::Error> {
+                if packet.id == msgs::id::UDPTunnel {
                     packet.bytes.try_into()
                 } else {
-                    Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        concat!("expected packet of type ", stringify!(UDPTunnel)),
-                    ))
+                    Err(Error::WrongPacketType {
+                        expected: msgs::id::UDPTunnel,
+                        actual: packet.id,
+                    })
                 }
             }
         }
         impl<$Dst: VoicePacketDst> TryFrom<Bytes> for VoicePacket<$Dst> {
-            type Error = io::Error;
+            type Error = Error;
 
             fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
-                cfg_if::cfg_if! {
-                    if #[cfg(feature = "asynchronous-codec")] {
-                        use asynchronous_codec::Decoder as _;
-                    } else {
-                        use tokio_util::codec::Decoder as _;
-                    }
-                }
-
-                VoiceCodec::<$Dst, $Dst>::default()
-                    .decode(&mut BytesMut::from(bytes.as_ref()))
-                    .map(|it| it.expect("VoiceCodec is stateless"))
+                VoiceCodec::<$Dst, $Dst>::default().decode_bytes(bytes)
             }
         }
         impl<$Dst: VoicePacketDst> From<$type> for ControlPacket<$Dst> {
@@ -313,6 +1116,47 @@ macro_rules! define_packet_from {
                 ControlPacket::UDPTunnel(Box::new(inner))
             }
         }
+        impl<$Dst: VoicePacketDst> TryFrom<ControlPacket<$Dst>> for $type {
+            type Error = ControlPacket<$Dst>;
+
+            fn try_from(packet: ControlPacket<$Dst>) -> Result<Self, Self::Error> {
+                match packet {
+                    ControlPacket::UDPTunnel(inner) => Ok(*inner),
+                    packet => Err(packet),
+                }
+            }
+        }
+        impl<$Dst: VoicePacketDst> ControlPacketMessage<$Dst> for $type {
+            const KIND: PacketKind = PacketKind::UDPTunnel;
+
+            fn from_packet_ref(packet: &ControlPacket<$Dst>) -> Option<&Self> {
+                match packet {
+                    ControlPacket::UDPTunnel(inner) => Some(inner),
+                    _ => None,
+                }
+            }
+
+            fn from_packet_mut(packet: &mut ControlPacket<$Dst>) -> Option<&mut Self> {
+                match packet {
+                    ControlPacket::UDPTunnel(inner) => Some(inner),
+                    _ => None,
+                }
+            }
+        }
+        impl<$Dst: VoicePacketDst> PacketBody for $type {
+            fn body_len(&self) -> usize {
+                self.encoded_len()
+            }
+
+            fn write_body(&self, dst: &mut BytesMut) -> Result<(), Error> {
+                VoiceCodec::<$Dst, $Dst>::default().encode_ref(self, dst)
+            }
+        }
+        impl<$Dst: VoicePacketDst> DisplayPacket for $type {
+            fn fmt_packet(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "UDPTunnel({})", self)
+            }
+        }
     };
     ( $Dst:ident $name:ident($type:ty) ) => {
         impl<$Dst: VoicePacketDst> From<$type> for ControlPacket<$Dst> {
@@ -320,37 +1164,79 @@ macro_rules! define_packet_from {
                 ControlPacket::$name(Box::new(inner))
             }
         }
+        impl<$Dst: VoicePacketDst> TryFrom<ControlPacket<$Dst>> for $type {
+            type Error = ControlPacket<$Dst>;
+
+            fn try_from(packet: ControlPacket<$Dst>) -> Result<Self, Self::Error> {
+                match packet {
+                    ControlPacket::$name(inner) => Ok(*inner),
+                    packet => Err(packet),
+                }
+            }
+        }
+        impl<$Dst: VoicePacketDst> ControlPacketMessage<$Dst> for $type {
+            const KIND: PacketKind = PacketKind::$name;
+
+            fn from_packet_ref(packet: &ControlPacket<$Dst>) -> Option<&Self> {
+                match packet {
+                    ControlPacket::$name(inner) => Some(inner),
+                    _ => None,
+                }
+            }
+
+            fn from_packet_mut(packet: &mut ControlPacket<$Dst>) -> Option<&mut Self> {
+                match packet {
+                    ControlPacket::$name(inner) => Some(inner),
+                    _ => None,
+                }
+            }
+        }
+        impl PacketBody for $type {
+            fn body_len(&self) -> usize {
+                backend::encoded_len(self)
+            }
+
+            fn write_body(&self, dst: &mut BytesMut) -> Result<(), Error> {
+                backend::write(self, dst)
+            }
+        }
+        impl DisplayPacket for $type {
+            fn fmt_packet(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                display::fmt_message(f, stringify!($name), self)
+            }
+        }
         impl From<$type> for RawControlPacket {
             fn from(msg: $type) -> Self {
-                Self {
-                    id: self::msgs::id::$name,
-                    bytes: msg.write_to_bytes().unwrap().into(),
-                }
+                Self::from_message(self::msgs::id::$name, &msg).expect(concat!(
+                    "all required fields of ",
+                    stringify!($name),
+                    " must be set"
+                ))
             }
         }
         impl TryFrom<RawControlPacket> for $type {
-            type Error = ProtobufError;
+            type Error = Error;
 
             fn try_from(packet: RawControlPacket) -> Result<Self, Self::Error> {
                 if packet.id == msgs::id::$name {
                     Self::try_from(packet.bytes)
                 } else {
-                    Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        concat!("expected packet of type ", stringify!($name)),
-                    ).into())
+                    Err(Error::WrongPacketType {
+                        expected: msgs::id::$name,
+                        actual: packet.id,
+                    })
                 }
             }
         }
         impl TryFrom<&[u8]> for $type {
-            type Error = ProtobufError;
+            type Error = Error;
 
             fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
-                Message::parse_from_bytes(bytes)
+                backend::parse(bytes)
             }
         }
         impl TryFrom<Bytes> for $type {
-            type Error = ProtobufError;
+            type Error = Error;
 
             fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
                 bytes.as_ref().try_into()
@@ -373,35 +1259,119 @@ macro_rules! define_packet_enum {
                 $name(Box<$type>),
             )*
             /// A packet of unknown type.
+            ///
+            /// Also used for packets which failed to parse if the codec is in lenient mode,
+            /// see [ControlCodec::set_lenient].
             Other(RawControlPacket),
         }
         impl<Dst: VoicePacketDst> TryFrom<RawControlPacket> for ControlPacket<$Dst> {
-            type Error = ProtobufError;
+            type Error = Error;
 
             fn try_from(packet: RawControlPacket) -> Result<Self, Self::Error> {
                 Ok(match packet.id {
                     $(
                         $(#[$attr])*
                         msgs::id::$name => {
-                            ControlPacket::$name(Box::new(packet.bytes.try_into()?))
+                            let inner = packet
+                                .bytes
+                                .clone()
+                                .try_into()
+                                .map_err(|err| Error::parse(packet.id, &packet.bytes, err))?;
+                            ControlPacket::$name(Box::new(inner))
                         }
                     )*
                         _ => ControlPacket::Other(packet),
                 })
             }
         }
-        impl<Dst: VoicePacketDst> From<ControlPacket<$Dst>> for RawControlPacket {
-            fn from(packet: ControlPacket<$Dst>) -> Self {
-                match packet {
+        /// Fails if the packet cannot be encoded, e.g. because a message lacks required fields or
+        /// an audio frame is too long for its length header.
+        impl<Dst: VoicePacketDst> TryFrom<ControlPacket<$Dst>> for RawControlPacket {
+            type Error = Error;
+
+            fn try_from(packet: ControlPacket<$Dst>) -> Result<Self, Self::Error> {
+                let packet = match packet {
+                    ControlPacket::Other(inner) => return Ok(inner),
+                    packet => packet,
+                };
+                let mut bytes = BytesMut::with_capacity(packet.encoded_len() - 6);
+                packet.write_body(&mut bytes)?;
+                Ok(RawControlPacket {
+                    id: packet.id(),
+                    bytes: bytes.freeze(),
+                })
+            }
+        }
+        /// Prints the packet name and its most relevant fields, omitting unset ones.
+        impl<Dst: VoicePacketDst> fmt::Display for ControlPacket<$Dst> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
                     $(
                         $(#[$attr])*
-                        ControlPacket::$name(inner) => (*inner).into(),
+                        ControlPacket::$name(inner) => inner.fmt_packet(f),
                     )*
-                        ControlPacket::Other(inner) => inner,
+                    ControlPacket::Other(inner) => write!(
+                        f,
+                        "Other{{id={}, len={}}}",
+                        inner.id,
+                        inner.bytes.len()
+                    ),
                 }
             }
         }
         impl<Dst: VoicePacketDst> ControlPacket<$Dst> {
+            /// Returns the amount of bytes this packet occupies once encoded, including the
+            /// 6 byte header.
+            pub fn encoded_len(&self) -> usize {
+                6 + match self {
+                    $(
+                        $(#[$attr])*
+                        ControlPacket::$name(inner) => inner.body_len(),
+                    )*
+                    ControlPacket::Other(inner) => inner.bytes.len(),
+                }
+            }
+
+            /// Writes the body of this packet, without the header, to `dst`.
+            fn write_body(&self, dst: &mut BytesMut) -> Result<(), Error> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        ControlPacket::$name(inner) => inner.write_body(dst),
+                    )*
+                    ControlPacket::Other(inner) => {
+                        dst.extend_from_slice(&inner.bytes);
+                        Ok(())
+                    }
+                }
+            }
+
+            /// Checks the packet for protocol violations which parsing does not catch.
+            ///
+            /// Packets of unknown type are never considered invalid. See [validate] for details.
+            pub fn validate(&self) -> Vec<ValidationIssue> {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        ControlPacket::$name(inner) => inner.validate(),
+                    )*
+                    ControlPacket::Other(_) => Vec::new(),
+                }
+            }
+
+            /// Returns the packet ID.
+            ///
+            /// See [msgs::id].
+            pub fn id(&self) -> u16 {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        ControlPacket::$name(_) => msgs::id::$name,
+                    )*
+                    ControlPacket::Other(inner) => inner.id,
+                }
+            }
+
             /// Returns the internal name of a packet (for debugging purposes).
             pub fn name(&self) -> &'static str {
                 match self {
@@ -416,52 +1386,1211 @@ macro_rules! define_packet_enum {
     };
 }
 
+/// Serializes as the equivalent [RawControlPacket], i.e. with the message in its wire format.
+#[cfg(feature = "serde")]
+impl<Dst: VoicePacketDst + Clone> serde::Serialize for ControlPacket<Dst> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RawControlPacket::try_from(self.clone())
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, Dst: VoicePacketDst> serde::Deserialize<'de> for ControlPacket<Dst> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        RawControlPacket::deserialize(deserializer)?
+            .try_into()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Generates the ControlPacketHandler trait and ControlPacket::dispatch()
+macro_rules! define_packet_handler {
+    ( $Dst:ident $( $(#[$attr:meta])* $name:ident($type:ty) => $handler:ident ),* ) => {
+        /// Handler for [ControlPacket]s with one method per packet type.
+        ///
+        /// Consumers override the methods for the packets they are interested in and pass packets
+        /// to [ControlPacket::dispatch]. Unlike a `match` with a catch-all arm, packets which are
+        /// not handled all end up in [on_unhandled](Self::on_unhandled), which can be made to
+        /// complain about them via [DENY_UNHANDLED](Self::DENY_UNHANDLED).
+        pub trait ControlPacketHandler<$Dst: VoicePacketDst> {
+            /// Whether [on_unhandled](Self::on_unhandled) complains about the packets passed to
+            /// it by default.
+            ///
+            /// If set, unhandled packets are logged (with the `tracing` feature) and cause a
+            /// panic in debug builds.
+            const DENY_UNHANDLED: bool = false;
+
+            $(
+                #[doc = concat!("Called for `", stringify!($name), "` packets.")]
+                $(#[$attr])*
+                fn $handler(&mut self, msg: $type) {
+                    self.on_unhandled(ControlPacket::$name(Box::new(msg)));
+                }
+            )*
+
+            /// Called for packets of unknown type.
+            fn on_other(&mut self, packet: RawControlPacket) {
+                self.on_unhandled(ControlPacket::Other(packet));
+            }
+
+            /// Called for all packets whose method is not overridden.
+            ///
+            /// Ignores the packet by default, see [DENY_UNHANDLED](Self::DENY_UNHANDLED).
+            fn on_unhandled(&mut self, packet: ControlPacket<$Dst>) {
+                if Self::DENY_UNHANDLED {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(name = packet.name(), id = packet.id(), "unhandled packet");
+                    debug_assert!(false, "unhandled {} packet", packet.name());
+                }
+                let _ = packet;
+            }
+        }
+
+        impl<$Dst: VoicePacketDst> ControlPacket<$Dst> {
+            /// Passes this packet to the method of `handler` matching its type.
+            pub fn dispatch<H: ControlPacketHandler<$Dst> + ?Sized>(self, handler: &mut H) {
+                match self {
+                    $(
+                        $(#[$attr])*
+                        ControlPacket::$name(inner) => handler.$handler(*inner),
+                    )*
+                    ControlPacket::Other(inner) => handler.on_other(inner),
+                }
+            }
+        }
+    };
+}
+
+/// Generates an enum containing only the packets valid in one direction, plus conversions from
+/// and to ControlPacket
+///
+/// Munches the packet table, keeping the packets whose directions include `$Dst`.
+macro_rules! define_narrowed_enum {
+    (
+        @filter $meta:tt $Dst:ident [$($acc:tt)*]
+        UDPTunnel($type:ty) $dirs:tt, $($rest:tt)*
+    ) => {
+        define_narrowed_enum!(
+            @filter $meta $Dst [$($acc)* UDPTunnel(VoicePacket<$Dst>),] $($rest)*
+        );
+    };
+    (
+        @filter $meta:tt $Dst:ident [$($acc:tt)*]
+        $(#[$attr:meta])* $name:ident($type:ty) [Serverbound, Clientbound], $($rest:tt)*
+    ) => {
+        define_narrowed_enum!(
+            @filter $meta $Dst [$($acc)* $(#[$attr])* $name($type),] $($rest)*
+        );
+    };
+    (
+        @filter $meta:tt Serverbound [$($acc:tt)*]
+        $(#[$attr:meta])* $name:ident($type:ty) [Serverbound], $($rest:tt)*
+    ) => {
+        define_narrowed_enum!(
+            @filter $meta Serverbound [$($acc)* $(#[$attr])* $name($type),] $($rest)*
+        );
+    };
+    (
+        @filter $meta:tt Clientbound [$($acc:tt)*]
+        $(#[$attr:meta])* $name:ident($type:ty) [Clientbound], $($rest:tt)*
+    ) => {
+        define_narrowed_enum!(
+            @filter $meta Clientbound [$($acc)* $(#[$attr])* $name($type),] $($rest)*
+        );
+    };
+    (
+        @filter $meta:tt $Dst:ident [$($acc:tt)*]
+        $(#[$attr:meta])* $name:ident($type:ty) $dirs:tt, $($rest:tt)*
+    ) => {
+        define_narrowed_enum!(@filter $meta $Dst [$($acc)*] $($rest)*);
+    };
+    (
+        @filter { $(#[$enum_attr:meta])* $enum:ident } $Dst:ident
+        [ $( $(#[$attr:meta])* $name:ident($type:ty), )* ]
+    ) => {
+        $(#[$enum_attr])*
+        #[derive(Debug, Clone, PartialEq)]
+        #[allow(clippy::large_enum_variant)]
+        #[non_exhaustive]
+        pub enum $enum {
+            $(
+                #[allow(missing_docs)]
+                $(#[$attr])*
+                $name(Box<$type>),
+            )*
+            /// A packet of unknown type, see [ControlPacket::Other].
+            Other(RawControlPacket),
+            /// A known packet which is not valid in this direction.
+            Unexpected(ControlPacket<$Dst>),
+        }
+        impl From<ControlPacket<$Dst>> for $enum {
+            fn from(packet: ControlPacket<$Dst>) -> Self {
+                match packet {
+                    $(
+                        $(#[$attr])*
+                        ControlPacket::$name(inner) => $enum::$name(inner),
+                    )*
+                    ControlPacket::Other(inner) => $enum::Other(inner),
+                    packet => $enum::Unexpected(packet),
+                }
+            }
+        }
+        impl From<$enum> for ControlPacket<$Dst> {
+            fn from(packet: $enum) -> Self {
+                match packet {
+                    $(
+                        $(#[$attr])*
+                        $enum::$name(inner) => ControlPacket::$name(inner),
+                    )*
+                    $enum::Other(inner) => ControlPacket::Other(inner),
+                    $enum::Unexpected(packet) => packet,
+                }
+            }
+        }
+        impl ControlPacket<$Dst> {
+            #[doc = concat!("Narrows this packet down to a [", stringify!($enum), "].")]
+            pub fn narrow(self) -> $enum {
+                self.into()
+            }
+        }
+    };
+}
+
+/// Generates everything derived from the packet table.
+///
+/// Each packet lists the directions it may be sent in, which determines
+/// [PacketKind::is_valid_for_direction] and the variants of [ClientMessage] and [ServerMessage].
 macro_rules! define_packets {
-    ( < $Dst:ident > $( $(#[$attr:meta])* $name:ident($type:ty), )* ) => {
+    (
+        < $Dst:ident >
+        $( $(#[$attr:meta])* $name:ident($type:ty) => $handler:ident [$($dir:ident),*], )*
+    ) => {
         #[allow(missing_docs)]
         mod generated_id {
             define_packet_mappings!($($(#[$attr])* $name),*);
         }
+        define_packet_kind!($($(#[$attr])* $name [$($dir),*]),*);
         define_packet_enum!($Dst $($(#[$attr])* $name($type)),*);
+        define_packet_handler!($Dst $($(#[$attr])* $name($type) => $handler),*);
         $(
             $(#[$attr])*
             define_packet_from!($Dst $name($type));
         )*
+        define_narrowed_enum!(
+            @filter {
+                /// A control packet sent by a client, i.e. one which is valid in
+                /// [Direction::Serverbound].
+                ///
+                /// Obtained from the output of a [ServerControlCodec] via [ControlPacket::narrow].
+                ClientMessage
+            }
+            Serverbound [] $($(#[$attr])* $name($type) [$($dir),*],)*
+        );
+        define_narrowed_enum!(
+            @filter {
+                /// A control packet sent by the server, i.e. one which is valid in
+                /// [Direction::Clientbound].
+                ///
+                /// Obtained from the output of a [ClientControlCodec] via [ControlPacket::narrow].
+                ServerMessage
+            }
+            Clientbound [] $($(#[$attr])* $name($type) [$($dir),*],)*
+        );
     };
 }
 
 define_packets![
     <Dst>
-    Version(msgs::Version),
-    UDPTunnel(VoicePacket<Dst>),
-    Authenticate(msgs::Authenticate),
-    Ping(msgs::Ping),
-    Reject(msgs::Reject),
-    ServerSync(msgs::ServerSync),
-    ChannelRemove(msgs::ChannelRemove),
-    ChannelState(msgs::ChannelState),
-    UserRemove(msgs::UserRemove),
-    UserState(msgs::UserState),
-    BanList(msgs::BanList),
-    TextMessage(msgs::TextMessage),
-    PermissionDenied(msgs::PermissionDenied),
-    ACL(msgs::ACL),
-    QueryUsers(msgs::QueryUsers),
-    CryptSetup(msgs::CryptSetup),
-    ContextActionModify(msgs::ContextActionModify),
-    ContextAction(msgs::ContextAction),
-    UserList(msgs::UserList),
-    VoiceTarget(msgs::VoiceTarget),
-    PermissionQuery(msgs::PermissionQuery),
-    CodecVersion(msgs::CodecVersion),
-    UserStats(msgs::UserStats),
-    RequestBlob(msgs::RequestBlob),
-    ServerConfig(msgs::ServerConfig),
-    SuggestConfig(msgs::SuggestConfig),
+    Version(msgs::Version) => on_version [Serverbound, Clientbound],
+    UDPTunnel(VoicePacket<Dst>) => on_udp_tunnel [Serverbound, Clientbound],
+    Authenticate(msgs::Authenticate) => on_authenticate [Serverbound],
+    Ping(msgs::Ping) => on_ping [Serverbound, Clientbound],
+    Reject(msgs::Reject) => on_reject [Clientbound],
+    ServerSync(msgs::ServerSync) => on_server_sync [Clientbound],
+    ChannelRemove(msgs::ChannelRemove) => on_channel_remove [Serverbound, Clientbound],
+    ChannelState(msgs::ChannelState) => on_channel_state [Serverbound, Clientbound],
+    UserRemove(msgs::UserRemove) => on_user_remove [Serverbound, Clientbound],
+    UserState(msgs::UserState) => on_user_state [Serverbound, Clientbound],
+    BanList(msgs::BanList) => on_ban_list [Serverbound, Clientbound],
+    TextMessage(msgs::TextMessage) => on_text_message [Serverbound, Clientbound],
+    PermissionDenied(msgs::PermissionDenied) => on_permission_denied [Clientbound],
+    ACL(msgs::ACL) => on_acl [Serverbound, Clientbound],
+    QueryUsers(msgs::QueryUsers) => on_query_users [Serverbound, Clientbound],
+    CryptSetup(msgs::CryptSetup) => on_crypt_setup [Serverbound, Clientbound],
+    ContextActionModify(msgs::ContextActionModify) => on_context_action_modify [Clientbound],
+    ContextAction(msgs::ContextAction) => on_context_action [Serverbound],
+    UserList(msgs::UserList) => on_user_list [Serverbound, Clientbound],
+    VoiceTarget(msgs::VoiceTarget) => on_voice_target [Serverbound],
+    PermissionQuery(msgs::PermissionQuery) => on_permission_query [Serverbound, Clientbound],
+    CodecVersion(msgs::CodecVersion) => on_codec_version [Clientbound],
+    UserStats(msgs::UserStats) => on_user_stats [Serverbound, Clientbound],
+    RequestBlob(msgs::RequestBlob) => on_request_blob [Serverbound],
+    ServerConfig(msgs::ServerConfig) => on_server_config [Clientbound],
+    SuggestConfig(msgs::SuggestConfig) => on_suggest_config [Clientbound],
+    // Shares its ID with the WebRTC message, whose protocol lacks it
+    #[cfg(not(feature = "webrtc-extensions"))]
+    PluginDataTransmission(msgs::PluginDataTransmission) => on_plugin_data_transmission
+        [Serverbound, Clientbound],
     #[cfg(feature = "webrtc-extensions")]
-    WebRTC(msgs::WebRTC),
+    WebRTC(msgs::WebRTC) => on_webrtc [Serverbound, Clientbound],
     #[cfg(feature = "webrtc-extensions")]
-    IceCandidate(msgs::IceCandidate),
+    IceCandidate(msgs::IceCandidate) => on_ice_candidate [Serverbound, Clientbound],
     #[cfg(feature = "webrtc-extensions")]
-    TalkingState(msgs::TalkingState),
+    TalkingState(msgs::TalkingState) => on_talking_state [Clientbound],
 ];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::backend::message;
+
+    #[test]
+    fn encode_rejects_oversized_payload() {
+        let mut codec = RawControlCodec::new();
+        let packet = RawControlPacket {
+            id: msgs::id::TextMessage,
+            bytes: Bytes::from(vec![0; DEFAULT_MAX_PAYLOAD + 1]),
+        };
+
+        let mut dst = BytesMut::new();
+        let err = codec.encode(packet, &mut dst).unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::PacketTooLong {
+                declared,
+                limit: DEFAULT_MAX_PAYLOAD,
+            } if declared == DEFAULT_MAX_PAYLOAD + 1
+        ));
+        assert!(dst.is_empty());
+    }
+
+    #[test]
+    fn decode_reserves_space_for_pending_body() {
+        let mut codec = RawControlCodec::new();
+        let len = 1024 * 1024;
+        let mut data = BytesMut::new();
+        codec
+            .encode(
+                RawControlPacket {
+                    id: msgs::id::UserList,
+                    bytes: Bytes::from(vec![0x42; len]),
+                },
+                &mut data,
+            )
+            .unwrap();
+
+        let mut buf = BytesMut::new();
+        let mut result = None;
+        for chunk in data.chunks(4096) {
+            buf.extend_from_slice(chunk);
+            if let Some(packet) = codec.decode(&mut buf).unwrap() {
+                result = Some(packet);
+                break;
+            }
+            assert!(buf.capacity() >= len);
+        }
+
+        let packet = result.expect("packet should be complete");
+        assert_eq!(msgs::id::UserList, packet.id);
+        assert_eq!(len, packet.bytes.len());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_packets_split_at_arbitrary_boundaries() {
+        let mut codec = RawControlCodec::new();
+        let first = RawControlPacket {
+            id: msgs::id::UserState,
+            bytes: Bytes::from_static(b"first packet"),
+        };
+        let second = RawControlPacket {
+            id: msgs::id::Ping,
+            bytes: Bytes::from_static(b"2nd"),
+        };
+        let mut data = BytesMut::new();
+        codec.encode(first.clone(), &mut data).unwrap();
+        codec.encode(second.clone(), &mut data).unwrap();
+
+        for split in [[1, 7, 20], [5, 6, 18], [3, 17, 19], [6, 18, 24]] {
+            let mut buf = BytesMut::new();
+            let mut packets = Vec::new();
+            let mut last = 0;
+            for end in split.iter().copied().chain(Some(data.len())) {
+                buf.extend_from_slice(&data[last..end]);
+                last = end;
+                while let Some(packet) = codec.decode(&mut buf).unwrap() {
+                    packets.push(packet);
+                }
+            }
+            assert_eq!(
+                vec![first.clone(), second.clone()],
+                packets,
+                "split at {:?}",
+                split
+            );
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_all_stops_at_partial_packet() {
+        let mut buf = BytesMut::new();
+        for i in 0..3 {
+            let msg = msgs::Ping {
+                timestamp: Some(i),
+                ..Default::default()
+            };
+            RawControlCodec::new().encode(msg.into(), &mut buf).unwrap();
+        }
+        let mut codec = ClientControlCodec::new();
+        buf.extend_from_slice(&[0, 3, 0]);
+
+        let result = codec.decode_all(&mut buf);
+        assert!(result.error.is_none());
+        assert_eq!(3, result.remaining);
+        assert_eq!(3, result.packets.len());
+        for (i, packet) in result.packets.into_iter().enumerate() {
+            match packet {
+                ControlPacket::Ping(msg) => assert_eq!(i as u64, msg.timestamp()),
+                _ => panic!("unexpected packet {:?}", packet),
+            }
+        }
+    }
+
+    #[test]
+    fn unparsed_packet_round_trips_unchanged() {
+        let msg = msgs::UserState {
+            session: Some(42),
+            name: Some("test".to_string()),
+            ..Default::default()
+        };
+        let mut input = BytesMut::new();
+        let mut codec = RawControlCodec::new();
+        codec.encode(msg.clone().into(), &mut input).unwrap();
+        let original = input.clone();
+
+        let packet = codec.decode(&mut input).unwrap().unwrap();
+        let view = packet.view();
+        assert_eq!(msgs::id::UserState, view.id());
+        assert_eq!(msg, view.parse_as::<msgs::UserState>().unwrap());
+        assert!(view.parse_as::<msgs::Ping>().is_err());
+
+        let mut output = BytesMut::new();
+        codec.encode(view.raw().clone(), &mut output).unwrap();
+        assert_eq!(original, output);
+    }
+
+    #[test]
+    fn encoded_len_matches_encoded_bytes() {
+        let version = msgs::Version {
+            release: Some("1.4.0".to_string()),
+            ..Default::default()
+        };
+        let user_state = msgs::UserState {
+            session: Some(1234),
+            comment: Some("x".repeat(300)),
+            ..Default::default()
+        };
+        let packets: Vec<ControlPacket<Clientbound>> = vec![
+            version.into(),
+            VoicePacket::<Clientbound>::Ping { timestamp: 1 << 40 }.into(),
+            VoicePacket::Audio {
+                _dst: PhantomData,
+                target: 0,
+                session_id: 300,
+                seq_num: 70_000,
+                payload: crate::voice::VoicePacketPayload::Opus(vec![0; 200].into(), true),
+                position_info: Some(vec![0; 12].into()),
+            }
+            .into(),
+            VoicePacket::Audio {
+                _dst: PhantomData,
+                target: 2,
+                session_id: 1,
+                seq_num: 5,
+                payload: crate::voice::VoicePacketPayload::CeltAlpha(vec![
+                    vec![0; 10].into(),
+                    vec![0; 127].into(),
+                ]),
+                position_info: None,
+            }
+            .into(),
+            msgs::Authenticate::default().into(),
+            msgs::Ping::default().into(),
+            msgs::Reject::default().into(),
+            msgs::ServerSync::default().into(),
+            message!(msgs::ChannelRemove {
+                #[cfg(feature = "protobuf")]
+                channel_id: Some(3),
+                #[cfg(feature = "prost")]
+                channel_id: 3,
+            })
+            .into(),
+            msgs::ChannelState::default().into(),
+            msgs::UserRemove {
+                #[cfg(feature = "protobuf")]
+                session: Some(3),
+                #[cfg(feature = "prost")]
+                session: 3,
+                ..Default::default()
+            }
+            .into(),
+            user_state.into(),
+            msgs::BanList::default().into(),
+            msgs::TextMessage::to_channel(0)
+                .text("hello")
+                .build()
+                .unwrap()
+                .into(),
+            msgs::PermissionDenied::default().into(),
+            msgs::ACL {
+                #[cfg(feature = "protobuf")]
+                channel_id: Some(0),
+                #[cfg(feature = "prost")]
+                channel_id: 0,
+                ..Default::default()
+            }
+            .into(),
+            msgs::QueryUsers::default().into(),
+            msgs::CryptSetup::default().into(),
+            msgs::ContextActionModify::remove("action").into(),
+            msgs::ContextAction {
+                #[cfg(feature = "protobuf")]
+                action: Some("action".to_string()),
+                #[cfg(feature = "prost")]
+                action: "action".to_string(),
+                ..Default::default()
+            }
+            .into(),
+            msgs::UserList::default().into(),
+            msgs::VoiceTarget::default().into(),
+            msgs::PermissionQuery::default().into(),
+            message!(msgs::CodecVersion {
+                #[cfg(feature = "protobuf")]
+                alpha: Some(-2147483637),
+                #[cfg(feature = "prost")]
+                alpha: -2147483637,
+                #[cfg(feature = "protobuf")]
+                beta: Some(0),
+                #[cfg(feature = "prost")]
+                beta: 0,
+                #[cfg(feature = "protobuf")]
+                prefer_alpha: Some(true),
+                #[cfg(feature = "prost")]
+                prefer_alpha: true,
+                opus: Some(true),
+            })
+            .into(),
+            msgs::UserStats::default().into(),
+            msgs::RequestBlob::default().into(),
+            msgs::ServerConfig::default().into(),
+            msgs::SuggestConfig::default().into(),
+            #[cfg(not(feature = "webrtc-extensions"))]
+            msgs::PluginDataTransmission::to_sessions([1, 2], "id", b"data".to_vec())
+                .unwrap()
+                .into(),
+            ControlPacket::Other(RawControlPacket {
+                id: 1000,
+                bytes: Bytes::from_static(b"unknown"),
+            }),
+        ];
+
+        for packet in packets {
+            let expected = packet.encoded_len();
+            let name = packet.name();
+            let mut dst = BytesMut::new();
+            RawControlCodec::new()
+                .encode(packet.try_into().unwrap(), &mut dst)
+                .unwrap();
+            assert_eq!(expected, dst.len(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn packet_kind_matches_ids() {
+        for (id, kind) in PacketKind::ALL.iter().enumerate() {
+            assert_eq!(id as u16, u16::from(*kind));
+            assert_eq!(Ok(*kind), PacketKind::try_from(id as u16));
+        }
+        assert_eq!(msgs::id::UDPTunnel, PacketKind::UDPTunnel.id());
+        assert_eq!("SuggestConfig", PacketKind::SuggestConfig.to_string());
+        assert_eq!(Err(UnknownPacketId(1000)), PacketKind::try_from(1000));
+    }
+
+    #[test]
+    fn packet_names_and_ids_round_trip() {
+        for kind in PacketKind::ALL {
+            assert_eq!(Some(kind.name()), name_for_id(kind.id()));
+            assert_eq!(Some(kind.id()), id_for_name(kind.name()));
+        }
+        assert_eq!(Some(msgs::id::UserState), id_for_name("userstate"));
+        assert_eq!(None, id_for_name("NotAPacket"));
+        assert_eq!(None, name_for_id(1000));
+    }
+
+    #[test]
+    fn lenient_mode_surfaces_malformed_packets() {
+        let raw = RawControlPacket {
+            id: msgs::id::UserStats,
+            bytes: Bytes::from_static(&[0xff, 0xff, 0xff]),
+        };
+        let mut buf = BytesMut::new();
+        RawControlCodec::new()
+            .encode(raw.clone(), &mut buf)
+            .unwrap();
+
+        let mut strict = ServerControlCodec::new();
+        assert!(strict.decode(&mut buf.clone()).is_err());
+
+        let mut lenient = ServerControlCodec::new_lenient();
+        assert_eq!(
+            Some(ControlPacket::Other(raw)),
+            lenient.decode(&mut buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn strict_direction_rejects_invalid_packets() {
+        let mut buf = BytesMut::new();
+        RawControlCodec::new()
+            .encode(msgs::ServerSync::default().into(), &mut buf)
+            .unwrap();
+
+        let mut client = ClientControlCodec::new();
+        client.set_strict_direction(true);
+        assert!(client.decode(&mut buf.clone()).unwrap().is_some());
+
+        let mut server = ServerControlCodec::new();
+        assert!(server.decode(&mut buf.clone()).unwrap().is_some());
+        server.set_strict_direction(true);
+        assert!(server.decode(&mut buf.clone()).is_err());
+        server.set_lenient(true);
+        match server.decode(&mut buf).unwrap() {
+            Some(ControlPacket::Other(raw)) => assert_eq!(msgs::id::ServerSync, raw.id),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn narrowing_separates_unexpected_packets() {
+        let packet: ControlPacket<Clientbound> = msgs::ServerSync::default().into();
+        let narrowed = packet.clone().narrow();
+        assert!(matches!(narrowed, ServerMessage::ServerSync(_)));
+        assert_eq!(packet, narrowed.into());
+
+        let packet: ControlPacket<Clientbound> = msgs::Authenticate::default().into();
+        let narrowed = packet.clone().narrow();
+        assert_eq!(ServerMessage::Unexpected(packet.clone()), narrowed);
+        assert_eq!(packet, narrowed.into());
+
+        let packet: ControlPacket<Serverbound> = msgs::Authenticate::default().into();
+        assert!(matches!(packet.narrow(), ClientMessage::Authenticate(_)));
+    }
+
+    #[test]
+    fn stats_count_packets_and_bytes() {
+        let mut codec = ServerControlCodec::with_stats();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(msgs::Ping::default().into(), &mut buf)
+            .unwrap();
+        codec
+            .encode(msgs::ServerSync::default().into(), &mut buf)
+            .unwrap();
+        let encoded_len = buf.len() as u64;
+        let mut ping = BytesMut::new();
+        RawControlCodec::new()
+            .encode(msgs::Ping::default().into(), &mut ping)
+            .unwrap();
+        codec.decode(&mut ping).unwrap().unwrap();
+
+        let stats = codec.take_stats().unwrap();
+        assert_eq!(1, stats.encoded[PacketKind::Ping]);
+        assert_eq!(1, stats.encoded[PacketKind::ServerSync]);
+        assert_eq!(2, stats.encoded.total());
+        assert_eq!(encoded_len, stats.encoded_bytes.total());
+        assert_eq!(1, stats.decoded[PacketKind::Ping]);
+        assert_eq!(6, stats.decoded_bytes[PacketKind::Ping]);
+        assert_eq!(Some(&ControlCodecStats::default()), codec.stats());
+
+        assert_eq!(None, ServerControlCodec::new().stats());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip_preserves_wire_format() {
+        let msg = msgs::UserState {
+            session: Some(42),
+            name: Some("test".to_string()),
+            ..Default::default()
+        };
+        let packets: Vec<ControlPacket<Clientbound>> = vec![
+            msg.into(),
+            VoicePacket::<Clientbound>::Audio {
+                _dst: PhantomData,
+                target: 1,
+                session_id: 42,
+                seq_num: 3,
+                payload: crate::voice::VoicePacketPayload::Opus(vec![1, 2, 3].into(), false),
+                position_info: None,
+            }
+            .into(),
+        ];
+
+        for packet in packets {
+            let json = serde_json::to_string(&packet).unwrap();
+            let result: ControlPacket<Clientbound> = serde_json::from_str(&json).unwrap();
+            assert_eq!(
+                RawControlPacket::try_from(packet).unwrap(),
+                RawControlPacket::try_from(result).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn converting_unencodable_voice_packets_fails() {
+        let packet = VoicePacket::<Clientbound>::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: 1,
+            seq_num: 0,
+            payload: crate::voice::VoicePacketPayload::Opus(vec![0; 9000].into(), false),
+            position_info: None,
+        };
+        assert!(matches!(
+            RawControlPacket::try_from(packet.clone()),
+            Err(Error::MalformedVoice(
+                crate::error::VoiceError::FrameTooLong { .. }
+            ))
+        ));
+        assert!(RawControlPacket::try_from(ControlPacket::from(packet)).is_err());
+    }
+
+    // Only rust-protobuf provides the reflection needed to list fields
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn display_shows_set_fields() {
+        let msg = msgs::UserState {
+            session: Some(42),
+            channel_id: Some(7),
+            name: Some("alice".to_string()),
+            self_mute: Some(true),
+            ..Default::default()
+        };
+        let packet: ControlPacket<Clientbound> = msg.into();
+        assert_eq!(
+            r#"UserState{session=42, name="alice", channel_id=7, self_mute}"#,
+            packet.to_string()
+        );
+
+        let msg = msgs::Authenticate {
+            username: Some("bob".to_string()),
+            password: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let packet: ControlPacket<Clientbound> = msg.into();
+        assert_eq!(
+            r#"Authenticate{username="bob", password=<redacted>}"#,
+            packet.to_string()
+        );
+    }
+
+    #[test]
+    fn display_summarizes_voice_and_unknown_packets() {
+        let packet: ControlPacket<Clientbound> = VoicePacket::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: 42,
+            seq_num: 3,
+            payload: crate::voice::VoicePacketPayload::Opus(vec![0; 120].into(), true),
+            position_info: None,
+        }
+        .into();
+        assert_eq!(
+            "UDPTunnel(Opus{target=0, session=42, seq=3, len=120, end})",
+            packet.to_string()
+        );
+
+        let packet: ControlPacket<Clientbound> = ControlPacket::Other(RawControlPacket {
+            id: 1000,
+            bytes: Bytes::from_static(b"abc"),
+        });
+        assert_eq!("Other{id=1000, len=3}", packet.to_string());
+    }
+
+    #[test]
+    fn packets_convert_back_into_messages() {
+        let msg = msgs::TextMessage::to_channel(0).text("hi").build().unwrap();
+        let packet: ControlPacket<Clientbound> = msg.clone().into();
+
+        assert_eq!(Some(&msg), packet.downcast_ref::<msgs::TextMessage>());
+        assert_eq!(None, packet.downcast_ref::<msgs::UserState>());
+        let packet = msgs::UserState::try_from(packet).unwrap_err();
+        assert_eq!(msg, msgs::TextMessage::try_from(packet).unwrap());
+
+        let voice = VoicePacket::<Clientbound>::Ping { timestamp: 5 };
+        let packet: ControlPacket<Clientbound> = voice.clone().into();
+        assert_eq!(Some(&voice), packet.downcast_ref());
+        assert_eq!(voice, VoicePacket::try_from(packet).unwrap());
+    }
+
+    #[test]
+    fn raw_packets_from_arbitrary_messages() {
+        let msg = msgs::Ping {
+            good: Some(3),
+            ..Default::default()
+        };
+        let packet = RawControlPacket::from_message(500, &msg).unwrap();
+        assert_eq!(500, packet.id);
+        assert_eq!(msg, packet.parse_message::<msgs::Ping>().unwrap());
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn raw_packets_fail_without_required_fields() {
+        let result = RawControlPacket::from_message(500, &msgs::UserRemove::default());
+        assert!(matches!(result, Err(Error::Protobuf(_))));
+    }
+
+    #[test]
+    fn decode_eof_reports_truncated_packet() {
+        let mut codec = RawControlCodec::new();
+        assert_eq!(None, codec.decode_eof(&mut BytesMut::new()).unwrap());
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(
+                RawControlPacket {
+                    id: msgs::id::UserState,
+                    bytes: Bytes::from_static(b"truncated"),
+                },
+                &mut buf,
+            )
+            .unwrap();
+        buf.truncate(10);
+
+        let err = codec.decode_eof(&mut buf).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Truncated {
+                id: Some(msgs::id::UserState),
+                received: 4,
+                expected: 9,
+            }
+        ));
+        assert_eq!(
+            "stream ended with 4 of 9 bytes of packet with id 9 pending",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn tunnels_shared_voice_packets() {
+        let voice = VoicePacket::<Clientbound>::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: 42,
+            seq_num: 7,
+            payload: crate::voice::VoicePacketPayload::Opus(Bytes::from_static(b"opus"), false),
+            position_info: None,
+        };
+        let mut codec = ServerControlCodec::new();
+        let mut expected = BytesMut::new();
+        codec.encode(voice.clone().into(), &mut expected).unwrap();
+
+        let raw = RawControlPacket::udp_tunnel(voice.encode_shared().unwrap());
+        let mut shared = BytesMut::new();
+        codec
+            .encode(ControlPacket::Other(raw), &mut shared)
+            .unwrap();
+        assert_eq!(expected, shared);
+    }
+
+    #[test]
+    fn raw_tunnel_mode_forwards_voice_verbatim() {
+        let voice = VoicePacket::<Clientbound>::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: 42,
+            seq_num: 7,
+            payload: crate::voice::VoicePacketPayload::Opus(
+                Bytes::from_static(b"opus frame"),
+                true,
+            ),
+            position_info: None,
+        };
+        let mut buf = BytesMut::new();
+        ServerControlCodec::new()
+            .encode(voice.into(), &mut buf)
+            .unwrap();
+        // Trailing garbage which the voice codec would not preserve
+        buf[5] += 1;
+        buf.put_u8(0xff);
+        let original = buf.clone().freeze();
+
+        let mut codec = ClientControlCodec::new();
+        codec.set_raw_tunnel(true);
+        let packet = codec.decode(&mut buf).unwrap().unwrap();
+        assert!(matches!(
+            &packet,
+            ControlPacket::Other(raw) if raw.id == msgs::id::UDPTunnel
+        ));
+
+        let mut codec = ServerControlCodec::new();
+        codec.set_raw_tunnel(true);
+        let mut encoded = BytesMut::new();
+        codec.encode(packet, &mut encoded).unwrap();
+        assert_eq!(original, encoded.freeze());
+
+        let mut version = BytesMut::new();
+        ServerControlCodec::new()
+            .encode(msgs::Version::default().into(), &mut version)
+            .unwrap();
+        let mut codec = ClientControlCodec::new();
+        codec.set_raw_tunnel(true);
+        assert!(matches!(
+            codec.decode(&mut version).unwrap(),
+            Some(ControlPacket::Version(_))
+        ));
+    }
+
+    #[test]
+    fn protobuf_tunnel_mode_uses_mumble_1_5_voice_packets() {
+        let voice = VoicePacket::<Clientbound>::Audio {
+            _dst: PhantomData,
+            target: 1,
+            session_id: 42,
+            seq_num: 7,
+            payload: crate::voice::VoicePacketPayload::Opus(Bytes::from_static(b"opus"), true),
+            position_info: None,
+        };
+        let mut codec = ServerControlCodec::new();
+        codec.set_protobuf_tunnel(true);
+        let mut buf = BytesMut::new();
+        codec.encode(voice.clone().into(), &mut buf).unwrap();
+        let mut body = buf.clone().split_off(6);
+        assert!(matches!(
+            crate::voice_v2::ClientVoiceCodecV2::new().decode(&mut body),
+            Ok(Some(VoicePacketV2::Audio {
+                target: 1,
+                session_id: 42,
+                frame_number: 7,
+                is_terminator: true,
+                ..
+            }))
+        ));
+
+        let mut codec = ClientControlCodec::new();
+        codec.set_protobuf_tunnel(true);
+        assert_eq!(
+            Some(ControlPacket::UDPTunnel(Box::new(voice))),
+            codec.decode(&mut buf).unwrap()
+        );
+
+        // Legacy packets do not parse as protobuf ones
+        let mut legacy = BytesMut::new();
+        ServerControlCodec::new()
+            .encode(
+                VoicePacket::<Clientbound>::Ping { timestamp: 3 }.into(),
+                &mut legacy,
+            )
+            .unwrap();
+        assert!(matches!(
+            codec.decode(&mut legacy),
+            Err(Error::Parse {
+                id: msgs::id::UDPTunnel,
+                ..
+            })
+        ));
+
+        let speex = VoicePacket::<Serverbound>::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: (),
+            seq_num: 0,
+            payload: crate::voice::VoicePacketPayload::speex([&b"ab"[..]]).unwrap(),
+            position_info: None,
+        };
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            codec.encode(speex.into(), &mut buf),
+            Err(Error::MalformedVoice(
+                crate::error::VoiceError::UnsupportedCodec
+            ))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encode_ref_matches_owned_encode() {
+        let channel_state = msgs::ChannelState {
+            channel_id: Some(3),
+            description: Some("x".repeat(1000)),
+            ..Default::default()
+        };
+        let packets: Vec<ControlPacket<Clientbound>> = vec![
+            channel_state.into(),
+            VoicePacket::<Clientbound>::Ping { timestamp: 42 }.into(),
+            ControlPacket::Other(RawControlPacket {
+                id: 1234,
+                bytes: Bytes::from_static(b"other"),
+            }),
+        ];
+        let mut codec = ServerControlCodec::with_stats();
+        for packet in packets {
+            let mut by_ref = BytesMut::new();
+            codec.encode_ref(&packet, &mut by_ref).unwrap();
+            let mut owned = BytesMut::new();
+            RawControlCodec::new()
+                .encode(packet.try_into().unwrap(), &mut owned)
+                .unwrap();
+            assert_eq!(owned, by_ref);
+        }
+        assert_eq!(3, codec.stats().unwrap().encoded.total());
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn encode_ref_leaves_no_partial_packet_behind() {
+        // Missing required fields must not leave a partial packet behind
+        let mut codec = ServerControlCodec::new();
+        let mut buf = BytesMut::from(&b"previous"[..]);
+        let packet = ControlPacket::<Clientbound>::from(msgs::ChannelRemove::default());
+        assert!(matches!(
+            codec.encode_ref(&packet, &mut buf),
+            Err(Error::Protobuf(_))
+        ));
+        assert_eq!(&b"previous"[..], &buf[..]);
+    }
+
+    #[test]
+    fn parse_errors_carry_packet_details() {
+        let raw = RawControlPacket {
+            id: msgs::id::UserState,
+            bytes: Bytes::from_static(&[0xff; 20]),
+        };
+        let err = ControlPacket::<Clientbound>::try_from(raw).unwrap_err();
+        assert!(matches!(
+            &err,
+            Error::Parse { id: msgs::id::UserState, len: 20, head, source }
+                if head.len() == crate::error::PARSE_ERROR_HEAD_LEN
+                    && matches!(**source, Error::Protobuf(_))
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("failed to parse UserState (id 9, 20 bytes): "));
+
+        let raw = RawControlPacket {
+            id: msgs::id::UDPTunnel,
+            bytes: Bytes::from_static(&[0x80]),
+        };
+        let err = ControlPacket::<Clientbound>::try_from(raw).unwrap_err();
+        assert_eq!(
+            "failed to parse UDPTunnel (id 1, 1 bytes): unexpected end of packet",
+            err.to_string()
+        );
+    }
+
+    #[cfg(feature = "protobuf")]
+    #[test]
+    fn file_descriptor_matches_proto() {
+        for kind in PacketKind::ALL {
+            if *kind == PacketKind::UDPTunnel {
+                continue;
+            }
+            assert!(MUMBLE_PROTO.contains(&format!("message {} {{", kind.name())));
+            assert!(file_descriptor()
+                .message_by_package_relative_name(kind.name())
+                .is_some());
+        }
+    }
+
+    #[test]
+    fn dispatch_calls_matching_handler() {
+        #[derive(Default)]
+        struct Handler {
+            sessions: Vec<u32>,
+            unhandled: Vec<&'static str>,
+        }
+
+        impl ControlPacketHandler<Clientbound> for Handler {
+            fn on_user_state(&mut self, msg: msgs::UserState) {
+                self.sessions.push(msg.session());
+            }
+
+            fn on_unhandled(&mut self, packet: ControlPacket<Clientbound>) {
+                self.unhandled.push(packet.name());
+            }
+        }
+
+        let user_state = msgs::UserState {
+            session: Some(5),
+            ..Default::default()
+        };
+        let packets: Vec<ControlPacket<Clientbound>> = vec![
+            user_state.into(),
+            msgs::Ping::default().into(),
+            VoicePacket::Ping { timestamp: 0 }.into(),
+            ControlPacket::Other(RawControlPacket {
+                id: 1234,
+                bytes: Bytes::new(),
+            }),
+        ];
+        let mut handler = Handler::default();
+        for packet in packets {
+            packet.dispatch(&mut handler);
+        }
+        assert_eq!(vec![5], handler.sessions);
+        assert_eq!(vec!["Ping", "UDPTunnel", "unknown"], handler.unhandled);
+    }
+
+    #[test]
+    fn validate_reports_protocol_violations() {
+        use validate::Severity;
+
+        let user_state = msgs::UserState {
+            self_deaf: Some(true),
+            self_mute: Some(false),
+            ..Default::default()
+        };
+        let issues = ControlPacket::<Serverbound>::from(user_state).validate();
+        assert_eq!(1, issues.len());
+        assert_eq!(Severity::Warning, issues[0].severity);
+        assert_eq!("self_mute", issues[0].field);
+
+        let channel_state = msgs::ChannelState {
+            channel_id: Some(3),
+            parent: Some(3),
+            ..Default::default()
+        };
+        let issues = ControlPacket::<Serverbound>::from(channel_state).validate();
+        assert_eq!(1, issues.len());
+        assert_eq!(Severity::Error, issues[0].severity);
+        assert_eq!("parent", issues[0].field);
+
+        let issues = ControlPacket::<Serverbound>::from(msgs::Authenticate::default()).validate();
+        assert_eq!(
+            vec!["username"],
+            issues.iter().map(|it| it.field).collect::<Vec<_>>()
+        );
+
+        let text_message = msgs::TextMessage::to_channel(0).text("hi").build().unwrap();
+        assert!(ControlPacket::<Serverbound>::from(text_message)
+            .validate()
+            .is_empty());
+        assert!(ControlPacket::<Serverbound>::from(msgs::Version::default())
+            .validate()
+            .is_empty());
+    }
+
+    #[test]
+    fn message_limits_reject_long_text_messages() {
+        let config = msgs::ServerConfig {
+            message_length: Some(5),
+            image_message_length: Some(0),
+            ..Default::default()
+        };
+        let limits = MessageLimits::from(&config);
+        assert_eq!(None, limits.image_message_length);
+
+        let msg = |message: &str| msgs::TextMessage {
+            #[cfg(feature = "protobuf")]
+            message: Some(message.to_owned()),
+            #[cfg(feature = "prost")]
+            message: message.to_owned(),
+            ..Default::default()
+        };
+        let text = |message: &str| ControlPacket::<Serverbound>::from(msg(message));
+        let mut client = ClientControlCodec::new();
+        client.set_message_limits(Some(limits));
+        let mut buf = BytesMut::new();
+        client.encode(text("hello"), &mut buf).unwrap();
+        client
+            .encode(text("<img src=\"data:image/png;base64,AAAA\"/>"), &mut buf)
+            .unwrap();
+        let len = buf.len();
+        let err = client.encode(text("hello!"), &mut buf).unwrap_err();
+        assert_eq!(
+            "text message too long: length 6 exceeds message limit of 5",
+            err.to_string()
+        );
+        assert_eq!(len, buf.len());
+
+        let mut server = ServerControlCodec::new();
+        server.set_message_limits(Some(limits));
+        let mut unlimited = ClientControlCodec::new();
+        unlimited.encode(text("hello!"), &mut buf).unwrap();
+        assert!(server.decode(&mut buf).unwrap().is_some());
+        assert!(server.decode(&mut buf).unwrap().is_some());
+        assert!(matches!(
+            server.decode(&mut buf),
+            Err(Error::TextMessageTooLong { len: 6, .. })
+        ));
+
+        unlimited.encode(text("hello!"), &mut buf).unwrap();
+        unlimited.encode(text("hello"), &mut buf).unwrap();
+        server.set_lenient(true);
+        assert!(matches!(
+            server.decode(&mut buf),
+            Err(Error::TextMessageTooLong { len: 6, .. })
+        ));
+        assert!(server.decode(&mut buf).unwrap().is_some());
+
+        let mut buf = BytesMut::new();
+        ServerControlCodec::new()
+            .encode(msg("hello!").into(), &mut buf)
+            .unwrap();
+        assert!(client.decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn frames_round_trip_over_short_reads() {
+        /// Reader which returns at most a single byte per call.
+        struct Trickle<'a>(&'a [u8]);
+
+        impl io::Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match (self.0.split_first(), buf.first_mut()) {
+                    (Some((&byte, rest)), Some(slot)) => {
+                        *slot = byte;
+                        self.0 = rest;
+                        Ok(1)
+                    }
+                    _ => Ok(0),
+                }
+            }
+        }
+
+        let packet = RawControlPacket {
+            id: msgs::id::UserState,
+            bytes: Bytes::from_static(b"payload"),
+        };
+        let mut data = Vec::new();
+        write_frame(&mut data, &packet).unwrap();
+        let mut buf = BytesMut::new();
+        RawControlCodec::new()
+            .encode(packet.clone(), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], &data[..]);
+
+        let mut reader = Trickle(&data);
+        assert_eq!(packet, read_frame(&mut reader).unwrap());
+        assert!(matches!(
+            read_frame(&mut reader),
+            Err(Error::Truncated {
+                id: None,
+                received: 0,
+                expected: 6,
+            })
+        ));
+
+        let oversized = [0, 0, 0xff, 0xff, 0xff, 0xff];
+        assert!(matches!(
+            read_frame(&oversized[..]),
+            Err(Error::PacketTooLong { .. })
+        ));
+    }
+}
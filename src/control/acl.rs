@@ -0,0 +1,497 @@
+//! Editing channel ACLs via the `ACL` message
+//!
+//! Sending a (non-query) `ACL` message replaces all ACL entries and groups defined in the
+//! channel with the ones in the message. [AclEditor] therefore starts from the server's
+//! response to a [query](AclEditor::query) and always emits the complete set, so entries which
+//! were not touched survive the edit.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use super::backend::message;
+use super::msgs;
+use super::permissions::Permissions;
+
+/// Who an [AclEntry] applies to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AclTarget {
+    /// A registered user, identified by user ID.
+    User(u32),
+    /// All members of a group, e.g. `all`, `auth`, `admin` or `~in` for users not in it.
+    Group(String),
+}
+
+/// A single ACL entry.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AclEntry {
+    /// Whether the entry applies to the channel itself.
+    pub apply_here: bool,
+    /// Whether the entry applies to the sub-channels.
+    pub apply_subs: bool,
+    /// Who the entry applies to.
+    pub target: AclTarget,
+    /// The permissions granted.
+    pub grant: Permissions,
+    /// The permissions denied.
+    pub deny: Permissions,
+}
+
+impl AclEntry {
+    /// Creates an entry for the target which applies to the channel and its sub-channels, but
+    /// neither grants nor denies anything yet.
+    pub fn new(target: AclTarget) -> Self {
+        AclEntry {
+            apply_here: true,
+            apply_subs: true,
+            target,
+            grant: Permissions::empty(),
+            deny: Permissions::empty(),
+        }
+    }
+
+    fn from_message(msg: &msgs::acl::ChanACL) -> Self {
+        let target = match (&msg.user_id, &msg.group) {
+            (Some(user_id), _) => AclTarget::User(*user_id),
+            (None, group) => AclTarget::Group(group.clone().unwrap_or_default()),
+        };
+        AclEntry {
+            apply_here: msg.apply_here(),
+            apply_subs: msg.apply_subs(),
+            target,
+            grant: Permissions::from_bits_retain(msg.grant.unwrap_or_default()),
+            deny: Permissions::from_bits_retain(msg.deny.unwrap_or_default()),
+        }
+    }
+
+    fn to_message(&self) -> msgs::acl::ChanACL {
+        let (user_id, group) = match &self.target {
+            AclTarget::User(user_id) => (Some(*user_id), None),
+            AclTarget::Group(group) => (None, Some(group.clone())),
+        };
+        message!(msgs::acl::ChanACL {
+            apply_here: Some(self.apply_here),
+            apply_subs: Some(self.apply_subs),
+            inherited: Some(false),
+            user_id,
+            group,
+            grant: Some(self.grant.bits()),
+            deny: Some(self.deny.bits()),
+        })
+    }
+}
+
+/// A group defined in or inherited by a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+    /// Name of the group.
+    pub name: String,
+    /// Whether the members of the group in the parent channel are members here, too.
+    pub inherit: bool,
+    /// Whether sub-channels can inherit the group.
+    pub inheritable: bool,
+    /// Users added to the group in this channel.
+    pub add: BTreeSet<u32>,
+    /// Inherited members removed from the group in this channel.
+    pub remove: BTreeSet<u32>,
+    inherited: bool,
+    inherited_members: BTreeSet<u32>,
+}
+
+impl Group {
+    /// Creates a group defined in the channel, without members.
+    pub fn new(name: impl Into<String>) -> Self {
+        Group {
+            name: name.into(),
+            inherit: true,
+            inheritable: true,
+            add: BTreeSet::new(),
+            remove: BTreeSet::new(),
+            inherited: false,
+            inherited_members: BTreeSet::new(),
+        }
+    }
+
+    /// Returns whether the group is defined in a parent channel.
+    pub fn is_inherited(&self) -> bool {
+        self.inherited
+    }
+
+    /// Returns the members inherited from the parent channel.
+    pub fn inherited_members(&self) -> &BTreeSet<u32> {
+        &self.inherited_members
+    }
+
+    /// Returns the members of the group in this channel.
+    pub fn members(&self) -> BTreeSet<u32> {
+        let mut members: BTreeSet<u32> = if self.inherit {
+            self.inherited_members
+                .difference(&self.remove)
+                .copied()
+                .collect()
+        } else {
+            BTreeSet::new()
+        };
+        members.extend(&self.add);
+        members
+    }
+
+    /// Returns whether the group differs from what it inherits, i.e. has to be sent.
+    fn is_modified(&self) -> bool {
+        !self.inherited
+            || !self.inherit
+            || !self.inheritable
+            || !self.add.is_empty()
+            || !self.remove.is_empty()
+    }
+
+    fn from_message(msg: &msgs::acl::ChanGroup) -> Self {
+        #[cfg(feature = "protobuf")]
+        let name = msg.name().to_owned();
+        #[cfg(feature = "prost")]
+        let name = msg.name.clone();
+        Group {
+            name,
+            inherit: msg.inherit(),
+            inheritable: msg.inheritable(),
+            add: msg.add.iter().copied().collect(),
+            remove: msg.remove.iter().copied().collect(),
+            inherited: msg.inherited(),
+            inherited_members: msg.inherited_members.iter().copied().collect(),
+        }
+    }
+
+    fn to_message(&self) -> msgs::acl::ChanGroup {
+        msgs::acl::ChanGroup {
+            #[cfg(feature = "protobuf")]
+            name: Some(self.name.clone()),
+            #[cfg(feature = "prost")]
+            name: self.name.clone(),
+            inherited: Some(self.inherited),
+            inherit: Some(self.inherit),
+            inheritable: Some(self.inheritable),
+            add: self.add.iter().copied().collect(),
+            remove: self.remove.iter().copied().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A reason an [AclEditor] refused to build a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AclError {
+    /// The message passed to [AclEditor::from_response] is a query, not a response.
+    NotAResponse,
+    /// An entry grants and denies the same permissions.
+    Conflicting {
+        /// Index of the entry.
+        index: usize,
+        /// The permissions both granted and denied.
+        permissions: Permissions,
+    },
+    /// An entry contains permissions which can not be assigned in the channel.
+    Unassignable {
+        /// Index of the entry.
+        index: usize,
+        /// The permissions which can not be assigned.
+        permissions: Permissions,
+    },
+    /// An entry or group has an empty group name.
+    EmptyGroupName,
+}
+
+impl fmt::Display for AclError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AclError::NotAResponse => f.write_str("ACL message is a query, not a response"),
+            AclError::Conflicting { index, permissions } => write!(
+                f,
+                "ACL entry {} both grants and denies {}",
+                index, permissions
+            ),
+            AclError::Unassignable { index, permissions } => write!(
+                f,
+                "ACL entry {} contains {}, which can not be assigned in this channel",
+                index, permissions
+            ),
+            AclError::EmptyGroupName => f.write_str("empty ACL group name"),
+        }
+    }
+}
+
+impl std::error::Error for AclError {}
+
+/// Editor for the ACL entries and groups of a channel.
+///
+/// Created from the server's response to [query](Self::query), edited and turned into the
+/// message replacing the channel's ACLs with [build](Self::build). Inherited entries are
+/// read-only, while inherited groups can be modified locally.
+///
+/// There is deliberately no way to create an editor from scratch other than
+/// [overwrite](Self::overwrite), since sending it would remove all existing entries.
+#[derive(Clone, Debug)]
+pub struct AclEditor {
+    channel_id: u32,
+    inherit_acls: bool,
+    inherited_acls: Vec<AclEntry>,
+    acls: Vec<AclEntry>,
+    groups: Vec<Group>,
+}
+
+impl AclEditor {
+    /// Creates the message querying the ACLs of a channel.
+    pub fn query(channel_id: u32) -> msgs::ACL {
+        msgs::ACL {
+            #[cfg(feature = "protobuf")]
+            channel_id: Some(channel_id),
+            #[cfg(feature = "prost")]
+            channel_id,
+            query: Some(true),
+            ..Default::default()
+        }
+    }
+
+    /// Creates an editor from the server's response to a query.
+    pub fn from_response(msg: &msgs::ACL) -> Result<Self, AclError> {
+        if msg.query() {
+            return Err(AclError::NotAResponse);
+        }
+        #[cfg(feature = "protobuf")]
+        let channel_id = msg.channel_id();
+        #[cfg(feature = "prost")]
+        let channel_id = msg.channel_id;
+        let (inherited, acls) = msg.acls.iter().partition::<Vec<_>, _>(|it| it.inherited());
+        Ok(AclEditor {
+            channel_id,
+            inherit_acls: msg.inherit_acls(),
+            inherited_acls: inherited.into_iter().map(AclEntry::from_message).collect(),
+            acls: acls.into_iter().map(AclEntry::from_message).collect(),
+            groups: msg.groups.iter().map(Group::from_message).collect(),
+        })
+    }
+
+    /// Creates an empty editor which replaces all existing entries and groups of the channel.
+    ///
+    /// Unless this is really what you want, use [from_response](Self::from_response).
+    pub fn overwrite(channel_id: u32) -> Self {
+        AclEditor {
+            channel_id,
+            inherit_acls: true,
+            inherited_acls: Vec::new(),
+            acls: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+
+    /// Returns the ID of the channel.
+    pub fn channel_id(&self) -> u32 {
+        self.channel_id
+    }
+
+    /// Returns whether the channel inherits the ACLs of its parent.
+    pub fn inherit_acls(&self) -> bool {
+        self.inherit_acls
+    }
+
+    /// Sets whether the channel inherits the ACLs of its parent.
+    pub fn set_inherit_acls(&mut self, inherit: bool) {
+        self.inherit_acls = inherit;
+    }
+
+    /// Returns the entries inherited from parent channels.
+    pub fn inherited_acls(&self) -> &[AclEntry] {
+        &self.inherited_acls
+    }
+
+    /// Returns the entries defined in this channel, in the order they are evaluated in.
+    pub fn acls(&self) -> &[AclEntry] {
+        &self.acls
+    }
+
+    /// Returns the entries defined in this channel for modification.
+    pub fn acls_mut(&mut self) -> &mut Vec<AclEntry> {
+        &mut self.acls
+    }
+
+    /// Appends an entry, which takes precedence over all previous ones.
+    pub fn add_acl(&mut self, entry: AclEntry) {
+        self.acls.push(entry);
+    }
+
+    /// Removes all entries for the target, returning whether there were any.
+    pub fn remove_acls_for(&mut self, target: &AclTarget) -> bool {
+        let len = self.acls.len();
+        self.acls.retain(|it| it.target != *target);
+        self.acls.len() != len
+    }
+
+    /// Returns all groups of the channel, including the inherited ones.
+    pub fn groups(&self) -> &[Group] {
+        &self.groups
+    }
+
+    /// Returns the group with the given name.
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|it| it.name == name)
+    }
+
+    /// Returns the group with the given name for modification, creating it if it does not
+    /// exist yet.
+    pub fn group_mut(&mut self, name: &str) -> &mut Group {
+        let pos = match self.groups.iter().position(|it| it.name == name) {
+            Some(pos) => pos,
+            None => {
+                self.groups.push(Group::new(name));
+                self.groups.len() - 1
+            }
+        };
+        &mut self.groups[pos]
+    }
+
+    /// Removes a group defined in this channel, or resets the local changes to an inherited
+    /// one. Returns whether there was anything to remove.
+    pub fn remove_group(&mut self, name: &str) -> bool {
+        let Some(pos) = self.groups.iter().position(|it| it.name == name) else {
+            return false;
+        };
+        let group = &mut self.groups[pos];
+        if !group.inherited {
+            self.groups.remove(pos);
+            return true;
+        }
+        let modified = group.is_modified();
+        group.inherit = true;
+        group.inheritable = true;
+        group.add.clear();
+        group.remove.clear();
+        modified
+    }
+
+    /// Checks the entries and builds the message replacing the channel's ACLs and groups.
+    ///
+    /// Fails if an entry grants and denies the same permissions or contains permissions which
+    /// can not be assigned in the channel.
+    pub fn build(&self) -> Result<msgs::ACL, AclError> {
+        let root = self.channel_id == 0;
+        for (index, entry) in self.acls.iter().enumerate() {
+            if entry.target == AclTarget::Group(String::new()) {
+                return Err(AclError::EmptyGroupName);
+            }
+            let permissions = entry.grant & entry.deny;
+            if !permissions.is_empty() {
+                return Err(AclError::Conflicting { index, permissions });
+            }
+            let permissions = (entry.grant | entry.deny).unassignable(root);
+            if !permissions.is_empty() {
+                return Err(AclError::Unassignable { index, permissions });
+            }
+        }
+        if self.groups.iter().any(|it| it.name.is_empty()) {
+            return Err(AclError::EmptyGroupName);
+        }
+        Ok(message!(msgs::ACL {
+            #[cfg(feature = "protobuf")]
+            channel_id: Some(self.channel_id),
+            #[cfg(feature = "prost")]
+            channel_id: self.channel_id,
+            inherit_acls: Some(self.inherit_acls),
+            groups: self
+                .groups
+                .iter()
+                .filter(|it| it.is_modified())
+                .map(Group::to_message)
+                .collect(),
+            acls: self.acls.iter().map(AclEntry::to_message).collect(),
+            query: Some(false),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response() -> msgs::ACL {
+        let mut msg = AclEditor::query(5);
+        msg.query = Some(false);
+        msg.acls = vec![
+            msgs::acl::ChanACL {
+                inherited: Some(true),
+                group: Some("all".to_owned()),
+                grant: Some(Permissions::ENTER.bits()),
+                ..Default::default()
+            },
+            msgs::acl::ChanACL {
+                inherited: Some(false),
+                user_id: Some(12),
+                grant: Some(Permissions::MOVE.bits()),
+                ..Default::default()
+            },
+        ];
+        msg.groups = vec![msgs::acl::ChanGroup {
+            inherited: Some(true),
+            inherited_members: vec![1, 2],
+            ..Group::new("admin").to_message()
+        }];
+        msg
+    }
+
+    #[test]
+    fn edits_seeded_acls() {
+        let mut editor = AclEditor::from_response(&response()).unwrap();
+        assert_eq!(1, editor.inherited_acls().len());
+        assert_eq!(AclTarget::User(12), editor.acls()[0].target);
+        assert_eq!(
+            BTreeSet::from([1, 2]),
+            editor.group("admin").unwrap().members()
+        );
+
+        // Unmodified inherited groups and entries are not sent
+        let msg = editor.build().unwrap();
+        assert_eq!(1, msg.acls.len());
+        assert!(msg.groups.is_empty());
+
+        editor.group_mut("admin").remove.insert(2);
+        editor.group_mut("mods").add.insert(7);
+        let mut entry = AclEntry::new(AclTarget::Group("mods".to_owned()));
+        entry.grant = Permissions::MUTE_DEAFEN;
+        editor.add_acl(entry);
+        let msg = editor.build().unwrap();
+        assert_eq!(2, msg.acls.len());
+        assert_eq!(2, msg.groups.len());
+        assert_eq!(
+            BTreeSet::from([1]),
+            editor.group("admin").unwrap().members()
+        );
+
+        assert!(editor.remove_group("admin"));
+        assert!(editor.remove_group("mods"));
+        assert!(editor.build().unwrap().groups.is_empty());
+    }
+
+    #[test]
+    fn rejects_dangerous_messages() {
+        assert_eq!(
+            Err(AclError::NotAResponse),
+            AclEditor::from_response(&AclEditor::query(0)).map(|_| ())
+        );
+
+        let mut editor = AclEditor::from_response(&response()).unwrap();
+        editor.acls_mut()[0].deny = Permissions::MOVE;
+        assert_eq!(
+            Err(AclError::Conflicting {
+                index: 0,
+                permissions: Permissions::MOVE
+            }),
+            editor.build()
+        );
+        editor.acls_mut()[0].deny = Permissions::KICK;
+        assert_eq!(
+            Err(AclError::Unassignable {
+                index: 0,
+                permissions: Permissions::KICK
+            }),
+            editor.build()
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Builder for the `Authenticate` message
+
+use super::msgs;
+use super::validate::ensure_valid;
+use super::validate::ValidationIssue;
+
+/// Bitstream version of CELT 0.7.0 as announced in [msgs::Authenticate::celt_versions].
+pub const CELT_0_7_0: i32 = 0x8000000bu32 as i32;
+/// Bitstream version of CELT 0.11.0 as announced in [msgs::Authenticate::celt_versions].
+pub const CELT_0_11_0: i32 = 0x80000010u32 as i32;
+
+/// Builder for [msgs::Authenticate] messages.
+///
+/// Announces Opus support and, for compatibility with servers which still check them, the CELT
+/// versions supported by the reference client unless configured otherwise.
+#[derive(Clone, Debug)]
+pub struct AuthenticateBuilder {
+    username: Option<String>,
+    password: Option<String>,
+    tokens: Vec<String>,
+    opus: bool,
+    celt_versions: Vec<i32>,
+}
+
+impl Default for AuthenticateBuilder {
+    fn default() -> Self {
+        AuthenticateBuilder {
+            username: None,
+            password: None,
+            tokens: Vec::new(),
+            opus: true,
+            celt_versions: vec![CELT_0_7_0, CELT_0_11_0],
+        }
+    }
+}
+
+impl AuthenticateBuilder {
+    /// Creates a builder with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the username, which is required by [build](Self::build).
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    /// Sets the server or user password.
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Adds an access token.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.tokens.push(token.into());
+        self
+    }
+
+    /// Adds multiple access tokens.
+    pub fn tokens<I: IntoIterator<Item = S>, S: Into<String>>(mut self, tokens: I) -> Self {
+        self.tokens.extend(tokens.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets whether Opus is supported, `true` by default.
+    pub fn opus(mut self, opus: bool) -> Self {
+        self.opus = opus;
+        self
+    }
+
+    /// Replaces the announced CELT versions, [CELT_0_7_0] and [CELT_0_11_0] by default.
+    pub fn celt_versions(mut self, celt_versions: Vec<i32>) -> Self {
+        self.celt_versions = celt_versions;
+        self
+    }
+
+    /// Builds the message sent when connecting.
+    ///
+    /// Fails if the username is missing or empty, see [Validate].
+    pub fn build(self) -> Result<msgs::Authenticate, ValidationIssue> {
+        let msg = msgs::Authenticate {
+            username: Some(self.username.unwrap_or_default()),
+            password: self.password,
+            tokens: self.tokens,
+            celt_versions: self.celt_versions,
+            opus: Some(self.opus),
+            ..Default::default()
+        };
+        ensure_valid(msg)
+    }
+
+    /// Builds the message sent after connecting to update the access tokens.
+    ///
+    /// Only the tokens are included, all other settings are ignored.
+    pub fn build_token_refresh(self) -> msgs::Authenticate {
+        msgs::Authenticate {
+            tokens: self.tokens,
+            ..Default::default()
+        }
+    }
+}
+
+impl msgs::Authenticate {
+    /// Creates an [AuthenticateBuilder].
+    pub fn builder() -> AuthenticateBuilder {
+        AuthenticateBuilder::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::validate::Validate;
+
+    #[test]
+    fn builds_authenticate_messages() {
+        let msg = msgs::Authenticate::builder()
+            .username("bot")
+            .token("secret")
+            .build()
+            .unwrap();
+        assert_eq!(Some("bot"), msg.username.as_deref());
+        assert_eq!(vec!["secret"], msg.tokens);
+        assert_eq!(Some(true), msg.opus);
+        assert_eq!(vec![CELT_0_7_0, CELT_0_11_0], msg.celt_versions);
+        assert_eq!(-2147483637, CELT_0_7_0);
+
+        let issue = msgs::Authenticate::builder()
+            .username(" ")
+            .build()
+            .unwrap_err();
+        assert_eq!("username", issue.field);
+
+        let msg = msgs::Authenticate::builder()
+            .username("ignored")
+            .tokens(["a", "b"])
+            .build_token_refresh();
+        assert_eq!(None, msg.username);
+        assert_eq!(vec!["a", "b"], msg.tokens);
+        assert!(msg.validate().is_empty());
+    }
+}
@@ -0,0 +1,81 @@
+//! Glue between control packets and the protobuf implementation selected via cargo features
+
+use bytes::Bytes;
+use bytes::BytesMut;
+
+use crate::error::Error;
+
+#[cfg(feature = "prost")]
+pub use prost::Message;
+#[cfg(feature = "protobuf")]
+pub use protobuf::Message;
+
+/// Builds a generated message from the given fields, leaving all others at their defaults.
+///
+/// rust-protobuf messages always have fields besides the ones of the protocol, so a struct
+/// expression needs `..Default::default()`. For prost messages it is redundant if all fields are
+/// given, which clippy points out.
+macro_rules! message {
+    ($($ty:ident)::+ { $($(#[$attr:meta])* $field:ident $(: $value:expr)?),* $(,)? }) => {{
+        #[allow(clippy::needless_update)]
+        let msg = $($ty)::+ {
+            $($(#[$attr])* $field $(: $value)?,)*
+            ..Default::default()
+        };
+        msg
+    }};
+}
+
+pub(crate) use message;
+
+/// Returns the amount of bytes the serialized message occupies.
+pub(crate) fn encoded_len<M: Message>(msg: &M) -> usize {
+    #[cfg(feature = "protobuf")]
+    return msg.compute_size() as usize;
+    #[cfg(feature = "prost")]
+    return msg.encoded_len();
+}
+
+/// Serializes the message, appending it to `dst`.
+pub(crate) fn write<M: Message>(msg: &M, dst: &mut BytesMut) -> Result<(), Error> {
+    #[cfg(feature = "protobuf")]
+    msg.write_to_writer(&mut bytes::BufMut::writer(dst))?;
+    #[cfg(feature = "prost")]
+    msg.encode(dst).expect("BytesMut grows as needed");
+    Ok(())
+}
+
+/// Serializes the message into a new buffer.
+pub(crate) fn to_bytes<M: Message>(msg: &M) -> Result<Bytes, Error> {
+    let mut buf = BytesMut::with_capacity(encoded_len(msg));
+    write(msg, &mut buf)?;
+    Ok(buf.freeze())
+}
+
+/// Parses a message from its serialized form.
+pub(crate) fn parse<M: Message + Default>(bytes: &[u8]) -> Result<M, Error> {
+    #[cfg(feature = "protobuf")]
+    return Ok(M::parse_from_bytes(bytes)?);
+    #[cfg(feature = "prost")]
+    return Ok(M::decode(bytes)?);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::control::msgs;
+    use crate::control::ControlPacket;
+    use crate::control::RawControlPacket;
+    use crate::voice::Clientbound;
+
+    #[test]
+    fn messages_round_trip_through_raw_packets() {
+        let msg = msgs::UserState {
+            session: Some(7),
+            ..Default::default()
+        };
+        let packet = ControlPacket::<Clientbound>::from(msg.clone());
+        let raw = RawControlPacket::try_from(packet.clone()).unwrap();
+        assert_eq!(msg, raw.parse_message::<msgs::UserState>().unwrap());
+        assert_eq!(packet, raw.try_into().unwrap());
+    }
+}
@@ -0,0 +1,445 @@
+//! Typed entries of the server's `BanList`
+//!
+//! On the wire, ban addresses are always 16 bytes with IPv4 addresses mapped into IPv6
+//! (`::ffff:a.b.c.d`), and the mask is a prefix length over all 128 bits. [BanEntry] uses
+//! [IpAddr] and a prefix length relative to the address family instead, i.e. an IPv4 `/24`
+//! ban is sent with a mask of 120.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use super::backend::message;
+use super::msgs;
+
+/// The bits an IPv4 address is shifted by when mapped into IPv6.
+const IPV4_MAPPED_PREFIX: u8 = 96;
+
+/// How long a ban lasts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BanDuration {
+    /// The ban never expires.
+    Permanent,
+    /// The ban expires after the given time from its start.
+    For(Duration),
+}
+
+/// A single ban, as sent in [msgs::BanList].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BanEntry {
+    /// The banned address.
+    pub address: IpAddr,
+    /// Length of the banned network's prefix, at most 32 for IPv4 and 128 for IPv6 addresses.
+    pub prefix_len: u8,
+    /// Name of the banned user, for identification purposes only.
+    pub name: Option<String>,
+    /// Certificate hash of the banned user.
+    pub hash: Option<String>,
+    /// Reason for the ban.
+    pub reason: Option<String>,
+    /// Start of the ban as sent by the server, in ISO 8601 format (`2024-01-31T12:00:00`, UTC).
+    ///
+    /// See [start_time](Self::start_time).
+    pub start: Option<String>,
+    /// How long the ban lasts.
+    pub duration: BanDuration,
+}
+
+/// The reason a [msgs::ban_list::BanEntry] could not be converted into a [BanEntry].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BanEntryError {
+    /// The address is neither 4 nor 16 bytes long.
+    InvalidAddress(usize),
+    /// The mask is longer than the address.
+    InvalidMask(u32),
+}
+
+impl fmt::Display for BanEntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanEntryError::InvalidAddress(len) => write!(f, "invalid ban address length {}", len),
+            BanEntryError::InvalidMask(mask) => write!(f, "invalid ban mask {}", mask),
+        }
+    }
+}
+
+impl std::error::Error for BanEntryError {}
+
+impl BanEntry {
+    /// Creates a permanent ban of the given network.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the prefix is longer than the address.
+    pub fn new(address: IpAddr, prefix_len: u8) -> Self {
+        assert!(
+            prefix_len <= max_prefix_len(address),
+            "prefix length {} too long for {}",
+            prefix_len,
+            address
+        );
+        BanEntry {
+            address,
+            prefix_len,
+            name: None,
+            hash: None,
+            reason: None,
+            start: None,
+            duration: BanDuration::Permanent,
+        }
+    }
+
+    /// Creates a permanent ban of a single address.
+    pub fn single(address: IpAddr) -> Self {
+        Self::new(address, max_prefix_len(address))
+    }
+
+    /// Returns whether the address is covered by this ban.
+    ///
+    /// IPv4 addresses and IPv4-mapped IPv6 addresses are considered equal. A ban whose
+    /// [prefix_len](Self::prefix_len) is longer than its address matches nothing.
+    pub fn matches(&self, addr: IpAddr) -> bool {
+        let mask = match self.mask() {
+            None => return false,
+            Some(0) => 0,
+            Some(len) => u128::MAX << (128 - u32::from(len)),
+        };
+        (to_bits(self.address) ^ to_bits(addr)) & mask == 0
+    }
+
+    /// Parses [start](Self::start) into a [SystemTime].
+    ///
+    /// Returns `None` if there is no start time or it is not in the format used by Murmur.
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.start.as_deref().and_then(parse_time)
+    }
+
+    /// Sets [start](Self::start) to the given time, truncated to seconds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the time lies before 1970.
+    pub fn set_start_time(&mut self, time: SystemTime) {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .expect("ban start before 1970")
+            .as_secs();
+        self.start = Some(format_time(secs));
+    }
+
+    /// Returns when the ban ends, `None` if it is permanent or its start is unknown.
+    pub fn end_time(&self) -> Option<SystemTime> {
+        match self.duration {
+            BanDuration::Permanent => None,
+            BanDuration::For(duration) => self.start_time()?.checked_add(duration),
+        }
+    }
+
+    /// The prefix length in terms of the IPv4-mapped 128 bit address, `None` if the prefix is
+    /// longer than the address.
+    fn mask(&self) -> Option<u8> {
+        if self.prefix_len > max_prefix_len(self.address) {
+            return None;
+        }
+        Some(match self.address {
+            IpAddr::V4(_) => self.prefix_len + IPV4_MAPPED_PREFIX,
+            IpAddr::V6(_) => self.prefix_len,
+        })
+    }
+}
+
+fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn to_bits(addr: IpAddr) -> u128 {
+    match addr {
+        IpAddr::V4(addr) => u128::from(addr.to_ipv6_mapped()),
+        IpAddr::V6(addr) => u128::from(addr),
+    }
+}
+
+impl TryFrom<&msgs::ban_list::BanEntry> for BanEntry {
+    type Error = BanEntryError;
+
+    fn try_from(msg: &msgs::ban_list::BanEntry) -> Result<Self, Self::Error> {
+        #[cfg(feature = "protobuf")]
+        let (bytes, mask) = (msg.address(), msg.mask());
+        #[cfg(feature = "prost")]
+        let (bytes, mask) = (&msg.address[..], msg.mask);
+        let (address, prefix_len) = if let Ok(bytes) = <[u8; 4]>::try_from(bytes) {
+            (IpAddr::V4(Ipv4Addr::from(bytes)), mask)
+        } else if let Ok(bytes) = <[u8; 16]>::try_from(bytes) {
+            let addr = Ipv6Addr::from(bytes);
+            match addr.to_ipv4_mapped() {
+                Some(v4) if mask >= u32::from(IPV4_MAPPED_PREFIX) => {
+                    (IpAddr::V4(v4), mask - u32::from(IPV4_MAPPED_PREFIX))
+                }
+                _ => (IpAddr::V6(addr), mask),
+            }
+        } else {
+            return Err(BanEntryError::InvalidAddress(bytes.len()));
+        };
+        if prefix_len > u32::from(max_prefix_len(address)) {
+            return Err(BanEntryError::InvalidMask(mask));
+        }
+        Ok(BanEntry {
+            address,
+            prefix_len: prefix_len as u8,
+            name: msg.name.clone(),
+            hash: msg.hash.clone(),
+            reason: msg.reason.clone(),
+            start: msg.start.clone(),
+            duration: match msg.duration {
+                None | Some(0) => BanDuration::Permanent,
+                Some(secs) => BanDuration::For(Duration::from_secs(secs.into())),
+            },
+        })
+    }
+}
+
+impl From<&BanEntry> for msgs::ban_list::BanEntry {
+    /// Converts the entry into its wire format.
+    ///
+    /// Permanent bans are sent with a duration of 0, durations longer than `u32::MAX` seconds
+    /// are saturated. A prefix longer than the address bans only the address itself.
+    fn from(entry: &BanEntry) -> Self {
+        let address = to_bits(entry.address).to_be_bytes().to_vec();
+        let mask = u32::from(entry.mask().unwrap_or(128));
+        message!(msgs::ban_list::BanEntry {
+            #[cfg(feature = "protobuf")]
+            address: Some(address),
+            #[cfg(feature = "prost")]
+            address,
+            #[cfg(feature = "protobuf")]
+            mask: Some(mask),
+            #[cfg(feature = "prost")]
+            mask,
+            name: entry.name.clone(),
+            hash: entry.hash.clone(),
+            reason: entry.reason.clone(),
+            start: entry.start.clone(),
+            duration: Some(match entry.duration {
+                BanDuration::Permanent => 0,
+                BanDuration::For(duration) => duration.as_secs().try_into().unwrap_or(u32::MAX),
+            }),
+        })
+    }
+}
+
+impl From<BanEntry> for msgs::ban_list::BanEntry {
+    fn from(entry: BanEntry) -> Self {
+        (&entry).into()
+    }
+}
+
+impl msgs::BanList {
+    /// Creates a request for the server's current ban list.
+    pub fn new_query() -> Self {
+        message!(msgs::BanList { query: Some(true) })
+    }
+
+    /// Creates a message replacing the server's ban list with the given entries.
+    pub fn replacement<'a>(entries: impl IntoIterator<Item = &'a BanEntry>) -> Self {
+        message!(msgs::BanList {
+            bans: entries.into_iter().map(Into::into).collect(),
+            query: Some(false),
+        })
+    }
+
+    /// Converts all entries, failing on the first invalid one.
+    pub fn entries(&self) -> Result<Vec<BanEntry>, BanEntryError> {
+        self.bans.iter().map(BanEntry::try_from).collect()
+    }
+}
+
+/// Parses a `yyyy-MM-ddTHH:mm:ss` UTC timestamp, optionally followed by `Z`.
+pub(super) fn parse_time(s: &str) -> Option<SystemTime> {
+    let s = s.strip_suffix('Z').unwrap_or(s);
+    let (date, time) = s.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<u64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if year < 1970 || !(1..=12).contains(&month) {
+        return None;
+    }
+    if day == 0 || day > days_in_month(year, month) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day)?
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+pub(super) fn format_time(secs: u64) -> String {
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let secs = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn days_in_month(year: u64, month: u64) -> u64 {
+    let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Howard Hinnant's algorithms, restricted to dates after 1970. Years too large for the day
+// count yield `None`.
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year % 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era.checked_mul(146097)?
+        .checked_add(doe)?
+        .checked_sub(719468)
+}
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matches_v4_prefix() {
+        let ban = BanEntry::new("192.168.1.0".parse().unwrap(), 24);
+        assert!(ban.matches("192.168.1.42".parse().unwrap()));
+        assert!(ban.matches("::ffff:192.168.1.42".parse().unwrap()));
+        assert!(!ban.matches("192.168.2.1".parse().unwrap()));
+        assert!(!ban.matches("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn matches_v6_prefix() {
+        let ban = BanEntry::new("2001:db8:0:1::".parse().unwrap(), 64);
+        assert!(ban.matches("2001:db8:0:1:dead:beef::1".parse().unwrap()));
+        assert!(!ban.matches("2001:db8:0:2::1".parse().unwrap()));
+        assert!(!ban.matches("192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn converts_v4_mapped_addresses() {
+        let mut ban = BanEntry::new("10.0.0.0".parse().unwrap(), 8);
+        ban.reason = Some("spam".to_owned());
+        ban.duration = BanDuration::For(Duration::from_secs(3600));
+        ban.set_start_time(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(Some("2023-11-14T22:13:20"), ban.start.as_deref());
+
+        let msg = msgs::ban_list::BanEntry::from(&ban);
+        #[cfg(feature = "protobuf")]
+        let (address, mask) = (msg.address(), msg.mask());
+        #[cfg(feature = "prost")]
+        let (address, mask) = (&msg.address[..], msg.mask);
+        assert_eq!(
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 0],
+            address
+        );
+        assert_eq!(104, mask);
+
+        let parsed = BanEntry::try_from(&msg).unwrap();
+        assert_eq!(ban, parsed);
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_003_600)),
+            parsed.end_time()
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_times() {
+        let ban = |start: &str| BanEntry {
+            start: Some(start.to_owned()),
+            duration: BanDuration::For(Duration::from_secs(60)),
+            ..BanEntry::single("10.0.0.1".parse().unwrap())
+        };
+        assert_eq!(None, ban("300000000000-01-01T00:00:00").start_time());
+        assert_eq!(None, ban("18446744073709551615-03-01T00:00:00").end_time());
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(60)),
+            ban("1970-01-01T00:00:00Z").end_time()
+        );
+    }
+
+    #[test]
+    fn rejects_days_beyond_the_month() {
+        assert_eq!(None, parse_time("2024-02-30T00:00:00"));
+        assert_eq!(None, parse_time("2023-02-29T00:00:00"));
+        assert_eq!(None, parse_time("2023-04-31T00:00:00"));
+        assert_eq!(None, parse_time("2100-02-29T00:00:00"));
+        assert!(parse_time("2024-02-29T00:00:00").is_some());
+        assert!(parse_time("2000-02-29T00:00:00").is_some());
+        assert!(parse_time("2023-12-31T23:59:59").is_some());
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            parse_time("2000-02-29T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn overlong_prefixes_match_nothing() {
+        let mut ban = BanEntry::single("10.0.0.1".parse().unwrap());
+        ban.prefix_len = 200;
+        assert!(!ban.matches("10.0.0.1".parse().unwrap()));
+        let msg = msgs::ban_list::BanEntry::from(&ban);
+        #[cfg(feature = "protobuf")]
+        assert_eq!(128, msg.mask());
+        #[cfg(feature = "prost")]
+        assert_eq!(128, msg.mask);
+
+        let mut ban = BanEntry::single("::1".parse().unwrap());
+        ban.prefix_len = 129;
+        assert!(!ban.matches("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        let mut msg = msgs::ban_list::BanEntry::from(BanEntry::single("::1".parse().unwrap()));
+        #[cfg(feature = "protobuf")]
+        msg.set_mask(129);
+        #[cfg(feature = "prost")]
+        {
+            msg.mask = 129;
+        }
+        assert_eq!(
+            Err(BanEntryError::InvalidMask(129)),
+            BanEntry::try_from(&msg)
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Builder for the `ChannelState` message
+
+use super::msgs;
+use super::validate::ensure_valid;
+use super::validate::ValidationIssue;
+
+/// Builder for [msgs::ChannelState] messages.
+///
+/// `ChannelState` uses two different ways of describing links: clients changing a channel list
+/// the links to add and remove (`links_add` and `links_remove`), while servers announcing a
+/// channel list all of its links (`links`). The builder picks the right fields depending on how
+/// it was created, see [full_state](Self::full_state).
+#[derive(Clone, Debug)]
+pub struct ChannelStateBuilder {
+    msg: msgs::ChannelState,
+    full_state: bool,
+}
+
+impl ChannelStateBuilder {
+    /// Starts a request to create a new channel below `parent`.
+    pub fn create_under(parent: u32, name: impl Into<String>) -> Self {
+        let mut builder = Self::empty(false);
+        builder.msg.parent = Some(parent);
+        builder.msg.name = Some(name.into());
+        builder
+    }
+
+    /// Starts a request to modify an existing channel.
+    pub fn modify(channel_id: u32) -> Self {
+        let mut builder = Self::empty(false);
+        builder.msg.channel_id = Some(channel_id);
+        builder
+    }
+
+    /// Starts the complete description of an existing channel, as sent by servers.
+    ///
+    /// [link](Self::link) and [unlink](Self::unlink) modify the full set of links (`links`)
+    /// instead of the delta fields.
+    pub fn full_state(channel_id: u32) -> Self {
+        let mut builder = Self::empty(true);
+        builder.msg.channel_id = Some(channel_id);
+        builder
+    }
+
+    fn empty(full_state: bool) -> Self {
+        ChannelStateBuilder {
+            msg: msgs::ChannelState::default(),
+            full_state,
+        }
+    }
+
+    /// Sets the name of the channel.
+    pub fn rename(mut self, name: impl Into<String>) -> Self {
+        self.msg.name = Some(name.into());
+        self
+    }
+
+    /// Moves the channel below `parent`, at the given sort position.
+    pub fn move_to(mut self, parent: u32, position: i32) -> Self {
+        self.msg.parent = Some(parent);
+        self.msg.position = Some(position);
+        self
+    }
+
+    /// Sets the sort position of the channel among its siblings.
+    pub fn position(mut self, position: i32) -> Self {
+        self.msg.position = Some(position);
+        self
+    }
+
+    /// Sets the description of the channel.
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.msg.description = Some(description.into());
+        self
+    }
+
+    /// Sets the maximum amount of users in the channel, `0` meaning the server default.
+    pub fn max_users(mut self, max_users: u32) -> Self {
+        self.msg.max_users = Some(max_users);
+        self
+    }
+
+    /// Sets whether the channel is temporary, i.e. removed once it is empty.
+    pub fn temporary(mut self, temporary: bool) -> Self {
+        self.msg.temporary = Some(temporary);
+        self
+    }
+
+    /// Links the channel to another one.
+    pub fn link(mut self, channel_id: u32) -> Self {
+        if self.full_state {
+            add_unique(&mut self.msg.links, channel_id);
+        } else {
+            self.msg.links_remove.retain(|it| *it != channel_id);
+            add_unique(&mut self.msg.links_add, channel_id);
+        }
+        self
+    }
+
+    /// Removes the link between the channel and another one.
+    pub fn unlink(mut self, channel_id: u32) -> Self {
+        if self.full_state {
+            self.msg.links.retain(|it| *it != channel_id);
+        } else {
+            self.msg.links_add.retain(|it| *it != channel_id);
+            add_unique(&mut self.msg.links_remove, channel_id);
+        }
+        self
+    }
+
+    /// Builds the message.
+    ///
+    /// Fails if the message would be rejected by Murmur, see [Validate].
+    pub fn build(self) -> Result<msgs::ChannelState, ValidationIssue> {
+        ensure_valid(self.msg)
+    }
+}
+
+fn add_unique(list: &mut Vec<u32>, channel_id: u32) {
+    if !list.contains(&channel_id) {
+        list.push(channel_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builds_link_deltas() {
+        let msg = ChannelStateBuilder::modify(3)
+            .link(4)
+            .link(5)
+            .unlink(4)
+            .unlink(6)
+            .build()
+            .unwrap();
+        assert_eq!(vec![5], msg.links_add);
+        assert_eq!(vec![4, 6], msg.links_remove);
+        assert!(msg.links.is_empty());
+
+        let msg = ChannelStateBuilder::full_state(3)
+            .link(4)
+            .link(5)
+            .unlink(4)
+            .build()
+            .unwrap();
+        assert_eq!(vec![5], msg.links);
+        assert!(msg.links_add.is_empty());
+    }
+
+    #[test]
+    fn refuses_invalid_messages() {
+        let msg = ChannelStateBuilder::create_under(0, "Lobby")
+            .temporary(true)
+            .build()
+            .unwrap();
+        assert_eq!(Some(0), msg.parent);
+        assert_eq!(None, msg.channel_id);
+
+        let issue = ChannelStateBuilder::create_under(0, "")
+            .build()
+            .unwrap_err();
+        assert_eq!("name", issue.field);
+        let issue = ChannelStateBuilder::modify(3)
+            .move_to(3, 0)
+            .build()
+            .unwrap_err();
+        assert_eq!("parent", issue.field);
+    }
+}
@@ -0,0 +1,238 @@
+//! Codec selection based on the `CodecVersion` message
+//!
+//! Servers announce which codec clients should encode with: Opus, if every connected client
+//! supports it, otherwise one of two CELT bitstream versions, which are sent as
+//! [CeltAlpha](VoicePacketPayload::CeltAlpha) and [CeltBeta](VoicePacketPayload::CeltBeta)
+//! respectively. The announcement may change whenever users join or leave.
+
+use bytes::Bytes;
+
+pub use super::authenticate::CELT_0_11_0;
+pub use super::authenticate::CELT_0_7_0;
+use super::msgs;
+use crate::voice::VoicePacketPayload;
+
+/// The codecs the local client can encode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodecCapabilities {
+    /// Whether Opus is supported.
+    pub opus: bool,
+    /// The supported CELT bitstream versions.
+    pub celt_versions: Vec<i32>,
+}
+
+impl Default for CodecCapabilities {
+    /// Opus only, as supported by all current clients.
+    fn default() -> Self {
+        CodecCapabilities {
+            opus: true,
+            celt_versions: Vec::new(),
+        }
+    }
+}
+
+/// The codec to encode with, as decided by [CodecNegotiation].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CodecDecision {
+    /// Encode with Opus.
+    UseOpus,
+    /// Encode with the CELT bitstream version announced as alpha.
+    UseCeltAlpha(i32),
+    /// Encode with the CELT bitstream version announced as beta.
+    UseCeltBeta(i32),
+    /// None of the supported codecs can be decoded by all other clients.
+    Incompatible,
+}
+
+impl CodecDecision {
+    /// Decides which codec to use, the way the reference client does.
+    ///
+    /// Opus is used if the server asks for it. Otherwise, the preferred CELT version is used,
+    /// falling back to the other one if it is not supported.
+    pub fn negotiate(capabilities: &CodecCapabilities, msg: &msgs::CodecVersion) -> Self {
+        if msg.opus() && capabilities.opus {
+            return CodecDecision::UseOpus;
+        }
+        #[cfg(feature = "protobuf")]
+        let (alpha, beta, prefer_alpha) = (msg.alpha(), msg.beta(), msg.prefer_alpha());
+        #[cfg(feature = "prost")]
+        let (alpha, beta, prefer_alpha) = (msg.alpha, msg.beta, msg.prefer_alpha);
+        let alpha = Some(CodecDecision::UseCeltAlpha(alpha))
+            .filter(|_| capabilities.celt_versions.contains(&alpha));
+        let beta = Some(CodecDecision::UseCeltBeta(beta))
+            .filter(|_| capabilities.celt_versions.contains(&beta));
+        let decision = if prefer_alpha {
+            alpha.or(beta)
+        } else {
+            beta.or(alpha)
+        };
+        decision.unwrap_or(CodecDecision::Incompatible)
+    }
+
+    /// Returns the CELT bitstream version to encode with, if a CELT codec was chosen.
+    pub fn celt_version(self) -> Option<i32> {
+        match self {
+            CodecDecision::UseCeltAlpha(version) | CodecDecision::UseCeltBeta(version) => {
+                Some(version)
+            }
+            CodecDecision::UseOpus | CodecDecision::Incompatible => None,
+        }
+    }
+
+    /// Wraps an encoded frame into the matching payload.
+    ///
+    /// For CELT, the end of a transmission is signaled by an additional empty frame. Returns
+    /// `None` if the decision is [Incompatible](CodecDecision::Incompatible).
+    pub fn payload(self, frame: Bytes, end_of_transmission: bool) -> Option<VoicePacketPayload> {
+        let frames = || {
+            let mut frames = vec![frame.clone()];
+            if end_of_transmission {
+                frames.push(Bytes::new());
+            }
+            frames
+        };
+        Some(match self {
+            CodecDecision::UseOpus => VoicePacketPayload::Opus(frame.clone(), end_of_transmission),
+            CodecDecision::UseCeltAlpha(_) => VoicePacketPayload::CeltAlpha(frames()),
+            CodecDecision::UseCeltBeta(_) => VoicePacketPayload::CeltBeta(frames()),
+            CodecDecision::Incompatible => return None,
+        })
+    }
+}
+
+/// Tracks the codec to encode with over the course of a session.
+#[derive(Clone, Debug)]
+pub struct CodecNegotiation {
+    capabilities: CodecCapabilities,
+    decision: CodecDecision,
+}
+
+impl CodecNegotiation {
+    /// Creates a negotiation based on the first `CodecVersion` sent by the server.
+    pub fn new(capabilities: CodecCapabilities, msg: &msgs::CodecVersion) -> Self {
+        let decision = CodecDecision::negotiate(&capabilities, msg);
+        CodecNegotiation {
+            capabilities,
+            decision,
+        }
+    }
+
+    /// Re-evaluates the decision when the server announced a new `CodecVersion`.
+    ///
+    /// Returns whether the decision changed, i.e. the encoder needs to be switched.
+    pub fn update(&mut self, msg: &msgs::CodecVersion) -> bool {
+        let decision = CodecDecision::negotiate(&self.capabilities, msg);
+        let changed = decision != self.decision;
+        self.decision = decision;
+        changed
+    }
+
+    /// Returns the local capabilities.
+    pub fn capabilities(&self) -> &CodecCapabilities {
+        &self.capabilities
+    }
+
+    /// Returns the current decision.
+    pub fn decision(&self) -> CodecDecision {
+        self.decision
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::backend::message;
+
+    fn codec_version(alpha: i32, beta: i32, prefer_alpha: bool, opus: bool) -> msgs::CodecVersion {
+        message!(msgs::CodecVersion {
+            #[cfg(feature = "protobuf")]
+            alpha: Some(alpha),
+            #[cfg(feature = "prost")]
+            alpha,
+            #[cfg(feature = "protobuf")]
+            beta: Some(beta),
+            #[cfg(feature = "prost")]
+            beta,
+            #[cfg(feature = "protobuf")]
+            prefer_alpha: Some(prefer_alpha),
+            #[cfg(feature = "prost")]
+            prefer_alpha,
+            opus: Some(opus),
+        })
+    }
+
+    #[test]
+    fn negotiates_codecs() {
+        let all = CodecCapabilities {
+            opus: true,
+            celt_versions: vec![CELT_0_7_0, CELT_0_11_0],
+        };
+        let celt_only = CodecCapabilities {
+            opus: false,
+            celt_versions: vec![CELT_0_11_0],
+        };
+        let negotiate = CodecDecision::negotiate;
+
+        let msg = codec_version(CELT_0_7_0, CELT_0_11_0, true, true);
+        assert_eq!(CodecDecision::UseOpus, negotiate(&all, &msg));
+        assert_eq!(
+            CodecDecision::UseCeltBeta(CELT_0_11_0),
+            negotiate(&celt_only, &msg)
+        );
+        assert_eq!(
+            CodecDecision::Incompatible,
+            negotiate(
+                &CodecCapabilities::default(),
+                &codec_version(CELT_0_7_0, 0, true, false)
+            )
+        );
+
+        let msg = codec_version(CELT_0_7_0, CELT_0_11_0, false, false);
+        assert_eq!(
+            CodecDecision::UseCeltBeta(CELT_0_11_0),
+            negotiate(&all, &msg)
+        );
+        let msg = codec_version(CELT_0_7_0, CELT_0_11_0, true, false);
+        assert_eq!(
+            CodecDecision::UseCeltAlpha(CELT_0_7_0),
+            negotiate(&all, &msg)
+        );
+    }
+
+    #[test]
+    fn switches_codecs_mid_session() {
+        let capabilities = CodecCapabilities {
+            opus: true,
+            celt_versions: vec![CELT_0_7_0],
+        };
+        let mut negotiation = CodecNegotiation::new(
+            capabilities,
+            &codec_version(CELT_0_7_0, CELT_0_11_0, true, true),
+        );
+        assert_eq!(CodecDecision::UseOpus, negotiation.decision());
+        // A legacy client joined
+        assert!(negotiation.update(&codec_version(CELT_0_7_0, CELT_0_11_0, true, false)));
+        assert_eq!(
+            CodecDecision::UseCeltAlpha(CELT_0_7_0),
+            negotiation.decision()
+        );
+        assert!(!negotiation.update(&codec_version(CELT_0_7_0, CELT_0_11_0, false, false)));
+    }
+
+    #[test]
+    fn wraps_frames_into_payloads() {
+        let frame = Bytes::from_static(b"frame");
+        assert_eq!(
+            Some(VoicePacketPayload::Opus(frame.clone(), true)),
+            CodecDecision::UseOpus.payload(frame.clone(), true)
+        );
+        assert_eq!(
+            Some(VoicePacketPayload::CeltBeta(vec![
+                frame.clone(),
+                Bytes::new()
+            ])),
+            CodecDecision::UseCeltBeta(CELT_0_11_0).payload(frame.clone(), true)
+        );
+        assert_eq!(None, CodecDecision::Incompatible.payload(frame, false));
+    }
+}
@@ -0,0 +1,184 @@
+//! Typed access to the `ContextActionModify` message
+
+use std::collections::BTreeMap;
+
+use bitflags::bitflags;
+
+use super::msgs;
+
+bitflags! {
+    /// Where a context action is displayed.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct ContextFlags: u32 {
+        /// In the context menu of the server.
+        const SERVER = 0x1;
+        /// In the context menu of channels.
+        const CHANNEL = 0x2;
+        /// In the context menu of users.
+        const USER = 0x4;
+    }
+}
+
+impl msgs::ContextActionModify {
+    /// Creates a message registering a context action (or replacing one with the same ID).
+    pub fn add(action: impl Into<String>, text: impl Into<String>, context: ContextFlags) -> Self {
+        let mut msg = msgs::ContextActionModify {
+            text: Some(text.into()),
+            context: Some(context.bits()),
+            ..Default::default()
+        };
+        msg.set_action_operation(action.into(), 0);
+        msg
+    }
+
+    /// Creates a message removing a context action.
+    pub fn remove(action: impl Into<String>) -> Self {
+        let mut msg = msgs::ContextActionModify::default();
+        msg.set_action_operation(action.into(), 1);
+        msg
+    }
+
+    fn set_action_operation(&mut self, action: String, operation: i32) {
+        #[cfg(feature = "protobuf")]
+        {
+            self.action = Some(action);
+            self.operation = Some(protobuf::EnumOrUnknown::from_i32(operation));
+        }
+        #[cfg(feature = "prost")]
+        {
+            self.action = action;
+            self.operation = Some(operation);
+        }
+    }
+
+    /// Returns where the action is displayed, including bits unknown to this crate.
+    pub fn context_flags(&self) -> ContextFlags {
+        ContextFlags::from_bits_retain(self.context.unwrap_or_default())
+    }
+
+    /// Returns whether the action is removed rather than added.
+    ///
+    /// Clients before 1.2.4 do not know the operation and always add the action.
+    pub fn is_removal(&self) -> bool {
+        #[cfg(feature = "protobuf")]
+        let operation = self.operation.map(|it| it.value());
+        #[cfg(feature = "prost")]
+        let operation = self.operation;
+        operation == Some(1)
+    }
+}
+
+/// A context action registered by the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContextActionEntry {
+    /// The text displayed in the menu.
+    pub text: String,
+    /// Where the action is displayed.
+    pub context: ContextFlags,
+}
+
+/// The context actions registered by the server, maintained from `ContextActionModify`
+/// messages.
+#[derive(Clone, Debug, Default)]
+pub struct ContextActions {
+    actions: BTreeMap<String, ContextActionEntry>,
+}
+
+impl ContextActions {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds, replaces or removes an action.
+    pub fn apply(&mut self, msg: &msgs::ContextActionModify) {
+        #[cfg(feature = "protobuf")]
+        let action = msg.action();
+        #[cfg(feature = "prost")]
+        let action = &msg.action;
+        if msg.is_removal() {
+            self.actions.remove(action);
+        } else {
+            self.actions.insert(
+                action.to_owned(),
+                ContextActionEntry {
+                    text: msg.text.clone().unwrap_or_else(|| action.to_owned()),
+                    context: msg.context_flags(),
+                },
+            );
+        }
+    }
+
+    /// Returns the action with the given ID.
+    pub fn get(&self, action: &str) -> Option<&ContextActionEntry> {
+        self.actions.get(action)
+    }
+
+    /// Returns the IDs and entries of the actions displayed in any of the given contexts.
+    pub fn for_context(
+        &self,
+        context: ContextFlags,
+    ) -> impl Iterator<Item = (&str, &ContextActionEntry)> + '_ {
+        self.actions
+            .iter()
+            .filter(move |(_, it)| it.context.intersects(context))
+            .map(|(action, it)| (action.as_str(), it))
+    }
+
+    /// Returns the amount of registered actions.
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// Returns whether no actions are registered.
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trips_context_flags() {
+        let msg = msgs::ContextActionModify::add(
+            "kick",
+            "Kick",
+            ContextFlags::USER | ContextFlags::CHANNEL,
+        );
+        assert_eq!(Some(6), msg.context);
+        assert!(!msg.is_removal());
+
+        let mut msg = msgs::ContextActionModify::remove("kick");
+        assert!(msg.is_removal());
+        msg.context = Some(0x104);
+        assert_eq!(0x104, msg.context_flags().bits());
+        assert!(msg.context_flags().contains(ContextFlags::USER));
+    }
+
+    #[test]
+    fn tracks_registered_actions() {
+        let mut actions = ContextActions::new();
+        actions.apply(&msgs::ContextActionModify::add(
+            "a",
+            "A",
+            ContextFlags::USER,
+        ));
+        actions.apply(&msgs::ContextActionModify::add(
+            "b",
+            "B",
+            ContextFlags::SERVER,
+        ));
+        assert_eq!(
+            vec!["a"],
+            actions
+                .for_context(ContextFlags::USER)
+                .map(|(it, _)| it)
+                .collect::<Vec<_>>()
+        );
+        actions.apply(&msgs::ContextActionModify::remove("a"));
+        assert_eq!(1, actions.len());
+        assert_eq!("B", actions.get("b").unwrap().text);
+    }
+}
@@ -0,0 +1,158 @@
+//! Typed access to the `CryptSetup` message
+//!
+//! Murmur sends `CryptSetup` in three shapes: the full setup with key and both nonces after
+//! authenticating, only the server nonce in response to a resync request, and an empty message
+//! to request the client's nonce. Clients request a resync by sending an empty message as well.
+
+use std::fmt;
+
+use super::msgs;
+
+/// Size in bytes of the key and the nonces.
+pub const CRYPT_SETUP_FIELD_SIZE: usize = 16;
+
+/// The meaning of a [msgs::CryptSetup], depending on the fields which are set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CryptSetupKind {
+    /// The initial setup, sent by servers.
+    Full {
+        /// The shared key.
+        key: [u8; CRYPT_SETUP_FIELD_SIZE],
+        /// The nonce the client encrypts with.
+        client_nonce: [u8; CRYPT_SETUP_FIELD_SIZE],
+        /// The nonce the server encrypts with.
+        server_nonce: [u8; CRYPT_SETUP_FIELD_SIZE],
+    },
+    /// A request for the other side's nonce.
+    ResyncRequest,
+    /// The server's current nonce, in response to a resync request.
+    ServerNonce([u8; CRYPT_SETUP_FIELD_SIZE]),
+    /// The client's current nonce, in response to a resync request.
+    ClientNonce([u8; CRYPT_SETUP_FIELD_SIZE]),
+}
+
+/// The reason a [msgs::CryptSetup] could not be interpreted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CryptSetupError {
+    /// A field does not have a length of [CRYPT_SETUP_FIELD_SIZE].
+    InvalidLength {
+        /// Name of the field.
+        field: &'static str,
+        /// The actual length.
+        len: usize,
+    },
+    /// The message has a different shape than expected.
+    UnexpectedKind,
+}
+
+impl fmt::Display for CryptSetupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptSetupError::InvalidLength { field, len } => write!(
+                f,
+                "CryptSetup {} has length {} instead of {}",
+                field, len, CRYPT_SETUP_FIELD_SIZE
+            ),
+            CryptSetupError::UnexpectedKind => f.write_str("unexpected kind of CryptSetup"),
+        }
+    }
+}
+
+impl std::error::Error for CryptSetupError {}
+
+fn field(
+    name: &'static str,
+    value: &Option<Vec<u8>>,
+) -> Result<Option<[u8; CRYPT_SETUP_FIELD_SIZE]>, CryptSetupError> {
+    match value {
+        None => Ok(None),
+        Some(value) => {
+            value
+                .as_slice()
+                .try_into()
+                .map(Some)
+                .map_err(|_| CryptSetupError::InvalidLength {
+                    field: name,
+                    len: value.len(),
+                })
+        }
+    }
+}
+
+impl msgs::CryptSetup {
+    /// Creates an empty message, requesting the other side's nonce.
+    pub fn resync_request() -> Self {
+        Self::default()
+    }
+
+    /// Returns the key, if it is set and has the correct length.
+    pub fn key_array(&self) -> Option<[u8; CRYPT_SETUP_FIELD_SIZE]> {
+        field("key", &self.key).ok().flatten()
+    }
+
+    /// Returns the client nonce, if it is set and has the correct length.
+    pub fn client_nonce_array(&self) -> Option<[u8; CRYPT_SETUP_FIELD_SIZE]> {
+        field("client_nonce", &self.client_nonce).ok().flatten()
+    }
+
+    /// Returns the server nonce, if it is set and has the correct length.
+    pub fn server_nonce_array(&self) -> Option<[u8; CRYPT_SETUP_FIELD_SIZE]> {
+        field("server_nonce", &self.server_nonce).ok().flatten()
+    }
+
+    /// Determines the meaning of the message.
+    ///
+    /// Fails if any field has the wrong length, or the combination of fields is not one of
+    /// [CryptSetupKind].
+    pub fn kind(&self) -> Result<CryptSetupKind, CryptSetupError> {
+        let key = field("key", &self.key)?;
+        let client_nonce = field("client_nonce", &self.client_nonce)?;
+        let server_nonce = field("server_nonce", &self.server_nonce)?;
+        match (key, client_nonce, server_nonce) {
+            (Some(key), Some(client_nonce), Some(server_nonce)) => Ok(CryptSetupKind::Full {
+                key,
+                client_nonce,
+                server_nonce,
+            }),
+            (None, None, None) => Ok(CryptSetupKind::ResyncRequest),
+            (None, None, Some(nonce)) => Ok(CryptSetupKind::ServerNonce(nonce)),
+            (None, Some(nonce), None) => Ok(CryptSetupKind::ClientNonce(nonce)),
+            _ => Err(CryptSetupError::UnexpectedKind),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::backend::message;
+
+    #[test]
+    fn determines_kind() {
+        assert_eq!(
+            Ok(CryptSetupKind::ResyncRequest),
+            msgs::CryptSetup::resync_request().kind()
+        );
+        let msg = message!(msgs::CryptSetup {
+            server_nonce: Some(vec![3; 16]),
+        });
+        assert_eq!(Ok(CryptSetupKind::ServerNonce([3; 16])), msg.kind());
+        assert_eq!(Some([3; 16]), msg.server_nonce_array());
+        assert_eq!(None, msg.key_array());
+
+        let msg = message!(msgs::CryptSetup {
+            key: Some(vec![1; 16]),
+            client_nonce: Some(vec![2; 16]),
+            server_nonce: Some(vec![3; 15]),
+        });
+        assert_eq!(
+            Err(CryptSetupError::InvalidLength {
+                field: "server_nonce",
+                len: 15
+            }),
+            msg.kind()
+        );
+        assert_eq!(None, msg.server_nonce_array());
+    }
+}
@@ -0,0 +1,238 @@
+//! Closure-based dispatching of received packets
+//!
+//! An alternative to implementing [ControlPacketHandler](super::ControlPacketHandler) for
+//! applications which prefer registering handlers at runtime, e.g. bots.
+
+use super::ControlPacket;
+use super::ControlPacketMessage;
+use super::PacketKind;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+
+type Handler<'a, Dst> = Box<dyn FnMut(ControlPacket<Dst>) + 'a>;
+
+/// Passes [ControlPacket]s to handlers registered per message type.
+///
+/// Handlers may borrow from their environment for the lifetime `'a`. Only message types which
+/// can be contained in a [ControlPacket] can be registered, see [ControlPacketMessage].
+pub struct Dispatcher<'a, Dst: VoicePacketDst> {
+    handlers: Vec<Option<Handler<'a, Dst>>>,
+    unhandled: Option<Handler<'a, Dst>>,
+}
+
+impl<'a, Dst: VoicePacketDst + 'a> Default for Dispatcher<'a, Dst> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a, Dst: VoicePacketDst + 'a> Dispatcher<'a, Dst> {
+    /// Creates a dispatcher without any handlers.
+    pub fn new() -> Self {
+        Dispatcher {
+            handlers: PacketKind::ALL.iter().map(|_| None).collect(),
+            unhandled: None,
+        }
+    }
+
+    /// Registers the handler for messages of type `M`, replacing any previous one.
+    pub fn on<M, F>(&mut self, mut handler: F) -> &mut Self
+    where
+        M: ControlPacketMessage<Dst> + TryFrom<ControlPacket<Dst>, Error = ControlPacket<Dst>>,
+        F: FnMut(M) + 'a,
+    {
+        self.handlers[M::KIND.id() as usize] = Some(Box::new(move |packet| {
+            if let Ok(msg) = M::try_from(packet) {
+                handler(msg);
+            }
+        }));
+        self
+    }
+
+    /// Registers the handler for voice packets tunneled via `UDPTunnel`.
+    pub fn on_voice<F: FnMut(VoicePacket<Dst>) + 'a>(&mut self, handler: F) -> &mut Self {
+        self.on(handler)
+    }
+
+    /// Registers the handler for all packets without a handler of their own, including packets
+    /// of unknown type.
+    pub fn on_unhandled<F: FnMut(ControlPacket<Dst>) + 'a>(&mut self, handler: F) -> &mut Self {
+        self.unhandled = Some(Box::new(handler));
+        self
+    }
+
+    /// Passes the packet to the handler registered for its type, or the unhandled handler.
+    ///
+    /// Packets without either are dropped.
+    pub fn dispatch(&mut self, packet: ControlPacket<Dst>) {
+        let handler = match (&packet, PacketKind::try_from(packet.id())) {
+            (ControlPacket::Other(_), _) | (_, Err(_)) => None,
+            (_, Ok(kind)) => self.handlers[kind.id() as usize].as_mut(),
+        };
+        if let Some(handler) = handler.or(self.unhandled.as_mut()) {
+            handler(packet);
+        }
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+pub use self::future::AsyncDispatcher;
+
+#[cfg(feature = "tokio-codec")]
+mod future {
+    use std::future::Future;
+    use std::pin::Pin;
+
+    use super::ControlPacket;
+    use super::ControlPacketMessage;
+    use super::PacketKind;
+    use crate::voice::VoicePacket;
+    use crate::voice::VoicePacketDst;
+
+    type BoxFuture<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+    type Handler<'a, Dst> = Box<dyn FnMut(ControlPacket<Dst>) -> BoxFuture<'a> + 'a>;
+
+    /// Like [Dispatcher](super::Dispatcher), but with handlers returning futures.
+    ///
+    /// The future returned by a handler is awaited by [dispatch](Self::dispatch) before the
+    /// next packet can be dispatched.
+    pub struct AsyncDispatcher<'a, Dst: VoicePacketDst> {
+        handlers: Vec<Option<Handler<'a, Dst>>>,
+        unhandled: Option<Handler<'a, Dst>>,
+    }
+
+    impl<'a, Dst: VoicePacketDst + 'a> Default for AsyncDispatcher<'a, Dst> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<'a, Dst: VoicePacketDst + 'a> AsyncDispatcher<'a, Dst> {
+        /// Creates a dispatcher without any handlers.
+        pub fn new() -> Self {
+            AsyncDispatcher {
+                handlers: PacketKind::ALL.iter().map(|_| None).collect(),
+                unhandled: None,
+            }
+        }
+
+        /// Registers the handler for messages of type `M`, replacing any previous one.
+        pub fn on<M, F, Fut>(&mut self, mut handler: F) -> &mut Self
+        where
+            M: ControlPacketMessage<Dst> + TryFrom<ControlPacket<Dst>, Error = ControlPacket<Dst>>,
+            F: FnMut(M) -> Fut + 'a,
+            Fut: Future<Output = ()> + 'a,
+        {
+            self.handlers[M::KIND.id() as usize] =
+                Some(Box::new(move |packet| match M::try_from(packet) {
+                    Ok(msg) => Box::pin(handler(msg)),
+                    Err(_) => Box::pin(async {}),
+                }));
+            self
+        }
+
+        /// Registers the handler for voice packets tunneled via `UDPTunnel`.
+        pub fn on_voice<F, Fut>(&mut self, handler: F) -> &mut Self
+        where
+            F: FnMut(VoicePacket<Dst>) -> Fut + 'a,
+            Fut: Future<Output = ()> + 'a,
+        {
+            self.on(handler)
+        }
+
+        /// Registers the handler for all packets without a handler of their own, including
+        /// packets of unknown type.
+        pub fn on_unhandled<F, Fut>(&mut self, mut handler: F) -> &mut Self
+        where
+            F: FnMut(ControlPacket<Dst>) -> Fut + 'a,
+            Fut: Future<Output = ()> + 'a,
+        {
+            self.unhandled = Some(Box::new(move |packet| Box::pin(handler(packet))));
+            self
+        }
+
+        /// Passes the packet to the handler registered for its type, or the unhandled handler,
+        /// and waits for it to finish.
+        ///
+        /// Packets without either are dropped.
+        pub async fn dispatch(&mut self, packet: ControlPacket<Dst>) {
+            let handler = match (&packet, PacketKind::try_from(packet.id())) {
+                (ControlPacket::Other(_), _) | (_, Err(_)) => None,
+                (_, Ok(kind)) => self.handlers[kind.id() as usize].as_mut(),
+            };
+            if let Some(handler) = handler.or(self.unhandled.as_mut()) {
+                handler(packet).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::control::msgs;
+    use crate::control::RawControlPacket;
+    use crate::voice::Clientbound;
+
+    fn packets() -> Vec<ControlPacket<Clientbound>> {
+        let text = msgs::TextMessage::to_channel(0).text("hi").build().unwrap();
+        vec![
+            text.into(),
+            VoicePacket::Ping { timestamp: 5 }.into(),
+            msgs::Ping::default().into(),
+            ControlPacket::Other(RawControlPacket {
+                id: 1234,
+                bytes: Bytes::new(),
+            }),
+        ]
+    }
+
+    fn content(msg: msgs::TextMessage) -> String {
+        #[cfg(feature = "protobuf")]
+        return msg.message().to_owned();
+        #[cfg(feature = "prost")]
+        return msg.message;
+    }
+
+    #[test]
+    fn dispatches_to_registered_handlers() {
+        let mut texts = Vec::new();
+        let mut voice = Vec::new();
+        let mut unhandled = Vec::new();
+        let mut dispatcher = Dispatcher::new();
+        dispatcher
+            .on(|msg: msgs::TextMessage| texts.push(content(msg)))
+            .on_voice(|packet| voice.push(packet))
+            .on_unhandled(|packet| unhandled.push(packet.id()));
+        for packet in packets() {
+            dispatcher.dispatch(packet);
+        }
+        drop(dispatcher);
+
+        assert_eq!(vec!["hi"], texts);
+        assert_eq!(vec![VoicePacket::Ping { timestamp: 5 }], voice);
+        assert_eq!(vec![msgs::id::Ping, 1234], unhandled);
+    }
+
+    #[cfg(feature = "tokio-codec")]
+    #[test]
+    fn async_dispatcher_awaits_handlers() {
+        use std::cell::RefCell;
+
+        let texts = RefCell::new(Vec::new());
+        let mut dispatcher = AsyncDispatcher::new();
+        dispatcher.on(|msg: msgs::TextMessage| {
+            let texts = &texts;
+            async move { texts.borrow_mut().push(content(msg)) }
+        });
+        futures::executor::block_on(async {
+            for packet in packets() {
+                dispatcher.dispatch(packet).await;
+            }
+        });
+        drop(dispatcher);
+        assert_eq!(vec!["hi"], texts.into_inner());
+    }
+}
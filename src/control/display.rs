@@ -0,0 +1,128 @@
+//! Human-readable formatting of control packets
+
+use std::fmt;
+
+#[cfg(feature = "protobuf")]
+use protobuf::reflect::ReflectFieldRef;
+#[cfg(feature = "protobuf")]
+use protobuf::reflect::ReflectValueRef;
+#[cfg(feature = "protobuf")]
+use protobuf::MessageDyn;
+#[cfg(feature = "protobuf")]
+use protobuf::MessageFull;
+
+#[cfg(feature = "prost")]
+use super::backend;
+
+/// Maximum amount of characters of string fields which are printed.
+#[cfg(feature = "protobuf")]
+const MAX_STRING_LEN: usize = 64;
+/// Maximum amount of elements of repeated fields which are printed individually.
+#[cfg(feature = "protobuf")]
+const MAX_REPEATED_LEN: usize = 8;
+/// Fields whose values must never end up in logs.
+#[cfg(feature = "protobuf")]
+const REDACTED_FIELDS: &[&str] = &["password"];
+
+/// Formats a message as its name followed by all fields which are set.
+#[cfg(feature = "protobuf")]
+pub(super) fn fmt_message<M: MessageFull>(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    msg: &M,
+) -> fmt::Result {
+    fmt_message_dyn(f, name, msg)
+}
+
+/// Formats a message as its name followed by its length.
+///
+/// Without reflection, fields can't be printed selectively, and printing all of them via `Debug`
+/// might leak passwords into logs.
+#[cfg(feature = "prost")]
+pub(super) fn fmt_message<M: prost::Message>(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    msg: &M,
+) -> fmt::Result {
+    write!(f, "{}{{<{} bytes>}}", name, backend::encoded_len(msg))
+}
+
+#[cfg(feature = "protobuf")]
+fn fmt_message_dyn(f: &mut fmt::Formatter<'_>, name: &str, msg: &dyn MessageDyn) -> fmt::Result {
+    write!(f, "{}{{", name)?;
+    let mut first = true;
+    for field in msg.descriptor_dyn().fields() {
+        let field_ref = field.get_reflect(msg);
+        let is_set = match &field_ref {
+            ReflectFieldRef::Optional(value) => value.value().is_some(),
+            ReflectFieldRef::Repeated(values) => !values.is_empty(),
+            ReflectFieldRef::Map(values) => !values.is_empty(),
+        };
+        if !is_set {
+            continue;
+        }
+        if !first {
+            f.write_str(", ")?;
+        }
+        first = false;
+
+        if REDACTED_FIELDS.contains(&field.name()) {
+            write!(f, "{}=<redacted>", field.name())?;
+            continue;
+        }
+        match field_ref {
+            ReflectFieldRef::Optional(value) => match value.value() {
+                Some(ReflectValueRef::Bool(true)) => f.write_str(field.name())?,
+                Some(value) => {
+                    write!(f, "{}=", field.name())?;
+                    fmt_value(f, value)?;
+                }
+                None => unreachable!(),
+            },
+            ReflectFieldRef::Repeated(values) => {
+                write!(f, "{}=[", field.name())?;
+                if values.len() > MAX_REPEATED_LEN {
+                    write!(f, "{} items", values.len())?;
+                } else {
+                    for i in 0..values.len() {
+                        if i > 0 {
+                            f.write_str(", ")?;
+                        }
+                        fmt_value(f, values.get(i))?;
+                    }
+                }
+                f.write_str("]")?;
+            }
+            ReflectFieldRef::Map(values) => {
+                write!(f, "{}={{{} entries}}", field.name(), values.len())?;
+            }
+        }
+    }
+    f.write_str("}")
+}
+
+#[cfg(feature = "protobuf")]
+fn fmt_value(f: &mut fmt::Formatter<'_>, value: ReflectValueRef<'_>) -> fmt::Result {
+    match value {
+        ReflectValueRef::U32(v) => write!(f, "{}", v),
+        ReflectValueRef::U64(v) => write!(f, "{}", v),
+        ReflectValueRef::I32(v) => write!(f, "{}", v),
+        ReflectValueRef::I64(v) => write!(f, "{}", v),
+        ReflectValueRef::F32(v) => write!(f, "{}", v),
+        ReflectValueRef::F64(v) => write!(f, "{}", v),
+        ReflectValueRef::Bool(v) => write!(f, "{}", v),
+        ReflectValueRef::String(v) => match v.char_indices().nth(MAX_STRING_LEN) {
+            Some((end, _)) => write!(f, "{:?}...", &v[..end]),
+            None => write!(f, "{:?}", v),
+        },
+        ReflectValueRef::Bytes(v) => write!(f, "<{} bytes>", v.len()),
+        ReflectValueRef::Enum(descriptor, v) => match descriptor.value_by_number(v) {
+            Some(value) => f.write_str(value.name()),
+            None => write!(f, "{}", v),
+        },
+        ReflectValueRef::Message(msg) => {
+            let descriptor = msg.descriptor_dyn();
+            fmt_message_dyn(f, descriptor.name(), &*msg)
+        }
+    }
+}
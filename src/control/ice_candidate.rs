@@ -0,0 +1,370 @@
+//! Parsing of the `IceCandidate` message of the WebRTC extension
+//!
+//! The message carries a single candidate in the form of the SDP `candidate` attribute
+//! ([RFC 8839](https://www.rfc-editor.org/rfc/rfc8839#section-5.1)), e.g.
+//! `candidate:7 1 UDP 2013266429 10.137.0.26 59220 typ host`.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use super::backend::message;
+use super::msgs;
+
+/// The type of an ICE candidate.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CandidateType {
+    /// An address of a local interface.
+    Host,
+    /// An address as seen by a STUN server.
+    ServerReflexive,
+    /// An address as seen by the peer.
+    PeerReflexive,
+    /// An address of a TURN server relaying the traffic.
+    Relay,
+    /// A type unknown to this crate.
+    Other(String),
+}
+
+impl CandidateType {
+    fn parse(s: &str) -> Self {
+        match s {
+            "host" => CandidateType::Host,
+            "srflx" => CandidateType::ServerReflexive,
+            "prflx" => CandidateType::PeerReflexive,
+            "relay" => CandidateType::Relay,
+            other => CandidateType::Other(other.to_owned()),
+        }
+    }
+
+    /// Returns the name used in SDP, e.g. `srflx`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            CandidateType::Host => "host",
+            CandidateType::ServerReflexive => "srflx",
+            CandidateType::PeerReflexive => "prflx",
+            CandidateType::Relay => "relay",
+            CandidateType::Other(other) => other,
+        }
+    }
+}
+
+/// Why an ICE candidate could not be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CandidateParseError {
+    /// The candidate does not start with `candidate:`.
+    MissingPrefix,
+    /// The candidate ended before the given field.
+    MissingField(&'static str),
+    /// The given field has an invalid value.
+    InvalidField {
+        /// The name of the field.
+        field: &'static str,
+        /// The invalid value.
+        value: String,
+    },
+}
+
+impl fmt::Display for CandidateParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CandidateParseError::MissingPrefix => {
+                write!(f, "ICE candidate does not start with \"candidate:\"")
+            }
+            CandidateParseError::MissingField(field) => {
+                write!(f, "ICE candidate lacks the {} field", field)
+            }
+            CandidateParseError::InvalidField { field, value } => {
+                write!(f, "ICE candidate has invalid {}: {:?}", field, value)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CandidateParseError {}
+
+/// An ICE candidate, parsed from the `candidate` attribute.
+///
+/// Formatting it yields the attribute again (without `a=`), see [to_a_line](Self::to_a_line).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ParsedCandidate {
+    /// Identifies candidates with the same base and server.
+    pub foundation: String,
+    /// The component, `1` for RTP.
+    pub component: u16,
+    /// The transport protocol as written, usually `UDP` or `TCP` in any case.
+    pub transport: String,
+    /// The priority of the candidate.
+    pub priority: u32,
+    /// The address, usually an IP address but may be a hostname (e.g. an mDNS `.local` name).
+    pub address: String,
+    /// The port.
+    pub port: u16,
+    /// The type of the candidate.
+    pub kind: CandidateType,
+    /// The related address (`raddr`) of reflexive and relay candidates.
+    pub related_address: Option<String>,
+    /// The related port (`rport`) of reflexive and relay candidates.
+    pub related_port: Option<u16>,
+    /// Further attributes in their original order, e.g. `("generation", "0")` or
+    /// `("tcptype", "passive")`.
+    pub extensions: Vec<(String, String)>,
+}
+
+impl ParsedCandidate {
+    /// Returns whether this is an address of a local interface.
+    pub fn is_host(&self) -> bool {
+        self.kind == CandidateType::Host
+    }
+
+    /// Returns whether the traffic is relayed by a TURN server.
+    pub fn is_relay(&self) -> bool {
+        self.kind == CandidateType::Relay
+    }
+
+    /// Returns whether the candidate uses TCP.
+    pub fn is_tcp(&self) -> bool {
+        self.transport.eq_ignore_ascii_case("tcp")
+    }
+
+    /// Returns the address, unless it is a hostname.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.address.parse().ok()
+    }
+
+    /// Returns the value of an extension attribute.
+    pub fn extension(&self, name: &str) -> Option<&str> {
+        self.extensions
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the candidate as SDP line, i.e. prefixed with `a=`.
+    pub fn to_a_line(&self) -> String {
+        format!("a={}", self)
+    }
+}
+
+fn parse_field<T: FromStr>(field: &'static str, value: &str) -> Result<T, CandidateParseError> {
+    value
+        .parse()
+        .map_err(|_| CandidateParseError::InvalidField {
+            field,
+            value: value.to_owned(),
+        })
+}
+
+/// Parses the attribute, with or without leading `a=`.
+impl FromStr for ParsedCandidate {
+    type Err = CandidateParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix("a=").unwrap_or(s);
+        let s = s
+            .strip_prefix("candidate:")
+            .ok_or(CandidateParseError::MissingPrefix)?;
+        let mut fields = s.split_ascii_whitespace();
+        let mut next = |field| {
+            fields
+                .next()
+                .ok_or(CandidateParseError::MissingField(field))
+        };
+
+        let foundation = next("foundation")?.to_owned();
+        let component = parse_field("component", next("component")?)?;
+        let transport = next("transport")?.to_owned();
+        let priority = parse_field("priority", next("priority")?)?;
+        let address = next("address")?.to_owned();
+        let port = parse_field("port", next("port")?)?;
+        match next("typ")? {
+            "typ" => {}
+            value => {
+                return Err(CandidateParseError::InvalidField {
+                    field: "typ",
+                    value: value.to_owned(),
+                })
+            }
+        }
+        let kind = CandidateType::parse(next("type")?);
+
+        let mut candidate = ParsedCandidate {
+            foundation,
+            component,
+            transport,
+            priority,
+            address,
+            port,
+            kind,
+            related_address: None,
+            related_port: None,
+            extensions: Vec::new(),
+        };
+        while let Some(name) = fields.next() {
+            let value = fields
+                .next()
+                .ok_or(CandidateParseError::MissingField("extension value"))?;
+            match name {
+                "raddr" if candidate.related_address.is_none() => {
+                    candidate.related_address = Some(value.to_owned());
+                }
+                "rport" if candidate.related_port.is_none() => {
+                    candidate.related_port = Some(parse_field("rport", value)?);
+                }
+                _ => candidate
+                    .extensions
+                    .push((name.to_owned(), value.to_owned())),
+            }
+        }
+        Ok(candidate)
+    }
+}
+
+impl fmt::Display for ParsedCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "candidate:{} {} {} {} {} {} typ {}",
+            self.foundation,
+            self.component,
+            self.transport,
+            self.priority,
+            self.address,
+            self.port,
+            self.kind.as_str()
+        )?;
+        if let Some(address) = &self.related_address {
+            write!(f, " raddr {}", address)?;
+        }
+        if let Some(port) = self.related_port {
+            write!(f, " rport {}", port)?;
+        }
+        for (name, value) in &self.extensions {
+            write!(f, " {} {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&msgs::IceCandidate> for ParsedCandidate {
+    type Error = CandidateParseError;
+
+    fn try_from(msg: &msgs::IceCandidate) -> Result<Self, Self::Error> {
+        #[cfg(feature = "protobuf")]
+        return msg.content().parse();
+        #[cfg(feature = "prost")]
+        return msg.content.parse();
+    }
+}
+
+impl From<&ParsedCandidate> for msgs::IceCandidate {
+    fn from(candidate: &ParsedCandidate) -> Self {
+        message!(msgs::IceCandidate {
+            #[cfg(feature = "protobuf")]
+            content: Some(candidate.to_string()),
+            #[cfg(feature = "prost")]
+            content: candidate.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip(s: &str) -> ParsedCandidate {
+        let candidate: ParsedCandidate = s.parse().unwrap();
+        assert_eq!(s, candidate.to_string());
+        candidate
+    }
+
+    #[test]
+    fn parses_host_candidates() {
+        let candidate = round_trip("candidate:7 1 UDP 2013266429 10.137.0.26 59220 typ host");
+        assert_eq!("7", candidate.foundation);
+        assert_eq!(1, candidate.component);
+        assert_eq!(2013266429, candidate.priority);
+        assert_eq!(Some("10.137.0.26".parse().unwrap()), candidate.ip());
+        assert_eq!(59220, candidate.port);
+        assert!(candidate.is_host());
+        assert!(!candidate.is_tcp());
+
+        let candidate = round_trip(
+            "candidate:1 1 udp 2122262783 2001:db8::1 51000 typ host generation 0 network-id 2",
+        );
+        assert_eq!(Some("2001:db8::1".parse().unwrap()), candidate.ip());
+        assert_eq!(Some("0"), candidate.extension("generation"));
+        assert_eq!(Some("2"), candidate.extension("network-id"));
+
+        let candidate = round_trip("candidate:3 1 udp 2122194687 abc.local 50000 typ host");
+        assert_eq!(None, candidate.ip());
+    }
+
+    #[test]
+    fn parses_other_candidates() {
+        let candidate = round_trip(
+            "candidate:842163049 1 udp 1677729535 203.0.113.7 61000 typ srflx \
+             raddr 192.168.1.2 rport 51000 generation 0",
+        );
+        assert_eq!(CandidateType::ServerReflexive, candidate.kind);
+        assert_eq!(Some("192.168.1.2"), candidate.related_address.as_deref());
+        assert_eq!(Some(51000), candidate.related_port);
+
+        let candidate =
+            round_trip("candidate:5 1 TCP 1015021823 10.0.0.1 9 typ host tcptype active");
+        assert!(candidate.is_tcp());
+        assert_eq!(Some("active"), candidate.extension("tcptype"));
+
+        let candidate = round_trip(
+            "candidate:9 2 udp 41885439 198.51.100.1 3478 typ relay raddr 0.0.0.0 rport 0",
+        );
+        assert!(candidate.is_relay());
+        assert_eq!(2, candidate.component);
+
+        let candidate: ParsedCandidate = "a=candidate:1 1 udp 1 10.0.0.1 1 typ future\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(CandidateType::Other("future".to_owned()), candidate.kind);
+        assert_eq!(
+            "a=candidate:1 1 udp 1 10.0.0.1 1 typ future",
+            candidate.to_a_line()
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_candidates() {
+        let parse = |s: &str| s.parse::<ParsedCandidate>().unwrap_err();
+        assert_eq!(CandidateParseError::MissingPrefix, parse("1 1 udp"));
+        assert_eq!(
+            CandidateParseError::MissingField("port"),
+            parse("candidate:1 1 udp 1 10.0.0.1")
+        );
+        assert_eq!(
+            CandidateParseError::InvalidField {
+                field: "port",
+                value: "70000".to_owned()
+            },
+            parse("candidate:1 1 udp 1 10.0.0.1 70000 typ host")
+        );
+        assert_eq!(
+            CandidateParseError::InvalidField {
+                field: "typ",
+                value: "type".to_owned()
+            },
+            parse("candidate:1 1 udp 1 10.0.0.1 1 type host")
+        );
+        assert_eq!(
+            CandidateParseError::MissingField("extension value"),
+            parse("candidate:1 1 udp 1 10.0.0.1 1 typ host generation")
+        );
+    }
+
+    #[test]
+    fn converts_messages() {
+        let candidate = round_trip("candidate:7 1 UDP 2013266429 10.137.0.26 59220 typ host");
+        let msg = msgs::IceCandidate::from(&candidate);
+        assert_eq!(Ok(candidate), ParsedCandidate::try_from(&msg));
+    }
+}
@@ -0,0 +1,293 @@
+//! Text message length and voice bandwidth limits advertised by the server
+//!
+//! Servers announce the maximum length of text messages in [msgs::ServerConfig] and Murmur
+//! rejects longer messages. [MessageLimits] allows checking messages before sending them and,
+//! on the server side, enforcing the limits on received ones. Likewise, [BandwidthLimit]
+//! checks voice packets against the announced maximum bandwidth.
+
+use std::fmt;
+
+use super::msgs;
+use super::ControlPacket;
+use crate::bandwidth;
+use crate::bandwidth::Transport;
+use crate::bandwidth::CRYPT_OVERHEAD;
+use crate::bandwidth::IP_UDP_OVERHEAD;
+use crate::bandwidth::POSITION_OVERHEAD;
+use crate::bandwidth::TUNNEL_OVERHEAD;
+use crate::error::Error;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+
+/// Marker which makes a text message count against the image limit, as in Mumble.
+const IMAGE_MARKER: &str = "data:image";
+
+/// Maximum lengths of `TextMessage`s, in UTF-16 code units like Murmur counts them.
+///
+/// Messages containing an inline image (`data:image`) are checked against
+/// [image_message_length](Self::image_message_length), all others against
+/// [message_length](Self::message_length). `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// Maximum length of messages without images.
+    pub message_length: Option<usize>,
+    /// Maximum length of messages with images.
+    pub image_message_length: Option<usize>,
+}
+
+impl MessageLimits {
+    /// Creates limits which allow messages of any length.
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    /// Returns the limit which applies to the given message text.
+    pub fn limit_for(&self, message: &str) -> Option<usize> {
+        if message.contains(IMAGE_MARKER) {
+            self.image_message_length
+        } else {
+            self.message_length
+        }
+    }
+
+    /// Checks the given message text against these limits.
+    ///
+    /// Returns [Error::TextMessageTooLong] if the message is too long.
+    pub fn check_message(&self, message: &str) -> Result<(), Error> {
+        let limit = match self.limit_for(message) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        // Cheap upper bound, the UTF-16 length never exceeds the UTF-8 length
+        if message.len() <= limit {
+            return Ok(());
+        }
+        let len = message.encode_utf16().count();
+        if len > limit {
+            return Err(Error::TextMessageTooLong {
+                len,
+                limit,
+                image: message.contains(IMAGE_MARKER),
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks the given packet against these limits.
+    ///
+    /// Only `TextMessage`s are ever rejected.
+    pub fn check<Dst: VoicePacketDst>(&self, packet: &ControlPacket<Dst>) -> Result<(), Error> {
+        match packet {
+            ControlPacket::TextMessage(msg) => {
+                #[cfg(feature = "protobuf")]
+                let message = msg.message();
+                #[cfg(feature = "prost")]
+                let message = msg.message.as_str();
+                self.check_message(message)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads the limits from a server's configuration. Zero or absent limits are unlimited.
+impl From<&msgs::ServerConfig> for MessageLimits {
+    fn from(config: &msgs::ServerConfig) -> Self {
+        let limit = |limit: Option<u32>| limit.filter(|it| *it != 0).map(|it| it as usize);
+        MessageLimits {
+            message_length: limit(config.message_length),
+            image_message_length: limit(config.image_message_length),
+        }
+    }
+}
+
+/// The maximum bandwidth of a user's voice packets, in bits per second.
+///
+/// Like Mumble, the bandwidth of a packet includes its IP, UDP and encryption headers and is
+/// spread over the duration of its audio. See [bandwidth](crate::bandwidth) for the
+/// calculations the official client does up front.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BandwidthLimit {
+    max_bandwidth: u32,
+    tunneled: bool,
+}
+
+impl BandwidthLimit {
+    /// Creates a limit of `max_bandwidth` bits per second.
+    pub fn new(max_bandwidth: u32) -> Self {
+        BandwidthLimit {
+            max_bandwidth,
+            tunneled: false,
+        }
+    }
+
+    /// Reads the limit from a server's configuration, `None` if it is zero or absent.
+    pub fn from_server_config(config: &msgs::ServerConfig) -> Option<Self> {
+        config
+            .max_bandwidth
+            .filter(|it| *it != 0)
+            .map(BandwidthLimit::new)
+    }
+
+    /// Sets whether voice is tunneled through the control channel, which adds
+    /// [TUNNEL_OVERHEAD](crate::bandwidth::TUNNEL_OVERHEAD) to every packet.
+    pub fn with_tunneled(mut self, tunneled: bool) -> Self {
+        self.tunneled = tunneled;
+        self
+    }
+
+    /// Returns the maximum bandwidth in bits per second.
+    pub fn max_bandwidth(&self) -> u32 {
+        self.max_bandwidth
+    }
+
+    /// Returns the bytes added to every encoded packet.
+    pub fn overhead(&self) -> usize {
+        let tunnel = if self.tunneled { TUNNEL_OVERHEAD } else { 0 };
+        (IP_UDP_OVERHEAD + CRYPT_OVERHEAD + tunnel) as usize
+    }
+
+    /// Returns how voice reaches the server.
+    pub fn transport(&self) -> Transport {
+        if self.tunneled {
+            Transport::Tcp
+        } else {
+            Transport::Udp
+        }
+    }
+
+    /// Returns the bandwidth the given packet uses in bits per second, `None` for pings.
+    pub fn bitrate<Dst: VoicePacketDst>(&self, packet: &VoicePacket<Dst>) -> Option<u64> {
+        let VoicePacket::Audio { payload, .. } = packet else {
+            return None;
+        };
+        let bits = (packet.encoded_len() + self.overhead()) as u64 * 8;
+        // There are 100 frames of 10 ms per second
+        Some(bits * 100 / payload.frame_count())
+    }
+
+    /// Returns the highest Opus bitrate which keeps serverbound packets of `frames` frames of
+    /// 10 ms within this limit, optionally with a position, as calculated by Mumble.
+    pub fn max_opus_bitrate(&self, frames: u32, position: bool) -> u32 {
+        let bitrate = bandwidth::max_bitrate_for(self.max_bandwidth, frames, self.transport());
+        if position {
+            bitrate.saturating_sub(POSITION_OVERHEAD * (800 / frames.max(1)))
+        } else {
+            bitrate
+        }
+    }
+
+    /// Checks the given packet against this limit. Pings are never rejected.
+    pub fn check<Dst: VoicePacketDst>(
+        &self,
+        packet: &VoicePacket<Dst>,
+    ) -> Result<(), BandwidthViolation> {
+        match self.bitrate(packet) {
+            Some(bitrate) if bitrate > u64::from(self.max_bandwidth) => Err(BandwidthViolation {
+                bitrate,
+                limit: self.max_bandwidth,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A voice packet exceeding a [BandwidthLimit].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BandwidthViolation {
+    /// The bandwidth of the packet in bits per second.
+    pub bitrate: u64,
+    /// The maximum bandwidth in bits per second.
+    pub limit: u32,
+}
+
+impl fmt::Display for BandwidthViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "voice bandwidth of {} bit/s exceeds the limit of {} bit/s",
+            self.bitrate, self.limit
+        )
+    }
+}
+
+impl std::error::Error for BandwidthViolation {}
+
+#[cfg(test)]
+mod test {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::error::VoiceError;
+    use crate::voice::AudioBuilder;
+    use crate::voice::ClientVoiceCodec;
+    use crate::voice::Serverbound;
+
+    fn packet(len: usize) -> VoicePacket<Serverbound> {
+        // 20 ms CELT-only Opus frames
+        let mut frame = vec![0; len];
+        frame[0] = 0xf8;
+        AudioBuilder::opus(frame).build().unwrap()
+    }
+
+    #[test]
+    fn reads_bandwidth_from_server_config() {
+        let config = |max_bandwidth| msgs::ServerConfig {
+            max_bandwidth,
+            ..Default::default()
+        };
+        assert_eq!(None, BandwidthLimit::from_server_config(&config(None)));
+        assert_eq!(None, BandwidthLimit::from_server_config(&config(Some(0))));
+        assert_eq!(
+            Some(BandwidthLimit::new(72_000)),
+            BandwidthLimit::from_server_config(&config(Some(72_000)))
+        );
+    }
+
+    #[test]
+    fn computes_bitrates_like_mumble() {
+        // 1 header, 1 sequence number, 1 length and 100 audio bytes plus 32 bytes overhead,
+        // 50 times a second
+        let limit = BandwidthLimit::new(54_000);
+        assert_eq!(Some(54_000), limit.bitrate(&packet(100)));
+        assert_eq!(Ok(()), limit.check(&packet(100)));
+        assert_eq!(
+            Err(BandwidthViolation {
+                bitrate: 54_400,
+                limit: 54_000
+            }),
+            limit.check(&packet(101))
+        );
+        let tunneled = limit.with_tunneled(true);
+        assert_eq!(Some(58_800), tunneled.bitrate(&packet(100)));
+        assert_eq!(None, limit.bitrate(&VoicePacket::<Serverbound>::ping_now()));
+
+        // 37 bytes of overhead 50 times a second
+        assert_eq!(
+            57_200,
+            BandwidthLimit::new(72_000).max_opus_bitrate(2, false)
+        );
+        assert_eq!(0, BandwidthLimit::new(1_000).max_opus_bitrate(1, true));
+    }
+
+    #[test]
+    fn codecs_refuse_to_encode_over_limit() {
+        let mut codec = ClientVoiceCodec::new();
+        codec.set_bandwidth_limit(Some(BandwidthLimit::new(50_000)));
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            codec.encode_ref(&packet(100), &mut buf),
+            Err(Error::MalformedVoice(VoiceError::BandwidthExceeded(
+                BandwidthViolation {
+                    bitrate: 54_000,
+                    limit: 50_000
+                }
+            )))
+        ));
+        assert!(buf.is_empty());
+        codec.encode_ref(&packet(80), &mut buf).unwrap();
+        codec
+            .encode_ref(&VoicePacket::Ping { timestamp: 1 }, &mut buf)
+            .unwrap();
+    }
+}
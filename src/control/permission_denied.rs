@@ -0,0 +1,256 @@
+//! Typed reasons for `PermissionDenied` messages
+
+use std::fmt;
+
+use super::msgs;
+use super::permissions::Permissions;
+
+/// The reason the server denied an operation, as sent in [msgs::PermissionDenied].
+///
+/// Each variant corresponds to one of the protocol's `DenyType`s and carries the fields which
+/// are set for it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DenyReason {
+    /// The operation was denied for the given reason.
+    Text(String),
+    /// A user lacks permissions in a channel.
+    InsufficientPermission {
+        /// The channel the permissions are missing in.
+        channel_id: u32,
+        /// The missing permissions.
+        permission: Permissions,
+        /// The user lacking the permissions.
+        session: u32,
+    },
+    /// The SuperUser cannot be modified.
+    SuperUser,
+    /// The channel name is invalid.
+    ChannelName,
+    /// The text message is too long.
+    TextTooLong,
+    /// A joke by Murmur's developers, sent when trying to move someone out of a channel which
+    /// would need to be deleted.
+    H9K,
+    /// The operation is not permitted in a temporary channel.
+    TemporaryChannel,
+    /// The operation requires a certificate.
+    MissingCertificate {
+        /// The user who lacks a certificate.
+        session: u32,
+    },
+    /// The username is invalid.
+    UserName {
+        /// The invalid name, if known.
+        name: Option<String>,
+    },
+    /// The channel is full.
+    ChannelFull,
+    /// Channels are nested too deeply.
+    NestingLimit,
+    /// The maximum amount of channels has been reached.
+    ChannelCountLimit,
+    /// The maximum amount of listeners in the channel has been reached.
+    ChannelListenerLimit,
+    /// The maximum amount of channels a user can listen to has been reached.
+    UserListenerLimit,
+    /// A deny type unknown to this crate.
+    Other {
+        /// The numeric deny type.
+        kind: i32,
+        /// The reason sent along, if any.
+        reason: Option<String>,
+    },
+}
+
+impl DenyReason {
+    /// Converts the reason into a message, as sent by servers.
+    pub fn to_message(&self) -> msgs::PermissionDenied {
+        let mut msg = msgs::PermissionDenied::default();
+        let kind = match self {
+            DenyReason::Text(reason) => {
+                msg.reason = Some(reason.clone());
+                0
+            }
+            DenyReason::InsufficientPermission {
+                channel_id,
+                permission,
+                session,
+            } => {
+                msg.channel_id = Some(*channel_id);
+                msg.permission = Some(permission.bits());
+                msg.session = Some(*session);
+                1
+            }
+            DenyReason::SuperUser => 2,
+            DenyReason::ChannelName => 3,
+            DenyReason::TextTooLong => 4,
+            DenyReason::H9K => 5,
+            DenyReason::TemporaryChannel => 6,
+            DenyReason::MissingCertificate { session } => {
+                msg.session = Some(*session);
+                7
+            }
+            DenyReason::UserName { name } => {
+                msg.name.clone_from(name);
+                8
+            }
+            DenyReason::ChannelFull => 9,
+            DenyReason::NestingLimit => 10,
+            DenyReason::ChannelCountLimit => 11,
+            DenyReason::ChannelListenerLimit => 12,
+            DenyReason::UserListenerLimit => 13,
+            DenyReason::Other { kind, reason } => {
+                msg.reason.clone_from(reason);
+                *kind
+            }
+        };
+        #[cfg(feature = "protobuf")]
+        {
+            msg.type_ = Some(protobuf::EnumOrUnknown::from_i32(kind));
+        }
+        #[cfg(feature = "prost")]
+        {
+            msg.r#type = Some(kind);
+        }
+        msg
+    }
+}
+
+impl From<&msgs::PermissionDenied> for DenyReason {
+    fn from(msg: &msgs::PermissionDenied) -> Self {
+        #[cfg(feature = "protobuf")]
+        let kind = msg.type_.map(|it| it.value());
+        #[cfg(feature = "prost")]
+        let kind = msg.r#type;
+        let session = msg.session.unwrap_or_default();
+        // Murmur omits the type for plain text denials
+        match kind.unwrap_or(0) {
+            0 => DenyReason::Text(msg.reason.clone().unwrap_or_default()),
+            1 => DenyReason::InsufficientPermission {
+                channel_id: msg.channel_id.unwrap_or_default(),
+                permission: Permissions::from_bits_retain(msg.permission.unwrap_or_default()),
+                session,
+            },
+            2 => DenyReason::SuperUser,
+            3 => DenyReason::ChannelName,
+            4 => DenyReason::TextTooLong,
+            5 => DenyReason::H9K,
+            6 => DenyReason::TemporaryChannel,
+            7 => DenyReason::MissingCertificate { session },
+            8 => DenyReason::UserName {
+                name: msg.name.clone(),
+            },
+            9 => DenyReason::ChannelFull,
+            10 => DenyReason::NestingLimit,
+            11 => DenyReason::ChannelCountLimit,
+            12 => DenyReason::ChannelListenerLimit,
+            13 => DenyReason::UserListenerLimit,
+            kind => DenyReason::Other {
+                kind,
+                reason: msg.reason.clone(),
+            },
+        }
+    }
+}
+
+impl From<&DenyReason> for msgs::PermissionDenied {
+    fn from(reason: &DenyReason) -> Self {
+        reason.to_message()
+    }
+}
+
+impl fmt::Display for DenyReason {
+    /// Formats the reason like the official client does, referring to channels by their ID.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DenyReason::Text(reason) if reason.is_empty() => f.write_str("Permission denied."),
+            DenyReason::Text(reason) => write!(f, "Denied: {}.", reason),
+            DenyReason::InsufficientPermission {
+                channel_id,
+                permission,
+                ..
+            } => write!(
+                f,
+                "You were denied {} privileges in channel {}.",
+                permission, channel_id
+            ),
+            DenyReason::SuperUser => f.write_str("Denied: Cannot modify SuperUser."),
+            DenyReason::ChannelName => f.write_str("Denied: Invalid channel name."),
+            DenyReason::TextTooLong => f.write_str("Denied: Text message too long."),
+            DenyReason::H9K => f.write_str("I'm sorry, Dave. I'm afraid I can't do that."),
+            DenyReason::TemporaryChannel => {
+                f.write_str("Denied: Operation not permitted in temporary channel.")
+            }
+            DenyReason::MissingCertificate { .. } => {
+                f.write_str("You need a certificate to perform this operation.")
+            }
+            DenyReason::UserName { name: Some(name) } => write!(f, "Invalid username: {}.", name),
+            DenyReason::UserName { name: None } => f.write_str("Invalid username."),
+            DenyReason::ChannelFull => f.write_str("Channel is full."),
+            DenyReason::NestingLimit => f.write_str("Channel nesting limit reached."),
+            DenyReason::ChannelCountLimit => f.write_str(
+                "Channel count limit reached. Need to delete channels before creating new ones.",
+            ),
+            DenyReason::ChannelListenerLimit => {
+                f.write_str("No more listeners allowed in this channel.")
+            }
+            DenyReason::UserListenerLimit => f.write_str(
+                "You are not allowed to listen to more channels than you currently are.",
+            ),
+            DenyReason::Other {
+                reason: Some(reason),
+                ..
+            } => write!(f, "Denied: {}.", reason),
+            DenyReason::Other { kind, reason: None } => {
+                write!(f, "Permission denied (type {}).", kind)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_deny_reasons() {
+        let reasons = [
+            DenyReason::Text("no".to_owned()),
+            DenyReason::InsufficientPermission {
+                channel_id: 3,
+                permission: Permissions::ENTER,
+                session: 7,
+            },
+            DenyReason::MissingCertificate { session: 7 },
+            DenyReason::UserName {
+                name: Some("bad name".to_owned()),
+            },
+            DenyReason::NestingLimit,
+            DenyReason::Other {
+                kind: 42,
+                reason: None,
+            },
+        ];
+        for reason in reasons {
+            assert_eq!(reason, DenyReason::from(&reason.to_message()));
+        }
+    }
+
+    #[test]
+    fn displays_client_strings() {
+        let reason = DenyReason::InsufficientPermission {
+            channel_id: 3,
+            permission: Permissions::ENTER,
+            session: 7,
+        };
+        assert_eq!(
+            "You were denied ENTER privileges in channel 3.",
+            reason.to_string()
+        );
+        assert_eq!(
+            "Denied: Text message too long.",
+            DenyReason::TextTooLong.to_string()
+        );
+    }
+}
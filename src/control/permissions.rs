@@ -0,0 +1,221 @@
+//! Typed ACL permission bits
+//!
+//! Permissions are transmitted as plain integers in [msgs::ServerSync],
+//! [msgs::PermissionQuery], [msgs::PermissionDenied] and the ACL entries of [msgs::ACL]. The
+//! extension traits in here convert them from and to [Permissions].
+//!
+//! Since the generated messages already have accessors named after the fields (e.g.
+//! `permissions()`), which would take precedence, the methods are suffixed with `_flags`.
+
+use std::fmt;
+
+use bitflags::bitflags;
+
+use super::msgs;
+
+bitflags! {
+    /// Permission bits, with the values used by Murmur.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct Permissions: u32 {
+        /// Modify the channel and its ACL.
+        const WRITE = 0x1;
+        /// Traverse the channel to reach its sub-channels.
+        const TRAVERSE = 0x2;
+        /// Enter the channel.
+        const ENTER = 0x4;
+        /// Speak in the channel.
+        const SPEAK = 0x8;
+        /// Mute and deafen other users.
+        const MUTE_DEAFEN = 0x10;
+        /// Move users between channels.
+        const MOVE = 0x20;
+        /// Create permanent sub-channels.
+        const MAKE_CHANNEL = 0x40;
+        /// Link channels.
+        const LINK_CHANNEL = 0x80;
+        /// Whisper to the channel.
+        const WHISPER = 0x100;
+        /// Send text messages to the channel.
+        const TEXT_MESSAGE = 0x200;
+        /// Create temporary sub-channels.
+        const MAKE_TEMP_CHANNEL = 0x400;
+        /// Listen to the channel without being in it.
+        const LISTEN = 0x800;
+        /// Kick users from the server. Root channel only.
+        const KICK = 0x10000;
+        /// Ban users from the server. Root channel only.
+        const BAN = 0x20000;
+        /// Register other users. Root channel only.
+        const REGISTER = 0x40000;
+        /// Register oneself. Root channel only.
+        const SELF_REGISTER = 0x80000;
+        /// Reset other users' avatars and comments. Root channel only.
+        const RESET_USER_CONTENT = 0x100000;
+        /// Set by the server in [msgs::PermissionQuery] to indicate that the permissions are
+        /// still valid. Never part of an ACL.
+        const CACHED = 0x8000000;
+    }
+}
+
+impl Permissions {
+    /// All permissions which can be granted or denied in any channel.
+    pub const CHANNEL: Permissions = Permissions::WRITE
+        .union(Permissions::TRAVERSE)
+        .union(Permissions::ENTER)
+        .union(Permissions::SPEAK)
+        .union(Permissions::MUTE_DEAFEN)
+        .union(Permissions::MOVE)
+        .union(Permissions::MAKE_CHANNEL)
+        .union(Permissions::LINK_CHANNEL)
+        .union(Permissions::WHISPER)
+        .union(Permissions::TEXT_MESSAGE)
+        .union(Permissions::MAKE_TEMP_CHANNEL)
+        .union(Permissions::LISTEN);
+    /// Permissions which only take effect in the root channel.
+    pub const ROOT_ONLY: Permissions = Permissions::KICK
+        .union(Permissions::BAN)
+        .union(Permissions::REGISTER)
+        .union(Permissions::SELF_REGISTER)
+        .union(Permissions::RESET_USER_CONTENT);
+
+    /// Returns the permissions which can be assigned via ACLs in the root channel, if
+    /// `root` is set, or any other channel.
+    pub fn assignable(root: bool) -> Permissions {
+        if root {
+            Permissions::CHANNEL | Permissions::ROOT_ONLY
+        } else {
+            Permissions::CHANNEL
+        }
+    }
+
+    /// Returns the bits of `self` which can not be assigned via ACLs in the root channel, if
+    /// `root` is set, or any other channel.
+    pub fn unassignable(self, root: bool) -> Permissions {
+        Permissions::from_bits_retain(self.bits() & !Permissions::assignable(root).bits())
+    }
+}
+
+/// Lists the set flags, e.g. `ENTER | SPEAK`.
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        bitflags::parser::to_writer(self, f)
+    }
+}
+
+/// Typed access to [msgs::ServerSync::permissions].
+pub trait ServerSyncExt {
+    /// Returns the permissions of the user in the root channel.
+    fn permission_flags(&self) -> Option<Permissions>;
+    /// Sets the permissions of the user in the root channel.
+    fn set_permission_flags(&mut self, permissions: Permissions);
+}
+
+impl ServerSyncExt for msgs::ServerSync {
+    fn permission_flags(&self) -> Option<Permissions> {
+        // Declared as uint64 by mistake, but never exceeds the uint32 range
+        self.permissions
+            .map(|it| Permissions::from_bits_retain(it as u32))
+    }
+
+    fn set_permission_flags(&mut self, permissions: Permissions) {
+        self.permissions = Some(permissions.bits().into());
+    }
+}
+
+/// Typed access to [msgs::PermissionQuery::permissions].
+pub trait PermissionQueryExt {
+    /// Returns the permissions of the user in the queried channel.
+    fn permission_flags(&self) -> Option<Permissions>;
+    /// Sets the permissions of the user in the queried channel.
+    fn set_permission_flags(&mut self, permissions: Permissions);
+}
+
+impl PermissionQueryExt for msgs::PermissionQuery {
+    fn permission_flags(&self) -> Option<Permissions> {
+        self.permissions.map(Permissions::from_bits_retain)
+    }
+
+    fn set_permission_flags(&mut self, permissions: Permissions) {
+        self.permissions = Some(permissions.bits());
+    }
+}
+
+/// Typed access to [msgs::PermissionDenied::permission].
+pub trait PermissionDeniedExt {
+    /// Returns the denied permissions, if the message is of type `Permission`.
+    fn denied_permission_flags(&self) -> Option<Permissions>;
+}
+
+impl PermissionDeniedExt for msgs::PermissionDenied {
+    fn denied_permission_flags(&self) -> Option<Permissions> {
+        #[cfg(feature = "protobuf")]
+        let kind = self.type_.map(|it| it.value());
+        #[cfg(feature = "prost")]
+        let kind = self.r#type;
+        // DenyType::Permission
+        if kind != Some(1) {
+            return None;
+        }
+        self.permission.map(Permissions::from_bits_retain)
+    }
+}
+
+/// Typed access to the `grant` and `deny` fields of ACL entries.
+pub trait ChanAclExt {
+    /// Returns the permissions granted by this entry.
+    fn grant_flags(&self) -> Option<Permissions>;
+    /// Returns the permissions denied by this entry.
+    fn deny_flags(&self) -> Option<Permissions>;
+    /// Sets the permissions granted by this entry.
+    fn set_grant_flags(&mut self, permissions: Permissions);
+    /// Sets the permissions denied by this entry.
+    fn set_deny_flags(&mut self, permissions: Permissions);
+}
+
+impl ChanAclExt for msgs::acl::ChanACL {
+    fn grant_flags(&self) -> Option<Permissions> {
+        self.grant.map(Permissions::from_bits_retain)
+    }
+
+    fn deny_flags(&self) -> Option<Permissions> {
+        self.deny.map(Permissions::from_bits_retain)
+    }
+
+    fn set_grant_flags(&mut self, permissions: Permissions) {
+        self.grant = Some(permissions.bits());
+    }
+
+    fn set_deny_flags(&mut self, permissions: Permissions) {
+        self.deny = Some(permissions.bits());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_permission_fields() {
+        let mut sync = msgs::ServerSync::default();
+        assert_eq!(None, sync.permission_flags());
+        sync.permissions = Some(0x10000 | 0x4);
+        let permissions = sync.permission_flags().unwrap();
+        assert_eq!(Permissions::KICK | Permissions::ENTER, permissions);
+        assert_eq!("ENTER | KICK", permissions.to_string());
+        assert_eq!(Permissions::KICK, permissions.unassignable(false));
+        assert!(permissions.unassignable(true).is_empty());
+
+        let mut denied = msgs::PermissionDenied {
+            permission: Some(0x8),
+            ..Default::default()
+        };
+        assert_eq!(None, denied.denied_permission_flags());
+        denied.set_type(msgs::permission_denied::DenyType::Permission);
+        assert_eq!(Some(Permissions::SPEAK), denied.denied_permission_flags());
+
+        let mut acl = msgs::acl::ChanACL::default();
+        acl.set_grant_flags(Permissions::SPEAK | Permissions::WHISPER);
+        assert_eq!(Some(0x108), acl.grant);
+        assert_eq!(None, acl.deny_flags());
+    }
+}
@@ -0,0 +1,266 @@
+//! Connection quality statistics exchanged via the control channel `Ping`
+//!
+//! Clients periodically send a [msgs::Ping] carrying their voice decryption statistics and
+//! measured round-trip times, which the server answers with its own decryption statistics and
+//! the client's timestamp.
+
+use std::time::Duration;
+
+use super::backend::message;
+use super::msgs;
+#[cfg(feature = "openssl")]
+use crate::crypt::CryptState;
+use crate::seq::VoiceStreamSnapshot;
+#[cfg(feature = "openssl")]
+use crate::voice::VoicePacketDst;
+
+/// Running count, average and variance of round-trip times.
+///
+/// Like the reference client, this covers all samples since the connection was established.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RttStats {
+    count: u32,
+    mean: f64,
+    m2: f64,
+}
+
+impl RttStats {
+    /// Adds a measured round-trip time.
+    pub fn add(&mut self, rtt: Duration) {
+        let sample = rtt.as_secs_f64() * 1000.0;
+        self.count = self.count.saturating_add(1);
+        let delta = sample - self.mean;
+        self.mean += delta / f64::from(self.count);
+        self.m2 += delta * (sample - self.mean);
+    }
+
+    /// Returns the amount of samples.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Returns the average round-trip time in milliseconds.
+    pub fn average_ms(&self) -> f64 {
+        self.mean
+    }
+
+    /// Returns the (population) variance of the round-trip time in square milliseconds.
+    pub fn variance_ms(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.m2 / f64::from(self.count)
+        }
+    }
+}
+
+/// Round-trip times measured via UDP and TCP pings.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PingStats {
+    /// Round-trip times of UDP pings.
+    pub udp: RttStats,
+    /// Round-trip times of control channel (TCP) pings.
+    pub tcp: RttStats,
+}
+
+impl PingStats {
+    /// Adds a measured UDP round-trip time.
+    pub fn add_udp(&mut self, rtt: Duration) {
+        self.udp.add(rtt);
+    }
+
+    /// Adds a measured TCP round-trip time.
+    pub fn add_tcp(&mut self, rtt: Duration) {
+        self.tcp.add(rtt);
+    }
+}
+
+/// The contents of a [msgs::Ping], with absent fields as `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PingReport {
+    /// Client timestamp, opaque to the server which echoes it back.
+    pub timestamp: Option<u64>,
+    /// Amount of voice packets decrypted successfully.
+    pub good: Option<u32>,
+    /// Amount of voice packets which arrived late.
+    pub late: Option<u32>,
+    /// Amount of voice packets which were lost.
+    pub lost: Option<u32>,
+    /// Amount of decrypt nonce resyncs.
+    pub resync: Option<u32>,
+    /// Amount of UDP pings the averages are based on.
+    pub udp_packets: Option<u32>,
+    /// Amount of TCP pings the averages are based on.
+    pub tcp_packets: Option<u32>,
+    /// Average UDP round-trip time in milliseconds.
+    pub udp_ping_avg: Option<f32>,
+    /// Variance of the UDP round-trip time.
+    pub udp_ping_var: Option<f32>,
+    /// Average TCP round-trip time in milliseconds.
+    pub tcp_ping_avg: Option<f32>,
+    /// Variance of the TCP round-trip time.
+    pub tcp_ping_var: Option<f32>,
+}
+
+impl From<&msgs::Ping> for PingReport {
+    fn from(msg: &msgs::Ping) -> Self {
+        PingReport {
+            timestamp: msg.timestamp,
+            good: msg.good,
+            late: msg.late,
+            lost: msg.lost,
+            resync: msg.resync,
+            udp_packets: msg.udp_packets,
+            tcp_packets: msg.tcp_packets,
+            udp_ping_avg: msg.udp_ping_avg,
+            udp_ping_var: msg.udp_ping_var,
+            tcp_ping_avg: msg.tcp_ping_avg,
+            tcp_ping_var: msg.tcp_ping_var,
+        }
+    }
+}
+
+impl From<PingReport> for msgs::Ping {
+    fn from(report: PingReport) -> Self {
+        message!(msgs::Ping {
+            timestamp: report.timestamp,
+            good: report.good,
+            late: report.late,
+            lost: report.lost,
+            resync: report.resync,
+            udp_packets: report.udp_packets,
+            tcp_packets: report.tcp_packets,
+            udp_ping_avg: report.udp_ping_avg,
+            udp_ping_var: report.udp_ping_var,
+            tcp_ping_avg: report.tcp_ping_avg,
+            tcp_ping_var: report.tcp_ping_var,
+        })
+    }
+}
+
+impl PingReport {
+    /// Fills in the round-trip time statistics.
+    pub fn with_ping_stats(mut self, stats: &PingStats) -> Self {
+        self.udp_packets = Some(stats.udp.count());
+        self.tcp_packets = Some(stats.tcp.count());
+        self.udp_ping_avg = Some(stats.udp.average_ms() as f32);
+        self.udp_ping_var = Some(stats.udp.variance_ms() as f32);
+        self.tcp_ping_avg = Some(stats.tcp.average_ms() as f32);
+        self.tcp_ping_var = Some(stats.tcp.variance_ms() as f32);
+        self
+    }
+
+    /// Fills in the packet counts from the statistics of received audio, e.g. when the voice
+    /// channel is not encrypted by a `CryptState`.
+    pub fn with_stream_stats(mut self, stats: &VoiceStreamSnapshot) -> Self {
+        let count = |it: u64| it.min(u64::from(u32::MAX)) as u32;
+        self.good = Some(count(stats.received));
+        self.late = Some(count(stats.late));
+        self.lost = Some(count(stats.lost));
+        self
+    }
+
+    /// Fills in the decryption statistics of the voice channel.
+    #[cfg(feature = "openssl")]
+    pub fn with_crypt_stats<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>(
+        mut self,
+        crypt: &CryptState<EncodeDst, DecodeDst>,
+    ) -> Self {
+        self.good = Some(crypt.get_good());
+        self.late = Some(crypt.get_late());
+        self.lost = Some(crypt.get_lost());
+        self.resync = Some(crypt.get_resync());
+        self
+    }
+}
+
+/// Builds the periodic `Ping` sent by clients.
+#[cfg(feature = "openssl")]
+pub fn build_ping<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>(
+    timestamp: u64,
+    crypt: &CryptState<EncodeDst, DecodeDst>,
+    stats: &PingStats,
+) -> msgs::Ping {
+    PingReport {
+        timestamp: Some(timestamp),
+        ..Default::default()
+    }
+    .with_crypt_stats(crypt)
+    .with_ping_stats(stats)
+    .into()
+}
+
+/// Builds the server's answer to a client's `Ping`.
+///
+/// Echoes the client's timestamp (or its absence) and includes the server's decryption
+/// statistics, like Murmur does.
+#[cfg(feature = "openssl")]
+pub fn build_ping_reply<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>(
+    request: &msgs::Ping,
+    crypt: &CryptState<EncodeDst, DecodeDst>,
+) -> msgs::Ping {
+    PingReport {
+        timestamp: request.timestamp,
+        ..Default::default()
+    }
+    .with_crypt_stats(crypt)
+    .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tracks_average_and_variance() {
+        let mut stats = PingStats::default();
+        for ms in [10, 20, 30] {
+            stats.add_tcp(Duration::from_millis(ms));
+        }
+        assert_eq!(3, stats.tcp.count());
+        assert!((stats.tcp.average_ms() - 20.0).abs() < 1e-9);
+        assert!((stats.tcp.variance_ms() - 200.0 / 3.0).abs() < 1e-9);
+        assert_eq!(0, stats.udp.count());
+        assert_eq!(0.0, stats.udp.variance_ms());
+
+        let report = PingReport::default().with_ping_stats(&stats);
+        let msg = msgs::Ping::from(report);
+        assert_eq!(Some(3), msg.tcp_packets);
+        assert_eq!(Some(20.0), msg.tcp_ping_avg);
+        assert_eq!(report, PingReport::from(&msg));
+    }
+
+    #[test]
+    fn reports_stream_stats() {
+        let stats = VoiceStreamSnapshot {
+            received: 10,
+            lost: 2,
+            late: 1,
+            ..Default::default()
+        };
+        let report = PingReport::default().with_stream_stats(&stats);
+        assert_eq!(
+            (Some(10), Some(1), Some(2)),
+            (report.good, report.late, report.lost)
+        );
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn reply_echoes_timestamp() {
+        use crate::crypt::ClientCryptState;
+        use crate::crypt::ServerCryptState;
+
+        let client = ClientCryptState::generate_new();
+        let ping = build_ping(42, &client, &PingStats::default());
+        assert_eq!(Some(42), ping.timestamp);
+        assert_eq!(Some(0), ping.good);
+
+        let mut server = ServerCryptState::generate_new();
+        server.set_decrypt_nonce(&[0; 16]);
+        let reply = build_ping_reply(&ping, &server);
+        assert_eq!(Some(42), reply.timestamp);
+        assert_eq!(Some(1), reply.resync);
+        assert_eq!(None, reply.tcp_packets);
+    }
+}
@@ -0,0 +1,180 @@
+//! Construction and forwarding of the `PluginDataTransmission` message
+//!
+//! Mumble plugins exchange opaque data with the plugins of other clients. The sender lists the
+//! receiving sessions, the server forwards a copy to each of them with the sender filled in.
+//!
+//! The protobuf backends name the fields differently (`dataID` vs. `data_id`), the helpers in
+//! here work with both.
+
+use std::fmt;
+
+use super::msgs;
+
+/// The maximum length of the data Murmur forwards, in bytes.
+pub const MAX_DATA_LENGTH: usize = 1000;
+
+/// The maximum length of the data ID Murmur forwards, in bytes.
+pub const MAX_DATA_ID_LENGTH: usize = 100;
+
+/// Why a `PluginDataTransmission` could not be built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PluginDataError {
+    /// No receiver was given.
+    NoReceivers,
+    /// The data is longer than [MAX_DATA_LENGTH].
+    DataTooLong(usize),
+    /// The data ID is longer than [MAX_DATA_ID_LENGTH].
+    DataIdTooLong(usize),
+}
+
+impl fmt::Display for PluginDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginDataError::NoReceivers => write!(f, "plugin data has no receivers"),
+            PluginDataError::DataTooLong(len) => write!(
+                f,
+                "plugin data is {} bytes long, at most {} are allowed",
+                len, MAX_DATA_LENGTH
+            ),
+            PluginDataError::DataIdTooLong(len) => write!(
+                f,
+                "plugin data ID is {} bytes long, at most {} are allowed",
+                len, MAX_DATA_ID_LENGTH
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PluginDataError {}
+
+fn message(
+    sender: Option<u32>,
+    receivers: Vec<u32>,
+    data_id: String,
+    data: Vec<u8>,
+) -> msgs::PluginDataTransmission {
+    #[cfg(feature = "protobuf")]
+    return msgs::PluginDataTransmission {
+        senderSession: sender,
+        receiverSessions: receivers,
+        data: Some(data),
+        dataID: Some(data_id),
+        ..Default::default()
+    };
+    #[cfg(feature = "prost")]
+    return msgs::PluginDataTransmission {
+        sender_session: sender,
+        receiver_sessions: receivers,
+        data: Some(data),
+        data_id: Some(data_id),
+    };
+}
+
+impl msgs::PluginDataTransmission {
+    /// Creates a message sending `data` to the plugins of the given sessions, as sent by clients.
+    ///
+    /// Fails if there are no receivers or if Murmur would drop the message for its size.
+    pub fn to_sessions(
+        receivers: impl IntoIterator<Item = u32>,
+        data_id: impl Into<String>,
+        data: impl Into<Vec<u8>>,
+    ) -> Result<Self, PluginDataError> {
+        let receivers: Vec<u32> = receivers.into_iter().collect();
+        let data_id = data_id.into();
+        let data = data.into();
+        if receivers.is_empty() {
+            return Err(PluginDataError::NoReceivers);
+        }
+        if data.len() > MAX_DATA_LENGTH {
+            return Err(PluginDataError::DataTooLong(data.len()));
+        }
+        if data_id.len() > MAX_DATA_ID_LENGTH {
+            return Err(PluginDataError::DataIdTooLong(data_id.len()));
+        }
+        Ok(message(None, receivers, data_id, data))
+    }
+
+    /// Returns the sessions the data should be forwarded to.
+    pub fn receivers(&self) -> &[u32] {
+        #[cfg(feature = "protobuf")]
+        return &self.receiverSessions;
+        #[cfg(feature = "prost")]
+        return &self.receiver_sessions;
+    }
+
+    /// Splits a message received from `sender` into the copies to forward, as done by servers.
+    ///
+    /// Each receiver is included once and the sender is skipped. Like Murmur, the copies carry
+    /// the sender but no receivers. Sizes are not checked, see [Validate](super::validate::Validate).
+    pub fn fan_out(&self, sender: u32) -> Vec<(u32, Self)> {
+        #[cfg(feature = "protobuf")]
+        let data_id = self.dataID();
+        #[cfg(feature = "prost")]
+        let data_id = self.data_id();
+        let mut receivers = Vec::<u32>::new();
+        for receiver in self.receivers() {
+            if *receiver != sender && !receivers.contains(receiver) {
+                receivers.push(*receiver);
+            }
+        }
+        receivers
+            .into_iter()
+            .map(|receiver| {
+                let copy = message(
+                    Some(sender),
+                    Vec::new(),
+                    data_id.to_owned(),
+                    self.data().to_vec(),
+                );
+                (receiver, copy)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::validate::Validate;
+
+    #[test]
+    fn builds_transmissions() {
+        let msg =
+            msgs::PluginDataTransmission::to_sessions([2, 3], "pos", b"data".to_vec()).unwrap();
+        assert_eq!(&[2, 3], msg.receivers());
+        assert_eq!(b"data", msg.data());
+        assert!(msg.validate().is_empty());
+
+        assert_eq!(
+            Err(PluginDataError::NoReceivers),
+            msgs::PluginDataTransmission::to_sessions([], "pos", Vec::new())
+        );
+        assert_eq!(
+            Err(PluginDataError::DataTooLong(1001)),
+            msgs::PluginDataTransmission::to_sessions([2], "pos", vec![0; 1001])
+        );
+        assert_eq!(
+            Err(PluginDataError::DataIdTooLong(101)),
+            msgs::PluginDataTransmission::to_sessions([2], "x".repeat(101), Vec::new())
+        );
+    }
+
+    #[test]
+    fn fans_out_to_receivers() {
+        let msg = msgs::PluginDataTransmission::to_sessions([2, 1, 3, 2], "pos", b"data".to_vec())
+            .unwrap();
+        let copies = msg.fan_out(1);
+        assert_eq!(
+            vec![2, 3],
+            copies.iter().map(|(it, _)| *it).collect::<Vec<_>>()
+        );
+        let (_, copy) = &copies[0];
+        assert!(copy.receivers().is_empty());
+        assert_eq!(b"data", copy.data());
+        #[cfg(feature = "protobuf")]
+        assert_eq!((1, "pos"), (copy.senderSession(), copy.dataID()));
+        #[cfg(feature = "prost")]
+        assert_eq!((1, "pos"), (copy.sender_session(), copy.data_id()));
+    }
+}
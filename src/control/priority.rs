@@ -0,0 +1,357 @@
+//! Send-side prioritization of voice over regular control traffic
+//!
+//! When voice is tunneled over the control channel, large control messages (e.g. channel
+//! descriptions or ban lists) queued in front of it delay the voice and cause audible gaps.
+//! [PriorityQueue] reorders outgoing packets so that only a bounded amount of regular traffic
+//! may go out ahead of queued voice.
+
+use std::collections::VecDeque;
+
+use super::ControlPacket;
+use crate::voice::VoicePacketDst;
+
+/// Default amount of bytes of normal priority packets which may be sent ahead of queued voice.
+pub const DEFAULT_MAX_PREEMPT_BYTES: usize = 1024;
+/// Default amount of high priority packets which are queued before the oldest one is dropped.
+pub const DEFAULT_MAX_QUEUED_HIGH: usize = 64;
+
+/// The priority of an outgoing packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Priority {
+    /// Latency sensitive packets, by default `UDPTunnel` and `Ping`.
+    High,
+    /// All other packets.
+    Normal,
+}
+
+impl Priority {
+    /// Returns the default priority of the given packet.
+    pub fn of<Dst: VoicePacketDst>(packet: &ControlPacket<Dst>) -> Self {
+        match packet {
+            ControlPacket::UDPTunnel(_) | ControlPacket::Ping(_) => Priority::High,
+            _ => Priority::Normal,
+        }
+    }
+}
+
+/// A queue of outgoing [ControlPacket]s which yields high priority packets (i.e. voice) with
+/// bounded delay.
+///
+/// Packets of the same priority are yielded in the order they were pushed. Normal priority
+/// packets may only overtake queued high priority packets as long as they amount to at most
+/// [max_preempt_bytes](Self::with_max_preempt_bytes) bytes since the last high priority packet;
+/// this keeps control traffic flowing during continuous voice transmission. A normal packet
+/// larger than that waits until no more high priority packets are queued.
+///
+/// High priority packets are only useful while they are fresh, so once more than
+/// [max_queued_high](Self::with_max_queued_high) of them are queued, the oldest ones are
+/// dropped. Normal priority packets are never dropped.
+#[derive(Debug)]
+pub struct PriorityQueue<Dst: VoicePacketDst> {
+    high: VecDeque<ControlPacket<Dst>>,
+    normal: VecDeque<ControlPacket<Dst>>,
+    max_preempt_bytes: usize,
+    max_queued_high: usize,
+    preempted_bytes: usize,
+    dropped: u64,
+}
+
+impl<Dst: VoicePacketDst> Default for PriorityQueue<Dst> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Dst: VoicePacketDst> PriorityQueue<Dst> {
+    /// Creates an empty queue with the default limits.
+    pub fn new() -> Self {
+        PriorityQueue {
+            high: VecDeque::new(),
+            normal: VecDeque::new(),
+            max_preempt_bytes: DEFAULT_MAX_PREEMPT_BYTES,
+            max_queued_high: DEFAULT_MAX_QUEUED_HIGH,
+            preempted_bytes: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Changes the amount of bytes of normal priority packets which may be yielded between two
+    /// high priority packets while the latter are queued.
+    ///
+    /// `0` results in strict prioritization.
+    pub fn with_max_preempt_bytes(mut self, max_preempt_bytes: usize) -> Self {
+        self.max_preempt_bytes = max_preempt_bytes;
+        self
+    }
+
+    /// Changes the amount of high priority packets which are queued before the oldest one is
+    /// dropped.
+    ///
+    /// # Panics
+    /// Panics if `max_queued_high` is `0`.
+    pub fn with_max_queued_high(mut self, max_queued_high: usize) -> Self {
+        assert!(max_queued_high > 0, "max_queued_high must not be zero");
+        self.max_queued_high = max_queued_high;
+        self
+    }
+
+    /// Queues a packet with its default [Priority].
+    pub fn push(&mut self, packet: ControlPacket<Dst>) {
+        let priority = Priority::of(&packet);
+        self.push_with_priority(packet, priority);
+    }
+
+    /// Queues a packet with the given priority.
+    pub fn push_with_priority(&mut self, packet: ControlPacket<Dst>, priority: Priority) {
+        match priority {
+            Priority::High => {
+                if self.high.len() >= self.max_queued_high {
+                    self.high.pop_front();
+                    self.dropped += 1;
+                }
+                self.high.push_back(packet);
+            }
+            Priority::Normal => self.normal.push_back(packet),
+        }
+    }
+
+    /// Returns the packet which should be sent next, if any.
+    pub fn pop_next(&mut self) -> Option<ControlPacket<Dst>> {
+        if let Some(normal) = self.normal.front() {
+            if self.high.is_empty() {
+                return self.normal.pop_front();
+            }
+            let len = normal.encoded_len();
+            if self.preempted_bytes + len <= self.max_preempt_bytes {
+                self.preempted_bytes += len;
+                return self.normal.pop_front();
+            }
+        }
+        let high = self.high.pop_front()?;
+        self.preempted_bytes = 0;
+        Some(high)
+    }
+
+    /// Returns the total amount of queued packets.
+    pub fn len(&self) -> usize {
+        self.high.len() + self.normal.len()
+    }
+
+    /// Returns whether no packets are queued.
+    pub fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.normal.is_empty()
+    }
+
+    /// Returns the amount of high priority packets which have been dropped because too many
+    /// were queued.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+pub use self::channel::{channel, PriorityReceiver, PrioritySender};
+
+#[cfg(feature = "tokio-codec")]
+mod channel {
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::task::Context;
+    use std::task::Poll;
+    use std::task::Waker;
+
+    use futures_util::Sink;
+    use futures_util::Stream;
+
+    use super::PriorityQueue;
+    use crate::control::ControlPacket;
+    use crate::error::Error;
+    use crate::voice::VoicePacketDst;
+
+    #[derive(Debug)]
+    struct Shared<Dst: VoicePacketDst> {
+        queue: PriorityQueue<Dst>,
+        senders: usize,
+        receiver_alive: bool,
+        waker: Option<Waker>,
+    }
+
+    impl<Dst: VoicePacketDst> Shared<Dst> {
+        fn wake(&mut self) {
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Splits a [PriorityQueue] into a `Sink` for the application to send packets into and a
+    /// `Stream` yielding them in prioritized order, e.g. to be forwarded into a `Framed`
+    /// transport.
+    pub fn channel<Dst: VoicePacketDst>(
+        queue: PriorityQueue<Dst>,
+    ) -> (PrioritySender<Dst>, PriorityReceiver<Dst>) {
+        let shared = Arc::new(Mutex::new(Shared {
+            queue,
+            senders: 1,
+            receiver_alive: true,
+            waker: None,
+        }));
+        (
+            PrioritySender {
+                shared: shared.clone(),
+            },
+            PriorityReceiver { shared },
+        )
+    }
+
+    /// The sending half of a [channel].
+    ///
+    /// Never applies backpressure; excess high priority packets are dropped as configured on the
+    /// [PriorityQueue]. Sending fails once the [PriorityReceiver] has been dropped.
+    #[derive(Debug)]
+    pub struct PrioritySender<Dst: VoicePacketDst> {
+        shared: Arc<Mutex<Shared<Dst>>>,
+    }
+
+    impl<Dst: VoicePacketDst> Clone for PrioritySender<Dst> {
+        fn clone(&self) -> Self {
+            self.shared.lock().unwrap().senders += 1;
+            PrioritySender {
+                shared: self.shared.clone(),
+            }
+        }
+    }
+
+    impl<Dst: VoicePacketDst> Drop for PrioritySender<Dst> {
+        fn drop(&mut self) {
+            let mut shared = self.shared.lock().unwrap();
+            shared.senders -= 1;
+            if shared.senders == 0 {
+                shared.wake();
+            }
+        }
+    }
+
+    impl<Dst: VoicePacketDst> Sink<ControlPacket<Dst>> for PrioritySender<Dst> {
+        type Error = Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: ControlPacket<Dst>) -> Result<(), Error> {
+            let mut shared = self.shared.lock().unwrap();
+            if !shared.receiver_alive {
+                return Err(Error::Io(io::ErrorKind::BrokenPipe.into()));
+            }
+            shared.queue.push(item);
+            shared.wake();
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// The receiving half of a [channel].
+    ///
+    /// Ends once all [PrioritySender]s have been dropped and the queue is empty.
+    #[derive(Debug)]
+    pub struct PriorityReceiver<Dst: VoicePacketDst> {
+        shared: Arc<Mutex<Shared<Dst>>>,
+    }
+
+    impl<Dst: VoicePacketDst> Drop for PriorityReceiver<Dst> {
+        fn drop(&mut self) {
+            self.shared.lock().unwrap().receiver_alive = false;
+        }
+    }
+
+    impl<Dst: VoicePacketDst> Stream for PriorityReceiver<Dst> {
+        type Item = ControlPacket<Dst>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let mut shared = self.shared.lock().unwrap();
+            if let Some(packet) = shared.queue.pop_next() {
+                return Poll::Ready(Some(packet));
+            }
+            if shared.senders == 0 {
+                return Poll::Ready(None);
+            }
+            shared.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::msgs;
+    use crate::voice::Clientbound;
+    use crate::voice::VoicePacket;
+
+    fn voice(timestamp: u64) -> ControlPacket<Clientbound> {
+        VoicePacket::Ping { timestamp }.into()
+    }
+
+    fn text(len: usize) -> ControlPacket<Clientbound> {
+        msgs::TextMessage::to_channel(0)
+            .text(&"x".repeat(len))
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn voice_overtakes_large_control_packets() {
+        let mut queue = PriorityQueue::new().with_max_preempt_bytes(100);
+        queue.push(text(50));
+        queue.push(text(1000));
+        queue.push(voice(1));
+        queue.push(voice(2));
+
+        // The small packet fits into the budget, the large one has to wait for the voice
+        assert_eq!(Some(text(50)), queue.pop_next());
+        assert_eq!(Some(voice(1)), queue.pop_next());
+        assert_eq!(Some(voice(2)), queue.pop_next());
+        assert_eq!(Some(text(1000)), queue.pop_next());
+        assert_eq!(None, queue.pop_next());
+    }
+
+    #[test]
+    fn drops_oldest_voice_when_full() {
+        let mut queue = PriorityQueue::new().with_max_queued_high(2);
+        for timestamp in 0..4 {
+            queue.push(voice(timestamp));
+        }
+        assert_eq!(2, queue.dropped());
+        assert_eq!(Some(voice(2)), queue.pop_next());
+        assert_eq!(Some(voice(3)), queue.pop_next());
+        assert!(queue.is_empty());
+    }
+
+    #[cfg(feature = "tokio-codec")]
+    #[test]
+    fn channel_yields_prioritized_packets() {
+        use futures::executor::block_on;
+        use futures::SinkExt;
+        use futures::StreamExt;
+
+        let (mut tx, rx) = channel(PriorityQueue::new().with_max_preempt_bytes(0));
+        block_on(async {
+            tx.send(text(10)).await.unwrap();
+            tx.send(voice(1)).await.unwrap();
+        });
+        drop(tx);
+        let packets: Vec<_> = block_on(rx.collect());
+        assert_eq!(vec![voice(1), text(10)], packets);
+    }
+}
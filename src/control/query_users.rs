@@ -0,0 +1,127 @@
+//! Resolving user IDs and names via `QueryUsers`
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+
+use super::backend::message;
+use super::msgs;
+
+/// A request for the names of registered user IDs and the IDs of registered user names.
+///
+/// The server answers a [msgs::QueryUsers] with the pairs it found, omitting unknown IDs and
+/// names. [resolve](Self::resolve) matches the answer back to the request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserQuery {
+    ids: BTreeSet<u32>,
+    names: BTreeSet<String>,
+}
+
+/// The answer to a [UserQuery].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserQueryResult {
+    /// Names of the requested IDs.
+    pub names: HashMap<u32, String>,
+    /// IDs of the requested names, keyed by the names as requested.
+    pub ids: HashMap<String, u32>,
+    /// Requested IDs the server did not know.
+    pub unresolved_ids: BTreeSet<u32>,
+    /// Requested names the server did not know.
+    pub unresolved_names: BTreeSet<String>,
+}
+
+impl UserQuery {
+    /// Creates an empty query.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a user ID to look up the name of.
+    pub fn id(mut self, id: u32) -> Self {
+        self.ids.insert(id);
+        self
+    }
+
+    /// Adds a user name to look up the ID of.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.names.insert(name.into());
+        self
+    }
+
+    /// Returns whether nothing is queried.
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty() && self.names.is_empty()
+    }
+
+    /// Creates the request message.
+    pub fn to_message(&self) -> msgs::QueryUsers {
+        message!(msgs::QueryUsers {
+            ids: self.ids.iter().copied().collect(),
+            names: self.names.iter().cloned().collect(),
+        })
+    }
+
+    /// Matches the server's answer to this query.
+    ///
+    /// Names are compared case-insensitively, like the server does.
+    pub fn resolve(&self, msg: &msgs::QueryUsers) -> UserQueryResult {
+        let mut result = UserQueryResult::default();
+        let names: HashMap<String, &String> = self
+            .names
+            .iter()
+            .map(|name| (name.to_lowercase(), name))
+            .collect();
+        for (id, name) in msg.ids.iter().zip(&msg.names) {
+            if self.ids.contains(id) {
+                result.names.insert(*id, name.clone());
+            }
+            if let Some(requested) = names.get(&name.to_lowercase()) {
+                result.ids.insert((*requested).clone(), *id);
+            }
+        }
+        result.unresolved_ids = self
+            .ids
+            .iter()
+            .filter(|it| !result.names.contains_key(it))
+            .copied()
+            .collect();
+        result.unresolved_names = self
+            .names
+            .iter()
+            .filter(|it| !result.ids.contains_key(*it))
+            .cloned()
+            .collect();
+        result
+    }
+}
+
+impl From<&UserQuery> for msgs::QueryUsers {
+    fn from(query: &UserQuery) -> Self {
+        query.to_message()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolves_answers() {
+        let query = UserQuery::new().id(1).id(2).name("Alice").name("nobody");
+        let request = query.to_message();
+        assert_eq!(vec![1, 2], request.ids);
+        assert_eq!(vec!["Alice", "nobody"], request.names);
+
+        let answer = message!(msgs::QueryUsers {
+            ids: vec![1, 5],
+            names: vec!["bob".to_owned(), "alice".to_owned()],
+        });
+        let result = query.resolve(&answer);
+        assert_eq!(Some("bob"), result.names.get(&1).map(String::as_str));
+        assert_eq!(Some(&5), result.ids.get("Alice"));
+        assert_eq!(BTreeSet::from([2]), result.unresolved_ids);
+        assert_eq!(
+            BTreeSet::from(["nobody".to_owned()]),
+            result.unresolved_names
+        );
+    }
+}
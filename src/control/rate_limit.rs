@@ -0,0 +1,226 @@
+//! Flood protection for the control channel
+//!
+//! Murmur limits how many control messages a single connection may send using a token bucket.
+//! [RateLimiter] implements the same scheme independent of any transport or runtime: the caller
+//! passes in the time at which each packet was received and acts on the returned [Decision].
+
+use std::collections::HashSet;
+use std::time::Instant;
+
+use super::PacketKind;
+
+/// Default amount of messages which may be sent in a burst.
+pub const DEFAULT_BUCKET_SIZE: u32 = 30;
+/// Default amount of messages per second which are added back to the bucket.
+pub const DEFAULT_REFILL_RATE: f64 = 6.4;
+/// Default amount of dropped messages after which the connection should be closed.
+pub const DEFAULT_DISCONNECT_AFTER: u32 = 100;
+
+/// What to do with a received packet, as returned by [RateLimiter::check].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Decision {
+    /// The packet should be processed.
+    Allow,
+    /// The packet should be silently discarded.
+    Drop,
+    /// The peer keeps flooding and the connection should be closed.
+    Disconnect,
+}
+
+/// A token bucket rate limiter for incoming control packets.
+///
+/// Every non-exempt packet takes one token out of the bucket, which is refilled continuously at
+/// a fixed rate. Packets arriving while the bucket is empty are dropped. Once too many packets
+/// have been dropped without the bucket ever filling up again in between, the peer is
+/// considered abusive and [Decision::Disconnect] is returned.
+///
+/// [PacketKind::Ping] and [PacketKind::UDPTunnel] are exempt by default, since they are sent
+/// periodically and with every voice frame respectively.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bucket_size: u32,
+    refill_rate: f64,
+    disconnect_after: u32,
+    exempt: HashSet<PacketKind>,
+    tokens: f64,
+    last_refill: Option<Instant>,
+    dropped: u32,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiter {
+    /// Creates a new rate limiter with Murmur's default limits and a full bucket.
+    pub fn new() -> Self {
+        RateLimiter {
+            bucket_size: DEFAULT_BUCKET_SIZE,
+            refill_rate: DEFAULT_REFILL_RATE,
+            disconnect_after: DEFAULT_DISCONNECT_AFTER,
+            exempt: [PacketKind::Ping, PacketKind::UDPTunnel]
+                .into_iter()
+                .collect(),
+            tokens: f64::from(DEFAULT_BUCKET_SIZE),
+            last_refill: None,
+            dropped: 0,
+        }
+    }
+
+    /// Changes the amount of messages which may be sent in a burst and refills the bucket.
+    pub fn with_bucket_size(mut self, bucket_size: u32) -> Self {
+        self.bucket_size = bucket_size;
+        self.tokens = f64::from(bucket_size);
+        self
+    }
+
+    /// Changes the amount of messages per second which are added back to the bucket.
+    pub fn with_refill_rate(mut self, refill_rate: f64) -> Self {
+        self.refill_rate = refill_rate;
+        self
+    }
+
+    /// Changes the amount of dropped messages after which [Decision::Disconnect] is returned.
+    ///
+    /// `0` disables disconnecting entirely.
+    pub fn with_disconnect_after(mut self, disconnect_after: u32) -> Self {
+        self.disconnect_after = disconnect_after;
+        self
+    }
+
+    /// Exempts packets of the given kind from rate limiting.
+    pub fn with_exempt(mut self, kind: PacketKind) -> Self {
+        self.exempt.insert(kind);
+        self
+    }
+
+    /// Subjects packets of the given kind to rate limiting, even if exempt by default.
+    pub fn without_exempt(mut self, kind: PacketKind) -> Self {
+        self.exempt.remove(&kind);
+        self
+    }
+
+    /// Returns whether packets of the given kind bypass the rate limit.
+    pub fn is_exempt(&self, kind: PacketKind) -> bool {
+        self.exempt.contains(&kind)
+    }
+
+    /// Returns the amount of messages which could currently be sent in a burst.
+    pub fn available(&self) -> u32 {
+        self.tokens as u32
+    }
+
+    /// Decides what to do with a packet of the given kind received at `now`.
+    ///
+    /// `now` should never go backwards, earlier instants are treated as if no time had passed.
+    pub fn check(&mut self, kind: PacketKind, now: Instant) -> Decision {
+        self.refill(now);
+        if self.is_exempt(kind) {
+            return Decision::Allow;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Decision::Allow;
+        }
+        self.dropped = self.dropped.saturating_add(1);
+        if self.disconnect_after != 0 && self.dropped >= self.disconnect_after {
+            Decision::Disconnect
+        } else {
+            Decision::Drop
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(last_refill) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last_refill).as_secs_f64();
+            self.tokens += elapsed * self.refill_rate;
+        }
+        let bucket_size = f64::from(self.bucket_size);
+        if self.tokens >= bucket_size {
+            self.tokens = bucket_size;
+            // The peer has calmed down, forget about past abuse
+            self.dropped = 0;
+        }
+        self.last_refill = Some(match self.last_refill {
+            Some(last_refill) => last_refill.max(now),
+            None => now,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn allows_bursts_up_to_bucket_size() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new().with_bucket_size(3);
+        for _ in 0..3 {
+            assert_eq!(Decision::Allow, limiter.check(PacketKind::TextMessage, now));
+        }
+        assert_eq!(Decision::Drop, limiter.check(PacketKind::TextMessage, now));
+        assert_eq!(Decision::Allow, limiter.check(PacketKind::Ping, now));
+        assert_eq!(Decision::Allow, limiter.check(PacketKind::UDPTunnel, now));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new().with_bucket_size(2).with_refill_rate(2.0);
+        assert_eq!(Decision::Allow, limiter.check(PacketKind::UserState, start));
+        assert_eq!(Decision::Allow, limiter.check(PacketKind::UserState, start));
+        assert_eq!(Decision::Drop, limiter.check(PacketKind::UserState, start));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(Decision::Allow, limiter.check(PacketKind::UserState, later));
+        assert_eq!(Decision::Drop, limiter.check(PacketKind::UserState, later));
+
+        // Going back in time must not produce tokens
+        assert_eq!(Decision::Drop, limiter.check(PacketKind::UserState, start));
+    }
+
+    #[test]
+    fn disconnects_persistent_flooders() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new()
+            .with_bucket_size(1)
+            .with_refill_rate(1.0)
+            .with_disconnect_after(3);
+        assert_eq!(
+            Decision::Allow,
+            limiter.check(PacketKind::TextMessage, start)
+        );
+        assert_eq!(
+            Decision::Drop,
+            limiter.check(PacketKind::TextMessage, start)
+        );
+        assert_eq!(
+            Decision::Drop,
+            limiter.check(PacketKind::TextMessage, start)
+        );
+
+        // A full bucket forgives earlier drops
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            Decision::Allow,
+            limiter.check(PacketKind::TextMessage, later)
+        );
+        assert_eq!(
+            Decision::Drop,
+            limiter.check(PacketKind::TextMessage, later)
+        );
+        assert_eq!(
+            Decision::Drop,
+            limiter.check(PacketKind::TextMessage, later)
+        );
+        assert_eq!(
+            Decision::Disconnect,
+            limiter.check(PacketKind::TextMessage, later)
+        );
+    }
+}
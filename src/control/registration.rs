@@ -0,0 +1,169 @@
+//! Registering users via `UserState` messages
+//!
+//! A `UserState` carrying a `user_id` asks the server to register the user with the given
+//! session. The value itself is ignored, clients send `0`. Registration requires a certificate,
+//! since Murmur identifies registered users by its hash. Deregistration is done via `UserList`.
+
+use super::msgs;
+use super::permission_denied::DenyReason;
+use super::permissions::Permissions;
+use crate::state::User;
+
+/// Whom a registration request is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RegistrationKind {
+    /// The sender registers themselves, which requires [Permissions::SELF_REGISTER].
+    SelfRegistration,
+    /// The sender registers another user, which requires [Permissions::REGISTER].
+    OtherUser,
+}
+
+/// A request to register a user, as received by a server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RegistrationRequest {
+    /// Session of the user to register.
+    pub session: u32,
+    /// Whether the sender registers themselves.
+    pub kind: RegistrationKind,
+}
+
+impl RegistrationRequest {
+    /// Returns the registration request contained in a message received from `sender`, `None`
+    /// if it is a normal state change.
+    ///
+    /// A message without session refers to the sender.
+    pub fn from_message(msg: &msgs::UserState, sender: u32) -> Option<Self> {
+        msg.user_id?;
+        let session = msg.session.unwrap_or(sender);
+        let kind = if session == sender {
+            RegistrationKind::SelfRegistration
+        } else {
+            RegistrationKind::OtherUser
+        };
+        Some(RegistrationRequest { session, kind })
+    }
+
+    /// Returns the permission the sender needs in the root channel.
+    pub fn required_permission(&self) -> Permissions {
+        match self.kind {
+            RegistrationKind::SelfRegistration => Permissions::SELF_REGISTER,
+            RegistrationKind::OtherUser => Permissions::REGISTER,
+        }
+    }
+
+    /// Checks whether the request may be granted, like Murmur does.
+    ///
+    /// `permissions` are the sender's permissions in the root channel, `cert_hash` and
+    /// `registered` describe the user to register. The hash is the one computed by
+    /// `certificate_info` (with the `openssl` feature), `None` if the user has no certificate.
+    pub fn verify(
+        &self,
+        permissions: Permissions,
+        cert_hash: Option<&str>,
+        registered: bool,
+    ) -> Result<(), DenyReason> {
+        let permission = self.required_permission();
+        if registered || !permissions.contains(permission) {
+            return Err(DenyReason::InsufficientPermission {
+                channel_id: 0,
+                permission,
+                session: self.session,
+            });
+        }
+        if cert_hash.is_none_or(str::is_empty) {
+            return Err(DenyReason::MissingCertificate {
+                session: self.session,
+            });
+        }
+        Ok(())
+    }
+
+    /// Like [verify](Self::verify), taking certificate and registration from the user.
+    pub fn verify_user(&self, permissions: Permissions, user: &User) -> Result<(), DenyReason> {
+        self.verify(permissions, user.hash.as_deref(), user.user_id.is_some())
+    }
+}
+
+impl msgs::UserState {
+    /// Creates a request to register the own user, as sent by clients.
+    pub fn register_self(session: u32) -> Self {
+        msgs::UserState {
+            session: Some(session),
+            user_id: Some(0),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a request to register another user, as sent by clients.
+    ///
+    /// The message is the same as for [register_self](Self::register_self), the server tells
+    /// them apart by the sender.
+    pub fn register_user(session: u32) -> Self {
+        Self::register_self(session)
+    }
+
+    /// Returns whether the message asks the server to register a user.
+    pub fn is_registration_request(&self) -> bool {
+        self.user_id.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classifies_registration_requests() {
+        let msg = msgs::UserState::register_self(3);
+        assert!(msg.is_registration_request());
+        assert_eq!(
+            Some(RegistrationRequest {
+                session: 3,
+                kind: RegistrationKind::SelfRegistration
+            }),
+            RegistrationRequest::from_message(&msg, 3)
+        );
+        let request = RegistrationRequest::from_message(&msgs::UserState::register_user(4), 3);
+        assert_eq!(Some(RegistrationKind::OtherUser), request.map(|it| it.kind));
+
+        let msg = msgs::UserState::builder(3).self_mute(true).build();
+        assert!(!msg.is_registration_request());
+        assert_eq!(None, RegistrationRequest::from_message(&msg, 3));
+    }
+
+    #[test]
+    fn verifies_like_murmur() {
+        let request = RegistrationRequest {
+            session: 4,
+            kind: RegistrationKind::OtherUser,
+        };
+        let denied = Err(DenyReason::InsufficientPermission {
+            channel_id: 0,
+            permission: Permissions::REGISTER,
+            session: 4,
+        });
+        assert_eq!(
+            denied,
+            request.verify(Permissions::SELF_REGISTER, Some("ab"), false)
+        );
+        assert_eq!(
+            denied,
+            request.verify(Permissions::REGISTER, Some("ab"), true)
+        );
+        assert_eq!(
+            Err(DenyReason::MissingCertificate { session: 4 }),
+            request.verify(Permissions::REGISTER, None, false)
+        );
+        assert_eq!(
+            Ok(()),
+            request.verify(Permissions::REGISTER, Some("ab"), false)
+        );
+
+        let user = User {
+            session: 4,
+            hash: Some("ab".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(Ok(()), request.verify_user(Permissions::all(), &user));
+    }
+}
@@ -0,0 +1,109 @@
+//! Typed reasons for `Reject` messages
+
+use std::fmt;
+
+use super::msgs;
+
+/// The reason the server rejected a connection, as sent in [msgs::Reject].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RejectReason {
+    /// The client version is incompatible with the server.
+    WrongVersion,
+    /// The username is invalid.
+    InvalidUsername,
+    /// The password of a registered user is wrong.
+    WrongUserPW,
+    /// The server password is wrong.
+    WrongServerPW,
+    /// Another user with the same name is already connected.
+    UsernameInUse,
+    /// The server has reached its user limit.
+    ServerFull,
+    /// The server requires a client certificate but none was provided.
+    NoCertificate,
+    /// The server's authenticator rejected the user.
+    AuthenticatorFail,
+    /// Any other reason, with the text provided by the server.
+    Other(String),
+}
+
+impl RejectReason {
+    /// Returns whether reconnecting later without changing anything might succeed.
+    ///
+    /// This is the case if the server is full, the username is still taken by a session which
+    /// is timing out, or the authenticator failed (which may be temporary). Unknown reasons are
+    /// not considered retryable.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            RejectReason::ServerFull
+                | RejectReason::UsernameInUse
+                | RejectReason::AuthenticatorFail
+        )
+    }
+}
+
+impl From<&msgs::Reject> for RejectReason {
+    fn from(msg: &msgs::Reject) -> Self {
+        #[cfg(feature = "protobuf")]
+        let kind = msg.type_.map(|it| it.value());
+        #[cfg(feature = "prost")]
+        let kind = msg.r#type;
+        match kind {
+            Some(1) => RejectReason::WrongVersion,
+            Some(2) => RejectReason::InvalidUsername,
+            Some(3) => RejectReason::WrongUserPW,
+            Some(4) => RejectReason::WrongServerPW,
+            Some(5) => RejectReason::UsernameInUse,
+            Some(6) => RejectReason::ServerFull,
+            Some(7) => RejectReason::NoCertificate,
+            Some(8) => RejectReason::AuthenticatorFail,
+            _ => RejectReason::Other(msg.reason.clone().unwrap_or_default()),
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RejectReason::WrongVersion => "client version is incompatible with the server",
+            RejectReason::InvalidUsername => "invalid username",
+            RejectReason::WrongUserPW => "wrong password for registered user",
+            RejectReason::WrongServerPW => "wrong server password",
+            RejectReason::UsernameInUse => "username is already in use",
+            RejectReason::ServerFull => "server is full",
+            RejectReason::NoCertificate => "server requires a certificate",
+            RejectReason::AuthenticatorFail => "authentication failed",
+            RejectReason::Other(reason) if reason.is_empty() => "connection rejected",
+            RejectReason::Other(reason) => reason,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_reject_reasons() {
+        let mut msg = msgs::Reject {
+            reason: Some("full".to_owned()),
+            ..Default::default()
+        };
+        msg.set_type(msgs::reject::RejectType::ServerFull);
+        let reason = RejectReason::from(&msg);
+        assert_eq!(RejectReason::ServerFull, reason);
+        assert_eq!("server is full", reason.to_string());
+        assert!(reason.is_retryable());
+
+        msg.set_type(msgs::reject::RejectType::None);
+        let reason = RejectReason::from(&msg);
+        assert_eq!(RejectReason::Other("full".to_owned()), reason);
+        assert_eq!("full", reason.to_string());
+        assert!(!reason.is_retryable());
+
+        let reason = RejectReason::from(&msgs::Reject::default());
+        assert_eq!("connection rejected", reason.to_string());
+    }
+}
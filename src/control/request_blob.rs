@@ -0,0 +1,202 @@
+//! Construction of `RequestBlob` messages and correlation of their answers
+//!
+//! Servers omit comments, textures and channel descriptions above a certain size from
+//! `UserState` and `ChannelState` messages and only send their hash. Clients request the full
+//! content with a `RequestBlob`, which the server answers with a `UserState` or `ChannelState`
+//! carrying just that content.
+
+use std::collections::BTreeSet;
+
+use super::msgs;
+
+/// The kind of content a `RequestBlob` asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlobKind {
+    /// The comment of a user.
+    UserComment,
+    /// The texture (avatar) of a user.
+    UserTexture,
+    /// The description of a channel.
+    ChannelDescription,
+}
+
+impl msgs::RequestBlob {
+    /// Creates a request for the comments of the users with the given sessions.
+    pub fn for_user_comments(sessions: impl IntoIterator<Item = u32>) -> Self {
+        msgs::RequestBlob {
+            session_comment: sessions.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a request for the textures of the users with the given sessions.
+    pub fn for_user_textures(sessions: impl IntoIterator<Item = u32>) -> Self {
+        msgs::RequestBlob {
+            session_texture: sessions.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a request for the descriptions of the channels with the given IDs.
+    pub fn for_channel_descriptions(channel_ids: impl IntoIterator<Item = u32>) -> Self {
+        msgs::RequestBlob {
+            channel_description: channel_ids.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Combines two requests into one, e.g.
+    /// `RequestBlob::for_user_comments([1]).combine(RequestBlob::for_user_textures([1]))`.
+    pub fn combine(mut self, other: Self) -> Self {
+        for (ids, other) in [
+            (&mut self.session_comment, other.session_comment),
+            (&mut self.session_texture, other.session_texture),
+            (&mut self.channel_description, other.channel_description),
+        ] {
+            for id in other {
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+        self
+    }
+}
+
+/// Keeps track of sent `RequestBlob`s until they are answered.
+///
+/// Allows telling the answers apart from ordinary state changes, e.g. to avoid announcing a
+/// requested comment as changed.
+#[derive(Clone, Debug, Default)]
+pub struct PendingBlobRequests {
+    comments: BTreeSet<u32>,
+    textures: BTreeSet<u32>,
+    descriptions: BTreeSet<u32>,
+}
+
+impl PendingBlobRequests {
+    /// Creates a tracker without pending requests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a request which was sent to the server.
+    pub fn record(&mut self, msg: &msgs::RequestBlob) {
+        self.comments.extend(msg.session_comment.iter().copied());
+        self.textures.extend(msg.session_texture.iter().copied());
+        self.descriptions
+            .extend(msg.channel_description.iter().copied());
+    }
+
+    /// Returns whether content of the given kind was requested for the user or channel and
+    /// not received yet.
+    pub fn is_pending(&self, kind: BlobKind, id: u32) -> bool {
+        match kind {
+            BlobKind::UserComment => self.comments.contains(&id),
+            BlobKind::UserTexture => self.textures.contains(&id),
+            BlobKind::ChannelDescription => self.descriptions.contains(&id),
+        }
+    }
+
+    /// Returns whether no requests are pending.
+    pub fn is_empty(&self) -> bool {
+        self.comments.is_empty() && self.textures.is_empty() && self.descriptions.is_empty()
+    }
+
+    /// Matches a received `UserState` against the pending requests, returning the kinds of
+    /// content it answers. These requests are no longer pending afterwards.
+    pub fn match_user_state(&mut self, msg: &msgs::UserState) -> Vec<BlobKind> {
+        let session = match msg.session {
+            Some(session) => session,
+            None => return Vec::new(),
+        };
+        let mut answered = Vec::new();
+        if msg.comment.is_some() && self.comments.remove(&session) {
+            answered.push(BlobKind::UserComment);
+        }
+        if msg.texture.is_some() && self.textures.remove(&session) {
+            answered.push(BlobKind::UserTexture);
+        }
+        answered
+    }
+
+    /// Matches a received `ChannelState` against the pending requests, returning whether it
+    /// answers one. The request is no longer pending afterwards.
+    pub fn match_channel_state(&mut self, msg: &msgs::ChannelState) -> bool {
+        match msg.channel_id {
+            Some(channel_id) if msg.description.is_some() => self.descriptions.remove(&channel_id),
+            _ => false,
+        }
+    }
+
+    /// Drops the pending requests for a user, e.g. once they disconnected.
+    pub fn remove_user(&mut self, session: u32) {
+        self.comments.remove(&session);
+        self.textures.remove(&session);
+    }
+
+    /// Drops the pending request for a channel, e.g. once it was removed.
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        self.descriptions.remove(&channel_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn combines_requests() {
+        let msg = msgs::RequestBlob::for_user_comments([1, 2])
+            .combine(msgs::RequestBlob::for_user_textures([2]))
+            .combine(msgs::RequestBlob::for_channel_descriptions([5]))
+            .combine(msgs::RequestBlob::for_user_comments([2, 3]));
+        assert_eq!(vec![1, 2, 3], msg.session_comment);
+        assert_eq!(vec![2], msg.session_texture);
+        assert_eq!(vec![5], msg.channel_description);
+    }
+
+    #[test]
+    fn matches_answers() {
+        let mut pending = PendingBlobRequests::new();
+        pending.record(
+            &msgs::RequestBlob::for_user_comments([1, 2])
+                .combine(msgs::RequestBlob::for_user_textures([1]))
+                .combine(msgs::RequestBlob::for_channel_descriptions([5])),
+        );
+        assert!(pending.is_pending(BlobKind::UserTexture, 1));
+        assert!(!pending.is_pending(BlobKind::UserTexture, 2));
+
+        // An ordinary state change of a user with pending requests
+        let moved = msgs::UserState {
+            session: Some(1),
+            channel_id: Some(3),
+            ..Default::default()
+        };
+        assert!(pending.match_user_state(&moved).is_empty());
+
+        let answer = msgs::UserState {
+            session: Some(1),
+            comment: Some("long comment".to_owned()),
+            texture: Some(vec![1, 2, 3]),
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![BlobKind::UserComment, BlobKind::UserTexture],
+            pending.match_user_state(&answer)
+        );
+        assert!(pending.match_user_state(&answer).is_empty());
+
+        let answer = msgs::ChannelState {
+            channel_id: Some(5),
+            description: Some("long description".to_owned()),
+            ..Default::default()
+        };
+        assert!(pending.match_channel_state(&answer));
+        assert!(!pending.match_channel_state(&answer));
+
+        assert!(!pending.is_empty());
+        pending.remove_user(2);
+        assert!(pending.is_empty());
+    }
+}
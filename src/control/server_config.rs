@@ -0,0 +1,175 @@
+//! Typed view of the `ServerConfig` message
+//!
+//! Servers send their configuration after the initial sync and may send it again later, each
+//! time including only some of the fields. [ServerCapabilities] accumulates them.
+
+use super::backend::message;
+use super::limits::MessageLimits;
+use super::msgs;
+use crate::text::Policy;
+
+/// The configuration of a server, as announced in `ServerConfig` messages.
+///
+/// Fields the server did not announce keep Murmur's defaults. A length limit of `0` means
+/// unlimited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    /// The maximum bandwidth clients should use for speech, in bits per second.
+    pub max_bandwidth: u32,
+    /// The welcome text shown after connecting.
+    pub welcome_text: String,
+    /// Whether text messages and comments may contain HTML.
+    pub allow_html: bool,
+    /// Maximum length of text messages without images.
+    pub message_length: u32,
+    /// Maximum length of text messages with images.
+    pub image_message_length: u32,
+    /// Maximum amount of users on the server.
+    pub max_users: u32,
+    /// Whether users may record.
+    ///
+    /// Always the default with the `webrtc-extensions` feature, its protocol lacks the field.
+    pub recording_allowed: bool,
+}
+
+impl Default for ServerCapabilities {
+    /// Murmur's defaults.
+    fn default() -> Self {
+        ServerCapabilities {
+            max_bandwidth: 558_000,
+            welcome_text: String::new(),
+            allow_html: true,
+            message_length: 5000,
+            image_message_length: 131_072,
+            max_users: 100,
+            recording_allowed: true,
+        }
+    }
+}
+
+impl ServerCapabilities {
+    /// Creates capabilities with Murmur's defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies the fields present in the message.
+    pub fn update(&mut self, msg: &msgs::ServerConfig) {
+        if let Some(max_bandwidth) = msg.max_bandwidth {
+            self.max_bandwidth = max_bandwidth;
+        }
+        if let Some(welcome_text) = &msg.welcome_text {
+            self.welcome_text.clone_from(welcome_text);
+        }
+        if let Some(allow_html) = msg.allow_html {
+            self.allow_html = allow_html;
+        }
+        if let Some(message_length) = msg.message_length {
+            self.message_length = message_length;
+        }
+        if let Some(image_message_length) = msg.image_message_length {
+            self.image_message_length = image_message_length;
+        }
+        if let Some(max_users) = msg.max_users {
+            self.max_users = max_users;
+        }
+        #[cfg(not(feature = "webrtc-extensions"))]
+        if let Some(recording_allowed) = msg.recording_allowed {
+            self.recording_allowed = recording_allowed;
+        }
+    }
+
+    /// Returns the limits text messages are checked against.
+    pub fn message_limits(&self) -> MessageLimits {
+        let limit = |limit: u32| Some(limit as usize).filter(|it| *it != 0);
+        MessageLimits {
+            message_length: limit(self.message_length),
+            image_message_length: limit(self.image_message_length),
+        }
+    }
+
+    /// Returns the maximum bandwidth for speech in bits per second, `None` if unlimited.
+    pub fn max_bandwidth(&self) -> Option<u32> {
+        Some(self.max_bandwidth).filter(|it| *it != 0)
+    }
+
+    /// Returns whether text messages and comments may contain HTML.
+    pub fn html_allowed(&self) -> bool {
+        self.allow_html
+    }
+
+    /// Returns the policy text messages should be sanitized with: the default one if HTML is
+    /// allowed, otherwise one keeping only the text.
+    pub fn text_policy(&self) -> Policy {
+        if self.allow_html {
+            Policy::default()
+        } else {
+            Policy::none()
+        }
+    }
+
+    /// Creates the message announcing all of the configuration, as sent by servers.
+    pub fn to_message(&self) -> msgs::ServerConfig {
+        message!(msgs::ServerConfig {
+            max_bandwidth: Some(self.max_bandwidth),
+            welcome_text: Some(self.welcome_text.clone()),
+            allow_html: Some(self.allow_html),
+            message_length: Some(self.message_length),
+            image_message_length: Some(self.image_message_length),
+            max_users: Some(self.max_users),
+            #[cfg(not(feature = "webrtc-extensions"))]
+            recording_allowed: Some(self.recording_allowed),
+        })
+    }
+}
+
+impl From<&msgs::ServerConfig> for ServerCapabilities {
+    fn from(msg: &msgs::ServerConfig) -> Self {
+        let mut capabilities = Self::default();
+        capabilities.update(msg);
+        capabilities
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn applies_present_fields() {
+        let mut capabilities = ServerCapabilities::new();
+        capabilities.update(&msgs::ServerConfig {
+            allow_html: Some(false),
+            message_length: Some(128),
+            ..Default::default()
+        });
+        capabilities.update(&msgs::ServerConfig {
+            max_bandwidth: Some(72_000),
+            image_message_length: Some(0),
+            ..Default::default()
+        });
+        assert!(!capabilities.html_allowed());
+        assert_eq!(Some(72_000), capabilities.max_bandwidth());
+        assert_eq!(
+            MessageLimits {
+                message_length: Some(128),
+                image_message_length: None,
+            },
+            capabilities.message_limits()
+        );
+        assert_eq!(100, capabilities.max_users);
+        assert!(capabilities.recording_allowed);
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let capabilities = ServerCapabilities {
+            welcome_text: "hi".to_owned(),
+            max_users: 10,
+            ..Default::default()
+        };
+        let msg = capabilities.to_message();
+        assert_eq!(capabilities, ServerCapabilities::from(&msg));
+        assert_eq!(MessageLimits::from(&msg), capabilities.message_limits());
+    }
+}
@@ -0,0 +1,70 @@
+//! Convenience extension for sinks of control packets
+
+use futures_util::sink::Send;
+use futures_util::Sink;
+use futures_util::SinkExt;
+
+use super::ControlPacket;
+use crate::voice::VoicePacketDst;
+
+/// An extension trait for [Sink]s of [ControlPacket]s, e.g. a `Framed` [ControlCodec].
+///
+/// Allows sending anything convertible into a [ControlPacket], i.e. any message from [msgs],
+/// [VoicePacket]s (which are tunneled via `UDPTunnel`) and [RawControlPacket]s, without having to
+/// convert it first.
+///
+/// [ControlCodec]: super::ControlCodec
+/// [msgs]: super::msgs
+/// [VoicePacket]: crate::voice::VoicePacket
+/// [RawControlPacket]: super::RawControlPacket
+pub trait SendControlExt<Dst: VoicePacketDst>: Sink<ControlPacket<Dst>> {
+    /// Converts the message into a [ControlPacket] and sends it.
+    ///
+    /// See [SinkExt::send].
+    fn send_msg<M: Into<ControlPacket<Dst>>>(
+        &mut self,
+        msg: M,
+    ) -> Send<'_, Self, ControlPacket<Dst>>
+    where
+        Self: Unpin,
+    {
+        self.send(msg.into())
+    }
+}
+
+impl<Dst: VoicePacketDst, S: Sink<ControlPacket<Dst>> + ?Sized> SendControlExt<Dst> for S {}
+
+#[cfg(all(test, feature = "tokio-codec"))]
+mod test {
+    use bytes::BytesMut;
+    use futures::executor::block_on;
+    use tokio_util::codec::FramedWrite;
+
+    use super::*;
+    use crate::control::msgs;
+    use crate::control::ClientControlCodec;
+    use crate::control::ServerControlCodec;
+    use crate::voice::VoicePacket;
+
+    #[test]
+    fn send_msg_converts_messages() {
+        let mut sink = FramedWrite::new(Vec::new(), ClientControlCodec::new());
+        block_on(async {
+            sink.send_msg(msgs::Ping::default()).await.unwrap();
+            sink.send_msg(VoicePacket::Ping { timestamp: 3 })
+                .await
+                .unwrap();
+        });
+
+        let mut buf = BytesMut::from(&sink.get_ref()[..]);
+        let mut codec = ServerControlCodec::new();
+        assert_eq!(
+            Some(msgs::Ping::default().into()),
+            codec.decode(&mut buf).unwrap()
+        );
+        assert_eq!(
+            Some(VoicePacket::Ping { timestamp: 3 }.into()),
+            codec.decode(&mut buf).unwrap()
+        );
+    }
+}
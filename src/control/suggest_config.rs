@@ -0,0 +1,214 @@
+//! Typed access to the `SuggestConfig` message
+//!
+//! Servers may suggest a minimum client version and whether positional audio and push-to-talk
+//! should be used. Absent fields mean that the server has no suggestion.
+
+use std::fmt;
+
+use super::msgs;
+use crate::version::ProtocolVersion;
+
+/// The configuration suggested by a server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SuggestedConfig {
+    /// The minimum client version.
+    pub version: Option<ProtocolVersion>,
+    /// Whether positional audio should be enabled.
+    pub positional: Option<bool>,
+    /// Whether push-to-talk should be enabled.
+    pub push_to_talk: Option<bool>,
+}
+
+impl SuggestedConfig {
+    /// Creates the message suggesting this configuration, as sent by servers.
+    pub fn to_message(&self) -> msgs::SuggestConfig {
+        let mut msg = msgs::SuggestConfig {
+            positional: self.positional,
+            push_to_talk: self.push_to_talk,
+            ..Default::default()
+        };
+        if let Some(version) = self.version {
+            #[cfg(not(feature = "webrtc-extensions"))]
+            {
+                msg.version_v1 = Some(version.to_v1());
+                msg.version_v2 = Some(version.to_v2());
+            }
+            #[cfg(feature = "webrtc-extensions")]
+            {
+                msg.version = Some(version.to_v1());
+            }
+        }
+        msg
+    }
+}
+
+impl From<&msgs::SuggestConfig> for SuggestedConfig {
+    fn from(msg: &msgs::SuggestConfig) -> Self {
+        // Prefer version_v2, the legacy field cannot represent minor and patch above 255
+        #[cfg(not(feature = "webrtc-extensions"))]
+        let version = msg
+            .version_v2
+            .map(ProtocolVersion::from_v2)
+            .or_else(|| msg.version_v1.map(ProtocolVersion::from_v1));
+        #[cfg(feature = "webrtc-extensions")]
+        let version = msg.version.map(ProtocolVersion::from_v1);
+        SuggestedConfig {
+            version,
+            positional: msg.positional,
+            push_to_talk: msg.push_to_talk,
+        }
+    }
+}
+
+/// A way in which the local configuration differs from the one suggested by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigMismatch {
+    /// The local client is older than the suggested version.
+    VersionTooOld {
+        /// The suggested minimum version.
+        suggested: ProtocolVersion,
+        /// The local version.
+        local: ProtocolVersion,
+    },
+    /// Positional audio should be enabled or disabled.
+    PositionalAudio {
+        /// Whether positional audio should be enabled.
+        suggested: bool,
+    },
+    /// Push-to-talk should be enabled or disabled.
+    PushToTalk {
+        /// Whether push-to-talk should be enabled.
+        suggested: bool,
+    },
+}
+
+/// The warnings shown by the Mumble client.
+impl fmt::Display for ConfigMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let enabled = |suggested: bool| if suggested { "enabled" } else { "disabled" };
+        match self {
+            ConfigMismatch::VersionTooOld { suggested, .. } => {
+                write!(
+                    f,
+                    "The server requests minimum client version {}",
+                    suggested
+                )
+            }
+            ConfigMismatch::PositionalAudio { suggested } => write!(
+                f,
+                "The server requests positional audio be {}.",
+                enabled(*suggested)
+            ),
+            ConfigMismatch::PushToTalk { suggested } => write!(
+                f,
+                "The server requests Push-to-Talk be {}.",
+                enabled(*suggested)
+            ),
+        }
+    }
+}
+
+/// The local configuration to compare a `SuggestConfig` against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SuggestConfigCheck {
+    /// The version of the local client.
+    pub version: ProtocolVersion,
+    /// Whether positional audio is enabled.
+    pub positional: bool,
+    /// Whether push-to-talk is enabled.
+    pub push_to_talk: bool,
+}
+
+impl SuggestConfigCheck {
+    /// Returns the ways in which the local configuration differs from the suggested one.
+    pub fn check(&self, msg: &msgs::SuggestConfig) -> Vec<ConfigMismatch> {
+        let suggested = SuggestedConfig::from(msg);
+        let mut mismatches = Vec::new();
+        if let Some(version) = suggested.version.filter(|it| self.version < *it) {
+            mismatches.push(ConfigMismatch::VersionTooOld {
+                suggested: version,
+                local: self.version,
+            });
+        }
+        if let Some(positional) = suggested.positional.filter(|it| *it != self.positional) {
+            mismatches.push(ConfigMismatch::PositionalAudio {
+                suggested: positional,
+            });
+        }
+        if let Some(push_to_talk) = suggested.push_to_talk.filter(|it| *it != self.push_to_talk) {
+            mismatches.push(ConfigMismatch::PushToTalk {
+                suggested: push_to_talk,
+            });
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LOCAL: SuggestConfigCheck = SuggestConfigCheck {
+        version: ProtocolVersion::new(1, 4, 287),
+        positional: false,
+        push_to_talk: true,
+    };
+
+    #[test]
+    fn ignores_absent_suggestions() {
+        assert!(LOCAL.check(&msgs::SuggestConfig::default()).is_empty());
+        let msg = SuggestedConfig {
+            version: Some(ProtocolVersion::new(1, 4, 0)),
+            push_to_talk: Some(true),
+            ..Default::default()
+        }
+        .to_message();
+        assert!(LOCAL.check(&msg).is_empty());
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let suggested = SuggestedConfig {
+            version: Some(ProtocolVersion::new(1, 5, 17)),
+            positional: Some(true),
+            push_to_talk: Some(false),
+        };
+        let msg = suggested.to_message();
+        assert_eq!(suggested, SuggestedConfig::from(&msg));
+        let mismatches = LOCAL.check(&msg);
+        assert_eq!(
+            vec![
+                ConfigMismatch::VersionTooOld {
+                    suggested: ProtocolVersion::new(1, 5, 17),
+                    local: LOCAL.version,
+                },
+                ConfigMismatch::PositionalAudio { suggested: true },
+                ConfigMismatch::PushToTalk { suggested: false },
+            ],
+            mismatches
+        );
+        assert_eq!(
+            "The server requests minimum client version 1.5.17",
+            mismatches[0].to_string()
+        );
+        assert_eq!(
+            "The server requests Push-to-Talk be disabled.",
+            mismatches[2].to_string()
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "webrtc-extensions"))]
+    fn prefers_version_v2() {
+        let msg = msgs::SuggestConfig {
+            version_v1: Some(ProtocolVersion::new(1, 5, 255).to_v1()),
+            version_v2: Some(ProtocolVersion::new(1, 5, 517).to_v2()),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(ProtocolVersion::new(1, 5, 517)),
+            SuggestedConfig::from(&msg).version
+        );
+    }
+}
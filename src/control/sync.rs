@@ -0,0 +1,161 @@
+//! Blocking control channel I/O for use without an async runtime
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use bytes::BytesMut;
+
+use super::ControlCodec;
+use super::ControlPacket;
+use crate::error::Error;
+use crate::voice::Clientbound;
+use crate::voice::Serverbound;
+use crate::voice::VoicePacketDst;
+
+/// Amount of bytes read from the underlying stream at once.
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Reads and writes [ControlPacket]s from and to a blocking stream, e.g. a TLS connection.
+///
+/// See [ServerMessageStream] and [ClientMessageStream] for the two most reasonable
+/// configurations.
+#[derive(Debug)]
+pub struct MessageStream<S, EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> {
+    stream: S,
+    codec: ControlCodec<EncodeDst, DecodeDst>,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+}
+/// The [MessageStream] used on the server side.
+pub type ServerMessageStream<S> = MessageStream<S, Clientbound, Serverbound>;
+/// The [MessageStream] used on the client side.
+pub type ClientMessageStream<S> = MessageStream<S, Serverbound, Clientbound>;
+
+impl<S, EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>
+    MessageStream<S, EncodeDst, DecodeDst>
+{
+    /// Wraps the given stream using a default [ControlCodec].
+    pub fn new(stream: S) -> Self {
+        Self::with_codec(stream, ControlCodec::new())
+    }
+
+    /// Wraps the given stream using the given, possibly pre-configured codec.
+    pub fn with_codec(stream: S, codec: ControlCodec<EncodeDst, DecodeDst>) -> Self {
+        MessageStream {
+            stream,
+            codec,
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    ///
+    /// Reading from or writing to it directly will likely corrupt the packet stream.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Returns a mutable reference to the codec, e.g. to change its limits.
+    pub fn codec_mut(&mut self) -> &mut ControlCodec<EncodeDst, DecodeDst> {
+        &mut self.codec
+    }
+
+    /// Unwraps the underlying stream.
+    ///
+    /// Any data which was already read but not yet decoded is lost.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Read, EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>
+    MessageStream<S, EncodeDst, DecodeDst>
+{
+    /// Blocks until the next packet has been received.
+    ///
+    /// Returns `None` once the stream has ended cleanly, i.e. not in the middle of a packet.
+    pub fn read_packet(&mut self) -> Result<Option<ControlPacket<DecodeDst>>, Error> {
+        loop {
+            if let Some(packet) = self.codec.decode(&mut self.read_buf)? {
+                return Ok(Some(packet));
+            }
+            let mut chunk = [0; READ_CHUNK_SIZE];
+            let len = match self.stream.read(&mut chunk) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            if len == 0 {
+                return self.codec.decode_eof(&mut self.read_buf);
+            }
+            self.read_buf.extend_from_slice(&chunk[..len]);
+        }
+    }
+}
+
+impl<S: Write, EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>
+    MessageStream<S, EncodeDst, DecodeDst>
+{
+    /// Writes a packet to the stream and flushes it.
+    pub fn write_packet(&mut self, packet: ControlPacket<EncodeDst>) -> Result<(), Error> {
+        self.write_buf.clear();
+        self.codec.encode(packet, &mut self.write_buf)?;
+        self.stream.write_all(&self.write_buf)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::control::msgs;
+
+    #[test]
+    fn packets_round_trip_over_stream() {
+        let mut server = ServerMessageStream::new(Cursor::new(Vec::new()));
+        let sync = msgs::ServerSync {
+            session: Some(3),
+            ..Default::default()
+        };
+        server
+            .write_packet(msgs::Version::default().into())
+            .unwrap();
+        server.write_packet(sync.clone().into()).unwrap();
+
+        let data = server.into_inner().into_inner();
+        let mut client = ClientMessageStream::new(Cursor::new(data));
+        assert_eq!(
+            Some(msgs::Version::default().into()),
+            client.read_packet().unwrap()
+        );
+        assert_eq!(Some(sync.into()), client.read_packet().unwrap());
+        assert_eq!(None, client.read_packet().unwrap());
+    }
+
+    #[test]
+    fn truncated_stream_is_an_error() {
+        let mut server = ServerMessageStream::new(Cursor::new(Vec::new()));
+        server
+            .write_packet(msgs::Version::default().into())
+            .unwrap();
+        let mut data = server.into_inner().into_inner();
+        data.extend_from_slice(&[0, 7, 0, 0]);
+
+        let mut client = ClientMessageStream::new(Cursor::new(data));
+        assert!(client.read_packet().unwrap().is_some());
+        assert!(matches!(
+            client.read_packet(),
+            Err(Error::Truncated { id: None, .. })
+        ));
+    }
+}
@@ -0,0 +1,305 @@
+//! Builder and recipient view for the `TextMessage` message
+
+use super::limits::MessageLimits;
+use super::msgs;
+use super::validate::ensure_valid;
+use super::validate::ValidationIssue;
+use crate::error::Error;
+use crate::state::ChannelTree;
+use crate::text::escape_html;
+use crate::text::split_message;
+use crate::text::EmbeddedImage;
+use crate::text::MIN_SPLIT_LIMIT;
+
+/// Builder for [msgs::TextMessage] messages.
+///
+/// Created via [msgs::TextMessage::to_user], [to_channel](msgs::TextMessage::to_channel) or
+/// [to_tree](msgs::TextMessage::to_tree). Further recipients can be added with the methods of
+/// the same name.
+#[derive(Clone, Debug, Default)]
+pub struct TextMessageBuilder {
+    msg: msgs::TextMessage,
+}
+
+impl TextMessageBuilder {
+    /// Starts a message without recipients or content.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the user with the given session as recipient.
+    pub fn to_user(mut self, session: u32) -> Self {
+        self.msg.session.push(session);
+        self
+    }
+
+    /// Adds the channel as recipient.
+    pub fn to_channel(mut self, channel_id: u32) -> Self {
+        self.msg.channel_id.push(channel_id);
+        self
+    }
+
+    /// Adds the channel and all of its sub-channels as recipients.
+    pub fn to_tree(mut self, channel_id: u32) -> Self {
+        self.msg.tree_id.push(channel_id);
+        self
+    }
+
+    /// Sets the sender, as done by servers when forwarding a message.
+    pub fn actor(mut self, session: u32) -> Self {
+        self.msg.actor = Some(session);
+        self
+    }
+
+    /// Sets the content to plain text, escaping it so it is not interpreted as HTML.
+    ///
+    /// Line breaks are converted to `<br>`.
+    pub fn text(self, text: &str) -> Self {
+        self.html(escape_html(text).replace('\n', "<br>"))
+    }
+
+    /// Sets the content to the given HTML.
+    pub fn html(mut self, html: impl Into<String>) -> Self {
+        let html = html.into();
+        #[cfg(feature = "protobuf")]
+        {
+            self.msg.message = Some(html);
+        }
+        #[cfg(feature = "prost")]
+        {
+            self.msg.message = html;
+        }
+        self
+    }
+
+    /// Appends plain text to the content, escaped as by [text](Self::text).
+    pub fn push_text(self, text: &str) -> Self {
+        self.push_html(&escape_html(text).replace('\n', "<br>"))
+    }
+
+    /// Appends HTML to the content.
+    pub fn push_html(mut self, html: &str) -> Self {
+        #[cfg(feature = "protobuf")]
+        self.msg
+            .message
+            .get_or_insert_with(String::new)
+            .push_str(html);
+        #[cfg(feature = "prost")]
+        self.msg.message.push_str(html);
+        self
+    }
+
+    /// Appends an embedded image to the content.
+    ///
+    /// Images are usually subject to a separate size limit, see [check_limits](Self::check_limits).
+    pub fn push_image(self, image: &EmbeddedImage) -> Self {
+        self.push_html(&image.to_html())
+    }
+
+    /// Checks the content against the limits advertised by the server.
+    pub fn check_limits(&self, limits: &MessageLimits) -> Result<(), Error> {
+        #[cfg(feature = "protobuf")]
+        return limits.check_message(self.msg.message());
+        #[cfg(feature = "prost")]
+        return limits.check_message(&self.msg.message);
+    }
+
+    /// Builds the message.
+    ///
+    /// Fails if there are no recipients, unless an [actor](Self::actor) is set: servers forward
+    /// messages to each recipient individually and may omit the targets.
+    pub fn build(self) -> Result<msgs::TextMessage, ValidationIssue> {
+        if self.msg.actor.is_some() {
+            return Ok(self.msg);
+        }
+        ensure_valid(self.msg)
+    }
+
+    /// Builds as many messages as needed to stay within the limits advertised by the server.
+    ///
+    /// The content is split with [split_message], each message is sent to all recipients.
+    /// Messages containing images are split at the image limit, others at the regular one.
+    pub fn build_split(
+        self,
+        limits: &MessageLimits,
+    ) -> Result<Vec<msgs::TextMessage>, ValidationIssue> {
+        let msg = self.build()?;
+        #[cfg(feature = "protobuf")]
+        let content = msg.message();
+        #[cfg(feature = "prost")]
+        let content = &msg.message;
+        if limits.check_message(content).is_ok() {
+            return Ok(vec![msg]);
+        }
+
+        let mut chunks = Vec::new();
+        let limit = |limit: usize| limit.max(MIN_SPLIT_LIMIT);
+        for chunk in split_message(
+            content,
+            limit(limits.limit_for(content).unwrap_or(usize::MAX)),
+        ) {
+            // A part without images may still exceed the regular limit
+            match limits.limit_for(&chunk) {
+                Some(max) if chunk.len() > max => chunks.extend(split_message(&chunk, limit(max))),
+                _ => chunks.push(chunk),
+            }
+        }
+        Ok(chunks
+            .into_iter()
+            .map(|chunk| {
+                let mut msg = msg.clone();
+                #[cfg(feature = "protobuf")]
+                {
+                    msg.message = Some(chunk);
+                }
+                #[cfg(feature = "prost")]
+                {
+                    msg.message = chunk;
+                }
+                msg
+            })
+            .collect())
+    }
+}
+
+/// How a received [msgs::TextMessage] reached a user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Addressing {
+    /// The message was sent to the user directly.
+    Direct,
+    /// The message was sent to the channel the user is in.
+    Channel,
+    /// The message was sent to the tree of the channel the user is in, or one above it.
+    Tree,
+}
+
+impl msgs::TextMessage {
+    /// Starts a [TextMessageBuilder] for a message to the user with the given session.
+    pub fn to_user(session: u32) -> TextMessageBuilder {
+        TextMessageBuilder::new().to_user(session)
+    }
+
+    /// Starts a [TextMessageBuilder] for a message to the channel.
+    pub fn to_channel(channel_id: u32) -> TextMessageBuilder {
+        TextMessageBuilder::new().to_channel(channel_id)
+    }
+
+    /// Starts a [TextMessageBuilder] for a message to the channel and its sub-channels.
+    pub fn to_tree(channel_id: u32) -> TextMessageBuilder {
+        TextMessageBuilder::new().to_tree(channel_id)
+    }
+
+    /// Returns how the message reached the user with the given session in the given channel.
+    ///
+    /// If the user was targeted in several ways, the most specific one is returned. Returns
+    /// `None` if none of the targets apply, e.g. because the server omitted them.
+    pub fn addressing(
+        &self,
+        session: u32,
+        channel_id: u32,
+        channels: &ChannelTree,
+    ) -> Option<Addressing> {
+        if self.session.contains(&session) {
+            return Some(Addressing::Direct);
+        }
+        if self.channel_id.contains(&channel_id) {
+            return Some(Addressing::Channel);
+        }
+        let tree =
+            std::iter::once(channel_id).chain(channels.ancestors(channel_id).map(|it| it.id));
+        for id in tree {
+            if self.tree_id.contains(&id) {
+                return Some(Addressing::Tree);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn content(msg: &msgs::TextMessage) -> &str {
+        #[cfg(feature = "protobuf")]
+        return msg.message();
+        #[cfg(feature = "prost")]
+        return &msg.message;
+    }
+
+    #[test]
+    fn builds_text_messages() {
+        let msg = msgs::TextMessage::to_user(3)
+            .to_channel(1)
+            .to_tree(0)
+            .text("a < b\nc")
+            .build()
+            .unwrap();
+        assert_eq!(vec![3], msg.session);
+        assert_eq!(vec![1], msg.channel_id);
+        assert_eq!(vec![0], msg.tree_id);
+        assert_eq!("a &lt; b<br>c", content(&msg));
+    }
+
+    #[test]
+    fn embeds_images_between_text() {
+        let builder = msgs::TextMessage::to_channel(0)
+            .push_text("look:")
+            .push_image(&EmbeddedImage::new("image/png", &b"\x89PNG"[..]))
+            .push_text("!");
+        let limits = MessageLimits {
+            message_length: Some(10),
+            image_message_length: Some(100),
+        };
+        assert!(builder.check_limits(&limits).is_ok());
+        assert!(builder
+            .check_limits(&MessageLimits {
+                image_message_length: Some(10),
+                ..limits
+            })
+            .is_err());
+        let msg = builder.build().unwrap();
+        assert_eq!(
+            "look:<img src=\"data:image/png;base64,iVBORw==\">!",
+            content(&msg)
+        );
+    }
+
+    #[test]
+    fn splits_long_messages() {
+        let limits = MessageLimits {
+            message_length: Some(20),
+            image_message_length: None,
+        };
+        let msgs = msgs::TextMessage::to_channel(4)
+            .text("a few words which do not fit into one message")
+            .build_split(&limits)
+            .unwrap();
+        assert_eq!(3, msgs.len());
+        for msg in msgs {
+            assert_eq!(vec![4], msg.channel_id);
+            assert!(limits.check_message(content(&msg)).is_ok());
+        }
+    }
+
+    #[test]
+    fn requires_recipients_unless_forwarded() {
+        assert!(TextMessageBuilder::new().html("<b>hi</b>").build().is_err());
+        assert!(TextMessageBuilder::new()
+            .actor(2)
+            .html("hi")
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn resolves_addressing() {
+        let channels = ChannelTree::new();
+        let msg = msgs::TextMessage::to_tree(7).to_channel(8).build().unwrap();
+        assert_eq!(Some(Addressing::Tree), msg.addressing(1, 7, &channels));
+        assert_eq!(Some(Addressing::Channel), msg.addressing(1, 8, &channels));
+        assert_eq!(None, msg.addressing(1, 9, &channels));
+        let msg = msgs::TextMessage::to_user(1).to_channel(8).build().unwrap();
+        assert_eq!(Some(Addressing::Direct), msg.addressing(1, 8, &channels));
+    }
+}
@@ -0,0 +1,216 @@
+//! Updating access tokens after connecting
+//!
+//! Clients may send another `Authenticate` message at any time after connecting. Murmur treats
+//! it as replacing the access tokens of the connection and ignores all other fields, so the
+//! message must always contain the complete set: an empty list removes all tokens.
+
+use std::collections::BTreeSet;
+
+use super::msgs;
+use super::permission_denied::DenyReason;
+
+/// A set of access tokens.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TokenSet(BTreeSet<String>);
+
+impl TokenSet {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a token, returning whether it was new.
+    pub fn insert(&mut self, token: impl Into<String>) -> bool {
+        self.0.insert(token.into())
+    }
+
+    /// Removes a token, returning whether it was present.
+    pub fn remove(&mut self, token: &str) -> bool {
+        self.0.remove(token)
+    }
+
+    /// Returns whether the token is in the set.
+    pub fn contains(&self, token: &str) -> bool {
+        self.0.contains(token)
+    }
+
+    /// Returns the tokens in lexicographic order.
+    pub fn iter(&self) -> impl Iterator<Item = &str> + '_ {
+        self.0.iter().map(String::as_str)
+    }
+
+    /// Returns the amount of tokens.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns whether there are no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for TokenSet {
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+        TokenSet(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+/// What an `Authenticate` message received by a server is meant to do, see
+/// [msgs::Authenticate::intent].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthenticateIntent {
+    /// The client logs in.
+    Login,
+    /// The client replaces its access tokens, permissions need to be re-evaluated.
+    TokenUpdate(TokenSet),
+}
+
+impl msgs::Authenticate {
+    /// Creates the message replacing the access tokens after connecting, as sent by clients.
+    ///
+    /// Contains nothing but the tokens. An empty set removes all tokens.
+    pub fn update_tokens(tokens: &TokenSet) -> Self {
+        msgs::Authenticate {
+            tokens: tokens.iter().map(str::to_owned).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the access tokens of the message.
+    pub fn token_set(&self) -> TokenSet {
+        self.tokens.iter().cloned().collect()
+    }
+
+    /// Returns what the message is meant to do, given whether the connection it was received on
+    /// is already authenticated, as done by servers.
+    ///
+    /// Like Murmur, every message on an authenticated connection is a token update regardless
+    /// of its other fields.
+    pub fn intent(&self, authenticated: bool) -> AuthenticateIntent {
+        if authenticated {
+            AuthenticateIntent::TokenUpdate(self.token_set())
+        } else {
+            AuthenticateIntent::Login
+        }
+    }
+}
+
+/// Keeps track of the access tokens of a client and of the ones the server knows about.
+#[derive(Clone, Debug, Default)]
+pub struct TokenTracker {
+    tokens: TokenSet,
+    sent: TokenSet,
+}
+
+impl TokenTracker {
+    /// Creates a tracker for the tokens sent when connecting.
+    pub fn new(tokens: TokenSet) -> Self {
+        TokenTracker {
+            tokens: tokens.clone(),
+            sent: tokens,
+        }
+    }
+
+    /// Adds a token, returning whether it was new.
+    pub fn add(&mut self, token: impl Into<String>) -> bool {
+        self.tokens.insert(token)
+    }
+
+    /// Removes a token, returning whether it was present.
+    pub fn remove(&mut self, token: &str) -> bool {
+        self.tokens.remove(token)
+    }
+
+    /// Returns the current tokens.
+    pub fn tokens(&self) -> &TokenSet {
+        &self.tokens
+    }
+
+    /// Returns whether the current tokens differ from the ones last sent to the server.
+    pub fn is_dirty(&self) -> bool {
+        self.tokens != self.sent
+    }
+
+    /// Returns the message updating the tokens on the server if they changed since they were
+    /// last sent, and considers them sent.
+    pub fn update(&mut self) -> Option<msgs::Authenticate> {
+        if !self.is_dirty() {
+            return None;
+        }
+        self.sent = self.tokens.clone();
+        Some(msgs::Authenticate::update_tokens(&self.tokens))
+    }
+
+    /// Returns whether sending the current tokens might resolve the denial, i.e. whether it is
+    /// due to missing permissions and the server does not know all tokens yet.
+    pub fn may_resolve(&self, msg: &msgs::PermissionDenied) -> bool {
+        matches!(
+            DenyReason::from(msg),
+            DenyReason::InsufficientPermission { .. }
+        ) && self.is_dirty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::permissions::Permissions;
+    use crate::control::validate::Validate;
+
+    #[test]
+    fn builds_minimal_updates() {
+        let tokens: TokenSet = ["b", "a", "b"].into_iter().collect();
+        assert_eq!(2, tokens.len());
+        let msg = msgs::Authenticate::update_tokens(&tokens);
+        assert_eq!(vec!["a", "b"], msg.tokens);
+        assert_eq!(None, msg.username);
+        assert_eq!(None, msg.opus);
+        assert!(msg.validate().is_empty());
+        assert_eq!(tokens, msg.token_set());
+    }
+
+    #[test]
+    fn classifies_authenticate_messages() {
+        let msg = msgs::Authenticate::builder()
+            .username("alice")
+            .token("a")
+            .build()
+            .unwrap();
+        assert_eq!(AuthenticateIntent::Login, msg.intent(false));
+        assert_eq!(
+            AuthenticateIntent::TokenUpdate(["a"].into_iter().collect()),
+            msg.intent(true)
+        );
+        let msg = msgs::Authenticate::update_tokens(&TokenSet::new());
+        assert_eq!(
+            AuthenticateIntent::TokenUpdate(TokenSet::new()),
+            msg.intent(true)
+        );
+    }
+
+    #[test]
+    fn tracks_tokens() {
+        let denied = DenyReason::InsufficientPermission {
+            channel_id: 1,
+            permission: Permissions::ENTER,
+            session: 2,
+        }
+        .to_message();
+        let mut tracker = TokenTracker::new(["a"].into_iter().collect());
+        assert_eq!(None, tracker.update());
+        assert!(!tracker.may_resolve(&denied));
+
+        assert!(tracker.add("b"));
+        assert!(tracker.may_resolve(&denied));
+        assert!(!tracker.may_resolve(&DenyReason::ChannelFull.to_message()));
+        assert_eq!(vec!["a", "b"], tracker.update().unwrap().tokens);
+        assert!(!tracker.may_resolve(&denied));
+
+        // Removing every token still needs an update, which clears them on the server
+        tracker.remove("a");
+        tracker.remove("b");
+        assert_eq!(Some(Vec::new()), tracker.update().map(|it| it.tokens));
+        assert!(tracker.tokens().is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+//! Administration of registered users via `UserList`
+//!
+//! `UserList` is sent empty to request the list of registered users, filled by the server to
+//! answer, and sent with selected users by clients to rename (entries with a new name) or
+//! deregister users (entries without name).
+
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use super::backend::message;
+use super::ban::format_time;
+use super::ban::parse_time;
+use super::msgs;
+
+/// A registered user, as listed in [msgs::UserList].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisteredUser {
+    /// The user's registration ID.
+    pub id: u32,
+    /// The user's name.
+    pub name: String,
+    /// When the user was last connected, if known.
+    pub last_seen: Option<SystemTime>,
+    /// The channel the user was last in, if known.
+    pub last_channel: Option<u32>,
+}
+
+impl From<&msgs::user_list::User> for RegisteredUser {
+    /// Converts a listed user, ignoring a `last_seen` which is not in ISO 8601 format.
+    fn from(msg: &msgs::user_list::User) -> Self {
+        #[cfg(feature = "protobuf")]
+        let id = msg.user_id();
+        #[cfg(feature = "prost")]
+        let id = msg.user_id;
+        RegisteredUser {
+            id,
+            name: msg.name.clone().unwrap_or_default(),
+            last_seen: msg.last_seen.as_deref().and_then(parse_time),
+            last_channel: msg.last_channel,
+        }
+    }
+}
+
+impl From<&RegisteredUser> for msgs::user_list::User {
+    fn from(user: &RegisteredUser) -> Self {
+        let last_seen = user.last_seen.map(|it| {
+            let secs = it.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+            format_time(secs.as_secs())
+        });
+        user_entry(
+            user.id,
+            Some(user.name.clone()),
+            last_seen,
+            user.last_channel,
+        )
+    }
+}
+
+fn user_entry(
+    id: u32,
+    name: Option<String>,
+    last_seen: Option<String>,
+    last_channel: Option<u32>,
+) -> msgs::user_list::User {
+    message!(msgs::user_list::User {
+        #[cfg(feature = "protobuf")]
+        user_id: Some(id),
+        #[cfg(feature = "prost")]
+        user_id: id,
+        name,
+        last_seen,
+        last_channel,
+    })
+}
+
+impl msgs::UserList {
+    /// Creates a request for the list of registered users.
+    pub fn query() -> Self {
+        Self::default()
+    }
+
+    /// Creates a request renaming a registered user.
+    pub fn rename(id: u32, name: impl Into<String>) -> Self {
+        let mut msg = Self::default();
+        msg.users
+            .push(user_entry(id, Some(name.into()), None, None));
+        msg
+    }
+
+    /// Creates a request deregistering a user.
+    pub fn deregister(id: u32) -> Self {
+        let mut msg = Self::default();
+        msg.users.push(user_entry(id, None, None, None));
+        msg
+    }
+
+    /// Returns the listed users.
+    pub fn registered_users(&self) -> Vec<RegisteredUser> {
+        self.users.iter().map(RegisteredUser::from).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_listed_users() {
+        let mut list = msgs::UserList::rename(3, "alice");
+        list.users[0].last_seen = Some("2023-11-14T22:13:20".to_owned());
+        list.users[0].last_channel = Some(7);
+        list.users
+            .push(user_entry(4, Some("bob".to_owned()), None, None));
+
+        let users = list.registered_users();
+        assert_eq!(
+            RegisteredUser {
+                id: 3,
+                name: "alice".to_owned(),
+                last_seen: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+                last_channel: Some(7),
+            },
+            users[0]
+        );
+        assert_eq!(None, users[1].last_seen);
+        assert_eq!(None, users[1].last_channel);
+        assert_eq!(list.users[0], msgs::user_list::User::from(&users[0]));
+    }
+
+    #[test]
+    fn builds_edits() {
+        assert!(msgs::UserList::query().users.is_empty());
+        let msg = msgs::UserList::deregister(3);
+        assert_eq!(1, msg.users.len());
+        assert_eq!(None, msg.users[0].name);
+        assert_eq!(
+            Some("carol"),
+            msgs::UserList::rename(3, "carol").users[0].name.as_deref()
+        );
+    }
+}
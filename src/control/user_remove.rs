@@ -0,0 +1,138 @@
+//! Kicking and banning users via `UserRemove`
+
+use super::msgs;
+
+/// Why a user was removed from the server, as described by a [msgs::UserRemove].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum UserRemoveAction {
+    /// The user disconnected on their own.
+    Disconnected,
+    /// The user was kicked.
+    Kicked {
+        /// Session of the user who kicked them, `None` if the server did.
+        actor: Option<u32>,
+        /// The reason given for the kick.
+        reason: Option<String>,
+    },
+    /// The user was banned.
+    Banned {
+        /// Session of the user who banned them, `None` if the server did.
+        actor: Option<u32>,
+        /// The reason given for the ban.
+        reason: Option<String>,
+    },
+}
+
+impl UserRemoveAction {
+    /// Returns the session of the user who kicked or banned the removed user.
+    pub fn actor(&self) -> Option<u32> {
+        match self {
+            UserRemoveAction::Disconnected => None,
+            UserRemoveAction::Kicked { actor, .. } | UserRemoveAction::Banned { actor, .. } => {
+                *actor
+            }
+        }
+    }
+
+    /// Returns the reason given for the kick or ban.
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            UserRemoveAction::Disconnected => None,
+            UserRemoveAction::Kicked { reason, .. } | UserRemoveAction::Banned { reason, .. } => {
+                reason.as_deref()
+            }
+        }
+    }
+}
+
+impl From<&msgs::UserRemove> for UserRemoveAction {
+    /// Interprets the message as sent by the server.
+    ///
+    /// The actor of a user who leaves on their own is either absent or the user themselves, so
+    /// a message without ban flag, reason or foreign actor is a plain disconnect. A kick or ban
+    /// by the server itself (e.g. through Ice) carries no actor.
+    fn from(msg: &msgs::UserRemove) -> Self {
+        #[cfg(feature = "protobuf")]
+        let session = msg.session();
+        #[cfg(feature = "prost")]
+        let session = msg.session;
+        let actor = msg.actor.filter(|it| *it != session);
+        let reason = msg.reason.clone();
+        if msg.ban == Some(true) {
+            UserRemoveAction::Banned { actor, reason }
+        } else if actor.is_some() || reason.is_some() {
+            UserRemoveAction::Kicked { actor, reason }
+        } else {
+            UserRemoveAction::Disconnected
+        }
+    }
+}
+
+impl msgs::UserRemove {
+    /// Creates a request to kick the user with the given session.
+    pub fn kick(session: u32, reason: impl Into<String>) -> Self {
+        Self::removal(session, reason.into(), false)
+    }
+
+    /// Creates a request to kick and ban the user with the given session.
+    ///
+    /// Named so because `ban()` is the generated accessor of the `ban` field.
+    pub fn kick_and_ban(session: u32, reason: impl Into<String>) -> Self {
+        Self::removal(session, reason.into(), true)
+    }
+
+    fn removal(session: u32, reason: String, ban: bool) -> Self {
+        msgs::UserRemove {
+            #[cfg(feature = "protobuf")]
+            session: Some(session),
+            #[cfg(feature = "prost")]
+            session,
+            reason: Some(reason),
+            ban: Some(ban),
+            ..Default::default()
+        }
+    }
+
+    /// Returns why the user was removed.
+    ///
+    /// See [UserRemoveAction].
+    pub fn action(&self) -> UserRemoveAction {
+        self.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kick_and_ban_differ_in_ban_flag() {
+        let kick = msgs::UserRemove::kick(3, "spam");
+        assert_eq!(Some(false), kick.ban);
+        assert_eq!(Some("spam"), kick.reason.as_deref());
+        assert_eq!(Some(true), msgs::UserRemove::kick_and_ban(3, "spam").ban);
+    }
+
+    #[test]
+    fn derives_action() {
+        let mut msg = msgs::UserRemove::kick(3, "spam");
+        assert_eq!(
+            UserRemoveAction::Kicked {
+                actor: None,
+                reason: Some("spam".to_owned())
+            },
+            msg.action()
+        );
+        msg.actor = Some(1);
+        msg.ban = Some(true);
+        assert_eq!(Some(1), msg.action().actor());
+        assert!(matches!(msg.action(), UserRemoveAction::Banned { .. }));
+
+        let mut msg = msgs::UserRemove::kick(3, "");
+        msg.reason = None;
+        msg.ban = None;
+        msg.actor = Some(3);
+        assert_eq!(UserRemoveAction::Disconnected, msg.action());
+    }
+}
@@ -0,0 +1,278 @@
+//! Builder and delta utilities for the `UserState` message
+//!
+//! `UserState` serves both as a full snapshot of a user (during the initial sync) and as a
+//! sparse update afterwards, where absent fields mean "unchanged". [merge] and [diff] convert
+//! between the two.
+
+use super::backend::message;
+use super::msgs;
+
+/// Calls the given macro for every field describing the state of a user.
+///
+/// Excludes `session` (which identifies the user), `actor` (which describes the update) and the
+/// repeated fields, which are commands rather than state.
+macro_rules! for_each_state_field {
+    ($f:ident) => {
+        $f!(name);
+        $f!(user_id);
+        $f!(channel_id);
+        $f!(mute);
+        $f!(deaf);
+        $f!(suppress);
+        $f!(self_mute);
+        $f!(self_deaf);
+        $f!(texture);
+        $f!(plugin_context);
+        $f!(plugin_identity);
+        $f!(comment);
+        $f!(hash);
+        $f!(comment_hash);
+        $f!(texture_hash);
+        $f!(priority_speaker);
+        $f!(recording);
+        #[cfg(feature = "webrtc-extensions")]
+        $f!(ssrc);
+    };
+}
+
+/// Applies an update onto a stored full state.
+///
+/// Fields absent from `delta` are left unchanged. `session`, `actor` and the repeated fields
+/// (e.g. `listening_channel_add`) are ignored.
+pub fn merge(full: &mut msgs::UserState, delta: &msgs::UserState) {
+    macro_rules! merge_field {
+        ($field:ident) => {
+            if delta.$field.is_some() {
+                full.$field.clone_from(&delta.$field);
+            }
+        };
+    }
+    for_each_state_field!(merge_field);
+}
+
+/// Returns the minimal update turning `old` into `new`.
+///
+/// Contains `session` plus every field which is set in `new` and differs from `old`. Since
+/// absent fields mean "unchanged", fields set in `old` but absent from `new` are not included.
+pub fn diff(old: &msgs::UserState, new: &msgs::UserState) -> msgs::UserState {
+    let mut delta = message!(msgs::UserState {
+        session: new.session.or(old.session),
+    });
+    macro_rules! diff_field {
+        ($field:ident) => {
+            if new.$field.is_some() && new.$field != old.$field {
+                delta.$field.clone_from(&new.$field);
+            }
+        };
+    }
+    for_each_state_field!(diff_field);
+    delta
+}
+
+/// Builder for [msgs::UserState] updates.
+#[derive(Clone, Debug, Default)]
+pub struct UserStateBuilder {
+    msg: msgs::UserState,
+}
+
+impl UserStateBuilder {
+    /// Starts an update of the given user.
+    pub fn new(session: u32) -> Self {
+        let mut builder = Self::default();
+        builder.msg.session = Some(session);
+        builder
+    }
+
+    /// Starts an update of the own user, for which the server fills in the session.
+    pub fn for_self() -> Self {
+        Self::default()
+    }
+
+    /// Sets the session of the user who performs the update, e.g. a moderator muting someone.
+    pub fn actor(mut self, actor: u32) -> Self {
+        self.msg.actor = Some(actor);
+        self
+    }
+
+    /// Moves the user to the given channel.
+    pub fn move_to(mut self, channel_id: u32) -> Self {
+        self.msg.channel_id = Some(channel_id);
+        self
+    }
+
+    /// Sets whether the user muted themselves.
+    pub fn self_mute(mut self, self_mute: bool) -> Self {
+        self.msg.self_mute = Some(self_mute);
+        if !self_mute {
+            self.msg.self_deaf = Some(false);
+        }
+        self
+    }
+
+    /// Sets whether the user deafened themselves. Deafening also mutes, like in Mumble.
+    pub fn self_deaf(mut self, self_deaf: bool) -> Self {
+        self.msg.self_deaf = Some(self_deaf);
+        if self_deaf {
+            self.msg.self_mute = Some(true);
+        }
+        self
+    }
+
+    /// Sets whether the user is muted by a moderator.
+    pub fn mute(mut self, mute: bool) -> Self {
+        self.msg.mute = Some(mute);
+        if !mute {
+            self.msg.deaf = Some(false);
+        }
+        self
+    }
+
+    /// Sets whether the user is deafened by a moderator. Deafening also mutes, like in Mumble.
+    pub fn deaf(mut self, deaf: bool) -> Self {
+        self.msg.deaf = Some(deaf);
+        if deaf {
+            self.msg.mute = Some(true);
+        }
+        self
+    }
+
+    /// Sets whether the user is suppressed, i.e. not allowed to speak in their channel.
+    pub fn suppress(mut self, suppress: bool) -> Self {
+        self.msg.suppress = Some(suppress);
+        self
+    }
+
+    /// Sets the comment of the user.
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.msg.comment = Some(comment.into());
+        self
+    }
+
+    /// Sets the avatar of the user. An empty texture removes it.
+    pub fn texture(mut self, texture: impl Into<Vec<u8>>) -> Self {
+        self.msg.texture = Some(texture.into());
+        self
+    }
+
+    /// Starts listening to the given channel without joining it.
+    ///
+    /// Not available with the `webrtc-extensions` feature, its protocol predates listeners.
+    #[cfg(not(feature = "webrtc-extensions"))]
+    pub fn listen_to(mut self, channel_id: u32) -> Self {
+        self.msg
+            .listening_channel_remove
+            .retain(|it| *it != channel_id);
+        if !self.msg.listening_channel_add.contains(&channel_id) {
+            self.msg.listening_channel_add.push(channel_id);
+        }
+        self
+    }
+
+    /// Stops listening to the given channel.
+    ///
+    /// Not available with the `webrtc-extensions` feature, its protocol predates listeners.
+    #[cfg(not(feature = "webrtc-extensions"))]
+    pub fn stop_listening(mut self, channel_id: u32) -> Self {
+        self.msg
+            .listening_channel_add
+            .retain(|it| *it != channel_id);
+        if !self.msg.listening_channel_remove.contains(&channel_id) {
+            self.msg.listening_channel_remove.push(channel_id);
+        }
+        self
+    }
+
+    /// Builds the message.
+    pub fn build(self) -> msgs::UserState {
+        self.msg
+    }
+}
+
+impl msgs::UserState {
+    /// Creates a [UserStateBuilder] for an update of the given user.
+    pub fn builder(session: u32) -> UserStateBuilder {
+        UserStateBuilder::new(session)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn full() -> msgs::UserState {
+        msgs::UserState {
+            session: Some(3),
+            name: Some("alice".to_owned()),
+            channel_id: Some(0),
+            self_mute: Some(false),
+            comment: Some("hi".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn diff_and_merge_round_trip() {
+        let old = full();
+        let mut new = full();
+        new.self_mute = Some(true);
+        new.comment = Some(String::new());
+        new.channel_id = Some(5);
+
+        let delta = diff(&old, &new);
+        assert_eq!(Some(3), delta.session);
+        assert_eq!(None, delta.name);
+        assert_eq!(Some(true), delta.self_mute);
+        assert_eq!(Some(""), delta.comment.as_deref());
+
+        let mut merged = old.clone();
+        merge(&mut merged, &delta);
+        assert_eq!(new, merged);
+        assert_eq!(msgs::UserState::builder(3).build(), diff(&new, &new));
+    }
+
+    #[test]
+    fn absent_fields_are_unchanged() {
+        let old = full();
+        let new = msgs::UserState {
+            session: Some(3),
+            self_deaf: Some(true),
+            ..Default::default()
+        };
+        let delta = diff(&old, &new);
+        assert_eq!(None, delta.comment);
+
+        let mut merged = old.clone();
+        merge(&mut merged, &delta);
+        assert_eq!(Some("hi"), merged.comment.as_deref());
+        assert_eq!(Some(true), merged.self_deaf);
+    }
+
+    #[test]
+    fn builds_updates() {
+        let msg = msgs::UserState::builder(3).actor(1).deaf(true).build();
+        assert_eq!(Some(1), msg.actor);
+        assert_eq!(Some(true), msg.mute);
+        assert_eq!(Some(true), msg.deaf);
+
+        let msg = UserStateBuilder::for_self()
+            .self_mute(false)
+            .move_to(2)
+            .build();
+        assert_eq!(None, msg.session);
+        assert_eq!(Some(false), msg.self_deaf);
+        assert_eq!(Some(2), msg.channel_id);
+    }
+
+    #[test]
+    #[cfg(not(feature = "webrtc-extensions"))]
+    fn builds_listener_updates() {
+        let msg = UserStateBuilder::for_self()
+            .listen_to(4)
+            .listen_to(5)
+            .stop_listening(4)
+            .listen_to(5)
+            .build();
+        assert_eq!(vec![5], msg.listening_channel_add);
+        assert_eq!(vec![4], msg.listening_channel_remove);
+    }
+}
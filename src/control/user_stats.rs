@@ -0,0 +1,302 @@
+//! Typed view of the `UserStats` message
+
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use super::msgs;
+use crate::version::ProtocolVersion;
+
+/// Packet statistics of the UDP connection in one direction.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PacketStats {
+    /// The amount of packets received intact.
+    pub good: u32,
+    /// The amount of packets received out of order.
+    pub late: u32,
+    /// The amount of packets never received.
+    pub lost: u32,
+    /// The amount of nonce resyncs.
+    pub resync: u32,
+}
+
+impl From<&msgs::user_stats::Stats> for PacketStats {
+    fn from(msg: &msgs::user_stats::Stats) -> Self {
+        PacketStats {
+            good: msg.good(),
+            late: msg.late(),
+            lost: msg.lost(),
+            resync: msg.resync(),
+        }
+    }
+}
+
+/// Ping statistics of one of the connections.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PingSummary {
+    /// The average round trip time.
+    pub average: Duration,
+    /// The variance of the round trip time, in milliseconds squared.
+    pub variance: f32,
+}
+
+impl PingSummary {
+    fn new(average: Option<f32>, variance: Option<f32>) -> Option<Self> {
+        Some(PingSummary {
+            average: Duration::try_from_secs_f32(average? / 1000.0).unwrap_or_default(),
+            variance: variance.unwrap_or_default(),
+        })
+    }
+}
+
+/// A [msgs::UserStats] with its fields converted to their actual types.
+///
+/// Replies with `stats_only` set lack everything except the packet and ping statistics.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserStatsView {
+    /// The session of the user.
+    pub session: Option<u32>,
+    /// Whether only packet and ping statistics are included.
+    pub stats_only: bool,
+    /// The DER encoded certificate chain of the user, starting with their own certificate.
+    pub certificates: Vec<Bytes>,
+    /// Whether the certificate was issued by a trusted CA.
+    pub strong_certificate: bool,
+    /// Statistics of the packets the server received from the client.
+    pub from_client: Option<PacketStats>,
+    /// Statistics of the packets the client received from the server.
+    pub from_server: Option<PacketStats>,
+    /// The amount of voice packets sent via UDP.
+    pub udp_packets: Option<u32>,
+    /// The amount of voice packets sent via TCP.
+    pub tcp_packets: Option<u32>,
+    /// Ping statistics of the UDP connection.
+    pub udp_ping: Option<PingSummary>,
+    /// Ping statistics of the TCP connection.
+    pub tcp_ping: Option<PingSummary>,
+    /// The version of the user's client.
+    pub version: Option<ProtocolVersion>,
+    /// The CELT bitstream versions supported by the user's client.
+    pub celt_versions: Vec<i32>,
+    /// Whether the user's client supports Opus.
+    pub opus: bool,
+    /// The address the user is connected from.
+    pub address: Option<IpAddr>,
+    /// The bandwidth used by the user, in bits per second.
+    pub bandwidth: Option<u32>,
+    /// How long the user has been connected.
+    pub online: Option<Duration>,
+    /// How long the user has been inactive.
+    pub idle: Option<Duration>,
+}
+
+impl From<&msgs::UserStats> for UserStatsView {
+    fn from(msg: &msgs::UserStats) -> Self {
+        UserStatsView {
+            session: msg.session,
+            stats_only: msg.stats_only(),
+            certificates: msg
+                .certificates
+                .iter()
+                .map(|it| Bytes::copy_from_slice(it))
+                .collect(),
+            strong_certificate: msg.strong_certificate(),
+            from_client: msg.from_client.as_ref().map(PacketStats::from),
+            from_server: msg.from_server.as_ref().map(PacketStats::from),
+            udp_packets: msg.udp_packets,
+            tcp_packets: msg.tcp_packets,
+            udp_ping: PingSummary::new(msg.udp_ping_avg, msg.udp_ping_var),
+            tcp_ping: PingSummary::new(msg.tcp_ping_avg, msg.tcp_ping_var),
+            version: msg
+                .version
+                .as_ref()
+                .and_then(ProtocolVersion::from_version_message),
+            celt_versions: msg.celt_versions.clone(),
+            opus: msg.opus(),
+            address: msg.address.as_deref().and_then(parse_address),
+            bandwidth: msg.bandwidth,
+            online: msg.onlinesecs.map(|it| Duration::from_secs(it.into())),
+            idle: msg.idlesecs.map(|it| Duration::from_secs(it.into())),
+        }
+    }
+}
+
+/// Servers send 16 bytes, with IPv4 addresses mapped into IPv6.
+fn parse_address(bytes: &[u8]) -> Option<IpAddr> {
+    if let Ok(bytes) = <[u8; 4]>::try_from(bytes) {
+        return Some(Ipv4Addr::from(bytes).into());
+    }
+    let addr = Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?);
+    Some(match addr.to_ipv4_mapped() {
+        Some(addr) => addr.into(),
+        None => addr.into(),
+    })
+}
+
+/// Subject and fingerprint of a certificate, see [certificate_info].
+#[cfg(feature = "openssl")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertificateInfo {
+    /// The subject, e.g. `CN=alice, O=Example`.
+    pub subject: String,
+    /// The lowercase hex encoded SHA1 hash of the certificate, as used by Murmur to identify
+    /// users (see [User::hash](crate::state::User::hash)).
+    pub fingerprint: String,
+}
+
+/// Extracts subject and fingerprint from a DER encoded certificate.
+#[cfg(feature = "openssl")]
+pub fn certificate_info(der: &[u8]) -> Result<CertificateInfo, openssl::error::ErrorStack> {
+    let cert = openssl::x509::X509::from_der(der)?;
+    let mut subject = Vec::new();
+    for entry in cert.subject_name().entries() {
+        // Attributes unknown to OpenSSL are shown by their numeric OID
+        let object = entry.object();
+        let name = match object.nid() {
+            openssl::nid::Nid::UNDEF => None,
+            nid => nid.short_name().ok(),
+        };
+        let name = name.map_or_else(|| object.to_string(), str::to_owned);
+        subject.push(format!("{}={}", name, entry.data().to_string()?));
+    }
+    let fingerprint = openssl::sha::sha1(der)
+        .iter()
+        .map(|it| format!("{:02x}", it))
+        .collect();
+    Ok(CertificateInfo {
+        subject: subject.join(", "),
+        fingerprint,
+    })
+}
+
+impl msgs::UserStats {
+    /// Creates a request for the full statistics of the user with the given session.
+    pub fn request(session: u32) -> Self {
+        msgs::UserStats {
+            session: Some(session),
+            ..Default::default()
+        }
+    }
+
+    /// Creates a request for only the packet and ping statistics of the user, e.g. to refresh
+    /// them periodically without receiving the certificates again.
+    pub fn request_stats_only(session: u32) -> Self {
+        msgs::UserStats {
+            session: Some(session),
+            stats_only: Some(true),
+            ..Default::default()
+        }
+    }
+
+    /// Returns a typed view of the message.
+    pub fn view(&self) -> UserStatsView {
+        self.into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_stats() {
+        let stats = msgs::user_stats::Stats {
+            good: Some(100),
+            lost: Some(2),
+            ..Default::default()
+        };
+        let msg = msgs::UserStats {
+            session: Some(4),
+            #[cfg(feature = "protobuf")]
+            from_client: protobuf::MessageField::some(stats),
+            #[cfg(feature = "prost")]
+            from_client: Some(stats),
+            udp_ping_avg: Some(12.5),
+            udp_ping_var: Some(3.0),
+            address: Some(vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 1]),
+            onlinesecs: Some(3600),
+            certificates: vec![vec![1, 2, 3]],
+            ..Default::default()
+        };
+        let view = msg.view();
+        assert_eq!(Some(4), view.session);
+        assert_eq!(
+            Some(PacketStats {
+                good: 100,
+                lost: 2,
+                ..Default::default()
+            }),
+            view.from_client
+        );
+        assert_eq!(None, view.from_server);
+        assert_eq!(
+            Some(Duration::from_micros(12500)),
+            view.udp_ping.map(|it| it.average)
+        );
+        assert_eq!(None, view.tcp_ping);
+        assert_eq!(Some("10.0.0.1".parse().unwrap()), view.address);
+        assert_eq!(Some(Duration::from_secs(3600)), view.online);
+        assert_eq!(vec![Bytes::from_static(&[1, 2, 3])], view.certificates);
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let v6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        assert_eq!(Some(v6.into()), parse_address(&v6.octets()));
+        assert_eq!(
+            Some("192.168.0.1".parse().unwrap()),
+            parse_address(&[192, 168, 0, 1])
+        );
+        assert_eq!(None, parse_address(&[1, 2, 3]));
+    }
+
+    #[test]
+    fn builds_requests() {
+        assert!(!msgs::UserStats::request(3).stats_only());
+        let msg = msgs::UserStats::request_stats_only(3);
+        assert_eq!(Some(3), msg.session);
+        assert!(msg.stats_only());
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn extracts_certificate_info() {
+        use openssl::asn1::Asn1Time;
+        use openssl::ec::EcGroup;
+        use openssl::ec::EcKey;
+        use openssl::hash::MessageDigest;
+        use openssl::nid::Nid;
+        use openssl::pkey::PKey;
+        use openssl::x509::X509Builder;
+        use openssl::x509::X509NameBuilder;
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "alice").unwrap();
+        name.append_entry_by_text("O", "Example").unwrap();
+        name.append_entry_by_text("1.3.6.1.4.1.99999.1", "custom")
+            .unwrap();
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        let now = Asn1Time::days_from_now(0).unwrap();
+        builder.set_not_before(&now).unwrap();
+        builder.set_not_after(&now).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let der = builder.build().to_der().unwrap();
+
+        let info = certificate_info(&der).unwrap();
+        assert_eq!(
+            "CN=alice, O=Example, 1.3.6.1.4.1.99999.1=custom",
+            info.subject
+        );
+        assert_eq!(40, info.fingerprint.len());
+        assert!(certificate_info(&der[1..]).is_err());
+    }
+}
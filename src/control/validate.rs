@@ -0,0 +1,377 @@
+//! Protocol-level validation of parsed messages
+//!
+//! Parsing only checks that a message is well-formed protobuf. The checks in here catch messages
+//! which are well-formed but make no sense according to the protocol, following what Murmur (the
+//! reference server) enforces.
+
+use std::fmt;
+
+use super::msgs;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+use crate::voice::VoicePacketPayload;
+
+/// How severe a [ValidationIssue] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Murmur accepts the message but corrects or ignores the offending part.
+    Warning,
+    /// Murmur rejects or ignores the message as a whole.
+    Error,
+}
+
+/// A protocol violation found by [Validate::validate].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationIssue {
+    /// How severe the issue is.
+    pub severity: Severity,
+    /// Name of the offending field.
+    pub field: &'static str,
+    /// Human-readable description of the issue.
+    pub message: &'static str,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{} in {}: {}", severity, self.field, self.message)
+    }
+}
+
+/// Messages which can be checked for protocol violations.
+pub trait Validate {
+    /// Returns all protocol violations found in this message.
+    fn validate(&self) -> Vec<ValidationIssue>;
+}
+
+/// Returns the message unless validating it finds an error, in which case the first one is
+/// returned. Warnings are ignored.
+pub(crate) fn ensure_valid<M: Validate>(msg: M) -> Result<M, ValidationIssue> {
+    match msg
+        .validate()
+        .into_iter()
+        .find(|issue| issue.severity == Severity::Error)
+    {
+        Some(issue) => Err(issue),
+        None => Ok(msg),
+    }
+}
+
+/// Collects issues for a single message.
+#[derive(Default)]
+struct Issues(Vec<ValidationIssue>);
+
+impl Issues {
+    fn check(&mut self, ok: bool, severity: Severity, field: &'static str, message: &'static str) {
+        if !ok {
+            self.0.push(ValidationIssue {
+                severity,
+                field,
+                message,
+            });
+        }
+    }
+}
+
+fn overlaps(a: &[u32], b: &[u32]) -> bool {
+    a.iter().any(|it| b.contains(it))
+}
+
+impl Validate for msgs::Authenticate {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Issues::default();
+        // Murmur rejects the connection with `InvalidUsername`. Messages carrying only tokens
+        // are sent after connecting to update them and don't need one.
+        let token_refresh = self.username.is_none() && !self.tokens.is_empty();
+        issues.check(
+            token_refresh
+                || self
+                    .username
+                    .as_deref()
+                    .is_some_and(|it| !it.trim().is_empty()),
+            Severity::Error,
+            "username",
+            "username is missing",
+        );
+        issues.0
+    }
+}
+
+impl Validate for msgs::UserState {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Issues::default();
+        // Murmur forces mute on when deafening, so requesting an unmuted deafened user is ignored
+        issues.check(
+            !(self.deaf == Some(true) && self.mute == Some(false)),
+            Severity::Warning,
+            "mute",
+            "deafened users are always muted",
+        );
+        issues.check(
+            !(self.self_deaf == Some(true) && self.self_mute == Some(false)),
+            Severity::Warning,
+            "self_mute",
+            "self-deafened users are always self-muted",
+        );
+        // Murmur applies additions before removals, so these channels are not listened to.
+        // The WebRTC fork of the protocol predates channel listeners.
+        #[cfg(not(feature = "webrtc-extensions"))]
+        issues.check(
+            !overlaps(&self.listening_channel_add, &self.listening_channel_remove),
+            Severity::Warning,
+            "listening_channel_add",
+            "channel is both added to and removed from the listened channels",
+        );
+        issues.0
+    }
+}
+
+impl Validate for msgs::ChannelState {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Issues::default();
+        match self.channel_id {
+            Some(channel_id) => {
+                // Murmur refuses to move a channel into itself
+                issues.check(
+                    self.parent != Some(channel_id),
+                    Severity::Error,
+                    "parent",
+                    "channel is its own parent",
+                );
+                // Murmur ignores links of a channel to itself
+                issues.check(
+                    !self.links.contains(&channel_id) && !self.links_add.contains(&channel_id),
+                    Severity::Warning,
+                    "links",
+                    "channel is linked to itself",
+                );
+            }
+            None => {
+                // Murmur ignores requests to create a channel without parent or name
+                issues.check(
+                    self.parent.is_some(),
+                    Severity::Error,
+                    "parent",
+                    "new channel has no parent",
+                );
+                issues.check(
+                    self.name.as_deref().is_some_and(|it| !it.is_empty()),
+                    Severity::Error,
+                    "name",
+                    "new channel has no name",
+                );
+            }
+        }
+        // Murmur only looks at the delta fields when receiving `ChannelState`, while clients
+        // only look at `links`
+        issues.check(
+            self.links.is_empty() || (self.links_add.is_empty() && self.links_remove.is_empty()),
+            Severity::Error,
+            "links",
+            "must not be combined with links_add or links_remove",
+        );
+        issues.check(
+            !overlaps(&self.links_add, &self.links_remove),
+            Severity::Warning,
+            "links_add",
+            "channel is both linked and unlinked",
+        );
+        issues.0
+    }
+}
+
+impl Validate for msgs::TextMessage {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Issues::default();
+        // Murmur only delivers messages to the listed targets, so nobody would receive it
+        issues.check(
+            !(self.session.is_empty() && self.channel_id.is_empty() && self.tree_id.is_empty()),
+            Severity::Error,
+            "session",
+            "message has no recipients",
+        );
+        issues.0
+    }
+}
+
+impl Validate for msgs::VoiceTarget {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Issues::default();
+        // 0 is regular talking and 31 the server loopback, Murmur ignores attempts to redefine
+        // either of them
+        issues.check(
+            matches!(self.id, Some(1..=30)),
+            Severity::Error,
+            "id",
+            "voice target id must be between 1 and 30",
+        );
+        issues.check(
+            self.targets
+                .iter()
+                .all(|it| !it.session.is_empty() || it.channel_id.is_some()),
+            Severity::Warning,
+            "targets",
+            "target includes neither users nor a channel",
+        );
+        issues.0
+    }
+}
+
+impl Validate for msgs::CryptSetup {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Issues::default();
+        // Murmur treats a client nonce of the wrong size as a request for a resync
+        for (field, value) in [
+            ("key", &self.key),
+            ("client_nonce", &self.client_nonce),
+            ("server_nonce", &self.server_nonce),
+        ] {
+            issues.check(
+                value.as_ref().is_none_or(|it| it.len() == 16),
+                Severity::Warning,
+                field,
+                "must be exactly 16 bytes long",
+            );
+        }
+        issues.0
+    }
+}
+
+#[cfg(feature = "webrtc-extensions")]
+impl Validate for msgs::WebRTC {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Issues::default();
+        for (field, value) in [
+            ("ice_ufrag", &self.ice_ufrag),
+            ("ice_pwd", &self.ice_pwd),
+            ("dtls_fingerprint", &self.dtls_fingerprint),
+        ] {
+            issues.check(
+                value.as_ref().is_some_and(|it| !it.is_empty()),
+                Severity::Error,
+                field,
+                "WebRTC session parameter is missing",
+            );
+        }
+        issues.0
+    }
+}
+
+impl Validate for msgs::RequestBlob {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Issues::default();
+        issues.check(
+            !(self.session_texture.is_empty()
+                && self.session_comment.is_empty()
+                && self.channel_description.is_empty()),
+            Severity::Warning,
+            "session_texture",
+            "no blobs are requested",
+        );
+        issues.0
+    }
+}
+
+impl<Dst: VoicePacketDst> Validate for VoicePacket<Dst> {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Issues::default();
+        if let VoicePacket::Audio {
+            target, payload, ..
+        } = self
+        {
+            issues.check(
+                *target <= 0x1f,
+                Severity::Error,
+                "target",
+                "target must be between 0 and 31",
+            );
+            match payload {
+                VoicePacketPayload::CeltAlpha(frames)
+                | VoicePacketPayload::CeltBeta(frames)
+                | VoicePacketPayload::Speex(frames) => issues.check(
+                    !frames.is_empty() && frames.iter().all(|frame| frame.len() <= 0x7f),
+                    Severity::Error,
+                    "payload",
+                    "frames must be non-empty and at most 127 bytes long each",
+                ),
+                VoicePacketPayload::Opus(frame, _) => issues.check(
+                    frame.len() <= 0x1fff,
+                    Severity::Error,
+                    "payload",
+                    "opus frame must be at most 8191 bytes long",
+                ),
+            }
+        }
+        issues.0
+    }
+}
+
+/// Implements [Validate] for messages which have no rules beyond being well-formed.
+#[cfg(not(feature = "webrtc-extensions"))]
+impl Validate for msgs::PluginDataTransmission {
+    fn validate(&self) -> Vec<ValidationIssue> {
+        use super::plugin_data::MAX_DATA_ID_LENGTH;
+        use super::plugin_data::MAX_DATA_LENGTH;
+
+        #[cfg(feature = "protobuf")]
+        let data_id = self.dataID();
+        #[cfg(feature = "prost")]
+        let data_id = self.data_id();
+        let mut issues = Issues::default();
+        // Murmur drops the message
+        issues.check(
+            self.data().len() <= MAX_DATA_LENGTH,
+            Severity::Error,
+            "data",
+            "data is too long",
+        );
+        issues.check(
+            data_id.len() <= MAX_DATA_ID_LENGTH,
+            Severity::Error,
+            "dataID",
+            "data ID is too long",
+        );
+        issues.0
+    }
+}
+
+macro_rules! no_rules {
+    ( $( $(#[$attr:meta])* $type:ty ),* $(,)? ) => {
+        $(
+            $(#[$attr])*
+            impl Validate for $type {
+                fn validate(&self) -> Vec<ValidationIssue> {
+                    Vec::new()
+                }
+            }
+        )*
+    };
+}
+
+no_rules![
+    msgs::Version,
+    msgs::Ping,
+    msgs::Reject,
+    msgs::ServerSync,
+    msgs::ChannelRemove,
+    msgs::UserRemove,
+    msgs::BanList,
+    msgs::PermissionDenied,
+    msgs::ACL,
+    msgs::QueryUsers,
+    msgs::ContextActionModify,
+    msgs::ContextAction,
+    msgs::UserList,
+    msgs::PermissionQuery,
+    msgs::CodecVersion,
+    msgs::UserStats,
+    msgs::ServerConfig,
+    msgs::SuggestConfig,
+    #[cfg(feature = "webrtc-extensions")]
+    msgs::IceCandidate,
+    #[cfg(feature = "webrtc-extensions")]
+    msgs::TalkingState,
+];
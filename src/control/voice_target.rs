@@ -0,0 +1,234 @@
+//! Builder and typed view for the `VoiceTarget` message
+//!
+//! A `VoiceTarget` registers whisper/shout target `id` (1 to 30) for the sending user, which
+//! voice packets can then be addressed to. Registering an ID again replaces the previous
+//! targets completely, and registering it without any targets removes it.
+
+use super::backend::message;
+use super::msgs;
+use super::validate::Validate;
+use super::validate::ValidationIssue;
+
+/// Whether voice sent to a channel also reaches the channels linked to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Links {
+    /// Linked channels are included.
+    Yes,
+    /// Only the channel itself is included.
+    #[default]
+    No,
+}
+
+/// Whether voice sent to a channel also reaches its sub-channels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Children {
+    /// All sub-channels are included, recursively.
+    Yes,
+    /// Only the channel itself is included.
+    #[default]
+    No,
+}
+
+impl From<bool> for Links {
+    fn from(value: bool) -> Self {
+        if value {
+            Links::Yes
+        } else {
+            Links::No
+        }
+    }
+}
+
+impl From<bool> for Children {
+    fn from(value: bool) -> Self {
+        if value {
+            Children::Yes
+        } else {
+            Children::No
+        }
+    }
+}
+
+/// One of the receivers of a voice target.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TargetSpec {
+    /// The users with the given sessions.
+    Users(Vec<u32>),
+    /// The users in a channel, optionally only those in an ACL group.
+    Channel {
+        /// The channel.
+        channel_id: u32,
+        /// The group users need to be in, evaluated in the channel.
+        group: Option<String>,
+        /// Whether linked channels are included.
+        links: Links,
+        /// Whether sub-channels are included.
+        children: Children,
+    },
+}
+
+impl TargetSpec {
+    fn to_message(&self) -> msgs::voice_target::Target {
+        let mut target = msgs::voice_target::Target::default();
+        match self {
+            TargetSpec::Users(sessions) => target.session.clone_from(sessions),
+            TargetSpec::Channel {
+                channel_id,
+                group,
+                links,
+                children,
+            } => {
+                target.channel_id = Some(*channel_id);
+                target.group.clone_from(group);
+                target.links = Some(*links == Links::Yes);
+                target.children = Some(*children == Children::Yes);
+            }
+        }
+        target
+    }
+}
+
+/// Builder for [msgs::VoiceTarget] messages.
+#[derive(Clone, Debug)]
+pub struct VoiceTargetBuilder {
+    id: u32,
+    targets: Vec<TargetSpec>,
+}
+
+impl VoiceTargetBuilder {
+    /// Starts a registration of the target with the given ID, without receivers.
+    pub fn new(id: u32) -> Self {
+        VoiceTargetBuilder {
+            id,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Adds users as receivers.
+    pub fn users(mut self, sessions: impl IntoIterator<Item = u32>) -> Self {
+        self.targets
+            .push(TargetSpec::Users(sessions.into_iter().collect()));
+        self
+    }
+
+    /// Adds all users in a channel as receivers.
+    pub fn channel(mut self, channel_id: u32, links: Links, children: Children) -> Self {
+        self.targets.push(TargetSpec::Channel {
+            channel_id,
+            group: None,
+            links,
+            children,
+        });
+        self
+    }
+
+    /// Adds the users in a channel which are members of the group as receivers.
+    pub fn group_in_channel(mut self, channel_id: u32, group: impl Into<String>) -> Self {
+        self.targets.push(TargetSpec::Channel {
+            channel_id,
+            group: Some(group.into()),
+            links: Links::No,
+            children: Children::No,
+        });
+        self
+    }
+
+    /// Adds any kind of receiver.
+    pub fn target(mut self, target: TargetSpec) -> Self {
+        self.targets.push(target);
+        self
+    }
+
+    /// Builds the message.
+    ///
+    /// Fails if the ID is not between 1 and 30, or if a receiver would be ignored by Murmur
+    /// (e.g. an empty list of users).
+    pub fn build(self) -> Result<msgs::VoiceTarget, ValidationIssue> {
+        let msg = message!(msgs::VoiceTarget {
+            id: Some(self.id),
+            targets: self.targets.iter().map(TargetSpec::to_message).collect(),
+        });
+        match msg.validate().into_iter().next() {
+            Some(issue) => Err(issue),
+            None => Ok(msg),
+        }
+    }
+}
+
+impl msgs::VoiceTarget {
+    /// Creates a [VoiceTargetBuilder] for the target with the given ID.
+    pub fn builder(id: u32) -> VoiceTargetBuilder {
+        VoiceTargetBuilder::new(id)
+    }
+
+    /// Returns the receivers of the target.
+    ///
+    /// Entries which address both users and a channel are returned as two receivers, entries
+    /// addressing neither are skipped like Murmur does.
+    pub fn target_specs(&self) -> Vec<TargetSpec> {
+        let mut specs = Vec::new();
+        for target in &self.targets {
+            if !target.session.is_empty() {
+                specs.push(TargetSpec::Users(target.session.clone()));
+            }
+            if let Some(channel_id) = target.channel_id {
+                specs.push(TargetSpec::Channel {
+                    channel_id,
+                    group: target.group.clone().filter(|it| !it.is_empty()),
+                    links: target.links().into(),
+                    children: target.children().into(),
+                });
+            }
+        }
+        specs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn builds_voice_targets() {
+        let msg = msgs::VoiceTarget::builder(3)
+            .users([42, 99])
+            .channel(7, Links::Yes, Children::No)
+            .group_in_channel(7, "admins")
+            .build()
+            .unwrap();
+        assert_eq!(Some(3), msg.id);
+        assert_eq!(
+            vec![
+                TargetSpec::Users(vec![42, 99]),
+                TargetSpec::Channel {
+                    channel_id: 7,
+                    group: None,
+                    links: Links::Yes,
+                    children: Children::No,
+                },
+                TargetSpec::Channel {
+                    channel_id: 7,
+                    group: Some("admins".to_owned()),
+                    links: Links::No,
+                    children: Children::No,
+                },
+            ],
+            msg.target_specs()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_targets() {
+        assert!(msgs::VoiceTarget::builder(0).users([1]).build().is_err());
+        assert!(msgs::VoiceTarget::builder(31).users([1]).build().is_err());
+        assert!(msgs::VoiceTarget::builder(1).users([]).build().is_err());
+    }
+
+    #[test]
+    fn registers_without_targets_to_remove() {
+        // Re-registering replaces all targets, so an empty registration removes the target
+        let msg = msgs::VoiceTarget::builder(5).build().unwrap();
+        assert!(msg.targets.is_empty());
+        assert!(msg.target_specs().is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+//! Typed access to the `WebRTC` message of the WebRTC extension
+//!
+//! No SDP is exchanged over the control channel. Instead, both sides send their ICE credentials
+//! and DTLS fingerprint in a `WebRTC` message and build the SDP of the other side themselves:
+//!
+//! 1. The client sets `webrtc` in its `Authenticate` message.
+//! 2. The server (e.g. a gateway like mumble-web-proxy) answers with its parameters right
+//!    away, before any other message. It acts as WebRTC offerer and DTLS server.
+//! 3. The client replies with its own parameters. It is the controlling ICE agent.
+//! 4. Both exchange `IceCandidate`s until the connection is established.
+//!
+//! The server sending another `WebRTC` message later triggers an ICE restart.
+
+use std::fmt;
+
+use super::backend::message;
+use super::msgs;
+use super::validate::Validate;
+use super::validate::ValidationIssue;
+
+/// The session parameters sent in a [msgs::WebRTC] message.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct WebRtcOffer {
+    /// The ICE username fragment (`a=ice-ufrag`).
+    pub ice_ufrag: String,
+    /// The ICE password (`a=ice-pwd`).
+    pub ice_pwd: String,
+    /// The DTLS certificate fingerprint including its hash function (`a=fingerprint`), e.g.
+    /// `sha-256 AB:CD:...`.
+    pub dtls_fingerprint: String,
+}
+
+impl WebRtcOffer {
+    /// Extracts the parameters from the session-level attributes of a local SDP, e.g. the one
+    /// created by a WebRTC implementation.
+    ///
+    /// Returns `None` if any of them is missing.
+    pub fn from_sdp(sdp: &str) -> Option<Self> {
+        let attribute = |name: &str| {
+            sdp.lines()
+                .find_map(|line| line.trim_end().strip_prefix(name))
+                .map(str::to_owned)
+        };
+        Some(WebRtcOffer {
+            ice_ufrag: attribute("a=ice-ufrag:")?,
+            ice_pwd: attribute("a=ice-pwd:")?,
+            dtls_fingerprint: attribute("a=fingerprint:")?,
+        })
+    }
+
+    /// Returns the parameters as SDP attribute lines, for building the SDP of the other side.
+    pub fn to_sdp_attributes(&self) -> String {
+        format!(
+            "a=ice-ufrag:{}\r\na=ice-pwd:{}\r\na=fingerprint:{}\r\n",
+            self.ice_ufrag, self.ice_pwd, self.dtls_fingerprint
+        )
+    }
+}
+
+/// Fails if any of the fields is missing.
+impl TryFrom<&msgs::WebRTC> for WebRtcOffer {
+    type Error = ValidationIssue;
+
+    fn try_from(msg: &msgs::WebRTC) -> Result<Self, Self::Error> {
+        if let Some(issue) = msg.validate().into_iter().next() {
+            return Err(issue);
+        }
+        Ok(WebRtcOffer {
+            ice_ufrag: msg.ice_ufrag().to_owned(),
+            ice_pwd: msg.ice_pwd().to_owned(),
+            dtls_fingerprint: msg.dtls_fingerprint().to_owned(),
+        })
+    }
+}
+
+impl From<&WebRtcOffer> for msgs::WebRTC {
+    fn from(offer: &WebRtcOffer) -> Self {
+        message!(msgs::WebRTC {
+            ice_ufrag: Some(offer.ice_ufrag.clone()),
+            ice_pwd: Some(offer.ice_pwd.clone()),
+            dtls_fingerprint: Some(offer.dtls_fingerprint.clone()),
+        })
+    }
+}
+
+/// How far the exchange of `WebRTC` messages has progressed, see [WebRtcNegotiation].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NegotiationState {
+    /// No parameters were exchanged yet.
+    New,
+    /// The server sent its parameters.
+    OfferSent,
+    /// The client replied with its parameters, ICE is in progress.
+    AnswerReceived,
+    /// The connection is established.
+    Established,
+}
+
+/// A `WebRTC` message arrived at an unexpected point of the negotiation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NegotiationError {
+    /// The message lacks mandatory fields.
+    Invalid(ValidationIssue),
+    /// The client sent its parameters before the server did.
+    AnswerBeforeOffer,
+    /// The connection was marked as established before parameters were exchanged.
+    NotNegotiated(NegotiationState),
+}
+
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NegotiationError::Invalid(issue) => write!(f, "invalid WebRTC message: {}", issue),
+            NegotiationError::AnswerBeforeOffer => {
+                write!(f, "client sent WebRTC parameters before the server")
+            }
+            NegotiationError::NotNegotiated(state) => {
+                write!(f, "WebRTC negotiation is incomplete ({:?})", state)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NegotiationError {}
+
+/// Tracks the exchange of `WebRTC` messages, for either side of the connection.
+///
+/// Both sides feed it the server's message via [offer](Self::offer) and the client's via
+/// [answer](Self::answer), regardless of which of them they sent themselves.
+#[derive(Clone, Debug)]
+pub struct WebRtcNegotiation {
+    state: NegotiationState,
+    offer: Option<WebRtcOffer>,
+    answer: Option<WebRtcOffer>,
+}
+
+impl Default for WebRtcNegotiation {
+    fn default() -> Self {
+        WebRtcNegotiation {
+            state: NegotiationState::New,
+            offer: None,
+            answer: None,
+        }
+    }
+}
+
+impl WebRtcNegotiation {
+    /// Creates a negotiation in the [New](NegotiationState::New) state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the server's parameters.
+    ///
+    /// May happen in any state: after the initial exchange, it starts an ICE restart.
+    pub fn offer(&mut self, msg: &msgs::WebRTC) -> Result<&WebRtcOffer, NegotiationError> {
+        let offer = WebRtcOffer::try_from(msg).map_err(NegotiationError::Invalid)?;
+        self.state = NegotiationState::OfferSent;
+        self.answer = None;
+        Ok(self.offer.insert(offer))
+    }
+
+    /// Records the client's parameters.
+    pub fn answer(&mut self, msg: &msgs::WebRTC) -> Result<&WebRtcOffer, NegotiationError> {
+        if self.state != NegotiationState::OfferSent {
+            return Err(NegotiationError::AnswerBeforeOffer);
+        }
+        let answer = WebRtcOffer::try_from(msg).map_err(NegotiationError::Invalid)?;
+        self.state = NegotiationState::AnswerReceived;
+        Ok(self.answer.insert(answer))
+    }
+
+    /// Marks the connection as established, once ICE and DTLS completed.
+    pub fn established(&mut self) -> Result<(), NegotiationError> {
+        match self.state {
+            NegotiationState::AnswerReceived | NegotiationState::Established => {
+                self.state = NegotiationState::Established;
+                Ok(())
+            }
+            state => Err(NegotiationError::NotNegotiated(state)),
+        }
+    }
+
+    /// Returns the current state.
+    pub fn state(&self) -> NegotiationState {
+        self.state
+    }
+
+    /// Returns the server's parameters of the current negotiation.
+    pub fn server_params(&self) -> Option<&WebRtcOffer> {
+        self.offer.as_ref()
+    }
+
+    /// Returns the client's parameters of the current negotiation.
+    pub fn client_params(&self) -> Option<&WebRtcOffer> {
+        self.answer.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn params(ufrag: &str) -> WebRtcOffer {
+        WebRtcOffer {
+            ice_ufrag: ufrag.to_owned(),
+            ice_pwd: "password".to_owned(),
+            dtls_fingerprint: "sha-256 AB:CD".to_owned(),
+        }
+    }
+
+    #[test]
+    fn converts_sdp_and_messages() {
+        let sdp = "v=0\r\na=ice-ufrag:abcd\r\na=ice-pwd:password\r\n\
+                   a=fingerprint:sha-256 AB:CD\r\nm=audio 9 UDP/TLS/RTP/SAVPF 97\r\n";
+        let offer = WebRtcOffer::from_sdp(sdp).unwrap();
+        assert_eq!(params("abcd"), offer);
+        assert_eq!(
+            "a=ice-ufrag:abcd\r\na=ice-pwd:password\r\na=fingerprint:sha-256 AB:CD\r\n",
+            offer.to_sdp_attributes()
+        );
+        assert_eq!(None, WebRtcOffer::from_sdp("v=0\r\na=ice-ufrag:abcd\r\n"));
+
+        let msg = msgs::WebRTC::from(&offer);
+        assert_eq!(Ok(offer), WebRtcOffer::try_from(&msg));
+        let msg = msgs::WebRTC {
+            ice_ufrag: Some("abcd".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!("ice_pwd", WebRtcOffer::try_from(&msg).unwrap_err().field);
+    }
+
+    #[test]
+    fn tracks_negotiation() {
+        let mut negotiation = WebRtcNegotiation::new();
+        let server = msgs::WebRTC::from(&params("server"));
+        let client = msgs::WebRTC::from(&params("client"));
+        assert_eq!(
+            Err(NegotiationError::AnswerBeforeOffer),
+            negotiation.answer(&client).map(|_| ())
+        );
+        assert_eq!(
+            Err(NegotiationError::NotNegotiated(NegotiationState::New)),
+            negotiation.established()
+        );
+
+        negotiation.offer(&server).unwrap();
+        assert_eq!(NegotiationState::OfferSent, negotiation.state());
+        negotiation.answer(&client).unwrap();
+        assert_eq!(NegotiationState::AnswerReceived, negotiation.state());
+        negotiation.established().unwrap();
+        assert_eq!(NegotiationState::Established, negotiation.state());
+        assert_eq!("client", negotiation.client_params().unwrap().ice_ufrag);
+
+        // ICE restart
+        negotiation.offer(&server).unwrap();
+        assert_eq!(NegotiationState::OfferSent, negotiation.state());
+        assert_eq!(None, negotiation.client_params());
+    }
+}
@@ -1,11 +1,17 @@
 //! Implementation of the cryptography used for Mumble's voice channel
 
-use std::io;
+use std::fmt;
 
+use bytes::BufMut;
 use bytes::BytesMut;
 use openssl::memcmp;
 use openssl::rand::rand_bytes;
 
+use crate::control::backend::message;
+use crate::control::crypt_setup::CryptSetupError;
+use crate::control::crypt_setup::CryptSetupKind;
+use crate::control::msgs;
+use crate::error::Error;
 use crate::voice::Clientbound;
 use crate::voice::Serverbound;
 use crate::voice::VoiceCodec;
@@ -43,6 +49,7 @@ pub struct CryptState<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> {
     good: u32,
     late: u32,
     lost: u32,
+    resync: u32,
 }
 /// The [CryptState] used on the server side.
 pub type ServerCryptState = CryptState<Clientbound, Serverbound>;
@@ -64,6 +71,19 @@ pub enum DecryptError {
     Mac,
 }
 
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DecryptError::Eof => "packet too short",
+            DecryptError::Repeat => "packet was repeated",
+            DecryptError::Late => "packet arrived too late",
+            DecryptError::Mac => "MAC mismatch",
+        })
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> CryptState<EncodeDst, DecodeDst> {
     /// Creates a new CryptState with randomly generated key and initial encrypt- and decrypt-nonce.
     pub fn generate_new() -> Self {
@@ -81,6 +101,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> CryptState<EncodeDst,
             good: 0,
             late: 0,
             lost: 0,
+            resync: 0,
         }
     }
 
@@ -101,6 +122,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> CryptState<EncodeDst,
             good: 0,
             late: 0,
             lost: 0,
+            resync: 0,
         }
     }
 
@@ -119,6 +141,12 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> CryptState<EncodeDst,
         self.lost
     }
 
+    /// Returns the amount of times the decrypt nonce was resynchronized via
+    /// [set_decrypt_nonce](Self::set_decrypt_nonce).
+    pub fn get_resync(&self) -> u32 {
+        self.resync
+    }
+
     /// Returns the shared, **private** key.
     pub fn get_key(&self) -> &[u8; KEY_SIZE] {
         &self.key
@@ -134,43 +162,58 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> CryptState<EncodeDst,
         self.decrypt_nonce.to_le_bytes()
     }
 
-    /// Updates the nonce used for decrypting.
+    /// Updates the nonce used for decrypting, e.g. after receiving a resync via `CryptSetup`.
     pub fn set_decrypt_nonce(&mut self, nonce: &[u8; BLOCK_SIZE]) {
         self.decrypt_nonce = u128::from_le_bytes(*nonce);
+        self.resync += 1;
     }
 
-    /// Encrypts an encoded voice packet and returns the resulting bytes.
-    pub fn encrypt(&mut self, packet: VoicePacket<EncodeDst>, dst: &mut BytesMut) {
-        self.encrypt_nonce = self.encrypt_nonce.wrapping_add(1);
-
+    /// Encodes and encrypts a voice packet, replacing the contents of `dst` with the resulting
+    /// bytes.
+    ///
+    /// Fails if the packet cannot be encoded, e.g. because an audio frame is too long for its
+    /// length header. The nonce is only advanced for packets which are sent.
+    pub fn encrypt(
+        &mut self,
+        packet: VoicePacket<EncodeDst>,
+        dst: &mut BytesMut,
+    ) -> Result<(), Error> {
         // Leave four bytes for header
         dst.resize(4, 0);
-        let mut inner = dst.split_off(4);
-
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "asynchronous-codec")] {
-                use asynchronous_codec::Encoder as _;
-            } else {
-                use tokio_util::codec::Encoder as _;
-            }
+        if let Err(err) = self.codec.encode(packet, dst) {
+            dst.clear();
+            return Err(err);
         }
+        self.seal(dst, 0);
+        Ok(())
+    }
 
-        self.codec
-            .encode(packet, &mut inner)
-            .expect("VoiceEncoder is infallible");
-
-        let tag = self.ocb_encrypt(inner.as_mut());
-        dst.unsplit(inner);
+    /// Encrypts a voice packet encoded with
+    /// [VoicePacket::encode_shared](crate::voice::VoicePacket::encode_shared), appending the
+    /// resulting bytes to `dst`.
+    ///
+    /// When sending the same packet to many recipients, this saves encoding it for each one.
+    pub fn encrypt_prepared(&mut self, packet: &[u8], dst: &mut BytesMut) {
+        let start = dst.len();
+        dst.reserve(4 + packet.len());
+        dst.put_bytes(0, 4);
+        dst.put_slice(packet);
+        self.seal(dst, start);
+    }
 
-        dst[0] = self.encrypt_nonce as u8;
-        dst[1..4].copy_from_slice(&tag.to_be_bytes()[0..3]);
+    /// Encrypts the plain packet following the four header bytes at `start` and fills them in.
+    fn seal(&mut self, dst: &mut BytesMut, start: usize) {
+        self.encrypt_nonce = self.encrypt_nonce.wrapping_add(1);
+        let tag = self.ocb_encrypt(&mut dst[start + 4..]);
+        dst[start] = self.encrypt_nonce as u8;
+        dst[start + 1..start + 4].copy_from_slice(&tag.to_be_bytes()[0..3]);
     }
 
     /// Decrypts a voice packet and (if successful) returns the `Result` of parsing the packet.
     pub fn decrypt(
         &mut self,
         buf: &mut BytesMut,
-    ) -> Result<Result<VoicePacket<DecodeDst>, io::Error>, DecryptError> {
+    ) -> Result<Result<VoicePacket<DecodeDst>, Error>, DecryptError> {
         if buf.len() < 4 {
             return Err(DecryptError::Eof);
         }
@@ -218,15 +261,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> CryptState<EncodeDst,
             self.late += 1;
             self.decrypt_nonce = saved_nonce;
         }
-        self.lost = (self.lost as i32 + lost as i32) as u32;
-
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "asynchronous-codec")] {
-                use asynchronous_codec::Decoder as _;
-            } else {
-                use tokio_util::codec::Decoder as _;
-            }
-        }
+        self.lost = (self.lost as i32 + lost) as u32;
 
         Ok(self
             .codec
@@ -344,18 +379,78 @@ fn s2(block: u128) -> u128 {
     rot ^ (carry * 0x86)
 }
 
+impl ClientCryptState {
+    /// Creates the client's CryptState from the initial `CryptSetup` sent by the server.
+    pub fn from_crypt_setup(msg: &msgs::CryptSetup) -> Result<Self, CryptSetupError> {
+        match msg.kind()? {
+            CryptSetupKind::Full {
+                key,
+                client_nonce,
+                server_nonce,
+            } => Ok(Self::new_from(key, client_nonce, server_nonce)),
+            _ => Err(CryptSetupError::UnexpectedKind),
+        }
+    }
+
+    /// Applies the server nonce sent in response to a resync request.
+    pub fn apply_nonce_update(&mut self, msg: &msgs::CryptSetup) -> Result<(), CryptSetupError> {
+        match msg.kind()? {
+            CryptSetupKind::ServerNonce(nonce) => {
+                self.set_decrypt_nonce(&nonce);
+                Ok(())
+            }
+            _ => Err(CryptSetupError::UnexpectedKind),
+        }
+    }
+
+    /// Creates the reply to a resync request by the server, containing the client nonce.
+    pub fn nonce_reply(&self) -> msgs::CryptSetup {
+        message!(msgs::CryptSetup {
+            client_nonce: Some(self.get_encrypt_nonce().to_vec()),
+        })
+    }
+}
+
+impl ServerCryptState {
+    /// Creates the initial `CryptSetup` for the client.
+    pub fn to_crypt_setup(&self) -> msgs::CryptSetup {
+        message!(msgs::CryptSetup {
+            key: Some(self.key.to_vec()),
+            client_nonce: Some(self.get_decrypt_nonce().to_vec()),
+            server_nonce: Some(self.get_encrypt_nonce().to_vec()),
+        })
+    }
+
+    /// Applies the client nonce sent in response to a resync request.
+    pub fn apply_nonce_update(&mut self, msg: &msgs::CryptSetup) -> Result<(), CryptSetupError> {
+        match msg.kind()? {
+            CryptSetupKind::ClientNonce(nonce) => {
+                self.set_decrypt_nonce(&nonce);
+                Ok(())
+            }
+            _ => Err(CryptSetupError::UnexpectedKind),
+        }
+    }
+
+    /// Creates the reply to a resync request by the client, containing the server nonce.
+    pub fn nonce_reply(&self) -> msgs::CryptSetup {
+        message!(msgs::CryptSetup {
+            server_nonce: Some(self.get_encrypt_nonce().to_vec()),
+        })
+    }
+}
+
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> CryptState<EncodeDst, DecodeDst> {
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<VoicePacket<DecodeDst>>, io::Error> {
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<VoicePacket<DecodeDst>>, Error> {
         if src.is_empty() {
             return Ok(None);
         }
         self.decrypt(src)
-            .unwrap_or_else(|_| {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "failed to decrypt",
-                ))
-            })
+            .unwrap_or_else(|err| Err(err.into()))
             .map(Some)
     }
 }
@@ -365,7 +460,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> tokio_util::codec::De
     for CryptState<EncodeDst, DecodeDst>
 {
     type Item = VoicePacket<DecodeDst>;
-    type Error = io::Error; // never
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         self.decode(src)
@@ -377,7 +472,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::D
     for CryptState<EncodeDst, DecodeDst>
 {
     type Item = VoicePacket<DecodeDst>;
-    type Error = io::Error; // never
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         self.decode(src)
@@ -385,13 +480,12 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::D
 }
 
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> CryptState<EncodeDst, DecodeDst> {
-    fn encode(
-        &mut self,
-        item: VoicePacket<EncodeDst>,
-        dst: &mut BytesMut,
-    ) -> Result<(), io::Error> {
-        self.encrypt(item, dst);
-        Ok(())
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    fn encode(&mut self, item: VoicePacket<EncodeDst>, dst: &mut BytesMut) -> Result<(), Error> {
+        self.encrypt(item, dst)
     }
 }
 
@@ -399,7 +493,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> CryptState<EncodeDst,
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>
     tokio_util::codec::Encoder<VoicePacket<EncodeDst>> for CryptState<EncodeDst, DecodeDst>
 {
-    type Error = io::Error; // never
+    type Error = Error;
 
     fn encode(
         &mut self,
@@ -415,7 +509,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::E
     for CryptState<EncodeDst, DecodeDst>
 {
     type Item = VoicePacket<EncodeDst>;
-    type Error = io::Error; // never
+    type Error = Error;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
         self.encode(item, dst)
@@ -426,6 +520,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::E
 mod test {
     use bytes::BufMut;
 
+    use crate::error::VoiceError;
     use crate::voice::VoicePacketPayload;
 
     use super::*;
@@ -549,7 +644,7 @@ mod test {
         };
 
         let mut buf = BytesMut::new();
-        server_state.encrypt(packet.clone(), &mut buf);
+        server_state.encrypt(packet.clone(), &mut buf).unwrap();
         let result = client_state
             .decrypt(&mut buf)
             .expect("Failed to decrypt")
@@ -557,4 +652,108 @@ mod test {
 
         assert_eq!(packet, result);
     }
+
+    #[test]
+    fn refuses_to_encrypt_unencodable_packets() {
+        let mut state =
+            ServerCryptState::new_from(Default::default(), Default::default(), Default::default());
+        let packet = VoicePacket::Audio {
+            _dst: std::marker::PhantomData,
+            target: 0,
+            session_id: 1,
+            seq_num: 0,
+            payload: VoicePacketPayload::Opus(vec![0; 9000].into(), false),
+            position_info: None,
+        };
+
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            state.encrypt(packet, &mut buf),
+            Err(Error::MalformedVoice(VoiceError::FrameTooLong {
+                len: 9000,
+                ..
+            }))
+        ));
+        assert!(buf.is_empty());
+        assert_eq!([0; BLOCK_SIZE], state.get_encrypt_nonce());
+    }
+
+    #[test]
+    fn encrypts_prepared_packets_like_packets() {
+        let packet = VoicePacket::<Clientbound>::Audio {
+            _dst: std::marker::PhantomData,
+            target: 0,
+            session_id: 7,
+            seq_num: 40,
+            payload: VoicePacketPayload::Opus(BytesMut::from("prepared").freeze(), false),
+            position_info: None,
+        };
+        let shared = packet.encode_shared().unwrap();
+        let key = [3; KEY_SIZE];
+        let mut state = ServerCryptState::new_from(key, [1; BLOCK_SIZE], Default::default());
+        let mut prepared_state =
+            ServerCryptState::new_from(key, [1; BLOCK_SIZE], Default::default());
+
+        let mut expected = BytesMut::new();
+        let mut prepared = BytesMut::from(&b"kept"[..]);
+        for _ in 0..2 {
+            expected.clear();
+            state.encrypt(packet.clone(), &mut expected).unwrap();
+            prepared.truncate(4);
+            prepared_state.encrypt_prepared(&shared, &mut prepared);
+            assert_eq!(b"kept", &prepared[..4]);
+            assert_eq!(expected, prepared[4..]);
+        }
+
+        let mut client_state = ClientCryptState::new_from(key, Default::default(), [1; BLOCK_SIZE]);
+        let mut buf = prepared.split_off(4);
+        client_state.decrypt(&mut buf).unwrap().unwrap();
+        assert_eq!(1, client_state.get_good());
+    }
+
+    #[test]
+    fn sets_up_from_crypt_setup_messages() {
+        let mut server_state = ServerCryptState::generate_new();
+        let mut client_state =
+            ClientCryptState::from_crypt_setup(&server_state.to_crypt_setup()).unwrap();
+        assert_eq!(server_state.get_key(), client_state.get_key());
+        assert_eq!(
+            server_state.get_encrypt_nonce(),
+            client_state.get_decrypt_nonce()
+        );
+
+        // Client requests a resync, server replies with its nonce only
+        assert_eq!(
+            Ok(CryptSetupKind::ResyncRequest),
+            msgs::CryptSetup::resync_request().kind()
+        );
+        let reply = server_state.nonce_reply();
+        client_state.apply_nonce_update(&reply).unwrap();
+        assert_eq!(1, client_state.get_resync());
+
+        // Server requests a resync, client replies with its nonce only
+        server_state
+            .apply_nonce_update(&client_state.nonce_reply())
+            .unwrap();
+        assert_eq!(
+            client_state.get_encrypt_nonce(),
+            server_state.get_decrypt_nonce()
+        );
+
+        assert_eq!(
+            Err(CryptSetupError::UnexpectedKind),
+            client_state.apply_nonce_update(&msgs::CryptSetup::resync_request())
+        );
+        let truncated = msgs::CryptSetup {
+            server_nonce: Some(vec![0; 8]),
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(CryptSetupError::InvalidLength {
+                field: "server_nonce",
+                len: 8
+            }),
+            client_state.apply_nonce_update(&truncated)
+        );
+    }
 }
@@ -0,0 +1,311 @@
+//! Error type shared by all codecs and conversions of this crate
+
+use std::fmt;
+use std::io;
+
+use bytes::Bytes;
+#[cfg(feature = "prost")]
+use prost::DecodeError as ProtobufError;
+#[cfg(feature = "protobuf")]
+use protobuf::Error as ProtobufError;
+
+use crate::control::limits::BandwidthViolation;
+#[cfg(feature = "openssl")]
+use crate::crypt::DecryptError;
+use crate::varint::VarintError;
+use crate::voice::Direction;
+
+/// Maximum amount of leading packet bytes recorded in [Error::Parse].
+pub const PARSE_ERROR_HEAD_LEN: usize = 16;
+
+/// An error which occurred while encoding, decoding or converting packets.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A packet is longer than the configured limit.
+    PacketTooLong {
+        /// Length of the packet's payload in bytes.
+        declared: usize,
+        /// Maximum accepted payload length in bytes.
+        limit: usize,
+    },
+    /// The stream ended in the middle of a packet.
+    Truncated {
+        /// ID of the incomplete packet, if its header was complete.
+        id: Option<u16>,
+        /// Amount of bytes of the packet (or its header) which were received.
+        received: usize,
+        /// Amount of bytes of the packet (or its header) which were expected.
+        expected: usize,
+    },
+    /// A packet was converted into a message of the wrong type.
+    WrongPacketType {
+        /// ID of the requested packet type.
+        expected: u16,
+        /// ID of the actual packet.
+        actual: u16,
+    },
+    /// A packet was received which must not be sent in the direction it was received from.
+    InvalidDirection {
+        /// ID of the packet.
+        id: u16,
+        /// Direction the packet was received in.
+        direction: Direction,
+    },
+    /// A control packet could not be parsed into its message type.
+    Parse {
+        /// ID of the packet.
+        id: u16,
+        /// Length of the packet's payload in bytes.
+        len: usize,
+        /// The first (up to [PARSE_ERROR_HEAD_LEN]) bytes of the payload.
+        head: Bytes,
+        /// The reason parsing failed, either [Error::Protobuf] or [Error::MalformedVoice].
+        source: Box<Error>,
+    },
+    /// A `TextMessage` exceeds the limits advertised by the server.
+    ///
+    /// See [MessageLimits](crate::control::limits::MessageLimits).
+    TextMessageTooLong {
+        /// Length of the message in UTF-16 code units.
+        len: usize,
+        /// The limit which was exceeded.
+        limit: usize,
+        /// Whether the message contains an image and was checked against the image limit.
+        image: bool,
+    },
+    /// The protobuf message of a control packet could not be parsed.
+    Protobuf(ProtobufError),
+    /// A voice packet could not be parsed.
+    MalformedVoice(VoiceError),
+    /// A voice packet could not be decrypted.
+    #[cfg(feature = "openssl")]
+    Crypt(DecryptError),
+    /// Audio could not be encoded or decoded.
+    #[cfg(feature = "opus")]
+    Opus(audiopus::Error),
+    /// An I/O error occurred.
+    Io(io::Error),
+}
+
+/// The reason a voice packet could not be parsed or encoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VoiceError {
+    /// The packet ended before all of its fields could be read.
+    Truncated,
+    /// The packet's type is unknown.
+    UnknownType(u8),
+    /// The packet has fewer bytes after its audio than the [Position](crate::voice::Position)
+    /// which has to come first.
+    MalformedPosition(usize),
+    /// A CELT or Speex payload to be encoded has no frames.
+    NoFrames,
+    /// An audio frame to be encoded is longer than its length header allows.
+    FrameTooLong {
+        /// The length of the frame.
+        len: usize,
+        /// The maximum length for its codec.
+        limit: usize,
+    },
+    /// A packet cannot be converted to the protobuf format since its audio is not Opus.
+    UnsupportedCodec,
+    /// A packet cannot be converted to the legacy format since its target or context does not
+    /// fit into 5 bits.
+    TargetOutOfRange(u32),
+    /// An audio packet to be encoded exceeds the bandwidth limit installed on the codec.
+    BandwidthExceeded(BandwidthViolation),
+    /// Audio packets to be repacketized use different codecs.
+    MixedCodecs,
+    /// An Opus packet's frames could not be read, see RFC 6716 section 3.2.
+    MalformedOpus,
+    /// A varint in the packet is malformed.
+    ///
+    /// Truncated varints are reported as [VoiceError::Truncated] instead.
+    MalformedVarint(VarintError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PacketTooLong { declared, limit } => write!(
+                f,
+                "packet too long: declared length {} exceeds limit of {} bytes",
+                declared, limit
+            ),
+            Error::Truncated {
+                id: None, received, ..
+            } => write!(
+                f,
+                "stream ended with {} bytes of an incomplete packet header pending",
+                received
+            ),
+            Error::Truncated {
+                id: Some(id),
+                received,
+                expected,
+            } => write!(
+                f,
+                "stream ended with {} of {} bytes of packet with id {} pending",
+                received, expected, id
+            ),
+            Error::WrongPacketType { expected, actual } => write!(
+                f,
+                "expected packet with id {} but got id {}",
+                expected, actual
+            ),
+            Error::InvalidDirection { id, direction } => write!(
+                f,
+                "packet with id {} is not valid in {:?} direction",
+                id, direction
+            ),
+            Error::Parse {
+                id, len, source, ..
+            } => {
+                let name = crate::control::name_for_id(*id).unwrap_or("unknown packet");
+                // The prefix of wrapped parse errors would only repeat what failed
+                let cause: &dyn fmt::Display = match &**source {
+                    Error::Protobuf(err) => err,
+                    Error::MalformedVoice(err) => err,
+                    err => err,
+                };
+                write!(
+                    f,
+                    "failed to parse {} (id {}, {} bytes): {}",
+                    name, id, len, cause
+                )
+            }
+            Error::TextMessageTooLong { len, limit, image } => write!(
+                f,
+                "text message too long: length {} exceeds {} limit of {}",
+                len,
+                if *image { "image message" } else { "message" },
+                limit
+            ),
+            Error::Protobuf(err) => write!(f, "failed to parse message: {}", err),
+            Error::MalformedVoice(err) => write!(f, "malformed voice packet: {}", err),
+            #[cfg(feature = "openssl")]
+            Error::Crypt(err) => write!(f, "failed to decrypt: {}", err),
+            #[cfg(feature = "opus")]
+            Error::Opus(err) => write!(f, "opus: {}", err),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for VoiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoiceError::Truncated => f.write_str("unexpected end of packet"),
+            VoiceError::UnknownType(kind) => write!(f, "unknown voice packet type {}", kind),
+            VoiceError::MalformedPosition(len) => write!(
+                f,
+                "{} bytes of positional data, expected at least {}",
+                len,
+                crate::voice::POSITION_LEN
+            ),
+            VoiceError::NoFrames => f.write_str("audio payload has no frames"),
+            VoiceError::FrameTooLong { len, limit } => write!(
+                f,
+                "audio frame of {} bytes exceeds the limit of {} bytes",
+                len, limit
+            ),
+            VoiceError::UnsupportedCodec => {
+                f.write_str("only Opus audio can be sent in protobuf voice packets")
+            }
+            VoiceError::TargetOutOfRange(target) => {
+                write!(f, "voice target {} does not fit into 5 bits", target)
+            }
+            VoiceError::BandwidthExceeded(violation) => violation.fmt(f),
+            VoiceError::MixedCodecs => f.write_str("audio packets use different codecs"),
+            VoiceError::MalformedOpus => f.write_str("malformed Opus packet"),
+            VoiceError::MalformedVarint(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse { source, .. } => Some(&**source),
+            Error::Protobuf(err) => Some(err),
+            #[cfg(feature = "opus")]
+            Error::Opus(err) => Some(err),
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for VoiceError {}
+
+impl Error {
+    /// Wraps an error which occurred while parsing the payload of a control packet.
+    pub(crate) fn parse(id: u16, payload: &[u8], source: Error) -> Self {
+        Error::Parse {
+            id,
+            len: payload.len(),
+            head: Bytes::copy_from_slice(&payload[..payload.len().min(PARSE_ERROR_HEAD_LEN)]),
+            source: Box::new(source),
+        }
+    }
+}
+
+impl From<ProtobufError> for Error {
+    fn from(err: ProtobufError) -> Self {
+        Error::Protobuf(err)
+    }
+}
+
+impl From<VarintError> for VoiceError {
+    fn from(err: VarintError) -> Self {
+        match err {
+            VarintError::Truncated => VoiceError::Truncated,
+            err => VoiceError::MalformedVarint(err),
+        }
+    }
+}
+
+impl From<VoiceError> for Error {
+    fn from(err: VoiceError) -> Self {
+        Error::MalformedVoice(err)
+    }
+}
+
+#[cfg(feature = "openssl")]
+impl From<DecryptError> for Error {
+    fn from(err: DecryptError) -> Self {
+        Error::Crypt(err)
+    }
+}
+
+#[cfg(feature = "opus")]
+impl From<audiopus::Error> for Error {
+    fn from(err: audiopus::Error) -> Self {
+        Error::Opus(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => {
+                let kind = match &err {
+                    Error::Truncated { .. } | Error::MalformedVoice(VoiceError::Truncated) => {
+                        io::ErrorKind::UnexpectedEof
+                    }
+                    Error::WrongPacketType { .. } => io::ErrorKind::InvalidInput,
+                    _ => io::ErrorKind::InvalidData,
+                };
+                io::Error::new(kind, err)
+            }
+        }
+    }
+}
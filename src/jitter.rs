@@ -0,0 +1,316 @@
+//! Buffering received audio for playback
+//!
+//! Audio packets arrive with varying delays, out of order or not at all. A [JitterBuffer] holds
+//! them back for a short while and hands them out in order, one packet's worth of audio at a
+//! time, telling where audio is missing so the decoder can conceal it.
+//!
+//! The buffer neither decodes audio nor awaits anything: it is driven by calling
+//! [pull](JitterBuffer::pull) whenever the audio output needs more data.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::seq;
+use crate::seq::SeqStatus;
+use crate::seq::VoiceStreamStats;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+
+/// The delay targeted by default.
+pub const DEFAULT_DELAY: Duration = Duration::from_millis(60);
+
+/// The delay never goes below this by default.
+pub const DEFAULT_MIN_DELAY: Duration = Duration::from_millis(20);
+
+/// The delay never goes above this by default.
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_millis(300);
+
+/// Without packets for this long, a transmission without terminator is considered over by
+/// default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What to play next, as returned by [JitterBuffer::pull].
+#[derive(Clone, Debug, PartialEq)]
+pub enum JitterOutput<Dst: VoicePacketDst> {
+    /// The next packet, always [VoicePacket::Audio].
+    ///
+    /// Its duration is given by [frame_count](crate::voice::VoicePacketPayload::frame_count).
+    Audio(VoicePacket<Dst>),
+    /// The next packet did not arrive in time, its audio should be concealed.
+    Missing {
+        /// The amount of 10 ms frames to conceal.
+        frames: u64,
+    },
+    /// Nobody is talking.
+    Silence,
+}
+
+#[derive(Clone, Debug)]
+enum State {
+    Idle,
+    /// A transmission started at `since`, playback starts once the delay passed.
+    Buffering {
+        since: Instant,
+    },
+    Playing {
+        next: u64,
+    },
+}
+
+/// Buffers the audio packets of a single user for playback.
+///
+/// Playback of a transmission starts once its first packet was held back for the delay, which
+/// is the configured one or three times the observed jitter, whichever is longer, but within the
+/// configured bounds. From then on, [pull](Self::pull) returns the packets in sequence, packets
+/// arriving after their turn are dropped. A transmission ends with its terminator or once no
+/// packets arrived for the timeout.
+#[derive(Clone, Debug)]
+pub struct JitterBuffer<Dst: VoicePacketDst> {
+    delay: Duration,
+    min_delay: Duration,
+    max_delay: Duration,
+    timeout: Duration,
+    stats: VoiceStreamStats,
+    packets: BTreeMap<u64, VoicePacket<Dst>>,
+    last_arrival: Option<Instant>,
+    /// The amount of frames of the packets, for concealing missing ones.
+    step: u64,
+    state: State,
+}
+
+impl<Dst: VoicePacketDst> Default for JitterBuffer<Dst> {
+    fn default() -> Self {
+        JitterBuffer {
+            delay: DEFAULT_DELAY,
+            min_delay: DEFAULT_MIN_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            timeout: DEFAULT_TIMEOUT,
+            stats: VoiceStreamStats::new(),
+            packets: BTreeMap::new(),
+            last_arrival: None,
+            step: 1,
+            state: State::Idle,
+        }
+    }
+}
+
+impl<Dst: VoicePacketDst> JitterBuffer<Dst> {
+    /// Creates a buffer with the [DEFAULT_DELAY], its bounds and the [DEFAULT_TIMEOUT].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the targeted delay.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Sets the bounds the delay adapts within.
+    pub fn with_delay_bounds(mut self, min_delay: Duration, max_delay: Duration) -> Self {
+        self.min_delay = min_delay;
+        self.max_delay = max_delay.max(min_delay);
+        self
+    }
+
+    /// Sets how long without packets ends a transmission without terminator.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the loss and jitter statistics of the received packets.
+    pub fn stats(&self) -> &VoiceStreamStats {
+        &self.stats
+    }
+
+    /// Returns the delay the next transmission will be played back with.
+    pub fn delay(&self, now: Instant) -> Duration {
+        let jitter = Duration::from_secs_f64(self.stats.snapshot(now).jitter_ms * 3.0 / 1000.0);
+        self.delay.max(jitter).clamp(self.min_delay, self.max_delay)
+    }
+
+    /// Returns the amount of packets held back.
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Returns whether no packets are held back.
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Adds a packet received at `arrival`, returning how it relates to the previous ones.
+    ///
+    /// Pings are ignored. Duplicates and packets arriving after their turn are dropped.
+    pub fn push(&mut self, packet: VoicePacket<Dst>, arrival: Instant) -> Option<SeqStatus> {
+        let VoicePacket::Audio {
+            seq_num, payload, ..
+        } = &packet
+        else {
+            return None;
+        };
+        let seq_num = *seq_num;
+        let status = self.stats.update(seq_num, payload.frame_count(), arrival);
+        self.last_arrival = Some(arrival);
+        match (status, &self.state) {
+            (SeqStatus::Duplicate, _) => return Some(status),
+            (SeqStatus::NewTransmission, _) | (_, State::Idle) => {
+                self.packets.clear();
+                self.state = State::Buffering { since: arrival };
+            }
+            (_, State::Playing { next }) if seq::compare(seq_num, *next).is_lt() => {
+                return Some(status)
+            }
+            _ => {}
+        }
+        self.packets.insert(seq_num, packet);
+        Some(status)
+    }
+
+    /// Returns what to play next.
+    ///
+    /// Should be called whenever the previous output was played, i.e. after the duration of its
+    /// frames, or 10 ms after [Silence](JitterOutput::Silence).
+    pub fn pull(&mut self, now: Instant) -> JitterOutput<Dst> {
+        let next = match self.state {
+            State::Idle => return JitterOutput::Silence,
+            State::Buffering { since } => {
+                if now < since + self.delay(now) {
+                    return JitterOutput::Silence;
+                }
+                match self.packets.keys().next() {
+                    Some(first) => *first,
+                    None => return self.flush(),
+                }
+            }
+            State::Playing { next } => next,
+        };
+
+        if let Some(packet) = self.packets.remove(&next) {
+            let VoicePacket::Audio { payload, .. } = &packet else {
+                unreachable!("only audio is buffered");
+            };
+            self.step = payload.frame_count();
+            self.state = if payload.is_terminator() {
+                self.packets.clear();
+                State::Idle
+            } else {
+                State::Playing {
+                    next: next.wrapping_add(self.step),
+                }
+            };
+            return JitterOutput::Audio(packet);
+        }
+
+        let timed_out = self
+            .last_arrival
+            .is_none_or(|it| now.saturating_duration_since(it) >= self.timeout);
+        if self.packets.is_empty() && timed_out {
+            return self.flush();
+        }
+        self.state = State::Playing {
+            next: next.wrapping_add(self.step),
+        };
+        JitterOutput::Missing { frames: self.step }
+    }
+
+    fn flush(&mut self) -> JitterOutput<Dst> {
+        self.packets.clear();
+        self.state = State::Idle;
+        JitterOutput::Silence
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::voice::AudioBuilder;
+    use crate::voice::Serverbound;
+
+    fn packet(seq: u64, last: bool) -> VoicePacket<Serverbound> {
+        // A 20 ms CELT-only Opus frame
+        AudioBuilder::opus(vec![0xf8, seq as u8])
+            .seq(seq)
+            .last(last)
+            .build()
+            .unwrap()
+    }
+
+    fn seq(output: JitterOutput<Serverbound>) -> Option<u64> {
+        match output {
+            JitterOutput::Audio(VoicePacket::Audio { seq_num, .. }) => Some(seq_num),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn plays_in_order_after_the_delay() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut buffer = JitterBuffer::new().with_delay(Duration::from_millis(40));
+        assert_eq!(JitterOutput::Silence, buffer.pull(start));
+
+        buffer.push(packet(0, false), ms(0));
+        assert_eq!(JitterOutput::Silence, buffer.pull(ms(20)));
+        buffer.push(packet(4, false), ms(25));
+        buffer.push(packet(2, false), ms(30));
+        assert_eq!(
+            Some(SeqStatus::Duplicate),
+            buffer.push(packet(2, false), ms(35))
+        );
+        assert_eq!(3, buffer.len());
+
+        assert_eq!(Some(0), seq(buffer.pull(ms(40))));
+        assert_eq!(Some(2), seq(buffer.pull(ms(60))));
+        assert_eq!(Some(4), seq(buffer.pull(ms(80))));
+        // 6 is lost, 8 arrives in time, 10 too late
+        buffer.push(packet(8, false), ms(90));
+        assert_eq!(JitterOutput::Missing { frames: 2 }, buffer.pull(ms(100)));
+        assert_eq!(Some(8), seq(buffer.pull(ms(120))));
+        assert_eq!(JitterOutput::Missing { frames: 2 }, buffer.pull(ms(140)));
+        buffer.push(packet(10, false), ms(150));
+        assert!(buffer.is_empty());
+
+        buffer.push(packet(12, true), ms(155));
+        assert_eq!(Some(12), seq(buffer.pull(ms(160))));
+        assert_eq!(JitterOutput::Silence, buffer.pull(ms(180)));
+    }
+
+    #[test]
+    fn ends_transmissions_without_terminator() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut buffer = JitterBuffer::new()
+            .with_delay(Duration::from_millis(20))
+            .with_timeout(Duration::from_millis(100));
+        buffer.push(packet(0, false), ms(0));
+        assert_eq!(Some(0), seq(buffer.pull(ms(20))));
+        assert_eq!(JitterOutput::Missing { frames: 2 }, buffer.pull(ms(40)));
+        assert_eq!(JitterOutput::Silence, buffer.pull(ms(100)));
+        assert_eq!(JitterOutput::Silence, buffer.pull(ms(120)));
+
+        // The next transmission starts over
+        buffer.push(packet(0, false), ms(2000));
+        assert_eq!(JitterOutput::Silence, buffer.pull(ms(2010)));
+        assert_eq!(Some(0), seq(buffer.pull(ms(2020))));
+        assert_eq!(2, buffer.stats().snapshot(ms(2020)).received);
+    }
+
+    #[test]
+    fn adapts_the_delay_to_jitter() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut buffer = JitterBuffer::<Serverbound>::new()
+            .with_delay(Duration::from_millis(20))
+            .with_delay_bounds(Duration::from_millis(10), Duration::from_millis(50));
+        assert_eq!(Duration::from_millis(20), buffer.delay(start));
+        // Every other packet arrives 40 ms late
+        for i in 0..50 {
+            let late = if i % 2 == 1 { 40 } else { 0 };
+            buffer.push(packet(i * 2, false), ms(i * 20 + late));
+        }
+        assert_eq!(Duration::from_millis(50), buffer.delay(ms(1000)));
+    }
+}
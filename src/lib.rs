@@ -5,15 +5,41 @@
 #![deny(missing_docs)]
 #![warn(clippy::all)]
 
+pub use error::Error;
+#[cfg(feature = "prost")]
+pub use prost;
+#[cfg(feature = "protobuf")]
+pub use protobuf;
 pub use voice::Clientbound;
+pub use voice::Direction;
 pub use voice::Serverbound;
 
+#[cfg(feature = "arbitrary")]
+mod arbitrary;
+pub mod bandwidth;
+pub mod capture;
 pub mod control;
 #[cfg(feature = "openssl")]
 pub mod crypt;
+pub mod error;
+pub mod jitter;
+#[cfg(feature = "tracing")]
+pub mod logging;
+#[cfg(feature = "opus")]
+pub mod opus;
 pub mod ping;
+pub mod repacketize;
+pub mod seq;
+pub mod state;
+#[cfg(test)]
+mod test_util;
+pub mod text;
 pub mod varint;
+pub mod version;
 pub mod voice;
+pub mod voice_v2;
 
-#[cfg(not(any(feature = "asynchronous-codec", feature = "tokio-codec")))]
-compile_error!("need at least one of asynchronous-codec or tokio-codec features to compile");
+#[cfg(all(feature = "protobuf", feature = "prost"))]
+compile_error!("features protobuf and prost are mutually exclusive");
+#[cfg(not(any(feature = "protobuf", feature = "prost")))]
+compile_error!("need one of the protobuf or prost features to compile");
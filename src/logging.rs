@@ -0,0 +1,353 @@
+//! Codec wrapper which logs all packets passing through it via `tracing`
+
+use tracing::Level;
+
+use crate::control::ControlPacket;
+use crate::control::RawControlPacket;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+
+/// Default maximum length of the debug representation of logged packets.
+pub const DEFAULT_MAX_DEBUG_LEN: usize = 256;
+
+/// Packets which can be logged by a [LoggingCodec].
+pub trait LoggedItem {
+    /// Returns the internal name of the packet.
+    fn name(&self) -> &'static str;
+    /// Returns the control packet ID, if this is a control packet.
+    fn id(&self) -> Option<u16>;
+    /// Returns the amount of bytes this packet occupies once encoded.
+    fn encoded_len(&self) -> usize;
+    /// Returns whether this packet carries voice data (and is therefore sent very frequently).
+    fn is_voice(&self) -> bool;
+}
+
+impl LoggedItem for RawControlPacket {
+    fn name(&self) -> &'static str {
+        crate::control::name_for_id(self.id).unwrap_or("unknown")
+    }
+
+    fn id(&self) -> Option<u16> {
+        Some(self.id)
+    }
+
+    fn encoded_len(&self) -> usize {
+        6 + self.bytes.len()
+    }
+
+    fn is_voice(&self) -> bool {
+        self.id == crate::control::msgs::id::UDPTunnel
+    }
+}
+
+impl<Dst: VoicePacketDst> LoggedItem for ControlPacket<Dst> {
+    fn name(&self) -> &'static str {
+        self.name()
+    }
+
+    fn id(&self) -> Option<u16> {
+        Some(self.id())
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.encoded_len()
+    }
+
+    fn is_voice(&self) -> bool {
+        matches!(self, ControlPacket::UDPTunnel(_))
+    }
+}
+
+impl<Dst: VoicePacketDst> LoggedItem for VoicePacket<Dst> {
+    fn name(&self) -> &'static str {
+        match self {
+            VoicePacket::Ping { .. } => "Ping",
+            VoicePacket::Audio { .. } => "Audio",
+        }
+    }
+
+    fn id(&self) -> Option<u16> {
+        None
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.encoded_len()
+    }
+
+    fn is_voice(&self) -> bool {
+        true
+    }
+}
+
+/// A `Codec` wrapper which emits a `tracing` event for every packet encoded or decoded by the
+/// inner codec.
+///
+/// Voice packets (including ones tunneled over the control channel) are logged at
+/// [Level::TRACE], all other packets at [Level::DEBUG] unless configured otherwise.
+#[derive(Debug)]
+pub struct LoggingCodec<C> {
+    inner: C,
+    level: Level,
+    voice_level: Level,
+    max_debug_len: usize,
+}
+
+impl<C> LoggingCodec<C> {
+    /// Wraps the given codec.
+    pub fn new(inner: C) -> Self {
+        LoggingCodec {
+            inner,
+            level: Level::DEBUG,
+            voice_level: Level::TRACE,
+            max_debug_len: DEFAULT_MAX_DEBUG_LEN,
+        }
+    }
+
+    /// Changes the levels at which regular packets and voice packets are logged.
+    pub fn with_levels(mut self, level: Level, voice_level: Level) -> Self {
+        self.level = level;
+        self.voice_level = voice_level;
+        self
+    }
+
+    /// Changes the length after which the debug representation of packets is truncated.
+    pub fn with_max_debug_len(mut self, max_debug_len: usize) -> Self {
+        self.max_debug_len = max_debug_len;
+        self
+    }
+
+    /// Returns a reference to the wrapped codec.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped codec.
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.inner
+    }
+
+    /// Unwraps the inner codec.
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    fn log<T: LoggedItem + std::fmt::Debug>(&self, action: &'static str, item: &T) {
+        let level = if item.is_voice() {
+            self.voice_level
+        } else {
+            self.level
+        };
+
+        macro_rules! log_at {
+            ($($level:ident),*) => {
+                match level {
+                    $(
+                        Level::$level => {
+                            if !tracing::enabled!(Level::$level) {
+                                return;
+                            }
+                            tracing::event!(
+                                Level::$level,
+                                action,
+                                name = item.name(),
+                                id = item.id(),
+                                len = item.encoded_len(),
+                                packet = %truncated_debug(item, self.max_debug_len),
+                            );
+                        }
+                    )*
+                }
+            };
+        }
+        log_at!(ERROR, WARN, INFO, DEBUG, TRACE);
+    }
+}
+
+#[cfg_attr(
+    not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+    allow(dead_code)
+)]
+fn truncated_debug<T: std::fmt::Debug>(item: &T, max_len: usize) -> String {
+    let mut debug = format!("{:?}", item);
+    if debug.len() > max_len {
+        let mut end = max_len;
+        while !debug.is_char_boundary(end) {
+            end -= 1;
+        }
+        debug.truncate(end);
+        debug.push_str("...");
+    }
+    debug
+}
+
+#[cfg(feature = "tokio-codec")]
+impl<C> tokio_util::codec::Decoder for LoggingCodec<C>
+where
+    C: tokio_util::codec::Decoder,
+    C::Item: LoggedItem + std::fmt::Debug,
+{
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let item = self.inner.decode(src)?;
+        if let Some(item) = &item {
+            self.log("decode", item);
+        }
+        Ok(item)
+    }
+
+    fn decode_eof(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let item = self.inner.decode_eof(src)?;
+        if let Some(item) = &item {
+            self.log("decode", item);
+        }
+        Ok(item)
+    }
+}
+
+#[cfg(feature = "asynchronous-codec")]
+impl<C> asynchronous_codec::Decoder for LoggingCodec<C>
+where
+    C: asynchronous_codec::Decoder,
+    C::Item: LoggedItem + std::fmt::Debug,
+{
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let item = self.inner.decode(src)?;
+        if let Some(item) = &item {
+            self.log("decode", item);
+        }
+        Ok(item)
+    }
+
+    fn decode_eof(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let item = self.inner.decode_eof(src)?;
+        if let Some(item) = &item {
+            self.log("decode", item);
+        }
+        Ok(item)
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl<C, T> tokio_util::codec::Encoder<T> for LoggingCodec<C>
+where
+    C: tokio_util::codec::Encoder<T>,
+    T: LoggedItem + std::fmt::Debug,
+{
+    type Error = C::Error;
+
+    fn encode(&mut self, item: T, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        self.log("encode", &item);
+        self.inner.encode(item, dst)
+    }
+}
+
+#[cfg(feature = "asynchronous-codec")]
+impl<C> asynchronous_codec::Encoder for LoggingCodec<C>
+where
+    C: asynchronous_codec::Encoder,
+    C::Item: LoggedItem + std::fmt::Debug,
+{
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+        self.log("encode", &item);
+        self.inner.encode(item, dst)
+    }
+}
+
+#[cfg(all(test, feature = "tokio-codec"))]
+mod test {
+    use std::fmt;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+    use tokio_util::codec::Encoder;
+    use tracing::field::Field;
+    use tracing::field::Visit;
+    use tracing::span;
+    use tracing::Event;
+    use tracing::Metadata;
+
+    use super::*;
+    use crate::control::msgs;
+    use crate::control::ClientControlCodec;
+    use crate::control::ServerControlCodec;
+    use crate::voice::Serverbound;
+
+    /// Subscriber which records every event as its level followed by its fields.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    struct FieldWriter<'a>(&'a mut String);
+
+    impl Visit for FieldWriter<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut line = event.metadata().level().to_string();
+            event.record(&mut FieldWriter(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    #[test]
+    fn logs_packets_passing_through() {
+        let recorder = Recorder::default();
+        let lines = tracing::subscriber::with_default(recorder.clone(), || {
+            let mut client = LoggingCodec::new(ClientControlCodec::new()).with_max_debug_len(10);
+            let mut server = LoggingCodec::new(ServerControlCodec::new()).with_max_debug_len(10);
+            let packet = ControlPacket::<Serverbound>::from(msgs::Ping::default());
+
+            let mut buf = BytesMut::new();
+            client.encode(packet.clone(), &mut buf).unwrap();
+            let mut expected = BytesMut::new();
+            ClientControlCodec::new()
+                .encode(packet.clone(), &mut expected)
+                .unwrap();
+            assert_eq!(expected, buf);
+
+            assert_eq!(Some(packet), server.decode(&mut buf).unwrap());
+            assert_eq!(None, server.decode(&mut buf).unwrap());
+            recorder.0.lock().unwrap().clone()
+        });
+        assert_eq!(
+            vec![
+                "DEBUG action=\"encode\" name=\"Ping\" id=3 len=6 packet=Ping(Ping ...",
+                "DEBUG action=\"decode\" name=\"Ping\" id=3 len=6 packet=Ping(Ping ...",
+            ],
+            lines
+        );
+    }
+}
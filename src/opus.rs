@@ -0,0 +1,439 @@
+//! Encoding and decoding Opus audio, requires the `opus` feature
+//!
+//! Mumble sends mono Opus audio sampled at 48 kHz. An [OpusEncoderPipeline] turns PCM samples
+//! into numbered serverbound packets, an [OpusDecoderPipeline] turns what a
+//! [JitterBuffer](crate::jitter::JitterBuffer) hands out back into samples, concealing lost
+//! packets. Neither sends nor receives anything, so they work with any transport.
+
+use std::time::Duration;
+
+use audiopus::coder::Decoder;
+use audiopus::coder::Encoder;
+use audiopus::coder::GenericCtl;
+use audiopus::packet::Packet;
+use audiopus::Application;
+use audiopus::Bitrate;
+use audiopus::Channels;
+use audiopus::MutSignals;
+use audiopus::SampleRate;
+use bytes::Bytes;
+
+use crate::bandwidth;
+use crate::bandwidth::Transport;
+use crate::error::Error;
+use crate::error::VoiceError;
+use crate::jitter::JitterOutput;
+use crate::voice::AudioBuilder;
+use crate::voice::Position;
+use crate::voice::SequenceCounter;
+use crate::voice::Serverbound;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+use crate::voice::VoicePacketPayload;
+use crate::voice::VoiceTargetId;
+use crate::voice::MAX_OPUS_FRAME_LEN;
+use crate::voice::SEQUENCE_FRAME_DURATION;
+
+/// The sample rate of Mumble's audio in Hz.
+pub const SAMPLE_RATE: u32 = 48_000;
+
+/// The amount of samples in a frame of 10 ms.
+pub const FRAME_SAMPLES: usize = 480;
+
+/// The most frames of 10 ms an Opus packet may contain.
+pub const MAX_PACKET_FRAMES: u32 = 12;
+
+/// Encodes PCM samples into serverbound Opus packets.
+///
+/// Samples are mono at [SAMPLE_RATE]. They are collected until there are enough for a packet,
+/// so they may be pushed in chunks of any size. Packets are numbered by a [SequenceCounter]
+/// which starts over with every transmission, and [finish](Self::finish) marks the end of one.
+///
+/// Like the official client, the encoder uses a constant bitrate, which can be fitted into the
+/// server's bandwidth limit using [set_bandwidth_limit](Self::set_bandwidth_limit).
+#[derive(Debug)]
+pub struct OpusEncoderPipeline {
+    encoder: Encoder,
+    preferred_bitrate: u32,
+    preferred_frames: u32,
+    limit: Option<(u32, Transport)>,
+    bitrate: u32,
+    frames: u32,
+    counter: SequenceCounter,
+    target: VoiceTargetId,
+    position: Option<Position>,
+    pending: Vec<i16>,
+    buf: Vec<u8>,
+}
+
+impl OpusEncoderPipeline {
+    /// Creates an encoder using the official client's defaults,
+    /// [DEFAULT_BITRATE](bandwidth::DEFAULT_BITRATE) and
+    /// [DEFAULT_FRAMES_PER_PACKET](bandwidth::DEFAULT_FRAMES_PER_PACKET), sending to the
+    /// [Normal](VoiceTargetId::Normal) target.
+    pub fn new() -> Result<Self, Error> {
+        let mut encoder = Encoder::new(SampleRate::Hz48000, Channels::Mono, Application::Voip)?;
+        encoder.set_vbr(false)?;
+        let mut pipeline = OpusEncoderPipeline {
+            encoder,
+            preferred_bitrate: bandwidth::DEFAULT_BITRATE,
+            preferred_frames: bandwidth::DEFAULT_FRAMES_PER_PACKET,
+            limit: None,
+            bitrate: 0,
+            frames: 0,
+            counter: SequenceCounter::new(SEQUENCE_FRAME_DURATION),
+            target: VoiceTargetId::Normal,
+            position: None,
+            pending: Vec::new(),
+            buf: vec![0; MAX_OPUS_FRAME_LEN],
+        };
+        pipeline.configure()?;
+        Ok(pipeline)
+    }
+
+    /// Returns the bitrate used, which may be lower than the configured one to fit the
+    /// bandwidth limit.
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate
+    }
+
+    /// Returns the amount of 10 ms frames per packet, which may be higher than the configured
+    /// one to fit the bandwidth limit.
+    pub fn frames_per_packet(&self) -> u32 {
+        self.frames
+    }
+
+    /// Sets the bitrate in bits per second.
+    pub fn set_bitrate(&mut self, bitrate: u32) -> Result<(), Error> {
+        self.preferred_bitrate = bitrate;
+        self.configure()
+    }
+
+    /// Sets the amount of 10 ms frames per packet.
+    ///
+    /// Opus packets last 10, 20, 40 or 60 ms, so this is rounded up to 1, 2, 4 or 6 frames.
+    /// Changing it in the middle of a transmission applies to samples not yet pushed.
+    pub fn set_frames_per_packet(&mut self, frames: u32) -> Result<(), Error> {
+        self.preferred_frames = match frames {
+            0..=1 => 1,
+            2 => 2,
+            3..=4 => 4,
+            _ => 6,
+        };
+        self.configure()
+    }
+
+    /// Fits the bitrate and frames per packet into the server's bandwidth limit the way the
+    /// official client does, see [bandwidth::adjust]. `None` removes the limit.
+    pub fn set_bandwidth_limit(
+        &mut self,
+        limit: Option<u32>,
+        transport: Transport,
+    ) -> Result<(), Error> {
+        self.limit = limit.map(|limit| (limit, transport));
+        self.configure()
+    }
+
+    /// Sets the target of the following packets.
+    pub fn set_target(&mut self, target: VoiceTargetId) {
+        self.target = target;
+    }
+
+    /// Sets the position attached to the following packets.
+    pub fn set_position(&mut self, position: Option<Position>) {
+        self.position = position;
+    }
+
+    /// Enables forward error correction for the given expected packet loss in percent, which
+    /// lets receivers [recover](OpusDecoderPipeline::recover) lost packets. 0 disables it.
+    pub fn set_expected_loss(&mut self, percent: u8) -> Result<(), Error> {
+        self.encoder.set_inband_fec(percent > 0)?;
+        self.encoder.set_packet_loss_perc(percent.min(100))?;
+        Ok(())
+    }
+
+    fn configure(&mut self) -> Result<(), Error> {
+        let (bitrate, frames) = match self.limit {
+            Some((limit, transport)) => bandwidth::adjust(
+                limit,
+                self.preferred_bitrate,
+                self.preferred_frames,
+                transport,
+            ),
+            None => (self.preferred_bitrate, self.preferred_frames),
+        };
+        if bitrate != self.bitrate {
+            let bits = i32::try_from(bitrate).unwrap_or(i32::MAX);
+            self.encoder.set_bitrate(Bitrate::BitsPerSecond(bits))?;
+            self.bitrate = bitrate;
+        }
+        if frames != self.frames {
+            let duration = SEQUENCE_FRAME_DURATION * frames;
+            self.counter = SequenceCounter::new(duration).with_start(self.counter.peek());
+            self.frames = frames;
+        }
+        Ok(())
+    }
+
+    /// Returns the duration of audio waiting for more samples to complete a packet.
+    pub fn pending(&self) -> Duration {
+        SEQUENCE_FRAME_DURATION * (self.pending.len() / FRAME_SAMPLES) as u32
+    }
+
+    /// Adds samples and returns the packets completed by them.
+    pub fn push(&mut self, pcm: &[i16]) -> Result<Vec<VoicePacket<Serverbound>>, Error> {
+        self.pending.extend_from_slice(pcm);
+        let samples = self.frames as usize * FRAME_SAMPLES;
+        let mut packets = Vec::with_capacity(self.pending.len() / samples);
+        let mut start = 0;
+        while self.pending.len() - start >= samples {
+            packets.push(self.encode(start, samples, false)?);
+            start += samples;
+        }
+        self.pending.drain(..start);
+        Ok(packets)
+    }
+
+    /// Ends the transmission, returning its last packet.
+    ///
+    /// Pending samples are padded with silence. Without any, a packet of silence carries the
+    /// terminator. The next pushed samples start a new transmission.
+    pub fn finish(&mut self) -> Result<VoicePacket<Serverbound>, Error> {
+        let samples = self.frames as usize * FRAME_SAMPLES;
+        self.pending.resize(samples, 0);
+        let packet = self.encode(0, samples, true);
+        self.pending.clear();
+        self.counter = SequenceCounter::new(SEQUENCE_FRAME_DURATION * self.frames);
+        self.encoder.reset_state()?;
+        packet
+    }
+
+    fn encode(
+        &mut self,
+        start: usize,
+        samples: usize,
+        last: bool,
+    ) -> Result<VoicePacket<Serverbound>, Error> {
+        let len = self
+            .encoder
+            .encode(&self.pending[start..start + samples], &mut self.buf)?;
+        let mut builder = AudioBuilder::opus(Bytes::copy_from_slice(&self.buf[..len]))
+            .target(self.target)
+            .seq(self.counter.advance())
+            .last(last);
+        if let Some(position) = self.position {
+            builder = builder.position(position);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Decodes the packets of a single user into PCM samples.
+///
+/// Samples are mono at [SAMPLE_RATE]. Each call returns the samples for the output of a
+/// [JitterBuffer](crate::jitter::JitterBuffer), using Opus' packet loss concealment for missing
+/// packets.
+#[derive(Debug)]
+pub struct OpusDecoderPipeline {
+    decoder: Decoder,
+    pcm: Vec<i16>,
+}
+
+impl OpusDecoderPipeline {
+    /// Creates a decoder.
+    pub fn new() -> Result<Self, Error> {
+        Ok(OpusDecoderPipeline {
+            decoder: Decoder::new(SampleRate::Hz48000, Channels::Mono)?,
+            pcm: vec![0; MAX_PACKET_FRAMES as usize * FRAME_SAMPLES],
+        })
+    }
+
+    /// Returns the samples to play for the given output of a jitter buffer.
+    ///
+    /// Audio packets are decoded, empty for an empty terminator frame. For missing packets, the
+    /// audio is concealed. Silence yields no samples. Packets which are not Opus are rejected
+    /// with [VoiceError::UnsupportedCodec].
+    pub fn decode<Dst: VoicePacketDst>(
+        &mut self,
+        output: &JitterOutput<Dst>,
+    ) -> Result<&[i16], Error> {
+        match output {
+            JitterOutput::Audio(packet) => self.decode_packet(packet),
+            JitterOutput::Missing { frames } => self.conceal(*frames),
+            JitterOutput::Silence => Ok(&[]),
+        }
+    }
+
+    /// Decodes a single packet, empty for pings and empty frames.
+    ///
+    /// After the last frame of a transmission, the decoder starts over.
+    pub fn decode_packet<Dst: VoicePacketDst>(
+        &mut self,
+        packet: &VoicePacket<Dst>,
+    ) -> Result<&[i16], Error> {
+        let VoicePacket::Audio { payload, .. } = packet else {
+            return Ok(&[]);
+        };
+        let VoicePacketPayload::Opus(frame, last) = payload else {
+            return Err(VoiceError::UnsupportedCodec.into());
+        };
+        if frame.is_empty() {
+            if *last {
+                self.decoder.reset_state()?;
+            }
+            return Ok(&[]);
+        }
+        let input = Packet::try_from(&frame[..])?;
+        let output = MutSignals::try_from(&mut self.pcm[..])?;
+        let len = self.decoder.decode(Some(input), output, false)?;
+        if *last {
+            self.decoder.reset_state()?;
+        }
+        Ok(&self.pcm[..len])
+    }
+
+    /// Returns `frames` frames of 10 ms concealing lost audio.
+    pub fn conceal(&mut self, frames: u64) -> Result<&[i16], Error> {
+        let samples = frames as usize * FRAME_SAMPLES;
+        if self.pcm.len() < samples {
+            self.pcm.resize(samples, 0);
+        }
+        let output = MutSignals::try_from(&mut self.pcm[..samples])?;
+        let len = self.decoder.decode(None, output, false)?;
+        Ok(&self.pcm[..len])
+    }
+
+    /// Returns `frames` frames of 10 ms preceding the given packet, recovered from the forward
+    /// error correction data it carries.
+    ///
+    /// This is better than [conceal](Self::conceal) if the packet after a lost one is already
+    /// at hand, provided its sender enabled forward error correction, see
+    /// [OpusEncoderPipeline::set_expected_loss].
+    pub fn recover<Dst: VoicePacketDst>(
+        &mut self,
+        next: &VoicePacket<Dst>,
+        frames: u64,
+    ) -> Result<&[i16], Error> {
+        let VoicePacket::Audio {
+            payload: VoicePacketPayload::Opus(frame, _),
+            ..
+        } = next
+        else {
+            return self.conceal(frames);
+        };
+        if frame.is_empty() {
+            return self.conceal(frames);
+        }
+        let samples = frames as usize * FRAME_SAMPLES;
+        if self.pcm.len() < samples {
+            self.pcm.resize(samples, 0);
+        }
+        let input = Packet::try_from(&frame[..])?;
+        let output = MutSignals::try_from(&mut self.pcm[..samples])?;
+        let len = self.decoder.decode(Some(input), output, true)?;
+        Ok(&self.pcm[..len])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tone(samples: usize) -> Vec<i16> {
+        (0..samples)
+            .map(|i| {
+                ((i as f32 * 440.0 * std::f32::consts::TAU / SAMPLE_RATE as f32).sin() * 8000.0)
+                    as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encodes_numbered_packets_and_terminates() {
+        let mut encoder = OpusEncoderPipeline::new().unwrap();
+        // 50 ms, two 20 ms packets and 10 ms pending
+        let packets = encoder.push(&tone(5 * FRAME_SAMPLES)).unwrap();
+        assert_eq!(2, packets.len());
+        assert_eq!(Duration::from_millis(10), encoder.pending());
+        let seqs: Vec<_> = packets
+            .iter()
+            .map(|packet| match packet {
+                VoicePacket::Audio {
+                    seq_num, payload, ..
+                } => {
+                    assert!(!payload.is_terminator());
+                    assert_eq!(2, payload.frame_count());
+                    *seq_num
+                }
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(vec![0, 2], seqs);
+
+        let last = encoder.finish().unwrap();
+        let VoicePacket::Audio {
+            seq_num, payload, ..
+        } = &last
+        else {
+            unreachable!()
+        };
+        assert_eq!(4, *seq_num);
+        assert!(payload.is_terminator());
+
+        // The next transmission starts over
+        let packets = encoder.push(&tone(2 * FRAME_SAMPLES)).unwrap();
+        assert!(matches!(packets[0], VoicePacket::Audio { seq_num: 0, .. }));
+    }
+
+    #[test]
+    fn fits_bandwidth_limit() {
+        let mut encoder = OpusEncoderPipeline::new().unwrap();
+        encoder
+            .set_bandwidth_limit(Some(32_000), Transport::Udp)
+            .unwrap();
+        assert_eq!(
+            (24_000, 4),
+            (encoder.bitrate(), encoder.frames_per_packet())
+        );
+        let packets = encoder.push(&tone(8 * FRAME_SAMPLES)).unwrap();
+        assert!(matches!(packets[1], VoicePacket::Audio { seq_num: 4, .. }));
+
+        encoder.set_bandwidth_limit(None, Transport::Udp).unwrap();
+        assert_eq!(
+            (40_000, 2),
+            (encoder.bitrate(), encoder.frames_per_packet())
+        );
+    }
+
+    #[test]
+    fn decodes_and_conceals() {
+        let mut encoder = OpusEncoderPipeline::new().unwrap();
+        encoder.set_expected_loss(10).unwrap();
+        let packets = encoder.push(&tone(6 * FRAME_SAMPLES)).unwrap();
+        let mut decoder = OpusDecoderPipeline::new().unwrap();
+
+        let output = JitterOutput::Audio(packets[0].clone());
+        assert_eq!(2 * FRAME_SAMPLES, decoder.decode(&output).unwrap().len());
+        let missing = JitterOutput::<Serverbound>::Missing { frames: 2 };
+        assert_eq!(2 * FRAME_SAMPLES, decoder.decode(&missing).unwrap().len());
+        assert_eq!(
+            2 * FRAME_SAMPLES,
+            decoder.recover(&packets[2], 2).unwrap().len()
+        );
+        let silence = JitterOutput::<Serverbound>::Silence;
+        assert!(decoder.decode(&silence).unwrap().is_empty());
+
+        let celt = VoicePacket::<Serverbound>::Audio {
+            _dst: std::marker::PhantomData,
+            target: 0,
+            session_id: (),
+            seq_num: 0,
+            payload: VoicePacketPayload::CeltAlpha(vec![Bytes::from_static(&[1])]),
+            position_info: None,
+        };
+        assert!(matches!(
+            decoder.decode_packet(&celt),
+            Err(Error::MalformedVoice(VoiceError::UnsupportedCodec))
+        ));
+    }
+}
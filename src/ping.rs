@@ -7,6 +7,25 @@
 //!
 //! Both packets are of fixed size and can be converted to/from `u8` arrays/slices via
 //! the respective `From`/`TryFrom` impls.
+//!
+//! Once connected, clients instead send [VoicePacket::Ping]s via the voice channel, which the
+//! server echoes back. [UdpPingTracker] measures their round-trip times and tells when the
+//! voice channel stopped working.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::control::ping_report::RttStats;
+use crate::voice;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+
+/// Echoes of pings sent longer ago than this are ignored by default.
+pub const DEFAULT_PING_WINDOW: Duration = Duration::from_secs(10);
+
+/// Without an echo for this long, the voice channel is considered broken by default.
+pub const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// A ping packet sent to the server.
 #[derive(Clone, Debug, PartialEq)]
@@ -135,3 +154,179 @@ impl From<PongPacket> for [u8; 24] {
         ]
     }
 }
+
+/// Tracks voice channel pings and their echoes.
+///
+/// Round-trip times are fed into [RttStats], which fill in the UDP fields of the control
+/// channel's `Ping` via [PingStats](crate::control::ping_report::PingStats). Echoes of pings
+/// which were not sent, were already answered or are older than the window are ignored.
+#[derive(Clone, Debug)]
+pub struct UdpPingTracker {
+    window: Duration,
+    timeout: Duration,
+    /// Timestamps of pings awaiting their echo and when they were sent, oldest first.
+    sent: VecDeque<(u64, Instant)>,
+    /// When the first ping since the last echo was sent.
+    waiting_since: Option<Instant>,
+    stats: RttStats,
+}
+
+impl Default for UdpPingTracker {
+    fn default() -> Self {
+        UdpPingTracker {
+            window: DEFAULT_PING_WINDOW,
+            timeout: DEFAULT_UDP_TIMEOUT,
+            sent: VecDeque::new(),
+            waiting_since: None,
+            stats: RttStats::default(),
+        }
+    }
+}
+
+impl UdpPingTracker {
+    /// Creates a tracker with the [DEFAULT_PING_WINDOW] and [DEFAULT_UDP_TIMEOUT].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how long pings wait for their echo.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Sets how long without echoes the voice channel is considered broken.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the round-trip times measured so far.
+    pub fn stats(&self) -> &RttStats {
+        &self.stats
+    }
+
+    /// Returns a ping to be sent `now` and records it.
+    pub fn ping<Dst: VoicePacketDst>(&mut self, now: Instant) -> VoicePacket<Dst> {
+        let timestamp = voice::monotonic_timestamp(now);
+        self.sent(timestamp, now);
+        VoicePacket::Ping { timestamp }
+    }
+
+    /// Records a ping with the given timestamp sent at `now`.
+    pub fn sent(&mut self, timestamp: u64, now: Instant) {
+        self.expire(now);
+        self.sent.push_back((timestamp, now));
+        self.waiting_since.get_or_insert(now);
+    }
+
+    /// Handles a packet received at `now`, returning the round-trip time if it is the echo of
+    /// a ping.
+    pub fn receive<Dst: VoicePacketDst>(
+        &mut self,
+        packet: &VoicePacket<Dst>,
+        now: Instant,
+    ) -> Option<Duration> {
+        self.echo(packet.ping_timestamp()?, now)
+    }
+
+    /// Handles the echo of the ping with the given timestamp received at `now`, returning the
+    /// round-trip time unless the echo is ignored.
+    pub fn echo(&mut self, timestamp: u64, now: Instant) -> Option<Duration> {
+        self.expire(now);
+        let index = self.sent.iter().position(|(sent, _)| *sent == timestamp)?;
+        let (_, sent_at) = self.sent.remove(index)?;
+        let rtt = now.saturating_duration_since(sent_at);
+        self.stats.add(rtt);
+        self.waiting_since = None;
+        Some(rtt)
+    }
+
+    /// Returns whether pings were sent for the timeout without any of them being echoed.
+    ///
+    /// Clients should then tunnel their voice through the control channel until echoes arrive
+    /// again.
+    pub fn is_timed_out(&self, now: Instant) -> bool {
+        self.waiting_since
+            .is_some_and(|since| now.saturating_duration_since(since) >= self.timeout)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((_, sent_at)) = self.sent.front() {
+            if now.saturating_duration_since(*sent_at) <= self.window {
+                break;
+            }
+            self.sent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::voice::Clientbound;
+    use crate::voice::Serverbound;
+
+    #[test]
+    fn measures_round_trip_times() {
+        // Timestamps count from the first ping of the process, which may be sent by other tests
+        let start = Instant::now() + Duration::from_secs(1);
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut tracker = UdpPingTracker::new();
+        let first: VoicePacket<Serverbound> = tracker.ping(ms(0));
+        let second: VoicePacket<Serverbound> = tracker.ping(ms(10));
+        assert!(first.is_ping());
+        assert!(first.ping_timestamp() < second.ping_timestamp());
+
+        let echo = |packet: &VoicePacket<Serverbound>| VoicePacket::<Clientbound>::Ping {
+            timestamp: packet.ping_timestamp().unwrap(),
+        };
+        assert_eq!(
+            Some(Duration::from_millis(30)),
+            tracker.receive(&echo(&second), ms(40))
+        );
+        assert_eq!(
+            Some(Duration::from_millis(50)),
+            tracker.receive(&echo(&first), ms(50))
+        );
+        assert_eq!(2, tracker.stats().count());
+        assert_eq!(40.0, tracker.stats().average_ms());
+        assert_eq!(100.0, tracker.stats().variance_ms());
+    }
+
+    #[test]
+    fn ignores_stale_echoes() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let mut tracker = UdpPingTracker::new().with_window(Duration::from_secs(2));
+        tracker.sent(1, secs(0));
+        tracker.sent(2, secs(1));
+        // Never sent
+        assert_eq!(None, tracker.echo(3, secs(1)));
+        // Sent longer ago than the window
+        assert_eq!(None, tracker.echo(1, secs(3)));
+        assert_eq!(Some(Duration::from_secs(2)), tracker.echo(2, secs(3)));
+        // Already answered
+        assert_eq!(None, tracker.echo(2, secs(3)));
+        assert_eq!(1, tracker.stats().count());
+        assert_eq!(2000.0, tracker.stats().average_ms());
+    }
+
+    #[test]
+    fn times_out_without_echoes() {
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let mut tracker = UdpPingTracker::new().with_timeout(Duration::from_secs(5));
+        assert!(!tracker.is_timed_out(secs(100)));
+
+        tracker.sent(1, secs(0));
+        tracker.sent(2, secs(4));
+        assert!(!tracker.is_timed_out(secs(4)));
+        assert!(tracker.is_timed_out(secs(5)));
+
+        tracker.echo(2, secs(6));
+        assert!(!tracker.is_timed_out(secs(20)));
+        tracker.sent(3, secs(20));
+        assert!(tracker.is_timed_out(secs(25)));
+    }
+}
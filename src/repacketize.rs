@@ -0,0 +1,500 @@
+//! Regrouping audio frames into packets of a different duration
+//!
+//! Packets may contain several frames of audio: the legacy codecs length-prefix each frame and
+//! Opus packets may hold up to 120 ms of frames, see RFC 6716 section 3.2. [repacketize] moves
+//! these frames between packets without decoding them, e.g. to trade latency for overhead when
+//! relaying or replaying audio.
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
+
+use crate::error::VoiceError;
+use crate::voice::AudioCodec;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+use crate::voice::VoicePacketPayload;
+use crate::voice::MAX_OPUS_FRAME_LEN;
+
+/// The duration of a legacy frame and of a sequence number in microseconds.
+const FRAME_US: u64 = 10_000;
+
+/// The longest duration an Opus packet may have in microseconds.
+const MAX_OPUS_PACKET_US: u64 = 120_000;
+
+/// The most frames an Opus packet may have.
+const MAX_OPUS_FRAMES: usize = 48;
+
+/// Regroups the frames of audio packets into packets of `frames_per_packet` frames of 10 ms.
+///
+/// Sequence numbers advance by the duration of the frames before each new packet's first frame,
+/// so gaps between the given packets are kept. Frames are never merged across such a gap, nor
+/// across packets of different speakers or targets, and a transmission's terminator flag ends up
+/// only on the packet containing its final frame. Each new packet has the positional data of the
+/// packet its first frame came from. Pings are passed through.
+///
+/// Opus frames are merged into a single packet as long as they were encoded with the same
+/// settings and last at most 120 ms in total. They are not split further, so a packet with a
+/// single 60 ms frame stays that long. Empty Opus packets, which may end a transmission, are
+/// passed through.
+///
+/// A packet using a different codec than the one before it yields
+/// [VoiceError::MixedCodecs] instead, a malformed Opus packet yields
+/// [VoiceError::MalformedOpus]. Both are dropped after pending frames have been returned.
+pub fn repacketize<Dst, I>(packets: I, frames_per_packet: usize) -> Repacketizer<Dst, I::IntoIter>
+where
+    Dst: VoicePacketDst,
+    I: IntoIterator<Item = VoicePacket<Dst>>,
+{
+    Repacketizer {
+        packets: packets.into_iter(),
+        duration_us: frames_per_packet.max(1) as u64 * FRAME_US,
+        codec: None,
+        next_seq: None,
+        pending: None,
+        ready: VecDeque::new(),
+    }
+}
+
+/// The iterator returned by [repacketize].
+#[derive(Clone, Debug)]
+pub struct Repacketizer<Dst: VoicePacketDst, I> {
+    packets: I,
+    duration_us: u64,
+    codec: Option<AudioCodec>,
+    next_seq: Option<u64>,
+    pending: Option<Pending<Dst>>,
+    ready: VecDeque<Result<VoicePacket<Dst>, VoiceError>>,
+}
+
+/// The frames of the packet being assembled.
+#[derive(Clone, Debug)]
+struct Pending<Dst: VoicePacketDst> {
+    target: u8,
+    session_id: Dst::SessionId,
+    seq_num: u64,
+    position_info: Option<Bytes>,
+    codec: AudioCodec,
+    /// The TOC byte of Opus frames without the frame count code.
+    toc: u8,
+    frames: Vec<Bytes>,
+    duration_us: u64,
+}
+
+impl<Dst: VoicePacketDst> Pending<Dst> {
+    fn fits(&self, toc: u8, frame: &[u8], frame_us: u64) -> bool {
+        if self.codec != AudioCodec::Opus {
+            return true;
+        }
+        // At most two bytes of length per frame, plus TOC and frame count
+        let len = 2 + self.frames.iter().map(|it| it.len() + 2).sum::<usize>();
+        self.toc == toc
+            && self.frames.len() < MAX_OPUS_FRAMES
+            && self.duration_us + frame_us <= MAX_OPUS_PACKET_US
+            && len + frame.len() + 2 <= MAX_OPUS_FRAME_LEN
+    }
+
+    fn into_packet(self, last: bool) -> VoicePacket<Dst> {
+        let payload = match self.codec {
+            AudioCodec::CeltAlpha => VoicePacketPayload::CeltAlpha(self.frames),
+            AudioCodec::CeltBeta => VoicePacketPayload::CeltBeta(self.frames),
+            AudioCodec::Speex => VoicePacketPayload::Speex(self.frames),
+            AudioCodec::Opus => VoicePacketPayload::Opus(write_opus(self.toc, &self.frames), last),
+        };
+        VoicePacket::Audio {
+            _dst: PhantomData,
+            target: self.target,
+            session_id: self.session_id,
+            seq_num: self.seq_num,
+            payload,
+            position_info: self.position_info,
+        }
+    }
+}
+
+impl<Dst: VoicePacketDst, I: Iterator<Item = VoicePacket<Dst>>> Iterator for Repacketizer<Dst, I> {
+    type Item = Result<VoicePacket<Dst>, VoiceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.ready.pop_front() {
+                return Some(item);
+            }
+            match self.packets.next() {
+                Some(packet) => self.push(packet),
+                None => {
+                    self.flush(false);
+                    return self.ready.pop_front();
+                }
+            }
+        }
+    }
+}
+
+impl<Dst: VoicePacketDst, I> Repacketizer<Dst, I> {
+    fn push(&mut self, packet: VoicePacket<Dst>) {
+        let (target, session_id, seq_num, payload, position_info) = match packet {
+            VoicePacket::Audio {
+                target,
+                session_id,
+                seq_num,
+                payload,
+                position_info,
+                ..
+            } => (target, session_id, seq_num, payload, position_info),
+            ping => {
+                self.flush(false);
+                self.ready.push_back(Ok(ping));
+                return;
+            }
+        };
+
+        let codec = payload.codec();
+        if matches!(self.codec.replace(codec), Some(previous) if previous != codec) {
+            self.fail(VoiceError::MixedCodecs);
+            return;
+        }
+        let continues = self.next_seq == Some(seq_num)
+            && self
+                .pending
+                .as_ref()
+                .is_some_and(|it| it.target == target && it.session_id == session_id);
+        if !continues {
+            self.flush(false);
+        }
+        self.next_seq = Some(seq_num.wrapping_add(payload.frame_count()));
+
+        let last = payload.is_terminator();
+        let (toc, frames, frame_us) = match payload {
+            VoicePacketPayload::Opus(data, _) if data.is_empty() => {
+                self.flush(false);
+                self.ready.push_back(Ok(VoicePacket::Audio {
+                    _dst: PhantomData,
+                    target,
+                    session_id,
+                    seq_num,
+                    payload: VoicePacketPayload::Opus(data, last),
+                    position_info,
+                }));
+                return;
+            }
+            VoicePacketPayload::Opus(data, _) => match read_opus(&data) {
+                Ok((toc, frames)) => (toc, frames, opus_frame_us(toc)),
+                Err(err) => {
+                    self.fail(err);
+                    return;
+                }
+            },
+            VoicePacketPayload::CeltAlpha(frames)
+            | VoicePacketPayload::CeltBeta(frames)
+            | VoicePacketPayload::Speex(frames) => (0, frames, FRAME_US),
+        };
+
+        let count = frames.len();
+        let mut offset_us = 0;
+        for (i, frame) in frames.into_iter().enumerate() {
+            if !self
+                .pending
+                .as_ref()
+                .is_some_and(|it| it.fits(toc, &frame, frame_us))
+            {
+                self.flush(false);
+            }
+            let pending = self.pending.get_or_insert_with(|| Pending {
+                target,
+                session_id: session_id.clone(),
+                seq_num: seq_num.wrapping_add(offset_us / FRAME_US),
+                position_info: position_info.clone(),
+                codec,
+                toc,
+                frames: Vec::new(),
+                duration_us: 0,
+            });
+            pending.frames.push(frame);
+            pending.duration_us += frame_us;
+            offset_us += frame_us;
+            if pending.duration_us >= self.duration_us {
+                self.flush(last && i + 1 == count);
+            }
+        }
+        if last {
+            self.flush(true);
+        }
+    }
+
+    fn flush(&mut self, last: bool) {
+        if let Some(pending) = self.pending.take() {
+            self.ready.push_back(Ok(pending.into_packet(last)));
+        }
+    }
+
+    fn fail(&mut self, err: VoiceError) {
+        self.flush(false);
+        self.next_seq = None;
+        self.ready.push_back(Err(err));
+    }
+}
+
+/// Returns the duration of the frames with the given TOC byte in microseconds.
+fn opus_frame_us(toc: u8) -> u64 {
+    let config = usize::from(toc >> 3);
+    match config {
+        0..=11 => [10_000, 20_000, 40_000, 60_000][config % 4],
+        12..=15 => [10_000, 20_000][config % 2],
+        _ => [2_500, 5_000, 10_000, 20_000][config % 4],
+    }
+}
+
+/// Splits an Opus packet into its frames, returning them with the TOC byte without the frame
+/// count code.
+fn read_opus(packet: &Bytes) -> Result<(u8, Vec<Bytes>), VoiceError> {
+    let toc = *packet.first().ok_or(VoiceError::MalformedOpus)?;
+    let frames = match toc & 0x03 {
+        0 => vec![packet.slice(1..)],
+        1 => {
+            if packet.len().is_multiple_of(2) {
+                return Err(VoiceError::MalformedOpus);
+            }
+            let middle = 1 + packet.len() / 2;
+            vec![packet.slice(1..middle), packet.slice(middle..)]
+        }
+        2 => {
+            let mut pos = 1;
+            let len = read_opus_len(packet, &mut pos)?;
+            if pos + len > packet.len() {
+                return Err(VoiceError::MalformedOpus);
+            }
+            vec![packet.slice(pos..pos + len), packet.slice(pos + len..)]
+        }
+        _ => {
+            let header = *packet.get(1).ok_or(VoiceError::MalformedOpus)?;
+            let count = usize::from(header & 0x3f);
+            if count == 0 {
+                return Err(VoiceError::MalformedOpus);
+            }
+            let mut pos = 2;
+            let mut end = packet.len();
+            if header & 0x40 != 0 {
+                loop {
+                    let padding = *packet.get(pos).ok_or(VoiceError::MalformedOpus)?;
+                    pos += 1;
+                    let len = if padding == 255 { 254 } else { padding };
+                    end = end
+                        .checked_sub(usize::from(len))
+                        .ok_or(VoiceError::MalformedOpus)?;
+                    if padding != 255 {
+                        break;
+                    }
+                }
+            }
+            let mut lens = Vec::with_capacity(count);
+            if header & 0x80 != 0 {
+                for _ in 1..count {
+                    lens.push(read_opus_len(packet, &mut pos)?);
+                }
+                let rest = end
+                    .checked_sub(pos)
+                    .and_then(|it| it.checked_sub(lens.iter().sum()))
+                    .ok_or(VoiceError::MalformedOpus)?;
+                lens.push(rest);
+            } else {
+                let rest = end.checked_sub(pos).ok_or(VoiceError::MalformedOpus)?;
+                if rest % count != 0 {
+                    return Err(VoiceError::MalformedOpus);
+                }
+                lens.resize(count, rest / count);
+            }
+            lens.into_iter()
+                .map(|len| {
+                    pos += len;
+                    packet.slice(pos - len..pos)
+                })
+                .collect()
+        }
+    };
+    Ok((toc & !0x03, frames))
+}
+
+fn read_opus_len(packet: &[u8], pos: &mut usize) -> Result<usize, VoiceError> {
+    let first = usize::from(*packet.get(*pos).ok_or(VoiceError::MalformedOpus)?);
+    *pos += 1;
+    if first < 252 {
+        return Ok(first);
+    }
+    let second = usize::from(*packet.get(*pos).ok_or(VoiceError::MalformedOpus)?);
+    *pos += 1;
+    Ok(first + 4 * second)
+}
+
+fn write_opus_len(buf: &mut BytesMut, len: usize) {
+    if len < 252 {
+        buf.put_u8(len as u8);
+    } else {
+        buf.put_u8((252 + (len & 0x03)) as u8);
+        buf.put_u8(((len - 252) >> 2) as u8);
+    }
+}
+
+/// Builds an Opus packet from frames sharing the given TOC byte.
+fn write_opus(toc: u8, frames: &[Bytes]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(2 + frames.iter().map(|it| it.len() + 2).sum::<usize>());
+    match frames {
+        [_] => buf.put_u8(toc),
+        [first, second] if first.len() == second.len() => buf.put_u8(toc | 1),
+        [first, _] => {
+            buf.put_u8(toc | 2);
+            write_opus_len(&mut buf, first.len());
+        }
+        _ => {
+            let vbr = frames.iter().any(|it| it.len() != frames[0].len());
+            buf.put_u8(toc | 3);
+            buf.put_u8(if vbr { 0x80 } else { 0 } | frames.len() as u8);
+            if vbr {
+                for frame in &frames[..frames.len() - 1] {
+                    write_opus_len(&mut buf, frame.len());
+                }
+            }
+        }
+    }
+    for frame in frames {
+        buf.put_slice(frame);
+    }
+    buf.freeze()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::voice::AudioBuilder;
+    use crate::voice::Position;
+    use crate::voice::Serverbound;
+
+    /// TOC byte of SILK narrowband 20 ms frames.
+    const SILK_20MS: u8 = 1 << 3;
+
+    fn speex(seq_num: u64, frames: &[u8]) -> VoicePacket<Serverbound> {
+        let frames = frames.iter().map(|it| Bytes::from(vec![*it; 3]));
+        AudioBuilder::new(VoicePacketPayload::speex(frames).unwrap())
+            .seq(seq_num)
+            .position(Position::new(seq_num as f32, 0.0, 0.0))
+            .build()
+            .unwrap()
+    }
+
+    fn opus(seq_num: u64, frame: &[u8], last: bool) -> VoicePacket<Serverbound> {
+        let mut data = vec![SILK_20MS];
+        data.extend_from_slice(frame);
+        AudioBuilder::opus(data)
+            .seq(seq_num)
+            .last(last)
+            .build()
+            .unwrap()
+    }
+
+    fn collect(
+        packets: Vec<VoicePacket<Serverbound>>,
+        frames_per_packet: usize,
+    ) -> Vec<VoicePacket<Serverbound>> {
+        repacketize(packets, frames_per_packet)
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn regroups_legacy_frames() {
+        let packets = (0..5).map(|it| speex(it, &[it as u8])).collect();
+        let packets = collect(packets, 2);
+        assert_eq!(3, packets.len());
+        assert_eq!(speex(0, &[0, 1]), packets[0]);
+        assert_eq!(speex(2, &[2, 3]), packets[1]);
+        assert_eq!(speex(4, &[4]), packets[2]);
+
+        // Split again, keeping the position of the source packet
+        let packets = collect(packets, 1);
+        let mut expected = speex(1, &[1]);
+        expected.set_position(Some(Position::new(0.0, 0.0, 0.0)));
+        assert_eq!(expected, packets[1]);
+        assert_eq!(speex(2, &[2]), packets[2]);
+    }
+
+    #[test]
+    fn does_not_merge_across_gaps() {
+        let packets = vec![
+            speex(0, &[0]),
+            speex(1, &[1]),
+            speex(5, &[5]),
+            speex(6, &[6]),
+        ];
+        let packets = collect(packets, 3);
+        assert_eq!(vec![speex(0, &[0, 1]), speex(5, &[5, 6])], packets);
+    }
+
+    #[test]
+    fn merges_and_splits_opus_packets() {
+        let original = vec![
+            opus(0, &[1, 2, 3], false),
+            opus(2, &[4, 5, 6], false),
+            opus(4, &[7, 8], true),
+            opus(6, &[9], false),
+        ];
+        let merged = collect(original.clone(), 6);
+        assert_eq!(2, merged.len());
+        // VBR code 3 packet with 3 frames
+        let data = [SILK_20MS | 3, 0x83, 3, 3, 1, 2, 3, 4, 5, 6, 7, 8];
+        let expected = AudioBuilder::opus(data.to_vec())
+            .last(true)
+            .build()
+            .unwrap();
+        assert_eq!(expected, merged[0]);
+        assert_eq!(original[3], merged[1]);
+
+        assert_eq!(original, collect(merged, 2));
+
+        // Two frames of the same size use code 1
+        let merged = collect(original[..2].to_vec(), 4);
+        let data = [SILK_20MS | 1, 1, 2, 3, 4, 5, 6];
+        assert_eq!(
+            vec![AudioBuilder::opus(data.to_vec()).build().unwrap()],
+            merged
+        );
+    }
+
+    #[test]
+    fn reads_padded_opus_packets() {
+        // CBR code 3 packet with 2 frames of 2 bytes and 3 bytes of padding
+        let packet = Bytes::from_static(&[SILK_20MS | 3, 0x42, 3, 1, 2, 3, 4, 0, 0, 0]);
+        let (toc, frames) = read_opus(&packet).unwrap();
+        assert_eq!(SILK_20MS, toc);
+        assert_eq!(vec![&[1, 2][..], &[3, 4][..]], frames);
+
+        let mut packet = vec![SILK_20MS | 2];
+        let mut buf = BytesMut::new();
+        write_opus_len(&mut buf, 300);
+        packet.extend_from_slice(&buf);
+        packet.extend_from_slice(&[7; 301]);
+        let (_, frames) = read_opus(&Bytes::from(packet)).unwrap();
+        assert_eq!((300, 1), (frames[0].len(), frames[1].len()));
+
+        assert_eq!(
+            Err(VoiceError::MalformedOpus),
+            read_opus(&Bytes::from_static(&[SILK_20MS | 1, 1, 2, 3]))
+        );
+    }
+
+    #[test]
+    fn rejects_mixed_codecs() {
+        let packets = vec![speex(0, &[0]), opus(1, &[1], false), opus(3, &[2], false)];
+        let result: Vec<_> = repacketize(packets, 2).collect();
+        assert_eq!(
+            vec![
+                Ok(speex(0, &[0])),
+                Err(VoiceError::MixedCodecs),
+                Ok(opus(3, &[2], false))
+            ],
+            result
+        );
+    }
+}
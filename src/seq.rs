@@ -0,0 +1,471 @@
+//! Tracking the sequence numbers of received audio
+//!
+//! Clients number their audio packets by the amount of audio sent, see
+//! [SequenceCounter](crate::voice::SequenceCounter), and start over at the beginning of each
+//! transmission. Packets may arrive reordered, duplicated or not at all. [SeqTracker] classifies
+//! each packet of a user accordingly, [VoiceStreamStats] derives loss and jitter from that.
+
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Without audio for this long, a user is assumed to have started a new transmission.
+pub const DEFAULT_SILENCE_GAP: Duration = Duration::from_secs(1);
+
+/// Packets older than the newest one by more than this are assumed to start a new transmission.
+///
+/// One second of audio in 10 ms frames.
+pub const DEFAULT_MAX_REORDER: u64 = 100;
+
+/// Compares two sequence numbers, taking wraparound into account.
+///
+/// `a` is less than `b` if `b` is ahead of it by less than half the range of `u64`.
+pub fn compare(a: u64, b: u64) -> Ordering {
+    (a.wrapping_sub(b) as i64).cmp(&0)
+}
+
+/// How a received packet relates to the ones received before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SeqStatus {
+    /// The first packet of a transmission.
+    NewTransmission,
+    /// The packet is newer than all others of the transmission.
+    InOrder {
+        /// The amount of packets between it and the previously newest one which have not been
+        /// received (yet).
+        skipped: u64,
+    },
+    /// The packet is older than the newest one of the transmission, but was not received before.
+    Late {
+        /// How far the packet is behind the newest one, in sequence numbers.
+        by: u64,
+    },
+    /// The packet was received before.
+    Duplicate,
+}
+
+/// Totals of the packets seen by a [SeqTracker].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SeqStats {
+    /// Packets received for the first time.
+    pub received: u64,
+    /// Packets received again.
+    pub duplicates: u64,
+    /// Packets received after newer ones.
+    pub late: u64,
+    /// Packets skipped and not received late.
+    pub missing: u64,
+    /// Transmissions started.
+    pub transmissions: u64,
+}
+
+/// Classifies the sequence numbers of the packets received from a single user.
+///
+/// Packets of a transmission advance the sequence number by the same step, which is the amount
+/// of 10 ms frames per packet. Unless set with [with_step](Self::with_step), it is inferred as
+/// the smallest advance seen in the current transmission.
+#[derive(Clone, Debug)]
+pub struct SeqTracker {
+    silence_gap: Duration,
+    max_reorder: u64,
+    fixed_step: Option<u64>,
+    current: Option<Transmission>,
+    stats: SeqStats,
+}
+
+#[derive(Clone, Debug)]
+struct Transmission {
+    start: u64,
+    newest: u64,
+    last_arrival: Instant,
+    step: Option<u64>,
+    /// Sequence numbers received within [SeqTracker::max_reorder] of the newest one.
+    seen: BTreeSet<u64>,
+}
+
+impl Default for SeqTracker {
+    fn default() -> Self {
+        SeqTracker {
+            silence_gap: DEFAULT_SILENCE_GAP,
+            max_reorder: DEFAULT_MAX_REORDER,
+            fixed_step: None,
+            current: None,
+            stats: SeqStats::default(),
+        }
+    }
+}
+
+impl SeqTracker {
+    /// Creates a tracker with the [DEFAULT_SILENCE_GAP] and [DEFAULT_MAX_REORDER].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how long without packets starts a new transmission.
+    pub fn with_silence_gap(mut self, silence_gap: Duration) -> Self {
+        self.silence_gap = silence_gap;
+        self
+    }
+
+    /// Sets how far behind the newest packet one may be before it starts a new transmission.
+    pub fn with_max_reorder(mut self, max_reorder: u64) -> Self {
+        self.max_reorder = max_reorder;
+        self
+    }
+
+    /// Sets the step between consecutive packets instead of inferring it, e.g. from
+    /// [SequenceCounter::step](crate::voice::SequenceCounter::step).
+    pub fn with_step(mut self, step: u64) -> Self {
+        self.fixed_step = Some(step.max(1));
+        self
+    }
+
+    /// Sets or, given `None`, unsets the step between consecutive packets, e.g. once the amount
+    /// of frames per packet changes.
+    pub fn set_step(&mut self, step: Option<u64>) {
+        self.fixed_step = step.map(|it| it.max(1));
+    }
+
+    /// Returns the totals of all packets so far.
+    pub fn stats(&self) -> &SeqStats {
+        &self.stats
+    }
+
+    /// Returns the newest sequence number of the current transmission.
+    pub fn newest(&self) -> Option<u64> {
+        self.current.as_ref().map(|it| it.newest)
+    }
+
+    /// Processes a packet received at `arrival`.
+    pub fn update(&mut self, seq: u64, arrival: Instant) -> SeqStatus {
+        let Some(current) = &mut self.current else {
+            return self.start_transmission(seq, arrival);
+        };
+        if arrival.saturating_duration_since(current.last_arrival) >= self.silence_gap {
+            return self.start_transmission(seq, arrival);
+        }
+        current.last_arrival = current.last_arrival.max(arrival);
+
+        let delta = seq.wrapping_sub(current.newest) as i64;
+        if delta > 0 {
+            let delta = delta as u64;
+            let step = match (self.fixed_step, current.step) {
+                (Some(step), _) => step,
+                (None, Some(step)) if step <= delta => step,
+                (None, _) => {
+                    current.step = Some(delta);
+                    delta
+                }
+            };
+            let skipped = (delta / step).saturating_sub(1);
+            current.newest = seq;
+            current.seen.insert(seq);
+            let (newest, max_reorder) = (current.newest, self.max_reorder);
+            current
+                .seen
+                .retain(|it| newest.wrapping_sub(*it) <= max_reorder);
+            self.stats.received += 1;
+            self.stats.missing += skipped;
+            return SeqStatus::InOrder { skipped };
+        }
+
+        let by = delta.unsigned_abs();
+        if current.seen.contains(&seq) {
+            self.stats.duplicates += 1;
+            return SeqStatus::Duplicate;
+        }
+        if by > self.max_reorder {
+            return self.start_transmission(seq, arrival);
+        }
+        current.seen.insert(seq);
+        self.stats.received += 1;
+        self.stats.late += 1;
+        // Stragglers from before the start of the transmission were never counted as skipped
+        if compare(seq, current.start) == Ordering::Greater {
+            self.stats.missing = self.stats.missing.saturating_sub(1);
+        }
+        SeqStatus::Late { by }
+    }
+
+    /// Forgets the current transmission, e.g. once it ended with a terminator. Totals are kept.
+    pub fn end_transmission(&mut self) {
+        self.current = None;
+    }
+
+    fn start_transmission(&mut self, seq: u64, arrival: Instant) -> SeqStatus {
+        self.current = Some(Transmission {
+            start: seq,
+            newest: seq,
+            last_arrival: arrival,
+            step: None,
+            seen: BTreeSet::from([seq]),
+        });
+        self.stats.received += 1;
+        self.stats.transmissions += 1;
+        SeqStatus::NewTransmission
+    }
+}
+
+/// The duration of audio a sequence number stands for, in milliseconds.
+const FRAME_MS: f64 = 10.0;
+
+/// The window [VoiceStreamStats] compute the loss percentage over by default.
+pub const DEFAULT_LOSS_WINDOW: Duration = Duration::from_secs(5);
+
+/// Statistics of a [VoiceStreamStats] at some point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VoiceStreamSnapshot {
+    /// Packets received for the first time.
+    pub received: u64,
+    /// Packets skipped and not received late.
+    pub lost: u64,
+    /// Packets received after newer ones.
+    pub late: u64,
+    /// Packets received again.
+    pub duplicates: u64,
+    /// Interarrival jitter in milliseconds.
+    pub jitter_ms: f64,
+    /// Percentage of packets lost within the loss window.
+    pub loss_percent: f64,
+}
+
+/// Packet loss and jitter of the audio received from a single user.
+///
+/// Jitter is estimated like RFC 3550 does, with the sequence numbers as the media clock. Only
+/// packets within a transmission count, so silence between transmissions is neither loss nor
+/// jitter.
+#[derive(Clone, Debug)]
+pub struct VoiceStreamStats {
+    tracker: SeqTracker,
+    loss_window: Duration,
+    /// Arrivals within the loss window with the amount of packets received and lost.
+    window: VecDeque<(Instant, u64, i64)>,
+    /// The previous packet of the transmission, for the jitter.
+    previous: Option<(u64, Instant)>,
+    jitter_ms: f64,
+}
+
+impl Default for VoiceStreamStats {
+    fn default() -> Self {
+        VoiceStreamStats {
+            tracker: SeqTracker::new(),
+            loss_window: DEFAULT_LOSS_WINDOW,
+            window: VecDeque::new(),
+            previous: None,
+            jitter_ms: 0.0,
+        }
+    }
+}
+
+impl VoiceStreamStats {
+    /// Creates statistics with the [DEFAULT_LOSS_WINDOW] and a default [SeqTracker].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the tracker used to classify packets, e.g. one with a different silence gap.
+    pub fn with_tracker(mut self, tracker: SeqTracker) -> Self {
+        self.tracker = tracker;
+        self
+    }
+
+    /// Sets the duration the loss percentage is computed over.
+    pub fn with_loss_window(mut self, loss_window: Duration) -> Self {
+        self.loss_window = loss_window;
+        self
+    }
+
+    /// Processes a packet with `frames` 10 ms frames of audio received at `arrival`.
+    ///
+    /// For CELT and Speex this is the amount of frames in the packet, for Opus the duration of
+    /// the packet divided by 10 ms.
+    pub fn update(&mut self, seq: u64, frames: u64, arrival: Instant) -> SeqStatus {
+        let lost_before = self.tracker.stats().missing;
+        self.tracker.set_step(Some(frames));
+        let status = self.tracker.update(seq, arrival);
+        let lost = self.tracker.stats().missing as i64 - lost_before as i64;
+
+        match status {
+            SeqStatus::Duplicate => return status,
+            SeqStatus::NewTransmission => {}
+            SeqStatus::InOrder { .. } | SeqStatus::Late { .. } => {
+                if let Some((previous_seq, previous_arrival)) = self.previous {
+                    let arrived_ms = if arrival >= previous_arrival {
+                        (arrival - previous_arrival).as_secs_f64() * 1000.0
+                    } else {
+                        -(previous_arrival - arrival).as_secs_f64() * 1000.0
+                    };
+                    let sent_ms = seq.wrapping_sub(previous_seq) as i64 as f64 * FRAME_MS;
+                    self.jitter_ms += ((arrived_ms - sent_ms).abs() - self.jitter_ms) / 16.0;
+                }
+            }
+        }
+        self.previous = Some((seq, arrival));
+        self.window.push_back((arrival, 1, lost));
+        while let Some((at, ..)) = self.window.front() {
+            if arrival.saturating_duration_since(*at) <= self.loss_window {
+                break;
+            }
+            self.window.pop_front();
+        }
+        status
+    }
+
+    /// Returns the statistics, with the loss percentage over the loss window before `now`.
+    pub fn snapshot(&self, now: Instant) -> VoiceStreamSnapshot {
+        let (received, lost) = self
+            .window
+            .iter()
+            .filter(|(at, ..)| now.saturating_duration_since(*at) <= self.loss_window)
+            .fold((0, 0), |(received, lost), (_, r, l)| {
+                (received + r, lost + l)
+            });
+        let lost = lost.max(0) as u64;
+        let loss_percent = if received + lost == 0 {
+            0.0
+        } else {
+            lost as f64 * 100.0 / (received + lost) as f64
+        };
+        let stats = self.tracker.stats();
+        VoiceStreamSnapshot {
+            received: stats.received,
+            lost: stats.missing,
+            late: stats.late,
+            duplicates: stats.duplicates,
+            jitter_ms: self.jitter_ms,
+            loss_percent,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compares_across_wraparound() {
+        assert_eq!(Ordering::Less, compare(1, 2));
+        assert_eq!(Ordering::Equal, compare(7, 7));
+        assert_eq!(Ordering::Less, compare(u64::MAX, 0));
+        assert_eq!(Ordering::Greater, compare(3, u64::MAX - 3));
+    }
+
+    #[test]
+    fn classifies_packets() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut tracker = SeqTracker::new();
+        assert_eq!(SeqStatus::NewTransmission, tracker.update(10, ms(0)));
+        // 20 ms packets, the step is inferred from the first advance
+        assert_eq!(
+            SeqStatus::InOrder { skipped: 0 },
+            tracker.update(12, ms(20))
+        );
+        assert_eq!(
+            SeqStatus::InOrder { skipped: 2 },
+            tracker.update(18, ms(40))
+        );
+        assert_eq!(SeqStatus::Late { by: 4 }, tracker.update(14, ms(45)));
+        assert_eq!(SeqStatus::Duplicate, tracker.update(14, ms(50)));
+        assert_eq!(SeqStatus::Duplicate, tracker.update(18, ms(50)));
+        assert_eq!(
+            SeqStatus::InOrder { skipped: 0 },
+            tracker.update(20, ms(60))
+        );
+        assert_eq!(
+            SeqStats {
+                received: 5,
+                duplicates: 2,
+                late: 1,
+                missing: 1,
+                transmissions: 1,
+            },
+            *tracker.stats()
+        );
+
+        // The next transmission starts over, either after a pause or with a large jump back
+        assert_eq!(SeqStatus::NewTransmission, tracker.update(0, ms(1100)));
+        assert_eq!(
+            SeqStatus::InOrder { skipped: 0 },
+            tracker.update(2, ms(1120))
+        );
+        assert_eq!(SeqStatus::NewTransmission, tracker.update(500, ms(2200)));
+        assert_eq!(
+            SeqStatus::InOrder { skipped: 0 },
+            tracker.update(502, ms(2220))
+        );
+        assert_eq!(SeqStatus::NewTransmission, tracker.update(0, ms(2240)));
+        assert_eq!(4, tracker.stats().transmissions);
+    }
+
+    #[test]
+    fn computes_loss_and_jitter() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut stats = VoiceStreamStats::new().with_loss_window(Duration::from_millis(500));
+        // 20 ms packets arriving in time have no jitter
+        for i in 0..10 {
+            stats.update(i * 2, 2, ms(i * 20));
+        }
+        let snapshot = stats.snapshot(ms(180));
+        assert_eq!(
+            (10, 0, 0.0),
+            (snapshot.received, snapshot.lost, snapshot.jitter_ms)
+        );
+
+        // One lost, one 40 ms late
+        stats.update(22, 2, ms(220));
+        stats.update(26, 2, ms(260));
+        stats.update(24, 2, ms(280));
+        let snapshot = stats.snapshot(ms(280));
+        assert_eq!(
+            (13, 1, 1),
+            (snapshot.received, snapshot.lost, snapshot.late)
+        );
+        assert!((snapshot.jitter_ms - 40.0 / 16.0).abs() < 1e-9);
+        stats.update(28, 2, ms(300));
+        stats.update(30, 2, ms(320));
+        let snapshot = stats.snapshot(ms(320));
+        assert_eq!(1, snapshot.lost);
+        assert!((snapshot.loss_percent - 100.0 / 16.0).abs() < 1e-9);
+        // Only the packets within the window count
+        assert!((stats.snapshot(ms(700)).loss_percent - 100.0 / 6.0).abs() < 1e-9);
+
+        // Silence between transmissions is not loss
+        stats.update(0, 2, ms(5000));
+        stats.update(2, 2, ms(5020));
+        let snapshot = stats.snapshot(ms(5020));
+        assert_eq!((1, 0.0), (snapshot.lost, snapshot.loss_percent));
+        assert_eq!(SeqStatus::Duplicate, stats.update(2, 2, ms(5030)));
+        assert_eq!(1, stats.snapshot(ms(5030)).duplicates);
+    }
+
+    #[test]
+    fn uses_fixed_steps() {
+        let start = Instant::now();
+        let mut tracker = SeqTracker::new().with_step(2).with_max_reorder(10);
+        tracker.update(0, start);
+        // A loss right at the start is recognized as such
+        assert_eq!(SeqStatus::InOrder { skipped: 1 }, tracker.update(4, start));
+        assert_eq!(
+            SeqStatus::NewTransmission,
+            tracker.update(u64::MAX - 20, start)
+        );
+        assert_eq!(
+            SeqStatus::InOrder { skipped: 0 },
+            tracker.update(u64::MAX - 18, start)
+        );
+        // Across wraparound
+        assert_eq!(SeqStatus::InOrder { skipped: 9 }, tracker.update(1, start));
+        assert_eq!(
+            SeqStatus::Late { by: 4 },
+            tracker.update(u64::MAX - 2, start)
+        );
+
+        tracker.end_transmission();
+        assert_eq!(None, tracker.newest());
+        assert_eq!(SeqStatus::NewTransmission, tracker.update(1, start));
+    }
+}
@@ -0,0 +1,37 @@
+//! Models of the server state, maintained from the messages sent by the server
+//!
+//! These mirror what the reference client keeps track of and are updated incrementally by
+//! passing them the relevant messages as they are received.
+
+#[cfg(feature = "openssl")]
+mod blobs;
+mod channels;
+mod permissions;
+mod talking;
+mod users;
+mod voice_targets;
+
+#[cfg(feature = "openssl")]
+pub use blobs::BlobCache;
+#[cfg(feature = "openssl")]
+pub use blobs::BlobHashMismatch;
+#[cfg(feature = "openssl")]
+pub use blobs::BlobStatus;
+pub use channels::Channel;
+pub use channels::ChannelTree;
+pub use channels::ChannelTreeError;
+pub use permissions::PermissionCache;
+pub use talking::TalkMode;
+pub use talking::TalkingDetector;
+pub use talking::TalkingEvent;
+#[cfg(feature = "webrtc-extensions")]
+pub use talking::TalkingTracker;
+pub use talking::DEFAULT_HANG_TIME;
+pub use users::User;
+pub use users::UserEvent;
+pub use users::UserRegistry;
+pub use users::UserRegistryError;
+pub use voice_targets::BuiltinGroups;
+pub use voice_targets::GroupMembership;
+pub use voice_targets::VoiceTargetRegistry;
+pub use voice_targets::VoiceTargetResolver;
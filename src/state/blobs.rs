@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+
+use bytes::Bytes;
+
+use crate::control::msgs;
+use crate::control::request_blob::BlobKind;
+use crate::control::request_blob::PendingBlobRequests;
+
+type Hash = [u8; 20];
+
+/// Whether the content of a comment, texture or description is available, see [BlobCache].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlobStatus {
+    /// The content is cached.
+    Cached(Bytes),
+    /// The content was requested from the server.
+    Pending,
+    /// There is no content, or it was not requested yet.
+    Unknown,
+}
+
+/// A comment, texture or description did not match the hash announced for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobHashMismatch {
+    /// What kind of content did not match.
+    pub kind: BlobKind,
+    /// The session of the user or ID of the channel the content belongs to.
+    pub id: u32,
+}
+
+impl fmt::Display for BlobHashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            BlobKind::UserComment => "comment of user",
+            BlobKind::UserTexture => "texture of user",
+            BlobKind::ChannelDescription => "description of channel",
+        };
+        write!(f, "{} {} does not match its hash", what, self.id)
+    }
+}
+
+impl std::error::Error for BlobHashMismatch {}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    data: Bytes,
+    last_used: u64,
+}
+
+/// Cache of comments, textures and channel descriptions, keyed by their SHA1 hash.
+///
+/// Servers only send the hash of large contents, which has to be requested separately. The
+/// cache observes `UserState` and `ChannelState` messages to learn the hashes and contents,
+/// and creates the `RequestBlob` for whatever is missing. Contents shared by several users
+/// (e.g. a default avatar) are stored once.
+///
+/// The total size of the cached contents is bounded, the least recently used ones are evicted
+/// first.
+#[derive(Clone, Debug)]
+pub struct BlobCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    blobs: HashMap<Hash, Entry>,
+    lru: BTreeMap<u64, Hash>,
+    hashes: HashMap<(BlobKind, u32), Hash>,
+    pending: PendingBlobRequests,
+}
+
+impl BlobCache {
+    /// Creates an empty cache holding at most `capacity` bytes of content.
+    pub fn new(capacity: usize) -> Self {
+        BlobCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            blobs: HashMap::new(),
+            lru: BTreeMap::new(),
+            hashes: HashMap::new(),
+            pending: PendingBlobRequests::new(),
+        }
+    }
+
+    /// Learns the comment and texture (or their hashes) of a user.
+    ///
+    /// Contents which do not match the hash previously announced for them are discarded, the
+    /// last mismatch is returned.
+    pub fn observe_user_state(&mut self, msg: &msgs::UserState) -> Result<(), BlobHashMismatch> {
+        let session = match msg.session {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let answered = self.pending.match_user_state(msg);
+        let comment = self.observe(
+            (BlobKind::UserComment, session),
+            msg.comment_hash.as_deref(),
+            msg.comment.as_ref().map(|it| it.as_bytes()),
+            answered.contains(&BlobKind::UserComment),
+        );
+        let texture = self.observe(
+            (BlobKind::UserTexture, session),
+            msg.texture_hash.as_deref(),
+            msg.texture.as_deref(),
+            answered.contains(&BlobKind::UserTexture),
+        );
+        comment.and(texture)
+    }
+
+    /// Learns the description (or its hash) of a channel.
+    ///
+    /// A description which does not match the hash previously announced for it is discarded.
+    pub fn observe_channel_state(
+        &mut self,
+        msg: &msgs::ChannelState,
+    ) -> Result<(), BlobHashMismatch> {
+        let channel_id = match msg.channel_id {
+            Some(channel_id) => channel_id,
+            None => return Ok(()),
+        };
+        let answered = self.pending.match_channel_state(msg);
+        self.observe(
+            (BlobKind::ChannelDescription, channel_id),
+            msg.description_hash.as_deref(),
+            msg.description.as_ref().map(|it| it.as_bytes()),
+            answered,
+        )
+    }
+
+    fn observe(
+        &mut self,
+        key: (BlobKind, u32),
+        hash: Option<&[u8]>,
+        content: Option<&[u8]>,
+        answered: bool,
+    ) -> Result<(), BlobHashMismatch> {
+        if let Some(hash) = hash {
+            match Hash::try_from(hash) {
+                Ok(hash) => self.hashes.insert(key, hash),
+                Err(_) => self.hashes.remove(&key),
+            };
+        }
+        let content = match content {
+            Some(content) => content,
+            None => return Ok(()),
+        };
+        if content.is_empty() {
+            self.hashes.remove(&key);
+            return Ok(());
+        }
+        let actual = openssl::sha::sha1(content);
+        // Otherwise, this is a new (small) content replacing the one the hash belongs to
+        if hash.is_some() || answered {
+            if let Some(expected) = self.hashes.get(&key) {
+                if *expected != actual {
+                    return Err(BlobHashMismatch {
+                        kind: key.0,
+                        id: key.1,
+                    });
+                }
+            }
+        }
+        self.hashes.insert(key, actual);
+        self.insert(actual, Bytes::copy_from_slice(content));
+        Ok(())
+    }
+
+    fn insert(&mut self, hash: Hash, data: Bytes) {
+        if data.len() > self.capacity {
+            return;
+        }
+        if let Some(entry) = self.blobs.remove(&hash) {
+            self.lru.remove(&entry.last_used);
+            self.size -= entry.data.len();
+        }
+        while self.size + data.len() > self.capacity {
+            let (_, oldest) = self.lru.pop_first().expect("size is accounted for");
+            let entry = self.blobs.remove(&oldest).expect("lru refers to blobs");
+            self.size -= entry.data.len();
+        }
+        self.tick += 1;
+        self.size += data.len();
+        self.lru.insert(self.tick, hash);
+        self.blobs.insert(
+            hash,
+            Entry {
+                data,
+                last_used: self.tick,
+            },
+        );
+    }
+
+    fn status(&mut self, kind: BlobKind, id: u32) -> BlobStatus {
+        let hash = match self.hashes.get(&(kind, id)) {
+            Some(hash) => *hash,
+            None => return BlobStatus::Unknown,
+        };
+        if let Some(entry) = self.blobs.get_mut(&hash) {
+            self.lru.remove(&entry.last_used);
+            self.tick += 1;
+            entry.last_used = self.tick;
+            self.lru.insert(self.tick, hash);
+            return BlobStatus::Cached(entry.data.clone());
+        }
+        if self.pending.is_pending(kind, id) {
+            BlobStatus::Pending
+        } else {
+            BlobStatus::Unknown
+        }
+    }
+
+    /// Returns the comment of the user, as UTF-8 encoded HTML.
+    pub fn comment_for(&mut self, session: u32) -> BlobStatus {
+        self.status(BlobKind::UserComment, session)
+    }
+
+    /// Returns the texture (avatar) of the user.
+    pub fn texture_for(&mut self, session: u32) -> BlobStatus {
+        self.status(BlobKind::UserTexture, session)
+    }
+
+    /// Returns the description of the channel, as UTF-8 encoded HTML.
+    pub fn description_for(&mut self, channel_id: u32) -> BlobStatus {
+        self.status(BlobKind::ChannelDescription, channel_id)
+    }
+
+    /// Creates a single request for all contents whose hash is known, but which are neither
+    /// cached nor already requested.
+    ///
+    /// The requested contents are [Pending](BlobStatus::Pending) afterwards, so calling this
+    /// again only requests what became missing since.
+    pub fn request_missing(&mut self) -> Option<msgs::RequestBlob> {
+        let mut missing: Vec<(BlobKind, u32)> = self
+            .hashes
+            .iter()
+            .filter(|(key, hash)| {
+                !self.blobs.contains_key(*hash) && !self.pending.is_pending(key.0, key.1)
+            })
+            .map(|(key, _)| *key)
+            .collect();
+        if missing.is_empty() {
+            return None;
+        }
+        missing.sort_by_key(|(_, id)| *id);
+        let ids = |kind| missing.iter().filter(move |it| it.0 == kind).map(|it| it.1);
+        let msg = msgs::RequestBlob::for_user_comments(ids(BlobKind::UserComment))
+            .combine(msgs::RequestBlob::for_user_textures(ids(
+                BlobKind::UserTexture,
+            )))
+            .combine(msgs::RequestBlob::for_channel_descriptions(ids(
+                BlobKind::ChannelDescription,
+            )));
+        self.pending.record(&msg);
+        Some(msg)
+    }
+
+    /// Forgets the hashes of a user's contents, e.g. once they disconnected.
+    ///
+    /// The contents themselves stay cached until they are evicted.
+    pub fn remove_user(&mut self, session: u32) {
+        self.hashes.remove(&(BlobKind::UserComment, session));
+        self.hashes.remove(&(BlobKind::UserTexture, session));
+        self.pending.remove_user(session);
+    }
+
+    /// Forgets the hash of a channel's description, e.g. once it was removed.
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        self.hashes
+            .remove(&(BlobKind::ChannelDescription, channel_id));
+        self.pending.remove_channel(channel_id);
+    }
+
+    /// Returns the total size of the cached contents in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hashed(session: u32, comment: &str) -> msgs::UserState {
+        msgs::UserState {
+            session: Some(session),
+            comment_hash: Some(openssl::sha::sha1(comment.as_bytes()).to_vec()),
+            ..Default::default()
+        }
+    }
+
+    fn comment(session: u32, comment: &str) -> msgs::UserState {
+        msgs::UserState {
+            session: Some(session),
+            comment: Some(comment.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn requests_missing_blobs() {
+        let mut cache = BlobCache::new(1024);
+        cache.observe_user_state(&hashed(1, "long")).unwrap();
+        cache.observe_user_state(&hashed(2, "long")).unwrap();
+        cache
+            .observe_user_state(&msgs::UserState {
+                session: Some(3),
+                texture_hash: Some(vec![0; 20]),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(BlobStatus::Unknown, cache.comment_for(1));
+
+        let request = cache.request_missing().unwrap();
+        assert_eq!(vec![1, 2], request.session_comment);
+        assert_eq!(vec![3], request.session_texture);
+        assert!(request.channel_description.is_empty());
+        assert_eq!(BlobStatus::Pending, cache.comment_for(1));
+        assert!(cache.request_missing().is_none());
+
+        // Both users share the same comment
+        cache.observe_user_state(&comment(1, "long")).unwrap();
+        let long = BlobStatus::Cached(Bytes::from_static(b"long"));
+        assert_eq!(long, cache.comment_for(1));
+        assert_eq!(long, cache.comment_for(2));
+        assert_eq!(BlobStatus::Unknown, cache.comment_for(4));
+    }
+
+    #[test]
+    fn verifies_hashes() {
+        let mut cache = BlobCache::new(1024);
+        cache.observe_user_state(&hashed(1, "expected")).unwrap();
+        cache.request_missing().unwrap();
+        assert_eq!(
+            Err(BlobHashMismatch {
+                kind: BlobKind::UserComment,
+                id: 1
+            }),
+            cache.observe_user_state(&comment(1, "forged"))
+        );
+        // The content can be requested again
+        assert_eq!(BlobStatus::Unknown, cache.comment_for(1));
+        assert!(cache.request_missing().is_some());
+
+        // Small contents are sent without hash and replace large ones
+        cache.observe_user_state(&hashed(2, "large")).unwrap();
+        cache.observe_user_state(&comment(2, "short")).unwrap();
+        assert_eq!(
+            BlobStatus::Cached(Bytes::from_static(b"short")),
+            cache.comment_for(2)
+        );
+        cache.observe_user_state(&comment(2, "")).unwrap();
+        assert_eq!(BlobStatus::Unknown, cache.comment_for(2));
+
+        let channel = msgs::ChannelState {
+            channel_id: Some(0),
+            description: Some("welcome".to_owned()),
+            ..Default::default()
+        };
+        cache.observe_channel_state(&channel).unwrap();
+        assert!(matches!(cache.description_for(0), BlobStatus::Cached(_)));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = BlobCache::new(10);
+        cache.observe_user_state(&comment(1, "aaaa")).unwrap();
+        cache.observe_user_state(&comment(2, "bbbb")).unwrap();
+        // Makes 2 the least recently used
+        assert!(matches!(cache.comment_for(1), BlobStatus::Cached(_)));
+        cache.observe_user_state(&comment(3, "cccc")).unwrap();
+        assert_eq!(8, cache.size());
+        assert!(matches!(cache.comment_for(1), BlobStatus::Cached(_)));
+        assert_eq!(BlobStatus::Unknown, cache.comment_for(2));
+        assert!(matches!(cache.comment_for(3), BlobStatus::Cached(_)));
+
+        // Evicted contents are requested again
+        assert_eq!(vec![2], cache.request_missing().unwrap().session_comment);
+
+        // Contents larger than the whole cache are not stored
+        cache
+            .observe_user_state(&comment(4, "too large to cache"))
+            .unwrap();
+        assert_eq!(8, cache.size());
+    }
+}
@@ -0,0 +1,455 @@
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::control::msgs;
+
+/// A channel as known from `ChannelState` messages.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Channel {
+    /// The channel's ID.
+    pub id: u32,
+    /// ID of the parent channel, `None` for the root channel.
+    pub parent: Option<u32>,
+    /// Name of the channel.
+    pub name: String,
+    /// Sort position among its siblings.
+    pub position: i32,
+    /// IDs of the channels this channel is linked to.
+    pub links: BTreeSet<u32>,
+    /// Description of the channel, if it was sent.
+    pub description: Option<String>,
+    /// SHA1 hash of the description, if the description itself was omitted.
+    pub description_hash: Option<Vec<u8>>,
+    /// Whether the channel is removed once it is empty.
+    pub temporary: bool,
+    /// Maximum amount of users in the channel, `0` meaning the server default.
+    pub max_users: u32,
+}
+
+impl Channel {
+    /// Compares two sibling channels the way Mumble sorts them: by position, then by name.
+    pub fn sort_cmp(&self, other: &Channel) -> Ordering {
+        self.position
+            .cmp(&other.position)
+            .then_with(|| self.name.cmp(&other.name))
+            .then_with(|| self.id.cmp(&other.id))
+    }
+
+    fn apply(&mut self, msg: &msgs::ChannelState) {
+        if let Some(parent) = msg.parent {
+            self.parent = Some(parent);
+        }
+        if let Some(name) = &msg.name {
+            self.name = name.clone();
+        }
+        if let Some(position) = msg.position {
+            self.position = position;
+        }
+        if let Some(description) = &msg.description {
+            self.description = Some(description.clone());
+            self.description_hash = None;
+        }
+        if let Some(hash) = &msg.description_hash {
+            self.description_hash = Some(hash.clone());
+        }
+        if let Some(temporary) = msg.temporary {
+            self.temporary = temporary;
+        }
+        if let Some(max_users) = msg.max_users {
+            self.max_users = max_users;
+        }
+    }
+}
+
+/// An inconsistency in the messages passed to a [ChannelTree].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ChannelTreeError {
+    /// A `ChannelState` did not contain a channel ID.
+    MissingChannelId,
+    /// The channel is not known.
+    UnknownChannel(u32),
+    /// A channel was removed while it still had sub-channels.
+    ///
+    /// Servers remove sub-channels first, so the channel is left in place.
+    HasChildren {
+        /// ID of the channel.
+        id: u32,
+        /// IDs of its sub-channels.
+        children: Vec<u32>,
+    },
+    /// A channel was moved below itself or one of its sub-channels.
+    ///
+    /// The channel is left in place.
+    Cycle {
+        /// ID of the channel.
+        id: u32,
+        /// ID of the requested parent.
+        parent: u32,
+    },
+}
+
+impl fmt::Display for ChannelTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelTreeError::MissingChannelId => f.write_str("ChannelState without channel id"),
+            ChannelTreeError::UnknownChannel(id) => write!(f, "unknown channel {}", id),
+            ChannelTreeError::HasChildren { id, children } => write!(
+                f,
+                "channel {} still has {} sub-channels",
+                id,
+                children.len()
+            ),
+            ChannelTreeError::Cycle { id, parent } => write!(
+                f,
+                "channel {} cannot be moved below its sub-channel {}",
+                id, parent
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChannelTreeError {}
+
+/// The channel tree of a server, maintained from `ChannelState` and `ChannelRemove` messages.
+///
+/// During the initial sync, servers may announce a channel before its parent. Such channels are
+/// parked as orphans and only become part of the tree once their parent is known. The same
+/// happens to a channel moved below an unknown parent, together with its sub-channels.
+#[derive(Clone, Debug, Default)]
+pub struct ChannelTree {
+    channels: HashMap<u32, Channel>,
+    orphans: HashMap<u32, Channel>,
+}
+
+impl ChannelTree {
+    /// Creates an empty tree.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates or updates a channel.
+    ///
+    /// Fails and leaves the tree unchanged if the channel would become its own ancestor.
+    pub fn apply(&mut self, msg: &msgs::ChannelState) -> Result<(), ChannelTreeError> {
+        let id = msg.channel_id.ok_or(ChannelTreeError::MissingChannelId)?;
+        if let Some(parent) = msg.parent {
+            if self.is_below(parent, id) {
+                return Err(ChannelTreeError::Cycle { id, parent });
+            }
+        }
+        let mut channel = self
+            .channels
+            .remove(&id)
+            .or_else(|| self.orphans.remove(&id))
+            .unwrap_or_else(|| Channel {
+                id,
+                ..Default::default()
+            });
+        channel.apply(msg);
+
+        let links_before = channel.links.clone();
+        if !msg.links.is_empty() {
+            channel.links = msg.links.iter().copied().collect();
+        }
+        channel.links.extend(msg.links_add.iter().copied());
+        for link in &msg.links_remove {
+            channel.links.remove(link);
+        }
+        // Links are symmetric, but servers only announce them for one of the two channels
+        for removed in links_before.difference(&channel.links) {
+            if let Some(other) = self.get_any_mut(*removed) {
+                other.links.remove(&id);
+            }
+        }
+        for added in channel.links.difference(&links_before) {
+            if let Some(other) = self.get_any_mut(*added) {
+                other.links.insert(id);
+            }
+        }
+
+        self.insert(channel);
+        Ok(())
+    }
+
+    /// Removes a channel.
+    ///
+    /// Fails and leaves the tree unchanged if the channel is unknown or still has sub-channels.
+    pub fn remove(&mut self, msg: &msgs::ChannelRemove) -> Result<Channel, ChannelTreeError> {
+        #[cfg(feature = "protobuf")]
+        let id = msg.channel_id();
+        #[cfg(feature = "prost")]
+        let id = msg.channel_id;
+        let children: Vec<u32> = self.children(id).map(|it| it.id).collect();
+        if !children.is_empty() {
+            return Err(ChannelTreeError::HasChildren { id, children });
+        }
+        let channel = self
+            .channels
+            .remove(&id)
+            .or_else(|| self.orphans.remove(&id))
+            .ok_or(ChannelTreeError::UnknownChannel(id))?;
+        for link in &channel.links {
+            if let Some(other) = self.get_any_mut(*link) {
+                other.links.remove(&id);
+            }
+        }
+        Ok(channel)
+    }
+
+    /// Returns the channel with the given ID, unless it is unknown or an orphan.
+    pub fn get(&self, id: u32) -> Option<&Channel> {
+        self.channels.get(&id)
+    }
+
+    /// Returns the root channel, if known.
+    pub fn root(&self) -> Option<&Channel> {
+        self.get(0)
+    }
+
+    /// Returns the parent of the given channel.
+    pub fn parent(&self, id: u32) -> Option<&Channel> {
+        self.get(self.get(id)?.parent?)
+    }
+
+    /// Returns the parents of the given channel, from its direct parent up to the root.
+    pub fn ancestors(&self, id: u32) -> impl Iterator<Item = &Channel> + '_ {
+        let mut current = self.get(id);
+        std::iter::from_fn(move || {
+            current = self.get(current?.parent?);
+            current
+        })
+    }
+
+    /// Returns the direct sub-channels of the given channel in Mumble's sort order.
+    pub fn children(&self, id: u32) -> impl Iterator<Item = &Channel> + '_ {
+        let mut children: Vec<&Channel> = self
+            .channels
+            .values()
+            .filter(|it| it.parent == Some(id))
+            .collect();
+        children.sort_by(|a, b| a.sort_cmp(b));
+        children.into_iter()
+    }
+
+    /// Returns the given channel followed by all of its descendants, depth-first and in
+    /// Mumble's sort order.
+    pub fn subtree(&self, id: u32) -> impl Iterator<Item = &Channel> + '_ {
+        let mut stack: Vec<&Channel> = self.get(id).into_iter().collect();
+        std::iter::from_fn(move || {
+            let channel = stack.pop()?;
+            let first = stack.len();
+            stack.extend(self.children(channel.id));
+            stack[first..].reverse();
+            Some(channel)
+        })
+    }
+
+    /// Returns all channels which are part of the tree, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> + '_ {
+        self.channels.values()
+    }
+
+    /// Returns the channels whose parent is not known yet, or which are below such a channel.
+    pub fn orphans(&self) -> impl Iterator<Item = &Channel> + '_ {
+        self.orphans.values()
+    }
+
+    /// Returns the amount of channels which are part of the tree.
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Returns whether no channels are part of the tree.
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    fn get_any(&self, id: u32) -> Option<&Channel> {
+        self.channels.get(&id).or_else(|| self.orphans.get(&id))
+    }
+
+    /// Returns whether `id` is `ancestor` or one of its descendants, including orphans.
+    fn is_below(&self, id: u32, ancestor: u32) -> bool {
+        let mut current = Some(id);
+        // Bounded in case orphans already form a cycle among themselves
+        for _ in 0..=self.channels.len() + self.orphans.len() {
+            match current {
+                Some(id) if id == ancestor => return true,
+                Some(id) => current = self.get_any(id).and_then(|it| it.parent),
+                None => return false,
+            }
+        }
+        false
+    }
+
+    fn get_any_mut(&mut self, id: u32) -> Option<&mut Channel> {
+        match self.channels.get_mut(&id) {
+            Some(channel) => Some(channel),
+            None => self.orphans.get_mut(&id),
+        }
+    }
+
+    /// Inserts a channel, parking it if its parent is unknown and adopting its orphans.
+    fn insert(&mut self, channel: Channel) {
+        let attached = match channel.parent {
+            None => true,
+            Some(parent) => self.channels.contains_key(&parent),
+        };
+        let id = channel.id;
+        if !attached {
+            self.orphans.insert(id, channel);
+            // Its sub-channels are no longer connected to the root either
+            let mut pending = vec![id];
+            while let Some(parent) = pending.pop() {
+                let children: Vec<u32> = self
+                    .channels
+                    .values()
+                    .filter(|it| it.parent == Some(parent))
+                    .map(|it| it.id)
+                    .collect();
+                for child in children {
+                    let channel = self.channels.remove(&child).expect("child exists");
+                    self.orphans.insert(child, channel);
+                    pending.push(child);
+                }
+            }
+            return;
+        }
+        self.channels.insert(id, channel);
+        let adopted: Vec<u32> = self
+            .orphans
+            .values()
+            .filter(|it| it.parent == Some(id))
+            .map(|it| it.id)
+            .collect();
+        for orphan in adopted {
+            let orphan = self.orphans.remove(&orphan).expect("orphan exists");
+            self.insert(orphan);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::backend::message;
+
+    fn state(id: u32, parent: Option<u32>, name: &str, position: i32) -> msgs::ChannelState {
+        msgs::ChannelState {
+            channel_id: Some(id),
+            parent,
+            name: Some(name.to_owned()),
+            position: Some(position),
+            ..Default::default()
+        }
+    }
+
+    fn remove(id: u32) -> msgs::ChannelRemove {
+        message!(msgs::ChannelRemove {
+            #[cfg(feature = "protobuf")]
+            channel_id: Some(id),
+            #[cfg(feature = "prost")]
+            channel_id: id,
+        })
+    }
+
+    fn names<'a>(channels: impl Iterator<Item = &'a Channel>) -> Vec<&'a str> {
+        channels.map(|it| it.name.as_str()).collect()
+    }
+
+    #[test]
+    fn builds_tree_from_out_of_order_sync() {
+        let mut tree = ChannelTree::new();
+        tree.apply(&state(3, Some(1), "Grandchild", 0)).unwrap();
+        tree.apply(&state(1, Some(0), "B", 0)).unwrap();
+        assert!(tree.get(1).is_none());
+        assert_eq!(2, tree.orphans().count());
+
+        tree.apply(&state(0, None, "Root", 0)).unwrap();
+        tree.apply(&state(2, Some(0), "A", 0)).unwrap();
+        tree.apply(&state(4, Some(0), "C", -1)).unwrap();
+        assert_eq!(0, tree.orphans().count());
+        assert_eq!(vec!["C", "A", "B"], names(tree.children(0)));
+        assert_eq!(
+            vec!["Root", "C", "A", "B", "Grandchild"],
+            names(tree.subtree(0))
+        );
+        assert_eq!("B", tree.parent(3).unwrap().name);
+    }
+
+    #[test]
+    fn tracks_links_and_removals() {
+        let mut tree = ChannelTree::new();
+        tree.apply(&state(0, None, "Root", 0)).unwrap();
+        tree.apply(&state(1, Some(0), "A", 0)).unwrap();
+        tree.apply(&state(2, Some(0), "B", 0)).unwrap();
+        tree.apply(&msgs::ChannelState {
+            channel_id: Some(1),
+            links_add: vec![2],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(tree.get(2).unwrap().links.contains(&1));
+
+        assert_eq!(
+            Err(ChannelTreeError::HasChildren {
+                id: 0,
+                children: vec![1, 2]
+            }),
+            tree.remove(&remove(0))
+        );
+        assert_eq!("A", tree.remove(&remove(1)).unwrap().name);
+        assert!(tree.get(2).unwrap().links.is_empty());
+        assert_eq!(
+            Err(ChannelTreeError::UnknownChannel(1)),
+            tree.remove(&remove(1))
+        );
+    }
+
+    #[test]
+    fn rejects_moving_channels_below_themselves() {
+        let mut tree = ChannelTree::new();
+        tree.apply(&state(0, None, "Root", 0)).unwrap();
+        tree.apply(&state(1, Some(0), "A", 0)).unwrap();
+        tree.apply(&state(2, Some(1), "B", 0)).unwrap();
+        tree.apply(&state(3, Some(2), "C", 0)).unwrap();
+        assert_eq!(
+            Err(ChannelTreeError::Cycle { id: 1, parent: 3 }),
+            tree.apply(&state(1, Some(3), "A", 0))
+        );
+        assert_eq!(
+            Err(ChannelTreeError::Cycle { id: 1, parent: 1 }),
+            tree.apply(&state(1, Some(1), "A", 0))
+        );
+        assert_eq!(Some(0), tree.get(1).unwrap().parent);
+        assert_eq!(vec!["A", "B", "C"], names(tree.subtree(1)));
+        assert_eq!(vec!["B", "A", "Root"], names(tree.ancestors(3)));
+
+        // Orphans cannot form cycles either
+        tree.apply(&state(5, Some(4), "E", 0)).unwrap();
+        assert_eq!(
+            Err(ChannelTreeError::Cycle { id: 4, parent: 5 }),
+            tree.apply(&state(4, Some(5), "D", 0))
+        );
+    }
+
+    #[test]
+    fn moves_subtrees_below_unknown_parents() {
+        let mut tree = ChannelTree::new();
+        tree.apply(&state(0, None, "Root", 0)).unwrap();
+        tree.apply(&state(1, Some(0), "A", 0)).unwrap();
+        tree.apply(&state(2, Some(1), "B", 0)).unwrap();
+        tree.apply(&state(3, Some(2), "C", 0)).unwrap();
+
+        tree.apply(&state(1, Some(4), "A", 0)).unwrap();
+        assert_eq!(vec!["Root"], names(tree.subtree(0)));
+        assert_eq!(3, tree.orphans().count());
+
+        tree.apply(&state(4, Some(0), "D", 0)).unwrap();
+        assert_eq!(0, tree.orphans().count());
+        assert_eq!(vec!["Root", "D", "A", "B", "C"], names(tree.subtree(0)));
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use super::ChannelTree;
+use crate::control::msgs;
+use crate::control::permissions::PermissionQueryExt;
+use crate::control::permissions::Permissions;
+
+/// The permissions of the own user per channel, maintained from `PermissionQuery` responses.
+///
+/// Permissions are only sent on request, so [needs_query](Self::needs_query) should be checked
+/// (and [query](Self::query) sent) before e.g. showing a context menu for a channel.
+#[derive(Clone, Debug, Default)]
+pub struct PermissionCache {
+    permissions: HashMap<u32, Permissions>,
+}
+
+impl PermissionCache {
+    /// Creates an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores the permissions sent by the server.
+    ///
+    /// If the message has `flush` set, all previously cached permissions are dropped first.
+    pub fn apply(&mut self, msg: &msgs::PermissionQuery) {
+        if msg.flush() {
+            self.permissions.clear();
+        }
+        if let (Some(channel_id), Some(permissions)) = (msg.channel_id, msg.permission_flags()) {
+            self.permissions.insert(channel_id, permissions);
+        }
+    }
+
+    /// Drops the permissions of channels affected by the `ChannelState`.
+    ///
+    /// Permissions are inherited, so when a channel is moved, the cached permissions of the
+    /// channel and all of its sub-channels are dropped. Works the same whether the message was
+    /// already applied to the tree or not.
+    pub fn apply_channel_state(&mut self, msg: &msgs::ChannelState, channels: &ChannelTree) {
+        let channel_id = match (msg.channel_id, msg.parent) {
+            (Some(channel_id), Some(_)) => channel_id,
+            _ => return,
+        };
+        self.permissions.remove(&channel_id);
+        for channel in channels.subtree(channel_id) {
+            self.permissions.remove(&channel.id);
+        }
+    }
+
+    /// Returns the cached permissions for the channel.
+    pub fn permissions_for(&self, channel_id: u32) -> Option<Permissions> {
+        self.permissions.get(&channel_id).copied()
+    }
+
+    /// Returns whether the permissions for the channel are not cached.
+    pub fn needs_query(&self, channel_id: u32) -> bool {
+        !self.permissions.contains_key(&channel_id)
+    }
+
+    /// Creates the request for the permissions for the channel, unless they are cached.
+    pub fn query(&self, channel_id: u32) -> Option<msgs::PermissionQuery> {
+        if !self.needs_query(channel_id) {
+            return None;
+        }
+        Some(msgs::PermissionQuery {
+            channel_id: Some(channel_id),
+            ..Default::default()
+        })
+    }
+
+    /// Drops the cached permissions for the channel, e.g. once it was removed.
+    pub fn invalidate(&mut self, channel_id: u32) {
+        self.permissions.remove(&channel_id);
+    }
+
+    /// Drops all cached permissions.
+    pub fn clear(&mut self) {
+        self.permissions.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(channel_id: u32, permissions: Permissions, flush: bool) -> msgs::PermissionQuery {
+        let mut msg = msgs::PermissionQuery {
+            channel_id: Some(channel_id),
+            flush: Some(flush),
+            ..Default::default()
+        };
+        msg.set_permission_flags(permissions);
+        msg
+    }
+
+    fn channel(id: u32, parent: Option<u32>) -> msgs::ChannelState {
+        msgs::ChannelState {
+            channel_id: Some(id),
+            parent,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn caches_and_flushes_permissions() {
+        let mut cache = PermissionCache::new();
+        assert!(cache.needs_query(1));
+        assert_eq!(Some(Some(1)), cache.query(1).map(|it| it.channel_id));
+
+        cache.apply(&response(1, Permissions::ENTER, false));
+        cache.apply(&response(2, Permissions::SPEAK, false));
+        assert_eq!(Some(Permissions::ENTER), cache.permissions_for(1));
+        assert!(!cache.needs_query(1));
+        assert!(cache.query(1).is_none());
+
+        cache.apply(&response(3, Permissions::WRITE, true));
+        assert_eq!(None, cache.permissions_for(1));
+        assert_eq!(None, cache.permissions_for(2));
+        assert_eq!(Some(Permissions::WRITE), cache.permissions_for(3));
+    }
+
+    #[test]
+    fn invalidates_moved_channels() {
+        let mut channels = ChannelTree::new();
+        for (id, parent) in [(0, None), (1, Some(0)), (2, Some(1)), (3, Some(0))] {
+            channels.apply(&channel(id, parent)).unwrap();
+        }
+        let mut cache = PermissionCache::new();
+        for id in 0..=3 {
+            cache.apply(&response(id, Permissions::TRAVERSE, false));
+        }
+
+        // Renaming does not affect permissions
+        let rename = msgs::ChannelState {
+            channel_id: Some(1),
+            name: Some("renamed".to_owned()),
+            ..Default::default()
+        };
+        cache.apply_channel_state(&rename, &channels);
+        assert!(!cache.needs_query(1));
+
+        let moved = channel(1, Some(3));
+        channels.apply(&moved).unwrap();
+        cache.apply_channel_state(&moved, &channels);
+        assert!(cache.needs_query(1));
+        assert!(cache.needs_query(2));
+        assert!(!cache.needs_query(0));
+        assert!(!cache.needs_query(3));
+    }
+}
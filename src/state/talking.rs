@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use std::time::Instant;
+
+#[cfg(feature = "webrtc-extensions")]
+use crate::control::msgs;
+use crate::voice::Clientbound;
+use crate::voice::VoicePacket;
+use crate::voice::VoiceTargetId;
+
+/// The hang time used by default, long enough to bridge the gaps between words.
+pub const DEFAULT_HANG_TIME: Duration = Duration::from_millis(250);
+
+/// How a user is talking, as given by the target of their audio in clientbound packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TalkMode {
+    /// Talking to their channel.
+    Normal,
+    /// Talking to a voice target which includes channels.
+    Shout,
+    /// Talking to a voice target which includes only users.
+    Whisper,
+    /// Talking to themselves via the server loopback.
+    Loopback,
+}
+
+impl TalkMode {
+    /// Returns the mode for a clientbound target, `None` for targets servers do not send.
+    pub fn from_target(target: u32) -> Option<Self> {
+        match target {
+            0 => Some(TalkMode::Normal),
+            1 => Some(TalkMode::Shout),
+            2 => Some(TalkMode::Whisper),
+            31 => Some(TalkMode::Loopback),
+            _ => None,
+        }
+    }
+
+    /// Returns the clientbound target of this mode.
+    pub fn target(self) -> u8 {
+        match self {
+            TalkMode::Normal => 0,
+            TalkMode::Shout => 1,
+            TalkMode::Whisper => 2,
+            TalkMode::Loopback => 31,
+        }
+    }
+}
+
+/// The clientbound target of the mode, as passed to
+/// [into_client_bound](crate::voice::VoicePacket::into_client_bound).
+impl From<TalkMode> for VoiceTargetId {
+    fn from(mode: TalkMode) -> Self {
+        VoiceTargetId::try_from(mode.target()).expect("clientbound targets fit into 5 bits")
+    }
+}
+
+/// A change of who is talking, as returned by [TalkingDetector] and `TalkingTracker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TalkingEvent {
+    /// A user started talking, or continued talking in a different mode.
+    StartedTalking {
+        /// Session of the user.
+        session: u32,
+        /// How they are talking.
+        mode: TalkMode,
+    },
+    /// A user stopped talking.
+    StoppedTalking {
+        /// Session of the user.
+        session: u32,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct Speaker {
+    mode: TalkMode,
+    /// When the user is considered to have stopped talking, if no further audio arrives.
+    stops_at: Option<Instant>,
+}
+
+/// The speaking set shared by [TalkingDetector] and `TalkingTracker`.
+#[derive(Clone, Debug)]
+struct Speakers {
+    hang_time: Duration,
+    speakers: BTreeMap<u32, Speaker>,
+}
+
+impl Speakers {
+    fn new() -> Self {
+        Speakers {
+            hang_time: DEFAULT_HANG_TIME,
+            speakers: BTreeMap::new(),
+        }
+    }
+
+    fn talk(
+        &mut self,
+        session: u32,
+        mode: TalkMode,
+        stops_at: Option<Instant>,
+    ) -> Option<TalkingEvent> {
+        let previous = self
+            .speakers
+            .insert(session, Speaker { mode, stops_at })
+            .map(|it| it.mode);
+        (previous != Some(mode)).then_some(TalkingEvent::StartedTalking { session, mode })
+    }
+
+    #[cfg(feature = "webrtc-extensions")]
+    fn quiet(&mut self, session: u32, now: Instant) {
+        if let Some(speaker) = self.speakers.get_mut(&session) {
+            speaker.stops_at.get_or_insert(now + self.hang_time);
+        }
+    }
+
+    fn remove(&mut self, session: u32) -> Option<TalkingEvent> {
+        self.speakers
+            .remove(&session)
+            .map(|_| TalkingEvent::StoppedTalking { session })
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<TalkingEvent> {
+        let mut events = Vec::new();
+        self.speakers.retain(|session, speaker| {
+            let stopped = speaker.stops_at.is_some_and(|it| it <= now);
+            if stopped {
+                events.push(TalkingEvent::StoppedTalking { session: *session });
+            }
+            !stopped
+        });
+        events
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.speakers.values().filter_map(|it| it.stops_at).min()
+    }
+}
+
+macro_rules! speaking_set_accessors {
+    () => {
+        /// Sets how long a user is still considered talking after their audio stopped.
+        ///
+        /// Audio resuming within this time does not cause any events.
+        pub fn with_hang_time(mut self, hang_time: Duration) -> Self {
+            self.speakers.hang_time = hang_time;
+            self
+        }
+
+        /// Returns whether the user is currently talking.
+        pub fn is_talking(&self, session: u32) -> bool {
+            self.speakers.speakers.contains_key(&session)
+        }
+
+        /// Returns how the user is currently talking, `None` if they are not.
+        pub fn mode(&self, session: u32) -> Option<TalkMode> {
+            self.speakers.speakers.get(&session).map(|it| it.mode)
+        }
+
+        /// Returns the users currently talking, ordered by session.
+        pub fn speaking(&self) -> impl Iterator<Item = (u32, TalkMode)> + '_ {
+            self.speakers
+                .speakers
+                .iter()
+                .map(|(session, speaker)| (*session, speaker.mode))
+        }
+
+        /// Returns the events of users whose hang time expired at `now`.
+        ///
+        /// Should be called at [next_deadline](Self::next_deadline) at the latest.
+        pub fn poll(&mut self, now: Instant) -> Vec<TalkingEvent> {
+            self.speakers.poll(now)
+        }
+
+        /// Returns when [poll](Self::poll) needs to be called next, if anyone is talking.
+        pub fn next_deadline(&self) -> Option<Instant> {
+            self.speakers.next_deadline()
+        }
+
+        /// Forgets a user, e.g. once they left the server.
+        pub fn remove_session(&mut self, session: u32) -> Option<TalkingEvent> {
+            self.speakers.remove(session)
+        }
+    };
+}
+
+/// Infers who is talking from the voice packets received from the server.
+///
+/// A user stops talking once no audio arrived from them for the hang time, whether or not their
+/// last packet ended the transmission. This also covers terminators lost on the way.
+#[derive(Clone, Debug)]
+pub struct TalkingDetector {
+    speakers: Speakers,
+}
+
+impl Default for TalkingDetector {
+    fn default() -> Self {
+        TalkingDetector {
+            speakers: Speakers::new(),
+        }
+    }
+}
+
+impl TalkingDetector {
+    /// Creates a detector with the [DEFAULT_HANG_TIME].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    speaking_set_accessors!();
+
+    /// Processes a voice packet received at `now`.
+    ///
+    /// Returns an event if the sender started talking or changed their mode. Pings and audio
+    /// with invalid targets are ignored.
+    pub fn voice_packet(
+        &mut self,
+        packet: &VoicePacket<Clientbound>,
+        now: Instant,
+    ) -> Option<TalkingEvent> {
+        match packet {
+            VoicePacket::Ping { .. } => None,
+            VoicePacket::Audio {
+                target, session_id, ..
+            } => {
+                let mode = TalkMode::from_target((*target).into())?;
+                let stops_at = now + self.speakers.hang_time;
+                self.speakers.talk(*session_id, mode, Some(stops_at))
+            }
+        }
+    }
+}
+
+/// Tracks who is talking from the `TalkingState` messages sent by WebRTC servers.
+///
+/// These are only sent when a user starts and stops talking (the latter without target), so a
+/// user keeps talking until told otherwise.
+#[cfg(feature = "webrtc-extensions")]
+#[derive(Clone, Debug)]
+pub struct TalkingTracker {
+    speakers: Speakers,
+}
+
+#[cfg(feature = "webrtc-extensions")]
+impl Default for TalkingTracker {
+    fn default() -> Self {
+        TalkingTracker {
+            speakers: Speakers::new(),
+        }
+    }
+}
+
+#[cfg(feature = "webrtc-extensions")]
+impl TalkingTracker {
+    /// Creates a tracker with the [DEFAULT_HANG_TIME].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    speaking_set_accessors!();
+
+    /// Processes a `TalkingState` message received at `now`.
+    ///
+    /// Returns an event if the user started talking or changed their mode. Stopping is only
+    /// reported by [poll](Self::poll) once the hang time expired. Messages without session or
+    /// with a target servers do not send are ignored.
+    pub fn update(&mut self, msg: &msgs::TalkingState, now: Instant) -> Option<TalkingEvent> {
+        let session = msg.session?;
+        match msg.target {
+            Some(target) => {
+                let mode = TalkMode::from_target(target)?;
+                self.speakers.talk(session, mode, None)
+            }
+            None => {
+                self.speakers.quiet(session, now);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::voice::VoicePacketPayload;
+    use bytes::Bytes;
+    use std::marker::PhantomData;
+
+    fn audio(session_id: u32, target: u8, end: bool) -> VoicePacket<Clientbound> {
+        VoicePacket::Audio {
+            _dst: PhantomData,
+            target,
+            session_id,
+            seq_num: 0,
+            payload: VoicePacketPayload::Opus(Bytes::from_static(&[0]), end),
+            position_info: None,
+        }
+    }
+
+    #[test]
+    fn detects_talking_from_voice_packets() {
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let mut detector = TalkingDetector::new().with_hang_time(Duration::from_millis(100));
+        assert_eq!(
+            Some(TalkingEvent::StartedTalking {
+                session: 1,
+                mode: TalkMode::Normal
+            }),
+            detector.voice_packet(&audio(1, 0, false), start)
+        );
+        assert_eq!(None, detector.voice_packet(&audio(1, 0, true), ms(20)));
+        assert_eq!(Some(ms(120)), detector.next_deadline());
+        // resuming within the hang time goes unnoticed
+        assert!(detector.poll(ms(100)).is_empty());
+        assert_eq!(None, detector.voice_packet(&audio(1, 0, false), ms(100)));
+        assert_eq!(
+            Some(TalkingEvent::StartedTalking {
+                session: 1,
+                mode: TalkMode::Whisper
+            }),
+            detector.voice_packet(&audio(1, 2, false), ms(120))
+        );
+        assert_eq!(
+            vec![(1, TalkMode::Whisper)],
+            detector.speaking().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![TalkingEvent::StoppedTalking { session: 1 }],
+            detector.poll(ms(220))
+        );
+        assert!(!detector.is_talking(1));
+        assert_eq!(None, detector.voice_packet(&audio(2, 5, false), ms(220)));
+    }
+
+    #[test]
+    #[cfg(feature = "webrtc-extensions")]
+    fn tracks_talking_states() {
+        use crate::control::backend::message;
+
+        let start = Instant::now();
+        let ms = |ms| start + Duration::from_millis(ms);
+        let state = |session, target| {
+            message!(msgs::TalkingState {
+                session: Some(session),
+                target,
+            })
+        };
+        let mut tracker = TalkingTracker::new().with_hang_time(Duration::from_millis(100));
+        assert_eq!(
+            Some(TalkingEvent::StartedTalking {
+                session: 1,
+                mode: TalkMode::Shout
+            }),
+            tracker.update(&state(1, Some(1)), start)
+        );
+        // no implicit timeout
+        assert!(tracker.poll(ms(1000)).is_empty());
+        assert_eq!(None, tracker.update(&state(1, None), ms(1000)));
+        assert_eq!(Some(TalkMode::Shout), tracker.mode(1));
+        assert_eq!(None, tracker.update(&state(1, Some(1)), ms(1050)));
+        assert!(tracker.poll(ms(1200)).is_empty());
+
+        assert_eq!(None, tracker.update(&state(1, None), ms(1200)));
+        assert_eq!(
+            vec![TalkingEvent::StoppedTalking { session: 1 }],
+            tracker.poll(ms(1300))
+        );
+        assert_eq!(None, tracker.next_deadline());
+
+        tracker.update(&state(2, Some(0)), ms(1300));
+        assert_eq!(
+            Some(TalkingEvent::StoppedTalking { session: 2 }),
+            tracker.remove_session(2)
+        );
+        assert_eq!(None, tracker.remove_session(2));
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+
+use super::Channel;
+use super::ChannelTree;
+use crate::control::msgs;
+use crate::control::user_remove::UserRemoveAction;
+
+/// A connected user as known from `UserState` messages.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct User {
+    /// The user's session ID.
+    pub session: u32,
+    /// Name of the user.
+    pub name: String,
+    /// ID of the channel the user is in.
+    ///
+    /// The channel may not be known yet during the initial sync, see [channel](Self::channel).
+    pub channel_id: u32,
+    /// ID of the registered user, `None` if unregistered.
+    pub user_id: Option<u32>,
+    /// Whether the user is muted by a moderator.
+    pub mute: bool,
+    /// Whether the user is deafened by a moderator.
+    pub deaf: bool,
+    /// Whether the user is suppressed in their channel.
+    pub suppress: bool,
+    /// Whether the user muted themselves.
+    pub self_mute: bool,
+    /// Whether the user deafened themselves.
+    pub self_deaf: bool,
+    /// Whether the user is a priority speaker.
+    pub priority_speaker: bool,
+    /// Whether the user is recording.
+    pub recording: bool,
+    /// Comment of the user, if it was sent.
+    pub comment: Option<String>,
+    /// SHA1 hash of the comment, if the comment itself was omitted.
+    pub comment_hash: Option<Vec<u8>>,
+    /// SHA1 hash of the user's certificate.
+    pub hash: Option<String>,
+    /// IDs of the channels the user listens to without being in them.
+    ///
+    /// Always empty with the `webrtc-extensions` feature, its protocol predates listeners.
+    pub listening_channels: BTreeSet<u32>,
+}
+
+impl User {
+    /// Returns the channel the user is in, if it is known.
+    pub fn channel<'a>(&self, channels: &'a ChannelTree) -> Option<&'a Channel> {
+        channels.get(self.channel_id)
+    }
+
+    /// Applies the fields present in the message, returning whether anything changed.
+    fn apply(&mut self, msg: &msgs::UserState) -> bool {
+        let before = self.clone();
+        if let Some(name) = &msg.name {
+            self.name.clone_from(name);
+        }
+        if let Some(channel_id) = msg.channel_id {
+            self.channel_id = channel_id;
+        }
+        if let Some(user_id) = msg.user_id {
+            // Murmur sends -1 (as u32) to unregister
+            self.user_id = Some(user_id).filter(|it| *it != u32::MAX);
+        }
+        macro_rules! apply_flag {
+            ($($field:ident),*) => {
+                $(
+                    if let Some(value) = msg.$field {
+                        self.$field = value;
+                    }
+                )*
+            };
+        }
+        apply_flag!(
+            mute,
+            deaf,
+            suppress,
+            self_mute,
+            self_deaf,
+            priority_speaker,
+            recording
+        );
+        if let Some(comment) = &msg.comment {
+            self.comment = Some(comment.clone());
+            self.comment_hash = None;
+        }
+        if let Some(hash) = &msg.comment_hash {
+            self.comment_hash = Some(hash.clone());
+        }
+        if let Some(hash) = &msg.hash {
+            self.hash = Some(hash.clone());
+        }
+        #[cfg(not(feature = "webrtc-extensions"))]
+        {
+            self.listening_channels
+                .extend(msg.listening_channel_add.iter().copied());
+            for channel_id in &msg.listening_channel_remove {
+                self.listening_channels.remove(channel_id);
+            }
+        }
+        *self != before
+    }
+}
+
+/// A change to the user list, as returned by [UserRegistry].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UserEvent {
+    /// A user connected (or was announced during the initial sync).
+    Joined {
+        /// Session of the user.
+        session: u32,
+    },
+    /// A user moved to a different channel.
+    Moved {
+        /// Session of the user.
+        session: u32,
+        /// The channel the user was in.
+        from: u32,
+        /// The channel the user is in now.
+        to: u32,
+        /// Session of the user who moved them, if not the user themselves.
+        actor: Option<u32>,
+    },
+    /// A user changed their name.
+    Renamed {
+        /// Session of the user.
+        session: u32,
+        /// The previous name.
+        from: String,
+        /// The new name.
+        to: String,
+    },
+    /// Any other property of a user changed.
+    Changed {
+        /// Session of the user.
+        session: u32,
+        /// Session of the user who made the change, if not the user themselves.
+        actor: Option<u32>,
+    },
+    /// A user disconnected or was removed from the server.
+    Left {
+        /// The user's last known state.
+        user: User,
+        /// Whether the user left on their own, was kicked or banned.
+        action: UserRemoveAction,
+    },
+}
+
+/// An inconsistency in the messages passed to a [UserRegistry].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UserRegistryError {
+    /// A `UserState` did not contain a session.
+    MissingSession,
+    /// The session is not known.
+    UnknownSession(u32),
+}
+
+impl fmt::Display for UserRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserRegistryError::MissingSession => f.write_str("UserState without session"),
+            UserRegistryError::UnknownSession(session) => write!(f, "unknown session {}", session),
+        }
+    }
+}
+
+impl std::error::Error for UserRegistryError {}
+
+/// The connected users of a server, maintained from `UserState` and `UserRemove` messages.
+///
+/// Users only refer to channels by ID, so users whose channel is not known to the
+/// [ChannelTree] yet (e.g. during the initial sync) are tracked just the same.
+#[derive(Clone, Debug, Default)]
+pub struct UserRegistry {
+    users: HashMap<u32, User>,
+    /// Sessions listening to each channel, mirroring [User::listening_channels].
+    listeners: HashMap<u32, BTreeSet<u32>>,
+}
+
+impl UserRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates or updates a user, returning the resulting events.
+    pub fn apply(&mut self, msg: &msgs::UserState) -> Result<Vec<UserEvent>, UserRegistryError> {
+        let session = msg.session.ok_or(UserRegistryError::MissingSession)?;
+        // Changes made by the user themselves carry their own session as actor
+        let actor = msg.actor.filter(|it| *it != session);
+        let user = match self.users.get_mut(&session) {
+            Some(user) => user,
+            None => {
+                let mut user = User {
+                    session,
+                    ..Default::default()
+                };
+                user.apply(msg);
+                index_listeners(
+                    &mut self.listeners,
+                    session,
+                    &BTreeSet::new(),
+                    &user.listening_channels,
+                );
+                self.users.insert(session, user);
+                return Ok(vec![UserEvent::Joined { session }]);
+            }
+        };
+
+        let before = user.clone();
+        if !user.apply(msg) {
+            return Ok(Vec::new());
+        }
+        index_listeners(
+            &mut self.listeners,
+            session,
+            &before.listening_channels,
+            &user.listening_channels,
+        );
+        let mut events = Vec::new();
+        if user.channel_id != before.channel_id {
+            events.push(UserEvent::Moved {
+                session,
+                from: before.channel_id,
+                to: user.channel_id,
+                actor,
+            });
+        }
+        if user.name != before.name {
+            events.push(UserEvent::Renamed {
+                session,
+                from: before.name.clone(),
+                to: user.name.clone(),
+            });
+        }
+        let other = User {
+            name: before.name.clone(),
+            channel_id: before.channel_id,
+            ..user.clone()
+        };
+        if other != before {
+            events.push(UserEvent::Changed { session, actor });
+        }
+        Ok(events)
+    }
+
+    /// Removes a user, returning the resulting event.
+    pub fn remove(&mut self, msg: &msgs::UserRemove) -> Result<UserEvent, UserRegistryError> {
+        #[cfg(feature = "protobuf")]
+        let session = msg.session();
+        #[cfg(feature = "prost")]
+        let session = msg.session;
+        let user = self
+            .users
+            .remove(&session)
+            .ok_or(UserRegistryError::UnknownSession(session))?;
+        index_listeners(
+            &mut self.listeners,
+            session,
+            &user.listening_channels,
+            &BTreeSet::new(),
+        );
+        Ok(UserEvent::Left {
+            user,
+            action: msg.action(),
+        })
+    }
+
+    /// Returns the user with the given session.
+    pub fn get(&self, session: u32) -> Option<&User> {
+        self.users.get(&session)
+    }
+
+    /// Returns the user with the given name.
+    pub fn by_name(&self, name: &str) -> Option<&User> {
+        self.users.values().find(|it| it.name == name)
+    }
+
+    /// Returns the users in the given channel, ordered by name.
+    pub fn in_channel(&self, channel_id: u32) -> impl Iterator<Item = &User> + '_ {
+        let mut users: Vec<&User> = self
+            .users
+            .values()
+            .filter(|it| it.channel_id == channel_id)
+            .collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name).then(a.session.cmp(&b.session)));
+        users.into_iter()
+    }
+
+    /// Returns the users listening to the given channel, ordered by session.
+    pub fn listeners_of(&self, channel_id: u32) -> impl Iterator<Item = &User> + '_ {
+        self.listeners
+            .get(&channel_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|it| self.users.get(it))
+    }
+
+    /// Stops all users from listening to a removed channel, returning the resulting events.
+    ///
+    /// Servers do not send updates for this, so it should be called along with
+    /// [ChannelTree::remove].
+    pub fn remove_channel(&mut self, channel_id: u32) -> Vec<UserEvent> {
+        let sessions = self.listeners.remove(&channel_id).unwrap_or_default();
+        let mut events = Vec::new();
+        for session in sessions {
+            if let Some(user) = self.users.get_mut(&session) {
+                user.listening_channels.remove(&channel_id);
+                events.push(UserEvent::Changed {
+                    session,
+                    actor: None,
+                });
+            }
+        }
+        events
+    }
+
+    /// Returns all users, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &User> + '_ {
+        self.users.values()
+    }
+
+    /// Returns the amount of users.
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// Returns whether there are no users.
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+/// Updates the listener index after a user's listening channels changed.
+fn index_listeners(
+    listeners: &mut HashMap<u32, BTreeSet<u32>>,
+    session: u32,
+    before: &BTreeSet<u32>,
+    after: &BTreeSet<u32>,
+) {
+    for channel_id in before.difference(after) {
+        if let Some(sessions) = listeners.get_mut(channel_id) {
+            sessions.remove(&session);
+            if sessions.is_empty() {
+                listeners.remove(channel_id);
+            }
+        }
+    }
+    for channel_id in after.difference(before) {
+        listeners.entry(*channel_id).or_default().insert(session);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::control::backend::message;
+
+    fn remove(session: u32, actor: Option<u32>, ban: bool) -> msgs::UserRemove {
+        message!(msgs::UserRemove {
+            #[cfg(feature = "protobuf")]
+            session: Some(session),
+            #[cfg(feature = "prost")]
+            session,
+            actor,
+            reason: Some("bye".to_owned()),
+            ban: Some(ban),
+        })
+    }
+
+    #[test]
+    fn tracks_users_and_emits_events() {
+        let mut users = UserRegistry::new();
+        let joined = users
+            .apply(&msgs::UserState {
+                session: Some(1),
+                name: Some("alice".to_owned()),
+                channel_id: Some(7),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(vec![UserEvent::Joined { session: 1 }], joined);
+        // The channel is not known yet, which is fine
+        assert!(users.get(1).unwrap().channel(&ChannelTree::new()).is_none());
+
+        let events = users
+            .apply(&msgs::UserState {
+                session: Some(1),
+                actor: Some(2),
+                channel_id: Some(0),
+                mute: Some(true),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            vec![
+                UserEvent::Moved {
+                    session: 1,
+                    from: 7,
+                    to: 0,
+                    actor: Some(2)
+                },
+                UserEvent::Changed {
+                    session: 1,
+                    actor: Some(2)
+                },
+            ],
+            events
+        );
+        assert_eq!(
+            vec!["alice"],
+            users.in_channel(0).map(|it| &it.name).collect::<Vec<_>>()
+        );
+        assert_eq!(1, users.by_name("alice").unwrap().session);
+
+        let event = users.remove(&remove(1, Some(2), true)).unwrap();
+        assert!(matches!(
+            event,
+            UserEvent::Left {
+                action: UserRemoveAction::Banned { actor: Some(2), .. },
+                ..
+            }
+        ));
+        assert!(users.is_empty());
+        assert_eq!(
+            Err(UserRegistryError::UnknownSession(1)),
+            users.remove(&remove(1, Some(1), false))
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "webrtc-extensions"))]
+    fn tracks_listeners() {
+        let mut users = UserRegistry::new();
+        for session in [1, 2] {
+            users
+                .apply(&msgs::UserState {
+                    session: Some(session),
+                    listening_channel_add: vec![5, 6],
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let listeners = |users: &UserRegistry, channel_id| {
+            users
+                .listeners_of(channel_id)
+                .map(|it| it.session)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec![1, 2], listeners(&users, 5));
+
+        let events = users
+            .apply(&msgs::UserState {
+                session: Some(1),
+                listening_channel_remove: vec![5],
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(
+            vec![UserEvent::Changed {
+                session: 1,
+                actor: None
+            }],
+            events
+        );
+        assert_eq!(vec![2], listeners(&users, 5));
+
+        users.remove(&remove(2, None, false)).unwrap();
+        assert!(listeners(&users, 5).is_empty());
+        assert_eq!(vec![1], listeners(&users, 6));
+
+        assert_eq!(
+            vec![UserEvent::Changed {
+                session: 1,
+                actor: None
+            }],
+            users.remove_channel(6)
+        );
+        assert!(listeners(&users, 6).is_empty());
+        assert!(users.get(1).unwrap().listening_channels.is_empty());
+        assert!(users.remove_channel(6).is_empty());
+    }
+}
@@ -0,0 +1,505 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use super::Channel;
+use super::ChannelTree;
+use super::User;
+use super::UserRegistry;
+use crate::control::msgs;
+use crate::control::voice_target::Children;
+use crate::control::voice_target::Links;
+use crate::control::voice_target::TargetSpec;
+
+/// Decides whether users are members of ACL groups, for voice targets addressing a group.
+///
+/// Implemented for closures taking the same arguments as [is_member](Self::is_member).
+pub trait GroupMembership {
+    /// Returns whether the user is a member of the group, evaluated in the given channel.
+    fn is_member(&self, channel: &Channel, group: &str, user: &User) -> bool;
+}
+
+impl<F: Fn(&Channel, &str, &User) -> bool> GroupMembership for F {
+    fn is_member(&self, channel: &Channel, group: &str, user: &User) -> bool {
+        self(channel, group, user)
+    }
+}
+
+/// [GroupMembership] knowing only the groups Murmur defines implicitly.
+///
+/// These are `all`, `auth` (registered users), `in` (users in the channel) and `out` (users
+/// not in it), optionally prefixed with `!` to invert them. All other groups have no members.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BuiltinGroups;
+
+impl GroupMembership for BuiltinGroups {
+    fn is_member(&self, channel: &Channel, group: &str, user: &User) -> bool {
+        let (invert, group) = match group.strip_prefix('!') {
+            Some(group) => (true, group),
+            None => (false, group),
+        };
+        let member = match group.strip_prefix('~').unwrap_or(group) {
+            "all" => true,
+            "auth" => user.user_id.is_some(),
+            "in" => user.channel_id == channel.id,
+            "out" => user.channel_id != channel.id,
+            _ => return false,
+        };
+        member != invert
+    }
+}
+
+/// The voice targets registered by each user, maintained from `VoiceTarget` messages.
+#[derive(Clone, Debug, Default)]
+pub struct VoiceTargetRegistry {
+    targets: HashMap<u32, HashMap<u32, Vec<TargetSpec>>>,
+}
+
+impl VoiceTargetRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a target for the user with the given session.
+    ///
+    /// Replaces all receivers previously registered under the same ID, a message without
+    /// receivers removes the target. Returns `false` and ignores the message if its ID is not
+    /// between 1 and 30.
+    pub fn register(&mut self, session: u32, msg: &msgs::VoiceTarget) -> bool {
+        let id = match msg.id {
+            Some(id @ 1..=30) => id,
+            _ => return false,
+        };
+        let specs = msg.target_specs();
+        if specs.is_empty() {
+            if let Some(targets) = self.targets.get_mut(&session) {
+                targets.remove(&id);
+                if targets.is_empty() {
+                    self.targets.remove(&session);
+                }
+            }
+        } else {
+            self.targets.entry(session).or_default().insert(id, specs);
+        }
+        true
+    }
+
+    /// Returns the receivers registered by the user under the given ID.
+    pub fn get(&self, session: u32, id: u32) -> Option<&[TargetSpec]> {
+        Some(self.targets.get(&session)?.get(&id)?)
+    }
+
+    /// Removes all targets of the user, e.g. once they disconnected.
+    pub fn remove_session(&mut self, session: u32) {
+        self.targets.remove(&session);
+    }
+}
+
+/// Expands the target of a voice packet into the sessions which should receive it.
+///
+/// Follows Murmur's rules, except for permissions: the speaker is never included (other than
+/// for loopback), neither are deafened users. Whether the speaker may speak or whisper into the
+/// channels in question has to be checked separately.
+#[derive(Clone, Copy, Debug)]
+pub struct VoiceTargetResolver<'a, G = BuiltinGroups> {
+    channels: &'a ChannelTree,
+    users: &'a UserRegistry,
+    targets: &'a VoiceTargetRegistry,
+    groups: G,
+}
+
+impl<'a> VoiceTargetResolver<'a> {
+    /// Creates a resolver evaluating groups with [BuiltinGroups].
+    pub fn new(
+        channels: &'a ChannelTree,
+        users: &'a UserRegistry,
+        targets: &'a VoiceTargetRegistry,
+    ) -> Self {
+        VoiceTargetResolver {
+            channels,
+            users,
+            targets,
+            groups: BuiltinGroups,
+        }
+    }
+}
+
+impl<'a, G: GroupMembership> VoiceTargetResolver<'a, G> {
+    /// Replaces the way group membership is evaluated.
+    pub fn with_groups<H: GroupMembership>(self, groups: H) -> VoiceTargetResolver<'a, H> {
+        VoiceTargetResolver {
+            channels: self.channels,
+            users: self.users,
+            targets: self.targets,
+            groups,
+        }
+    }
+
+    /// Returns the sessions receiving voice the speaker sends to the given target.
+    ///
+    /// Target 0 is the speaker's channel including linked channels and listeners, targets 1 to
+    /// 30 are the ones the speaker registered and 31 is loopback. Unregistered or unknown
+    /// targets and unknown speakers have no receivers.
+    pub fn resolve(&self, speaker: u32, target: u8) -> HashSet<u32> {
+        let speaker = match self.users.get(speaker) {
+            Some(speaker) => speaker,
+            None => return HashSet::new(),
+        };
+        match target {
+            0 => {
+                let channels = self.expand(speaker.channel_id, Links::Yes, Children::No);
+                self.receivers(speaker, &channels, None)
+            }
+            1..=30 => match self.targets.get(speaker.session, target.into()) {
+                Some(specs) => self.resolve_specs(speaker.session, specs),
+                None => HashSet::new(),
+            },
+            31 => HashSet::from([speaker.session]),
+            _ => HashSet::new(),
+        }
+    }
+
+    /// Returns the sessions receiving voice the speaker sends to the given receivers directly,
+    /// i.e. without registering them as target first.
+    pub fn resolve_specs(&self, speaker: u32, specs: &[TargetSpec]) -> HashSet<u32> {
+        let speaker = match self.users.get(speaker) {
+            Some(speaker) => speaker,
+            None => return HashSet::new(),
+        };
+        let mut receivers = HashSet::new();
+        for spec in specs {
+            match spec {
+                TargetSpec::Users(sessions) => receivers.extend(
+                    sessions
+                        .iter()
+                        .filter_map(|it| self.users.get(*it))
+                        .filter(|it| it.session != speaker.session && !is_deaf(it))
+                        .map(|it| it.session),
+                ),
+                TargetSpec::Channel {
+                    channel_id,
+                    group,
+                    links,
+                    children,
+                } => {
+                    let channels = self.expand(*channel_id, *links, *children);
+                    receivers.extend(self.receivers(speaker, &channels, group.as_deref()));
+                }
+            }
+        }
+        receivers
+    }
+
+    /// Returns the channel, plus everything linked to it (directly or not), plus the subtrees of
+    /// all of those.
+    fn expand(&self, channel_id: u32, links: Links, children: Children) -> BTreeSet<u32> {
+        let mut channels = BTreeSet::new();
+        if self.channels.get(channel_id).is_none() {
+            return channels;
+        }
+        let mut pending = vec![channel_id];
+        while let Some(id) = pending.pop() {
+            if !channels.insert(id) {
+                continue;
+            }
+            if links == Links::Yes {
+                if let Some(channel) = self.channels.get(id) {
+                    pending.extend(channel.links.iter().copied());
+                }
+            }
+        }
+        if children == Children::Yes {
+            for id in channels.clone() {
+                channels.extend(self.channels.subtree(id).map(|it| it.id));
+            }
+        }
+        channels
+    }
+
+    fn receivers(
+        &self,
+        speaker: &User,
+        channels: &BTreeSet<u32>,
+        group: Option<&str>,
+    ) -> HashSet<u32> {
+        let mut receivers = HashSet::new();
+        for channel in channels.iter().filter_map(|it| self.channels.get(*it)) {
+            let users = self
+                .users
+                .in_channel(channel.id)
+                .chain(self.users.listeners_of(channel.id));
+            for user in users {
+                if user.session == speaker.session || is_deaf(user) {
+                    continue;
+                }
+                if group.is_none_or(|group| self.groups.is_member(channel, group, user)) {
+                    receivers.insert(user.session);
+                }
+            }
+        }
+        receivers
+    }
+}
+
+fn is_deaf(user: &User) -> bool {
+    user.deaf || user.self_deaf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    //    0
+    //   / \
+    //  1   2 <-> 4
+    //  |   |
+    //  3   5
+    fn channels() -> ChannelTree {
+        let mut channels = ChannelTree::new();
+        for (id, parent) in [(0, None), (1, Some(0)), (2, Some(0)), (3, Some(1))] {
+            channels
+                .apply(&msgs::ChannelState {
+                    channel_id: Some(id),
+                    parent,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        channels
+            .apply(&msgs::ChannelState {
+                channel_id: Some(4),
+                parent: Some(0),
+                links: vec![2],
+                ..Default::default()
+            })
+            .unwrap();
+        channels
+            .apply(&msgs::ChannelState {
+                channel_id: Some(5),
+                parent: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+        channels
+    }
+
+    // Session n is in channel n, session 10 + n as well and is registered
+    fn users() -> UserRegistry {
+        let mut users = UserRegistry::new();
+        for channel_id in 0..=5 {
+            for (session, user_id) in [(channel_id, None), (10 + channel_id, Some(channel_id))] {
+                users
+                    .apply(&msgs::UserState {
+                        session: Some(session),
+                        name: Some(format!("user{}", session)),
+                        channel_id: Some(channel_id),
+                        user_id,
+                        ..Default::default()
+                    })
+                    .unwrap();
+            }
+        }
+        users
+    }
+
+    fn sessions<const N: usize>(sessions: [u32; N]) -> HashSet<u32> {
+        HashSet::from(sessions)
+    }
+
+    #[test]
+    #[cfg(not(feature = "webrtc-extensions"))]
+    fn resolves_channel_speech_with_links_and_listeners() {
+        let channels = channels();
+        let mut users = users();
+        users
+            .apply(&msgs::UserState {
+                session: Some(3),
+                listening_channel_add: vec![4],
+                ..Default::default()
+            })
+            .unwrap();
+        let targets = VoiceTargetRegistry::new();
+        let resolver = VoiceTargetResolver::new(&channels, &users, &targets);
+        assert_eq!(sessions([12, 4, 14, 3]), resolver.resolve(2, 0));
+        // Sub-channels are not included
+        assert_eq!(sessions([11]), resolver.resolve(1, 0));
+        assert_eq!(sessions([5]), resolver.resolve(15, 0));
+    }
+
+    #[test]
+    #[cfg(not(feature = "webrtc-extensions"))]
+    fn includes_listeners_in_channel_targets() {
+        let channels = channels();
+        let mut users = users();
+        // Session 13 is in channel 3 already, listening to it as well must not matter
+        for session in [1, 13] {
+            users
+                .apply(&msgs::UserState {
+                    session: Some(session),
+                    listening_channel_add: vec![3],
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let targets = VoiceTargetRegistry::new();
+        let resolver = VoiceTargetResolver::new(&channels, &users, &targets);
+        let specs = [TargetSpec::Channel {
+            channel_id: 3,
+            group: None,
+            links: Links::No,
+            children: Children::No,
+        }];
+        assert_eq!(sessions([3, 13, 1]), resolver.resolve_specs(0, &specs));
+
+        users.remove_channel(3);
+        let resolver = VoiceTargetResolver::new(&channels, &users, &targets);
+        assert_eq!(sessions([3, 13]), resolver.resolve_specs(0, &specs));
+    }
+
+    #[test]
+    fn resolves_registered_targets() {
+        let channels = channels();
+        let users = users();
+        let mut targets = VoiceTargetRegistry::new();
+        let target = msgs::VoiceTarget::builder(1)
+            .users([3, 5, 42])
+            .channel(1, Links::No, Children::Yes)
+            .build()
+            .unwrap();
+        assert!(targets.register(1, &target));
+        let target = msgs::VoiceTarget::builder(2)
+            .channel(4, Links::Yes, Children::Yes)
+            .build()
+            .unwrap();
+        assert!(targets.register(1, &target));
+        let resolver = VoiceTargetResolver::new(&channels, &users, &targets);
+
+        // Unknown sessions are skipped, the speaker is excluded
+        assert_eq!(sessions([3, 5, 11, 13]), resolver.resolve(1, 1));
+        // Children of linked channels are included as well
+        assert_eq!(sessions([2, 12, 4, 14, 5, 15]), resolver.resolve(1, 2));
+        assert!(resolver.resolve(1, 3).is_empty());
+        assert!(resolver.resolve(2, 1).is_empty());
+        assert_eq!(sessions([1]), resolver.resolve(1, 31));
+        assert!(resolver.resolve(1, 32).is_empty());
+        assert!(resolver.resolve(99, 31).is_empty());
+    }
+
+    #[test]
+    fn follows_links_transitively() {
+        let mut channels = channels();
+        channels
+            .apply(&msgs::ChannelState {
+                channel_id: Some(3),
+                links_add: vec![4],
+                ..Default::default()
+            })
+            .unwrap();
+        let users = users();
+        let targets = VoiceTargetRegistry::new();
+        let resolver = VoiceTargetResolver::new(&channels, &users, &targets);
+        assert_eq!(sessions([13, 4, 14, 2, 12]), resolver.resolve(3, 0));
+        let specs = [TargetSpec::Channel {
+            channel_id: 2,
+            group: None,
+            links: Links::No,
+            children: Children::No,
+        }];
+        assert_eq!(sessions([12]), resolver.resolve_specs(2, &specs));
+    }
+
+    #[test]
+    fn replaces_and_removes_targets() {
+        let channels = channels();
+        let users = users();
+        let mut targets = VoiceTargetRegistry::new();
+        let target = msgs::VoiceTarget::builder(5).users([2]).build().unwrap();
+        assert!(targets.register(1, &target));
+        let target = msgs::VoiceTarget::builder(5).users([3]).build().unwrap();
+        assert!(targets.register(1, &target));
+        assert_eq!(
+            sessions([3]),
+            VoiceTargetResolver::new(&channels, &users, &targets).resolve(1, 5)
+        );
+
+        let target = msgs::VoiceTarget::builder(5).build().unwrap();
+        assert!(targets.register(1, &target));
+        assert!(targets.get(1, 5).is_none());
+        assert!(!targets.register(
+            1,
+            &msgs::VoiceTarget {
+                id: Some(31),
+                ..Default::default()
+            }
+        ));
+    }
+
+    #[test]
+    fn excludes_deafened_users() {
+        let channels = channels();
+        let mut users = users();
+        for (session, deaf, self_deaf) in [(2, Some(true), None), (12, None, Some(true))] {
+            users
+                .apply(&msgs::UserState {
+                    session: Some(session),
+                    deaf,
+                    self_deaf,
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let mut targets = VoiceTargetRegistry::new();
+        let target = msgs::VoiceTarget::builder(1).users([2, 3]).build().unwrap();
+        targets.register(1, &target);
+        let resolver = VoiceTargetResolver::new(&channels, &users, &targets);
+        assert_eq!(sessions([14]), resolver.resolve(4, 0));
+        assert_eq!(sessions([3]), resolver.resolve(1, 1));
+    }
+
+    #[test]
+    #[cfg(not(feature = "webrtc-extensions"))]
+    fn evaluates_groups() {
+        let channels = channels();
+        let mut users = users();
+        // Listeners are not in the channel the group is evaluated in
+        users
+            .apply(&msgs::UserState {
+                session: Some(0),
+                listening_channel_add: vec![2],
+                ..Default::default()
+            })
+            .unwrap();
+        let mut targets = VoiceTargetRegistry::new();
+        let target = msgs::VoiceTarget::builder(1)
+            .group_in_channel(2, "auth")
+            .target(TargetSpec::Channel {
+                channel_id: 0,
+                group: Some("!auth".to_owned()),
+                links: Links::No,
+                children: Children::Yes,
+            })
+            .build()
+            .unwrap();
+        targets.register(1, &target);
+        let target = msgs::VoiceTarget::builder(2)
+            .group_in_channel(2, "out")
+            .build()
+            .unwrap();
+        targets.register(1, &target);
+        let resolver = VoiceTargetResolver::new(&channels, &users, &targets);
+        assert_eq!(sessions([12, 0, 2, 3, 4, 5]), resolver.resolve(1, 1));
+        assert_eq!(sessions([0]), resolver.resolve(1, 2));
+
+        let admins = |_: &Channel, group: &str, user: &User| group == "admins" && user.session > 13;
+        let resolver = resolver.with_groups(admins);
+        assert_eq!(sessions([]), resolver.resolve(1, 2));
+        let specs = [TargetSpec::Channel {
+            channel_id: 0,
+            group: Some("admins".to_owned()),
+            links: Links::No,
+            children: Children::Yes,
+        }];
+        assert_eq!(sessions([14, 15]), resolver.resolve_specs(1, &specs));
+    }
+}
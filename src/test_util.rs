@@ -0,0 +1,19 @@
+//! Helpers shared by the unit tests
+
+/// Deterministic xorshift64 pseudo-random number generator.
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    /// Creates a generator with a fixed seed, so tests see the same sequence on every run.
+    pub(crate) fn new() -> Self {
+        XorShift(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Advances the generator and returns its new state.
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
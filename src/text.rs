@@ -0,0 +1,1196 @@
+//! Handling of the HTML content of `TextMessage`s
+//!
+//! Mumble clients send text messages as (Qt flavoured) HTML. [to_plain_text] renders it for
+//! places which cannot display HTML, and [sanitize_html] reduces it to a small set of harmless
+//! tags before it is displayed or relayed.
+//!
+//! Both are based on a small, permissive tokenizer rather than a full HTML5 parser: malformed
+//! input never fails, but may be interpreted differently than a browser would.
+
+use bytes::Bytes;
+
+use crate::control::msgs;
+
+/// Tags whose content is never displayed.
+const HIDDEN_TAGS: &[&str] = &["head", "script", "style", "title"];
+
+/// Tags which start on a new line in plain text.
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "blockquote",
+    "div",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "table",
+    "tr",
+    "ul",
+];
+
+/// Tags which never have content or a closing tag.
+const VOID_TAGS: &[&str] = &["br", "hr", "img"];
+
+/// URL schemes allowed in links, including the ones the Mumble client uses internally.
+const LINK_SCHEMES: &[&str] = &[
+    "http",
+    "https",
+    "ftp",
+    "mailto",
+    "mumble",
+    "clientid",
+    "channelid",
+];
+
+/// CSS properties allowed in `style` attributes.
+const STYLE_PROPERTIES: &[&str] = &[
+    "background-color",
+    "color",
+    "font-family",
+    "font-size",
+    "font-style",
+    "font-weight",
+    "text-decoration",
+];
+
+/// Longest entity name (or numeric reference) which is decoded, excluding `&` and `;`.
+const MAX_ENTITY_LEN: usize = 8;
+
+/// The tags and attributes [sanitize_html] keeps.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    tags: Vec<(String, Vec<String>)>,
+    remote_images: bool,
+}
+
+impl Default for Policy {
+    /// Allows basic formatting (`b`, `i`, `u`, `span` with harmless `style`s), line breaks and
+    /// paragraphs, links and embedded (`data:`) images, which mirrors what the Mumble client
+    /// displays.
+    fn default() -> Self {
+        Policy::none()
+            .allow("b", &[])
+            .allow("i", &[])
+            .allow("u", &[])
+            .allow("br", &[])
+            .allow("p", &[])
+            .allow("span", &["style"])
+            .allow("a", &["href"])
+            .allow("img", &["src", "alt", "width", "height"])
+    }
+}
+
+impl Policy {
+    /// Creates a policy which allows no tags at all, i.e. only keeps the text.
+    pub fn none() -> Self {
+        Policy {
+            tags: Vec::new(),
+            remote_images: false,
+        }
+    }
+
+    /// Allows a tag with the given attributes, replacing any previous attributes of it.
+    ///
+    /// `href`, `src` and `style` attributes are always restricted to harmless values.
+    pub fn allow(mut self, tag: &str, attributes: &[&str]) -> Self {
+        let tag = tag.to_ascii_lowercase();
+        let attributes = attributes
+            .iter()
+            .map(|it| it.to_ascii_lowercase())
+            .collect();
+        self.tags.retain(|(name, _)| *name != tag);
+        self.tags.push((tag, attributes));
+        self
+    }
+
+    /// Sets whether images may be loaded from `http(s)` URLs, instead of only being embedded.
+    ///
+    /// Disallowed by default, since loading them reveals the reader's address.
+    pub fn allow_remote_images(mut self, allow: bool) -> Self {
+        self.remote_images = allow;
+        self
+    }
+
+    fn tag(&self, name: &str) -> Option<usize> {
+        self.tags
+            .iter()
+            .position(|(tag, _)| tag.eq_ignore_ascii_case(name))
+    }
+
+    fn allows_attribute(&self, tag: usize, name: &str) -> bool {
+        self.tags[tag]
+            .1
+            .iter()
+            .any(|it| it.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Converts a message to plain text.
+///
+/// Tags are removed, entities decoded and whitespace collapsed. Line breaks and block elements
+/// (paragraphs, list items, ...) start a new line, links are rendered as `label <url>` and
+/// images by their `alt` text.
+pub fn to_plain_text(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut scratch = String::new();
+    let mut hidden: Option<&str> = None;
+    let mut pre = 0usize;
+    // Start of the label and target of each open link
+    let mut links: Vec<(usize, Option<String>)> = Vec::new();
+
+    for token in Tokenizer::new(html) {
+        if let Some(tag) = hidden {
+            if matches!(token, Token::End(name) if name.eq_ignore_ascii_case(tag)) {
+                hidden = None;
+            }
+            continue;
+        }
+        match token {
+            Token::Text(text) => {
+                scratch.clear();
+                decode_entities(text, &mut scratch);
+                for c in scratch.chars() {
+                    if pre == 0 && c.is_ascii_whitespace() {
+                        if !out.is_empty() && !out.ends_with([' ', '\n']) {
+                            out.push(' ');
+                        }
+                    } else {
+                        out.push(c);
+                    }
+                }
+            }
+            Token::Start {
+                name,
+                attributes,
+                self_closing,
+            } => {
+                if is_one_of(name, HIDDEN_TAGS) {
+                    if !self_closing {
+                        hidden = Some(name);
+                    }
+                } else if name.eq_ignore_ascii_case("br") {
+                    trim_spaces(&mut out);
+                    out.push('\n');
+                } else if name.eq_ignore_ascii_case("img") {
+                    if let Some(alt) = attribute(attributes, "alt") {
+                        out.push_str(&alt);
+                    }
+                } else if name.eq_ignore_ascii_case("a") {
+                    if !self_closing {
+                        links.push((out.len(), attribute(attributes, "href")));
+                    }
+                } else if is_one_of(name, BLOCK_TAGS) {
+                    new_line(&mut out);
+                    if name.eq_ignore_ascii_case("pre") && !self_closing {
+                        pre += 1;
+                    } else if name.eq_ignore_ascii_case("li") {
+                        out.push_str("- ");
+                    }
+                }
+            }
+            Token::End(name) => {
+                if name.eq_ignore_ascii_case("a") {
+                    if let Some((start, Some(href))) = links.pop() {
+                        if out[start..].trim() != href {
+                            trim_spaces(&mut out);
+                            out.push_str(" <");
+                            out.push_str(&href);
+                            out.push('>');
+                        }
+                    }
+                } else if is_one_of(name, BLOCK_TAGS) {
+                    new_line(&mut out);
+                    if name.eq_ignore_ascii_case("pre") {
+                        pre = pre.saturating_sub(1);
+                    }
+                }
+            }
+        }
+    }
+
+    out.truncate(out.trim_end().len());
+    out
+}
+
+/// Removes all tags and attributes not allowed by the policy.
+///
+/// Disallowed tags are dropped while their text is kept, except for scripts and style sheets
+/// which are dropped entirely. Links are restricted to common URL schemes, images to embedded
+/// ones (see [Policy::allow_remote_images]) and styles to fonts and colors. Text and attribute
+/// values are re-escaped and all tags are balanced, so the result is well-formed.
+pub fn sanitize_html(html: &str, policy: &Policy) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut scratch = String::new();
+    let mut hidden: Option<&str> = None;
+    let mut open: Vec<usize> = Vec::new();
+
+    for token in Tokenizer::new(html) {
+        if let Some(tag) = hidden {
+            if matches!(token, Token::End(name) if name.eq_ignore_ascii_case(tag)) {
+                hidden = None;
+            }
+            continue;
+        }
+        match token {
+            Token::Text(text) => {
+                scratch.clear();
+                decode_entities(text, &mut scratch);
+                escape_into(&scratch, &mut out);
+            }
+            Token::Start {
+                name,
+                attributes,
+                self_closing,
+            } => {
+                if is_one_of(name, HIDDEN_TAGS) {
+                    if !self_closing {
+                        hidden = Some(name);
+                    }
+                    continue;
+                }
+                let void = is_one_of(name, VOID_TAGS);
+                let Some(tag) = policy.tag(name) else {
+                    continue;
+                };
+                if self_closing && !void {
+                    // An empty element, nothing to keep
+                    continue;
+                }
+                out.push('<');
+                out.push_str(&policy.tags[tag].0);
+                for (attr, value) in Attributes(attributes) {
+                    if !policy.allows_attribute(tag, attr) {
+                        continue;
+                    }
+                    scratch.clear();
+                    decode_entities(value.unwrap_or_default(), &mut scratch);
+                    let Some(value) = filter_attribute(attr, &scratch, policy) else {
+                        continue;
+                    };
+                    out.push(' ');
+                    out.push_str(&attr.to_ascii_lowercase());
+                    out.push_str("=\"");
+                    escape_into(&value, &mut out);
+                    out.push('"');
+                }
+                out.push('>');
+                if !void {
+                    open.push(tag);
+                }
+            }
+            Token::End(name) => {
+                let Some(tag) = policy.tag(name) else {
+                    continue;
+                };
+                // Implicitly closes everything opened after it, ignore it if it is not open
+                if let Some(pos) = open.iter().rposition(|it| *it == tag) {
+                    for tag in open.drain(pos..).rev() {
+                        close_tag(&policy.tags[tag].0, &mut out);
+                    }
+                }
+            }
+        }
+    }
+
+    for tag in open.into_iter().rev() {
+        close_tag(&policy.tags[tag].0, &mut out);
+    }
+    out
+}
+
+/// Escapes text so it is displayed literally when interpreted as HTML.
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    escape_into(text, &mut out);
+    out
+}
+
+/// An image embedded into a message as `data:` URI.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EmbeddedImage {
+    /// MIME type of the image, e.g. `image/png`.
+    pub mime: String,
+    /// The raw image data.
+    pub data: Bytes,
+}
+
+impl EmbeddedImage {
+    /// Creates an image of the given type.
+    pub fn new(mime: impl Into<String>, data: impl Into<Bytes>) -> Self {
+        EmbeddedImage {
+            mime: mime.into(),
+            data: data.into(),
+        }
+    }
+
+    /// Returns the `<img>` tag embedding the image.
+    pub fn to_html(&self) -> String {
+        let mut html = String::with_capacity(self.data.len() * 4 / 3 + self.mime.len() + 32);
+        html.push_str("<img src=\"data:");
+        escape_into(&self.mime, &mut html);
+        html.push_str(";base64,");
+        encode_base64(&self.data, &mut html);
+        html.push_str("\">");
+        html
+    }
+
+    /// Parses a `data:image/...;base64,...` URI.
+    fn from_data_uri(uri: &str) -> Option<Self> {
+        let uri = uri.trim();
+        if !starts_with_ignore_case(uri, "data:image/") {
+            return None;
+        }
+        let (header, data) = uri["data:".len()..].split_once(',')?;
+        let mut params = header.split(';');
+        let mime = params.next()?.to_owned();
+        if !params.any(|it| it.eq_ignore_ascii_case("base64")) {
+            return None;
+        }
+        Some(EmbeddedImage::new(mime, decode_base64(data)?))
+    }
+}
+
+/// Returns all images embedded into the message, in the order they appear in.
+///
+/// Only images with a base64 encoded `data:image/` source are returned, others are skipped.
+pub fn extract_images(msg: &msgs::TextMessage) -> Vec<EmbeddedImage> {
+    #[cfg(feature = "protobuf")]
+    let html = msg.message();
+    #[cfg(feature = "prost")]
+    let html = &msg.message;
+    images(html).collect()
+}
+
+/// Returns an iterator over the images embedded into the HTML, see [extract_images].
+pub fn images(html: &str) -> impl Iterator<Item = EmbeddedImage> + '_ {
+    let mut scratch = String::new();
+    Tokenizer::new(html).filter_map(move |token| match token {
+        Token::Start {
+            name, attributes, ..
+        } if name.eq_ignore_ascii_case("img") => {
+            let (_, src) = Attributes(attributes).find(|(it, _)| it.eq_ignore_ascii_case("src"))?;
+            scratch.clear();
+            decode_entities(src?, &mut scratch);
+            EmbeddedImage::from_data_uri(&scratch)
+        }
+        _ => None,
+    })
+}
+
+/// Smallest limit accepted by [split_message], enough for any character or entity.
+pub const MIN_SPLIT_LIMIT: usize = 16;
+
+/// Splits a message into chunks of at most `limit` bytes each.
+///
+/// Splits never happen inside a tag, an entity or a character, and prefer line breaks and
+/// paragraph ends over word boundaries over anything else. Formatting which is open at a split
+/// is closed at the end of the chunk and reopened at the start of the next one, so each chunk
+/// is valid on its own. Tags which do not fit into a chunk on their own are dropped.
+///
+/// The length in bytes is never lower than the UTF-16 length the server's
+/// [MessageLimits](crate::control::limits::MessageLimits) are measured in, so the chunks fit
+/// either way.
+///
+/// # Panics
+///
+/// Panics if `limit` is smaller than [MIN_SPLIT_LIMIT].
+pub fn split_message(html: &str, limit: usize) -> Vec<String> {
+    assert!(
+        limit >= MIN_SPLIT_LIMIT,
+        "split limit {} smaller than {}",
+        limit,
+        MIN_SPLIT_LIMIT
+    );
+    if html.len() <= limit {
+        return vec![html.to_owned()];
+    }
+
+    let mut atoms = atoms(html);
+    let mut chunks = Vec::new();
+    let mut open: Vec<(&str, &str)> = Vec::new();
+    let mut i = 0;
+    while i < atoms.len() {
+        let mut chunk = Chunk::new(&open, limit);
+        let first = i;
+        // Byte length, priority and atom index of the places the chunk could end
+        let mut breaks: Vec<(usize, u8, usize)> = Vec::new();
+        while i < atoms.len() {
+            if chunk.push(atoms[i]) {
+                i += 1;
+                // Ending the chunk right after an opening tag would leave it empty
+                if !matches!(atoms[i - 1], Atom::Start { .. }) {
+                    let priority = atoms[i - 1].break_priority();
+                    breaks.push((chunk.content.len(), priority, i));
+                }
+                continue;
+            }
+            if let Some(index) = best_break(&breaks, chunk.content.len()) {
+                // Replay up to the chosen break to restore the formatting open there
+                chunk = Chunk::new(&open, limit);
+                for atom in &atoms[first..index] {
+                    chunk.push(*atom);
+                }
+                i = index;
+            } else if let Atom::Word(word) = atoms[i] {
+                let mut len = chunk.available().min(word.len());
+                while !word.is_char_boundary(len) {
+                    len -= 1;
+                }
+                chunk.push(Atom::Word(&word[..len]));
+                atoms[i] = Atom::Word(&word[len..]);
+            } else if chunk.is_empty() {
+                // Does not even fit into an empty chunk
+                i += 1;
+                continue;
+            }
+            break;
+        }
+        open = chunk.open.clone();
+        if !chunk.is_empty() {
+            chunks.push(chunk.finish());
+        }
+    }
+    chunks
+}
+
+fn escape_into(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn close_tag(name: &str, out: &mut String) {
+    out.push_str("</");
+    out.push_str(name);
+    out.push('>');
+}
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(data: &[u8], out: &mut String) {
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, b)| acc | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+/// Decodes standard base64, ignoring whitespace and tolerating missing padding.
+fn decode_base64(text: &str) -> Option<Bytes> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            c if c.is_ascii_whitespace() => continue,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(value);
+        count += 1;
+        if count == 4 {
+            out.extend_from_slice(&bits.to_be_bytes()[1..]);
+            bits = 0;
+            count = 0;
+        }
+    }
+    match count {
+        0 => {}
+        2 => out.push((bits >> 4) as u8),
+        3 => out.extend_from_slice(&((bits >> 2) as u16).to_be_bytes()),
+        _ => return None,
+    }
+    Some(out.into())
+}
+
+/// Returns the value to keep for an allowed attribute, or `None` if it must be dropped.
+fn filter_attribute(name: &str, value: &str, policy: &Policy) -> Option<String> {
+    if name.eq_ignore_ascii_case("href") {
+        has_scheme(value, LINK_SCHEMES).then(|| value.trim().to_owned())
+    } else if name.eq_ignore_ascii_case("src") {
+        let value = value.trim();
+        let allowed = starts_with_ignore_case(value, "data:image/")
+            || (policy.remote_images && has_scheme(value, &["http", "https"]));
+        allowed.then(|| value.to_owned())
+    } else if name.eq_ignore_ascii_case("style") {
+        let style = filter_style(value);
+        (!style.is_empty()).then_some(style)
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+fn has_scheme(url: &str, schemes: &[&str]) -> bool {
+    match url.trim().split_once(':') {
+        Some((scheme, _)) => is_one_of(scheme, schemes),
+        None => false,
+    }
+}
+
+/// Keeps the allowed declarations of a `style` attribute.
+fn filter_style(style: &str) -> String {
+    let mut out = String::new();
+    for declaration in style.split(';') {
+        let Some((property, value)) = declaration.split_once(':') else {
+            continue;
+        };
+        let (property, value) = (property.trim(), value.trim());
+        // Anything which could load resources or escape the value is dropped
+        let harmless = value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || " #%,.-'\"".contains(c));
+        if is_one_of(property, STYLE_PROPERTIES) && harmless && !value.is_empty() {
+            if !out.is_empty() {
+                out.push_str("; ");
+            }
+            out.push_str(&property.to_ascii_lowercase());
+            out.push_str(": ");
+            out.push_str(value);
+        }
+    }
+    out
+}
+
+fn new_line(out: &mut String) {
+    trim_spaces(out);
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+}
+
+fn trim_spaces(out: &mut String) {
+    out.truncate(out.trim_end_matches(' ').len());
+}
+
+fn is_one_of(name: &str, names: &[&str]) -> bool {
+    names.iter().any(|it| it.eq_ignore_ascii_case(name))
+}
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.get(..prefix.len())
+        .is_some_and(|it| it.eq_ignore_ascii_case(prefix))
+}
+
+/// Returns the decoded value of an attribute.
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let (_, value) = Attributes(attributes).find(|(it, _)| it.eq_ignore_ascii_case(name))?;
+    let mut out = String::new();
+    decode_entities(value.unwrap_or_default(), &mut out);
+    Some(out)
+}
+
+/// Decodes character references, leaving unknown or malformed ones as they are.
+fn decode_entities(text: &str, out: &mut String) {
+    let mut rest = text;
+    while let Some(pos) = rest.find('&') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        let decoded = rest[1..]
+            .char_indices()
+            .take(MAX_ENTITY_LEN + 1)
+            .find(|(_, c)| *c == ';')
+            .and_then(|(end, _)| Some((decode_entity(&rest[1..end + 1])?, end + 2)));
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => number.parse(),
+        };
+        return code.ok().and_then(char::from_u32).filter(|c| *c != '\0');
+    }
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        _ => return None,
+    })
+}
+
+/// The pieces a message is split between, see [split_message].
+#[derive(Clone, Copy, Debug)]
+enum Atom<'a> {
+    Start { name: &'a str, raw: &'a str },
+    End(&'a str),
+    Void { name: &'a str, raw: &'a str },
+    Space(&'a str),
+    Word(&'a str),
+    Entity(&'a str),
+}
+
+impl Atom<'_> {
+    /// How well suited the place after this atom is for splitting.
+    fn break_priority(&self) -> u8 {
+        match self {
+            Atom::Void { name, .. } if name.eq_ignore_ascii_case("br") => 2,
+            Atom::End(name) if is_one_of(name, BLOCK_TAGS) => 2,
+            Atom::Space(_) => 1,
+            _ => 0,
+        }
+    }
+}
+
+fn atoms(html: &str) -> Vec<Atom<'_>> {
+    let mut atoms = Vec::new();
+    for token in Tokenizer::new(html) {
+        match token {
+            Token::Text(mut text) => {
+                while let Some(c) = text.chars().next() {
+                    let len = if c.is_ascii_whitespace() {
+                        c.len_utf8()
+                    } else if let Some(len) = (c == '&').then(|| entity_len(text)).flatten() {
+                        len
+                    } else {
+                        // At least the character itself, as it may be an `&` starting no entity
+                        c.len_utf8()
+                            + text[c.len_utf8()..]
+                                .find(|c: char| c.is_ascii_whitespace() || c == '&')
+                                .unwrap_or(text.len() - c.len_utf8())
+                    };
+                    let (atom, rest) = text.split_at(len);
+                    atoms.push(if c.is_ascii_whitespace() {
+                        Atom::Space(atom)
+                    } else if c == '&' && len > 1 {
+                        Atom::Entity(atom)
+                    } else {
+                        Atom::Word(atom)
+                    });
+                    text = rest;
+                }
+            }
+            Token::Start {
+                name,
+                attributes,
+                self_closing,
+            } => {
+                // The tag is `<`, name, attributes and `>` directly after each other
+                let raw = &html[offset(html, name) - 1..][..name.len() + attributes.len() + 2];
+                atoms.push(if self_closing || is_one_of(name, VOID_TAGS) {
+                    Atom::Void { name, raw }
+                } else {
+                    Atom::Start { name, raw }
+                });
+            }
+            Token::End(name) => atoms.push(Atom::End(name)),
+        }
+    }
+    atoms
+}
+
+fn offset(outer: &str, inner: &str) -> usize {
+    inner.as_ptr() as usize - outer.as_ptr() as usize
+}
+
+/// Returns the length of the entity at the start of the text, including `&` and `;`.
+fn entity_len(text: &str) -> Option<usize> {
+    let name = text.strip_prefix('&')?;
+    let end = name
+        .char_indices()
+        .take(MAX_ENTITY_LEN + 1)
+        .find(|(_, c)| *c == ';')?
+        .0;
+    decode_entity(&name[..end]).map(|_| end + 2)
+}
+
+/// Picks the break to end a chunk of the given length at.
+///
+/// The break with the highest priority in the second half of the chunk wins, or the last one
+/// if there is none.
+fn best_break(breaks: &[(usize, u8, usize)], len: usize) -> Option<usize> {
+    breaks
+        .iter()
+        .filter(|(at, _, _)| *at * 2 >= len)
+        .max_by_key(|(at, priority, _)| (*priority, *at))
+        .or(breaks.last())
+        .map(|(_, _, index)| *index)
+}
+
+/// A chunk being filled by [split_message].
+struct Chunk<'a> {
+    content: String,
+    open: Vec<(&'a str, &'a str)>,
+    /// Length of the closing tags of `open`.
+    closing_len: usize,
+    limit: usize,
+    empty: bool,
+}
+
+impl<'a> Chunk<'a> {
+    /// Starts a chunk, reopening the given formatting if there is enough space.
+    fn new(open: &[(&'a str, &'a str)], limit: usize) -> Self {
+        let mut chunk = Chunk {
+            content: String::new(),
+            open: Vec::new(),
+            closing_len: 0,
+            limit,
+            empty: true,
+        };
+        for (name, raw) in open {
+            chunk.push(Atom::Start { name, raw });
+        }
+        // Keep at least half of the chunk for the actual content, and enough for any entity
+        if chunk.available() < MIN_SPLIT_LIMIT.max(limit / 2) {
+            chunk.content.clear();
+            chunk.open.clear();
+            chunk.closing_len = 0;
+        }
+        chunk.empty = true;
+        chunk
+    }
+
+    fn is_empty(&self) -> bool {
+        self.empty
+    }
+
+    /// Space left for content, taking the closing tags into account.
+    fn available(&self) -> usize {
+        self.limit - self.content.len() - self.closing_len
+    }
+
+    /// Appends the atom if it fits.
+    fn push(&mut self, atom: Atom<'a>) -> bool {
+        let fits = match atom {
+            Atom::Start { name, raw } => {
+                let closing_len = close_tag_len(name);
+                if raw.len() + closing_len > self.available() {
+                    return false;
+                }
+                self.content.push_str(raw);
+                self.open.push((name, raw));
+                self.closing_len += closing_len;
+                true
+            }
+            Atom::End(name) => {
+                let Some(pos) = self
+                    .open
+                    .iter()
+                    .rposition(|(it, _)| it.eq_ignore_ascii_case(name))
+                else {
+                    // Closes nothing, drop it
+                    return true;
+                };
+                // Closing tags were already accounted for
+                for (name, _) in self.open.drain(pos..).rev() {
+                    close_tag(name, &mut self.content);
+                    self.closing_len -= close_tag_len(name);
+                }
+                true
+            }
+            Atom::Void { raw: text, .. }
+            | Atom::Space(text)
+            | Atom::Word(text)
+            | Atom::Entity(text) => {
+                if text.len() > self.available() {
+                    return false;
+                }
+                self.content.push_str(text);
+                true
+            }
+        };
+        self.empty = false;
+        fits
+    }
+
+    fn finish(mut self) -> String {
+        for (name, _) in self.open.iter().rev() {
+            close_tag(name, &mut self.content);
+        }
+        self.content
+    }
+}
+
+fn close_tag_len(name: &str) -> usize {
+    name.len() + 3
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token<'a> {
+    Text(&'a str),
+    Start {
+        name: &'a str,
+        /// Everything between the name and the closing `>`.
+        attributes: &'a str,
+        self_closing: bool,
+    },
+    End(&'a str),
+}
+
+/// Splits HTML into text and tags. Comments, doctypes and processing instructions are skipped,
+/// a `<` not starting a tag is treated as text and an unterminated tag at the end is dropped.
+struct Tokenizer<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn new(html: &'a str) -> Self {
+        Tokenizer { rest: html }
+    }
+
+    fn advance(&mut self, len: usize) -> &'a str {
+        let (head, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        head
+    }
+
+    fn skip_past(&mut self, pattern: &str) {
+        let len = self
+            .rest
+            .find(pattern)
+            .map_or(self.rest.len(), |pos| pos + pattern.len());
+        self.advance(len);
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+            let bytes = self.rest.as_bytes();
+            if bytes[0] != b'<' {
+                let len = self.rest.find('<').unwrap_or(self.rest.len());
+                return Some(Token::Text(self.advance(len)));
+            }
+            let closing = bytes.get(1) == Some(&b'/');
+            let name_start = if closing { 2 } else { 1 };
+            if self.rest.starts_with("<!--") {
+                self.skip_past("-->");
+                continue;
+            }
+            if self.rest.starts_with("<!") || self.rest.starts_with("<?") {
+                self.skip_past(">");
+                continue;
+            }
+            if !bytes.get(name_start).is_some_and(u8::is_ascii_alphabetic) {
+                return Some(Token::Text(self.advance(1)));
+            }
+            let name_len = bytes[name_start..]
+                .iter()
+                .position(|it| !it.is_ascii_alphanumeric())
+                .unwrap_or(bytes.len() - name_start);
+            let name_end = name_start + name_len;
+            let Some(end) = find_tag_end(&self.rest[name_end..]) else {
+                self.advance(self.rest.len());
+                return None;
+            };
+            let tag = self.advance(name_end + end + 1);
+            let name = &tag[name_start..name_end];
+            if closing {
+                return Some(Token::End(name));
+            }
+            let attributes = &tag[name_end..tag.len() - 1];
+            let self_closing = attributes.ends_with('/');
+            return Some(Token::Start {
+                name,
+                attributes,
+                self_closing,
+            });
+        }
+    }
+}
+
+/// Finds the `>` ending a tag, skipping over quoted attribute values.
+fn find_tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.bytes().enumerate() {
+        match (quote, c) {
+            (None, b'>') => return Some(i),
+            (None, b'"' | b'\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Iterates over the names and raw values of a tag's attributes.
+struct Attributes<'a>(&'a str);
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = (&'a str, Option<&'a str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self
+            .0
+            .trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+        if s.is_empty() {
+            return None;
+        }
+        let name_len = s
+            .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/')
+            .unwrap_or(s.len())
+            .max(1);
+        let (name, rest) = s.split_at(name_len);
+        let rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace());
+        let Some(rest) = rest.strip_prefix('=') else {
+            self.0 = rest;
+            return Some((name, None));
+        };
+        let rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace());
+        let (value, rest) = match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => match rest[1..].find(quote) {
+                Some(end) => (&rest[1..end + 1], &rest[end + 2..]),
+                None => (&rest[1..], ""),
+            },
+            _ => {
+                let end = rest
+                    .find(|c: char| c.is_ascii_whitespace())
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        self.0 = rest;
+        Some((name, Some(value)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::XorShift;
+
+    #[test]
+    fn converts_to_plain_text() {
+        assert_eq!(
+            "Hello world & <you>\nsecond line",
+            to_plain_text("<b>Hello</b>   world &amp; &lt;you&gt;<br/>second  line")
+        );
+        assert_eq!(
+            "intro\n- one\n- two\nsee docs <https://mumble.info/>",
+            to_plain_text(
+                "<p>intro</p><ul><li>one</li><li>two</li></ul>\
+                 see <a href=\"https://mumble.info/\">docs</a>"
+            )
+        );
+        assert_eq!(
+            "https://mumble.info/",
+            to_plain_text("<a href='https://mumble.info/'>https://mumble.info/</a>")
+        );
+        assert_eq!(
+            "visible",
+            to_plain_text("<html><head><style>p { color: red }</style></head>visible</html>")
+        );
+    }
+
+    #[test]
+    fn sanitizes_html() {
+        let policy = Policy::default();
+        assert_eq!(
+            "<b>bold</b> alert(1)",
+            sanitize_html(
+                "<b onclick=\"x()\">bold</b> <script>evil()</script>alert(1)",
+                &policy
+            )
+        );
+        assert_eq!(
+            "<a href=\"https://mumble.info/\">ok</a><a>bad</a>",
+            sanitize_html(
+                "<a href=\"https://mumble.info/\">ok</a><a href=\"javascript:x()\">bad</a>",
+                &policy
+            )
+        );
+        assert_eq!(
+            "<span style=\"color: red\">red</span>",
+            sanitize_html(
+                "<span style=\"color: red; background: url(x)\">red</span>",
+                &policy
+            )
+        );
+        assert_eq!(
+            "<img src=\"data:image/png;base64,AAAA\"><img>",
+            sanitize_html(
+                "<img src=\"data:image/png;base64,AAAA\"><img src=\"https://example.com/x.png\">",
+                &policy
+            )
+        );
+        assert_eq!(
+            "<b><i>unbalanced</i></b>",
+            sanitize_html("<b><i>unbalanced</b></i>", &policy)
+        );
+        assert_eq!("a &lt; b", sanitize_html("a < b<div>", &Policy::none()));
+    }
+
+    #[test]
+    fn decodes_entities_defensively() {
+        let mut out = String::new();
+        decode_entities("&amp;&#65;&#x42;&bogus;&#99999999999;&#0;&", &mut out);
+        assert_eq!("&AB&bogus;&#99999999999;&#0;&", out);
+    }
+
+    #[test]
+    fn extracts_images_in_order() {
+        let html = "<p>a<img src=\"data:image/png;base64,AAEC\">b</p>\
+                    <IMG SRC='data:image/jpeg;base64,\n/w=='>\
+                    <img src=\"https://example.com/x.png\"><img src=\"data:image/gif;base64,!!\">";
+        let images: Vec<_> = images(html).collect();
+        assert_eq!(
+            vec![
+                EmbeddedImage::new("image/png", &[0, 1, 2][..]),
+                EmbeddedImage::new("image/jpeg", &[0xff][..]),
+            ],
+            images
+        );
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        for len in 0..8 {
+            let data: Vec<u8> = (0..len).map(|it| it * 37).collect();
+            let mut encoded = String::new();
+            encode_base64(&data, &mut encoded);
+            assert_eq!(Some(Bytes::from(data)), decode_base64(&encoded));
+        }
+        assert_eq!(None, decode_base64("A"));
+    }
+
+    fn assert_chunks(chunks: &[String], limit: usize) {
+        for chunk in chunks {
+            assert!(
+                chunk.len() <= limit,
+                "{} > {}: {:?}",
+                chunk.len(),
+                limit,
+                chunk
+            );
+            assert_eq!(chunk.matches("<b>").count(), chunk.matches("</b>").count());
+        }
+    }
+
+    #[test]
+    fn splits_long_words() {
+        let word = "x".repeat(100_000);
+        let chunks = split_message(&word, 5000);
+        assert_chunks(&chunks, 5000);
+        assert_eq!(20, chunks.len());
+        assert_eq!(word, chunks.concat());
+
+        let word = "äöü🦀".repeat(1000);
+        let chunks = split_message(&word, 99);
+        assert_chunks(&chunks, 99);
+        assert_eq!(word, chunks.concat());
+    }
+
+    #[test]
+    fn splits_words_starting_with_non_ascii_characters() {
+        // A `;` shortly after a multi-byte character used to be checked for an entity
+        let text = "Ça va; ".repeat(10);
+        let chunks = split_message(&text, 32);
+        assert_chunks(&chunks, 32);
+        assert_eq!(text.trim_end(), chunks.concat().trim_end());
+        assert_eq!("Ça", to_plain_text("Ça"));
+        assert_eq!(None, entity_len("Ça;"));
+    }
+
+    #[test]
+    fn splits_on_preferred_boundaries() {
+        let chunks = split_message("first line<br>second line of text", 24);
+        assert_eq!(vec!["first line<br>", "second line of text"], chunks);
+
+        let chunks = split_message("one two three four five six", 16);
+        assert_eq!(vec!["one two three ", "four five six"], chunks);
+
+        let html = "&amp;".repeat(20);
+        let chunks = split_message(&html, 17);
+        assert_chunks(&chunks, 17);
+        assert!(chunks.iter().all(|it| it.len() % 5 == 0));
+        assert_eq!(html, chunks.concat());
+    }
+
+    #[test]
+    fn carries_formatting_and_keeps_tags_whole() {
+        let chunks = split_message("<b>bold words which go on and on</b> plain", 24);
+        assert_chunks(&chunks, 24);
+        assert!(chunks[..2].iter().all(|it| it.starts_with("<b>")));
+        assert_eq!(
+            "bold words which go on and on plain",
+            to_plain_text(&chunks.join(" "))
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+
+        let link = "<a href=\"https://example.com/a/rather/long/path\">link</a>";
+        let html = "some text before the link ".to_owned() + link + " after";
+        let chunks = split_message(&html, 60);
+        assert_chunks(&chunks, 60);
+        assert_eq!("some text before the link ", chunks[0]);
+        assert!(chunks[1].starts_with(link));
+    }
+
+    #[test]
+    fn survives_malformed_input() {
+        const ALPHABET: &[&str] = &[
+            "<", ">", "</", "/>", "&", ";", "#", "x", "=", "\"", "'", " ", "\n", "b", "a", "img",
+            "script", "href", "src", "<!--", "-->", "&amp", "ä", "🦀", "Ç",
+        ];
+        let mut rng = XorShift::new();
+        for _ in 0..2000 {
+            let mut input = String::new();
+            for _ in 0..rng.next_u64() % 64 {
+                input.push_str(ALPHABET[(rng.next_u64() % ALPHABET.len() as u64) as usize]);
+            }
+            let sanitized = sanitize_html(&input, &Policy::default());
+            assert!(sanitized.len() <= input.len() * 8, "{:?}", input);
+            assert_eq!(sanitized, sanitize_html(&sanitized, &Policy::default()));
+            assert!(
+                to_plain_text(&input).len() <= input.len() * 2,
+                "{:?}",
+                input
+            );
+            for chunk in split_message(&input, MIN_SPLIT_LIMIT) {
+                assert!(chunk.len() <= MIN_SPLIT_LIMIT, "{:?}", input);
+            }
+        }
+
+        // Deep nesting and long entity-like runs stay linear
+        let nested = "<b>".repeat(10_000) + &"&".repeat(10_000) + &"&#x1111111".repeat(1000);
+        assert!(sanitize_html(&nested, &Policy::default()).len() < nested.len() * 3);
+    }
+}
@@ -1,10 +1,98 @@
 //! Extension traits for Mumble's varint format.
+//!
+//! The format encodes 64-bit integers with a prefix in the first byte:
+//!
+//! | Prefix      | Bytes | Value                                          |
+//! |-------------|-------|------------------------------------------------|
+//! | `0xxxxxxx`  | 1     | 7-bit positive number                          |
+//! | `10xxxxxx`  | 2     | 14-bit positive number                         |
+//! | `110xxxxx`  | 3     | 21-bit positive number                         |
+//! | `1110xxxx`  | 4     | 28-bit positive number                         |
+//! | `111100__`  | 5     | 32-bit positive number                         |
+//! | `111101__`  | 9     | 64-bit number                                  |
+//! | `111110__`  | 1+    | negated (bitwise) varint following             |
+//! | `111111xx`  | 1     | bitwise negated two-bit number, i.e. -1 to -4  |
+//!
+//! [encode] and [decode] work with signed values, the extension traits with the same bit patterns
+//! as `u64`. Like Murmur, negative numbers are only written in the negated forms down to
+//! `-0x1_0000_0000`, smaller ones use the 64-bit form. Reading accepts either.
 
+use std::fmt;
 use std::io;
 
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
+use bytes::Buf;
 use bytes::BufMut;
+use bytes::BytesMut;
+
+/// Error returned by [decode].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VarintError {
+    /// The input ended in the middle of the varint.
+    Truncated,
+    /// The varint starts with a prefix which is never produced by encoders, e.g. a negated
+    /// varint which is negated again, or one with the unused (`_`) bits set.
+    ReservedPrefix(u8),
+}
+
+impl fmt::Display for VarintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VarintError::Truncated => f.write_str("truncated varint"),
+            VarintError::ReservedPrefix(prefix) => {
+                write!(f, "reserved varint prefix {:#010b}", prefix)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VarintError {}
+
+/// Appends `value` as a varint.
+pub fn encode(value: i64, dst: &mut BytesMut) {
+    dst.put_varint(value as u64);
+}
+
+/// Reads a varint.
+///
+/// Bytes which were read before an error occurred are consumed.
+pub fn decode(src: &mut impl Buf) -> Result<i64, VarintError> {
+    match read_u8(src)? {
+        0xf8 => match read_u8(src)? {
+            b1 @ 0xf8..=0xff => Err(VarintError::ReservedPrefix(b1)),
+            b1 => Ok(!decode_single(b1, src)?),
+        },
+        b0 => decode_single(b0, src),
+    }
+}
+
+fn read_u8(src: &mut impl Buf) -> Result<u8, VarintError> {
+    if src.has_remaining() {
+        Ok(src.get_u8())
+    } else {
+        Err(VarintError::Truncated)
+    }
+}
+
+/// Decodes the rest of a varint which is not a negated varint, given its first byte.
+fn decode_single(b0: u8, src: &mut impl Buf) -> Result<i64, VarintError> {
+    let (len, mut value) = match b0 {
+        0x00..=0x7f => return Ok(i64::from(b0)),
+        0x80..=0xbf => (1, u64::from(b0 & 0x3f)),
+        0xc0..=0xdf => (2, u64::from(b0 & 0x1f)),
+        0xe0..=0xef => (3, u64::from(b0 & 0x0f)),
+        0xf0 => (4, 0),
+        0xf4 => (8, 0),
+        0xfc..=0xff => return Ok(!i64::from(b0 & 0x03)),
+        _ => return Err(VarintError::ReservedPrefix(b0)),
+    };
+    for _ in 0..len {
+        value = value << 8 | u64::from(read_u8(src)?);
+    }
+    Ok(value as i64)
+}
 
 /// Extension trait for reading varint values.
 pub trait ReadExt: io::Read {
@@ -24,6 +112,30 @@ pub trait BufMutExt: BufMut {
     fn put_varint(&mut self, val: u64);
 }
 
+/// Returns whether `value` is negative and written as a negated varint, which like Murmur is
+/// only done if that is shorter than the 64-bit form.
+fn is_short_negative(value: u64) -> bool {
+    value & 0x8000_0000_0000_0000 != 0 && !value <= 0xffff_ffff
+}
+
+/// Returns the amount of bytes [encode] writes for `value`.
+pub fn encoded_len(value: i64) -> usize {
+    if (-4..0).contains(&value) {
+        return 1;
+    }
+    if is_short_negative(value as u64) {
+        return 1 + encoded_len(!value);
+    }
+    match value as u64 {
+        0x1_0000_0000.. => 9,
+        0x1000_0000.. => 5,
+        0x20_0000.. => 4,
+        0x4000.. => 3,
+        0x80.. => 2,
+        _ => 1,
+    }
+}
+
 impl<T: io::Read> ReadExt for T {
     fn read_varint(&mut self) -> io::Result<u64> {
         let b0 = self.read_u8()?;
@@ -78,7 +190,7 @@ impl<T: io::Write> WriteExt for T {
         if value & 0xffff_ffff_ffff_fffc == 0xffff_ffff_ffff_fffc {
             return self.write_u8(0b1111_1100 | (!value as u8));
         }
-        if value & 0x8000_0000_0000_0000 == 0x8000_0000_0000_0000 {
+        if is_short_negative(value) {
             self.write_u8(0b1111_1000)?;
             return self.write_varint(!value);
         }
@@ -132,3 +244,134 @@ impl<T: BufMut> BufMutExt for T {
             .expect("BufMut::writer never errors");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::XorShift;
+
+    fn encoded(value: i64) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        encode(value, &mut dst);
+        dst.to_vec()
+    }
+
+    #[test]
+    fn round_trips_boundary_values() {
+        // (value, encoded length, first byte)
+        let cases: &[(i64, usize, u8)] = &[
+            (0, 1, 0x00),
+            (0x7f, 1, 0x7f),
+            (0x80, 2, 0x80),
+            (0x3fff, 2, 0xbf),
+            (0x4000, 3, 0xc0),
+            (0x1f_ffff, 3, 0xdf),
+            (0x20_0000, 4, 0xe0),
+            (0xfff_ffff, 4, 0xef),
+            (0x1000_0000, 5, 0xf0),
+            (0xffff_ffff, 5, 0xf0),
+            (0x1_0000_0000, 9, 0xf4),
+            (i64::MAX, 9, 0xf4),
+            (-1, 1, 0xfc),
+            (-4, 1, 0xff),
+            (-5, 2, 0xf8),
+            (-0x80, 2, 0xf8),
+            (-0x81, 3, 0xf8),
+            (-0x1_0000_0000, 6, 0xf8),
+            (-0x1_0000_0001, 9, 0xf4),
+            (i64::MIN, 9, 0xf4),
+        ];
+        for &(value, len, first) in cases {
+            let bytes = encoded(value);
+            assert_eq!((len, first), (bytes.len(), bytes[0]), "{}", value);
+            assert_eq!(len, encoded_len(value), "{}", value);
+            assert_eq!(Ok(value), decode(&mut &bytes[..]), "{}", value);
+            assert_eq!(
+                value as u64,
+                io::Cursor::new(&bytes).read_varint().unwrap(),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn round_trips_full_range() {
+        // Shifted by varying amounts to cover every magnitude of either sign
+        let mut rng = XorShift::new();
+        for i in 0..100_000 {
+            let value = (rng.next_u64() as i64) >> (i % 64);
+            let bytes = encoded(value);
+            assert_eq!(bytes.len(), encoded_len(value), "{}", value);
+            assert_eq!(Ok(value), decode(&mut &bytes[..]), "{}", value);
+            let mut cursor = io::Cursor::new(&bytes);
+            assert_eq!(value as u64, cursor.read_varint().unwrap(), "{}", value);
+            let mut written = Vec::new();
+            written.write_varint(value as u64).unwrap();
+            assert_eq!(bytes, written);
+        }
+    }
+
+    #[test]
+    fn reads_long_negated_forms() {
+        // Written by older versions of this crate instead of the 64-bit form
+        let bytes = [0xf8, 0xf4, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(Ok(i64::MIN), decode(&mut &bytes[..]));
+        let value = io::Cursor::new(&bytes).read_varint().unwrap();
+        assert_eq!(i64::MIN as u64, value);
+        // Negated small forms may also be written the long way
+        assert_eq!(Ok(-1), decode(&mut &[0xf8, 0x00][..]));
+        assert_eq!(Ok(-0x4001), decode(&mut &[0xf8, 0xc0, 0x40, 0x00][..]));
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        for value in [
+            0x80,
+            0x4000,
+            0x20_0000,
+            0x1000_0000,
+            i64::MAX,
+            -5,
+            -0x1_0000_0000,
+            i64::MIN,
+        ] {
+            let bytes = encoded(value);
+            for len in 0..bytes.len() {
+                assert_eq!(
+                    Err(VarintError::Truncated),
+                    decode(&mut &bytes[..len]),
+                    "{} truncated to {}",
+                    value,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_prefixes() {
+        for prefix in [0xf1, 0xf3, 0xf5, 0xf7, 0xf9, 0xfb] {
+            assert_eq!(
+                Err(VarintError::ReservedPrefix(prefix)),
+                decode(&mut &[prefix, 0, 0, 0, 0, 0, 0, 0, 0][..])
+            );
+        }
+        // Negating twice
+        assert_eq!(
+            Err(VarintError::ReservedPrefix(0xf8)),
+            decode(&mut &[0xf8, 0xf8, 0x05][..])
+        );
+        assert_eq!(
+            Err(VarintError::ReservedPrefix(0xfc)),
+            decode(&mut &[0xf8, 0xfc][..])
+        );
+    }
+
+    #[test]
+    fn leaves_trailing_bytes() {
+        let mut src = &[0x81, 0x00, 0x42][..];
+        assert_eq!(Ok(0x100), decode(&mut src));
+        assert_eq!(&[0x42], src);
+    }
+}
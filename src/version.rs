@@ -0,0 +1,296 @@
+//! Packing and unpacking of Mumble version numbers
+//!
+//! Mumble transmits versions in two formats: the legacy one packs major, minor and patch into a
+//! `u32` as `(major << 16) | (minor << 8) | patch`, the newer one (`version_v2`) uses 16 bits for
+//! each of them in a `u64` as `(major << 48) | (minor << 32) | (patch << 16)`.
+
+use std::fmt;
+
+use crate::control::msgs;
+
+/// Packs a version into the legacy `u32` format.
+///
+/// Since minor and patch only have 8 bits in this format, values above 255 are saturated to
+/// 255 rather than overflowing into the neighbouring component.
+pub fn encode_version(major: u16, minor: u16, patch: u16) -> u32 {
+    let minor = minor.min(0xff) as u32;
+    let patch = patch.min(0xff) as u32;
+    (major as u32) << 16 | minor << 8 | patch
+}
+
+/// Unpacks a version in the legacy `u32` format into major, minor and patch.
+pub fn decode_version(version: u32) -> (u16, u8, u8) {
+    ((version >> 16) as u16, (version >> 8) as u8, version as u8)
+}
+
+/// Packs a version into the `u64` format of `version_v2`.
+pub fn encode_version_v2(major: u16, minor: u16, patch: u16) -> u64 {
+    (major as u64) << 48 | (minor as u64) << 32 | (patch as u64) << 16
+}
+
+/// Unpacks a version in the `u64` format of `version_v2` into major, minor and patch.
+pub fn decode_version_v2(version: u64) -> (u16, u16, u16) {
+    (
+        (version >> 48) as u16,
+        (version >> 32) as u16,
+        (version >> 16) as u16,
+    )
+}
+
+/// A Mumble version, ordered by major, minor and patch.
+///
+/// Feature cut-offs can be expressed as constants, e.g.
+/// `const OPUS: ProtocolVersion = ProtocolVersion::new(1, 2, 4);`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion {
+    /// Major version.
+    pub major: u16,
+    /// Minor version.
+    pub minor: u16,
+    /// Patch level.
+    pub patch: u16,
+}
+
+impl ProtocolVersion {
+    /// Creates a new version.
+    pub const fn new(major: u16, minor: u16, patch: u16) -> Self {
+        ProtocolVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Unpacks a version in the legacy `u32` format.
+    pub fn from_v1(version: u32) -> Self {
+        let (major, minor, patch) = decode_version(version);
+        ProtocolVersion::new(major, minor.into(), patch.into())
+    }
+
+    /// Packs this version into the legacy `u32` format, see [encode_version].
+    pub fn to_v1(self) -> u32 {
+        encode_version(self.major, self.minor, self.patch)
+    }
+
+    /// Unpacks a version in the `u64` format of `version_v2`.
+    pub fn from_v2(version: u64) -> Self {
+        let (major, minor, patch) = decode_version_v2(version);
+        ProtocolVersion::new(major, minor, patch)
+    }
+
+    /// Packs this version into the `u64` format of `version_v2`.
+    pub fn to_v2(self) -> u64 {
+        encode_version_v2(self.major, self.minor, self.patch)
+    }
+
+    /// Reads the version from a [msgs::Version] message.
+    ///
+    /// Prefers `version_v2` if present, since the legacy field cannot represent minor and patch
+    /// versions above 255. Returns `None` if neither is set.
+    pub fn from_version_message(msg: &msgs::Version) -> Option<Self> {
+        #[cfg(not(feature = "webrtc-extensions"))]
+        return msg
+            .version_v2
+            .map(ProtocolVersion::from_v2)
+            .or_else(|| msg.version_v1.map(ProtocolVersion::from_v1));
+        // The WebRTC fork of the protocol only knows the legacy format
+        #[cfg(feature = "webrtc-extensions")]
+        return msg.version.map(ProtocolVersion::from_v1);
+    }
+
+    /// Sets the version of a [msgs::Version] message in all formats known to the protocol.
+    pub fn apply_to(self, msg: &mut msgs::Version) {
+        #[cfg(not(feature = "webrtc-extensions"))]
+        {
+            msg.version_v1 = Some(self.to_v1());
+            msg.version_v2 = Some(self.to_v2());
+        }
+        #[cfg(feature = "webrtc-extensions")]
+        {
+            msg.version = Some(self.to_v1());
+        }
+    }
+
+    /// Returns whether this version is the given one or newer.
+    pub fn at_least(self, major: u16, minor: u16, patch: u16) -> bool {
+        self >= ProtocolVersion::new(major, minor, patch)
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Convenience methods for the version number of [msgs::Version].
+pub trait VersionExt {
+    /// Returns the version, see [ProtocolVersion::from_version_message].
+    fn semver(&self) -> Option<ProtocolVersion>;
+    /// Sets the version, see [ProtocolVersion::apply_to].
+    fn set_semver(&mut self, version: ProtocolVersion);
+}
+
+impl VersionExt for msgs::Version {
+    fn semver(&self) -> Option<ProtocolVersion> {
+        ProtocolVersion::from_version_message(self)
+    }
+
+    fn set_semver(&mut self, version: ProtocolVersion) {
+        version.apply_to(self)
+    }
+}
+
+/// Release name sent by [VersionBuilder] unless overridden.
+pub const DEFAULT_RELEASE: &str = concat!("mumble-protocol-2x ", env!("CARGO_PKG_VERSION"));
+
+/// Builder for the initial [msgs::Version] message.
+///
+/// Fills in the release name and the operating system the application is running on, see
+/// [os](Self::os).
+#[derive(Clone, Debug)]
+pub struct VersionBuilder {
+    version: ProtocolVersion,
+    release: String,
+    os: Option<(String, Option<String>)>,
+}
+
+impl VersionBuilder {
+    /// Creates a builder for a message announcing the given version.
+    pub fn new(version: ProtocolVersion) -> Self {
+        VersionBuilder {
+            version,
+            release: DEFAULT_RELEASE.to_owned(),
+            os: None,
+        }
+    }
+
+    /// Sets the release name, [DEFAULT_RELEASE] by default.
+    pub fn release(mut self, release: impl Into<String>) -> Self {
+        self.release = release.into();
+        self
+    }
+
+    /// Sets the operating system name and version.
+    ///
+    /// If not set, they are detected at runtime with the `os-info` feature. Otherwise only the
+    /// name is filled in from [std::env::consts::OS].
+    pub fn os(mut self, os: impl Into<String>, os_version: Option<String>) -> Self {
+        self.os = Some((os.into(), os_version));
+        self
+    }
+
+    /// Builds the message.
+    pub fn build(self) -> msgs::Version {
+        let (os, os_version) = self.os.unwrap_or_else(detect_os);
+        let mut msg = msgs::Version {
+            release: Some(self.release),
+            os: Some(os),
+            os_version,
+            ..Default::default()
+        };
+        self.version.apply_to(&mut msg);
+        msg
+    }
+}
+
+impl msgs::Version {
+    /// Creates a [VersionBuilder] for a message announcing the given version.
+    pub fn builder(version: ProtocolVersion) -> VersionBuilder {
+        VersionBuilder::new(version)
+    }
+}
+
+#[cfg(feature = "os-info")]
+fn detect_os() -> (String, Option<String>) {
+    let info = os_info::get();
+    (info.os_type().to_string(), Some(info.version().to_string()))
+}
+
+#[cfg(not(feature = "os-info"))]
+fn detect_os() -> (String, Option<String>) {
+    (std::env::consts::OS.to_owned(), None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(not(feature = "webrtc-extensions"))]
+    #[test]
+    fn prefers_version_v2() {
+        let legacy = msgs::Version {
+            version_v1: Some(0x010400),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(ProtocolVersion::new(1, 4, 0)),
+            ProtocolVersion::from_version_message(&legacy)
+        );
+
+        let modern = msgs::Version {
+            version_v2: Some(encode_version_v2(1, 5, 634)),
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(ProtocolVersion::new(1, 5, 634)),
+            ProtocolVersion::from_version_message(&modern)
+        );
+
+        let conflicting = msgs::Version {
+            version_v1: Some(0x010400),
+            version_v2: Some(encode_version_v2(1, 5, 0)),
+            ..Default::default()
+        };
+        let version = ProtocolVersion::from_version_message(&conflicting).unwrap();
+        assert_eq!(ProtocolVersion::new(1, 5, 0), version);
+        assert!(version.at_least(1, 5, 0));
+    }
+
+    #[test]
+    fn packs_versions() {
+        assert_eq!(0x010400, encode_version(1, 4, 0));
+        assert_eq!((1, 4, 0), decode_version(0x010400));
+        assert_eq!(0x0105ff, encode_version(1, 5, 300));
+        assert_eq!((1, 5, 300), decode_version_v2(encode_version_v2(1, 5, 300)));
+    }
+
+    #[test]
+    fn compares_versions() {
+        let version = ProtocolVersion::from_v1(0x010304);
+        assert_eq!("1.3.4", version.to_string());
+        assert!(version.at_least(1, 3, 0));
+        assert!(version.at_least(1, 3, 4));
+        assert!(!version.at_least(1, 4, 0));
+    }
+
+    #[test]
+    fn sets_message_version() {
+        let mut msg = msgs::Version::default();
+        assert_eq!(None, msg.semver());
+        msg.set_semver(ProtocolVersion::new(1, 4, 2));
+        assert_eq!(Some(ProtocolVersion::new(1, 4, 2)), msg.semver());
+        #[cfg(not(feature = "webrtc-extensions"))]
+        {
+            msg.set_semver(ProtocolVersion::new(1, 5, 634));
+            assert_eq!(Some(0x0105ff), msg.version_v1);
+            assert_eq!(Some(ProtocolVersion::new(1, 5, 634)), msg.semver());
+        }
+    }
+
+    #[test]
+    fn builds_version_message() {
+        let msg = msgs::Version::builder(ProtocolVersion::new(1, 4, 0))
+            .release("bot 1.0")
+            .os("Linux", Some("6.0".to_owned()))
+            .build();
+        assert_eq!(Some(ProtocolVersion::new(1, 4, 0)), msg.semver());
+        assert_eq!(Some("bot 1.0"), msg.release.as_deref());
+        assert_eq!(Some("Linux"), msg.os.as_deref());
+        assert_eq!(Some("6.0"), msg.os_version.as_deref());
+
+        let msg = VersionBuilder::new(ProtocolVersion::new(1, 4, 0)).build();
+        assert_eq!(Some(DEFAULT_RELEASE), msg.release.as_deref());
+        assert!(msg.os.is_some());
+    }
+}
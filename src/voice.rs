@@ -1,21 +1,34 @@
 //! Voice channel packets and codecs
 
+use std::fmt;
 use std::fmt::Debug;
-use std::io;
-use std::io::{Cursor, Read};
 use std::marker::PhantomData;
+use std::num::NonZeroU8;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
 
-use byteorder::ReadBytesExt;
 use bytes::Buf;
 use bytes::BufMut;
 use bytes::Bytes;
 use bytes::BytesMut;
 
+use super::control::limits::BandwidthLimit;
+use super::error::Error;
+use super::error::VoiceError;
+use super::varint;
 use super::varint::BufMutExt;
-use super::varint::ReadExt;
 
 /// A packet transmitted via Mumble's voice channel.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "Dst::SessionId: serde::Serialize",
+        deserialize = "Dst::SessionId: serde::Deserialize<'de>"
+    ))
+)]
 pub enum VoicePacket<Dst: VoicePacketDst> {
     /// Ping packets contain opaque timestamp-like values which should simply be echoed back.
     Ping {
@@ -27,7 +40,7 @@ pub enum VoicePacket<Dst: VoicePacketDst> {
     Audio {
         /// Destination. Required due to encoding differences depending on packet flow direction.
         _dst: PhantomData<Dst>,
-        /// The target.
+        /// The target, see [VoiceTargetId] and [target](Self::target).
         ///
         /// Only values 0-31 are valid (when serialized, this field is 5-bits long).
         target: u8,
@@ -41,33 +54,816 @@ pub enum VoicePacket<Dst: VoicePacketDst> {
         payload: VoicePacketPayload,
         /// Positional audio information.
         ///
-        /// Usually `[f32; 3]` but may contain additional or different data if all clients
-        /// receiving this packet can deal with such values (e.g. games with builtin Mumble
-        /// client may use this field to transmit additional data to other game clients).
+        /// Usually a [Position] but may contain additional data if all clients receiving this
+        /// packet can deal with such values (e.g. games with builtin Mumble client may use this
+        /// field to transmit additional data to other game clients). It is never shorter than
+        /// [POSITION_LEN], see [position](Self::position) and [set_position](Self::set_position).
         position_info: Option<Bytes>,
     },
 }
 
+/// The target of serverbound audio, as given by the 5 bits in the header of voice packets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VoiceTargetId {
+    /// Talking to the own channel, target 0.
+    Normal,
+    /// Whispering or shouting to a target registered with a `VoiceTarget` message.
+    Whisper(WhisperTarget),
+    /// Sending audio back to oneself via the server, target 31.
+    ServerLoopback,
+}
+
+impl VoiceTargetId {
+    /// Returns the target for a registrable voice target ID, `None` unless it is 1 to 30.
+    pub fn whisper(id: u8) -> Option<Self> {
+        WhisperTarget::new(id).map(VoiceTargetId::Whisper)
+    }
+}
+
+impl TryFrom<u8> for VoiceTargetId {
+    type Error = InvalidVoiceTarget;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(VoiceTargetId::Normal),
+            31 => Ok(VoiceTargetId::ServerLoopback),
+            _ => VoiceTargetId::whisper(value).ok_or(InvalidVoiceTarget(value)),
+        }
+    }
+}
+
+impl From<VoiceTargetId> for u8 {
+    fn from(target: VoiceTargetId) -> Self {
+        match target {
+            VoiceTargetId::Normal => 0,
+            VoiceTargetId::Whisper(target) => target.get(),
+            VoiceTargetId::ServerLoopback => 31,
+        }
+    }
+}
+
+/// The ID of a voice target clients can register, between 1 and 30.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WhisperTarget(NonZeroU8);
+
+impl WhisperTarget {
+    /// The highest ID.
+    pub const MAX: u8 = 30;
+
+    /// Returns the target with the given ID, `None` unless it is 1 to 30.
+    pub fn new(id: u8) -> Option<Self> {
+        match id {
+            1..=Self::MAX => NonZeroU8::new(id).map(WhisperTarget),
+            _ => None,
+        }
+    }
+
+    /// Returns the ID, as sent in voice packets.
+    pub fn get(self) -> u8 {
+        self.0.get()
+    }
+}
+
+/// The ID as used in `VoiceTarget` messages.
+impl From<WhisperTarget> for u32 {
+    fn from(target: WhisperTarget) -> Self {
+        target.get().into()
+    }
+}
+
+/// A voice packet target outside of the 5 bits available for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidVoiceTarget(pub u8);
+
+impl fmt::Display for InvalidVoiceTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "voice target {} is out of range, at most 31 is allowed",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidVoiceTarget {}
+
+/// Length of the positional audio data, i.e. three floats.
+pub const POSITION_LEN: usize = 12;
+
+/// The position of a speaker for positional audio, in meters.
+///
+/// On the wire, it is appended to the audio payload as three little-endian IEEE 754 floats in
+/// the order `x`, `y`, `z`. A position of all zeros conventionally means that the speaker has no
+/// usable position, e.g. because their game is not running.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Position {
+    /// Right from the listener's perspective in Mumble's coordinate system.
+    pub x: f32,
+    /// Up.
+    pub y: f32,
+    /// Forward.
+    pub z: f32,
+}
+
+impl Position {
+    /// Creates a position from its coordinates.
+    pub fn new(x: f32, y: f32, z: f32) -> Self {
+        Position { x, y, z }
+    }
+
+    /// Creates the position conventionally meaning that there is no usable position.
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether this is the position meaning that there is no usable position.
+    pub fn is_zero(&self) -> bool {
+        self.x == 0.0 && self.y == 0.0 && self.z == 0.0
+    }
+
+    /// Reads a position from the start of `bytes`, `None` if it is shorter than [POSITION_LEN].
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let float = |i: usize| {
+            let bytes = bytes.get(i * 4..i * 4 + 4)?;
+            Some(f32::from_le_bytes(bytes.try_into().expect("length is 4")))
+        };
+        Some(Position::new(float(0)?, float(1)?, float(2)?))
+    }
+
+    /// Returns the position as it is written on the wire.
+    pub fn to_bytes(&self) -> [u8; POSITION_LEN] {
+        let mut bytes = [0; POSITION_LEN];
+        bytes[0..4].copy_from_slice(&self.x.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.y.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.z.to_le_bytes());
+        bytes
+    }
+}
+
+impl From<[f32; 3]> for Position {
+    fn from([x, y, z]: [f32; 3]) -> Self {
+        Position { x, y, z }
+    }
+}
+
+impl From<Position> for [f32; 3] {
+    fn from(position: Position) -> Self {
+        [position.x, position.y, position.z]
+    }
+}
+
+impl<Dst: VoicePacketDst> VoicePacket<Dst> {
+    /// Returns a ping whose timestamp is the current time of a monotonic clock.
+    ///
+    /// See [monotonic_timestamp] for its unit. [UdpPingTracker](crate::ping::UdpPingTracker) also
+    /// records when the ping was sent.
+    pub fn ping_now() -> Self {
+        VoicePacket::Ping {
+            timestamp: monotonic_timestamp(Instant::now()),
+        }
+    }
+
+    /// Returns whether this is a ping.
+    pub fn is_ping(&self) -> bool {
+        matches!(self, VoicePacket::Ping { .. })
+    }
+
+    /// Returns the timestamp of a ping, `None` for audio.
+    pub fn ping_timestamp(&self) -> Option<u64> {
+        match self {
+            VoicePacket::Ping { timestamp } => Some(*timestamp),
+            VoicePacket::Audio { .. } => None,
+        }
+    }
+
+    /// Returns the target of the audio, `None` for pings.
+    ///
+    /// This interprets the target as sent by clients. In clientbound packets, the server
+    /// replaces it with how the audio reached the receiver, see
+    /// [TalkMode](crate::state::TalkMode).
+    pub fn target(&self) -> Option<VoiceTargetId> {
+        VoiceTargetId::try_from(self.raw_target()?).ok()
+    }
+
+    /// Sets the target of the audio. Does nothing for pings.
+    pub fn set_target(&mut self, target: VoiceTargetId) {
+        if let VoicePacket::Audio { target: raw, .. } = self {
+            *raw = target.into();
+        }
+    }
+
+    /// Returns the target of the audio as sent in the header, `None` for pings.
+    pub fn raw_target(&self) -> Option<u8> {
+        match self {
+            VoicePacket::Ping { .. } => None,
+            VoicePacket::Audio { target, .. } => Some(*target),
+        }
+    }
+
+    /// Returns the position of the speaker, `None` for pings and audio without position.
+    ///
+    /// Any data following the position is ignored.
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            VoicePacket::Ping { .. } => None,
+            VoicePacket::Audio { position_info, .. } => {
+                Position::from_bytes(position_info.as_deref()?)
+            }
+        }
+    }
+
+    /// Sets or, given `None`, removes the position of the speaker, discarding any additional
+    /// positional data. Does nothing for pings.
+    pub fn set_position(&mut self, position: Option<Position>) {
+        if let VoicePacket::Audio { position_info, .. } = self {
+            *position_info = position.map(|position| Bytes::copy_from_slice(&position.to_bytes()));
+        }
+    }
+
+    /// Returns the codec of the audio, `None` for pings.
+    pub fn codec(&self) -> Option<AudioCodec> {
+        match self {
+            VoicePacket::Ping { .. } => None,
+            VoicePacket::Audio { payload, .. } => Some(payload.codec()),
+        }
+    }
+
+    /// Returns the length of the audio in bytes without frame headers, 0 for pings.
+    pub fn payload_len(&self) -> usize {
+        match self {
+            VoicePacket::Ping { .. } => 0,
+            VoicePacket::Audio { payload, .. } => payload.len(),
+        }
+    }
+
+    /// Returns the amount of 10 ms frames of audio, 0 for pings.
+    ///
+    /// CELT and Speex frames are counted as is. Opus packets are not split into frames, so their
+    /// duration is read from the TOC byte, with malformed ones counting as a single frame, see
+    /// [VoicePacketPayload::frame_count]. Neither allocates.
+    pub fn frame_count(&self) -> u64 {
+        match self {
+            VoicePacket::Ping { .. } => 0,
+            VoicePacket::Audio { payload, .. } => payload.frame_count(),
+        }
+    }
+
+    /// Returns whether this audio ends the speaker's transmission, `false` for pings.
+    ///
+    /// Opus audio is marked with the terminator bit, see [VoicePacketPayload::is_terminator].
+    /// CELT and Speex audio has no such marker, instead Mumble ends a transmission with an empty
+    /// frame.
+    pub fn is_end_of_transmission(&self) -> bool {
+        match self {
+            VoicePacket::Ping { .. } => false,
+            VoicePacket::Audio { payload, .. } => match payload {
+                VoicePacketPayload::Opus(_, last) => *last,
+                VoicePacketPayload::CeltAlpha(frames)
+                | VoicePacketPayload::CeltBeta(frames)
+                | VoicePacketPayload::Speex(frames) => frames.last().is_some_and(Bytes::is_empty),
+            },
+        }
+    }
+
+    /// Returns the amount of bytes this packet occupies once encoded.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            VoicePacket::Ping { timestamp } => 1 + varint::encoded_len(*timestamp as i64),
+            VoicePacket::Audio {
+                session_id,
+                seq_num,
+                payload,
+                position_info,
+                ..
+            } => {
+                1 + Dst::session_id_len(session_id)
+                    + varint::encoded_len(*seq_num as i64)
+                    + payload.encoded_len()
+                    + position_info.as_ref().map_or(0, |bytes| bytes.len())
+            }
+        }
+    }
+
+    /// Encodes the packet once for sending it to many recipients.
+    ///
+    /// The result can be encrypted for each recipient with
+    /// [CryptState::encrypt_prepared](crate::crypt::CryptState::encrypt_prepared) and tunneled
+    /// with [RawControlPacket::udp_tunnel](crate::control::RawControlPacket::udp_tunnel), neither
+    /// of which encodes the packet again.
+    pub fn encode_shared(&self) -> Result<Bytes, Error> {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        VoiceCodec::<Dst, Dst>::new().encode_ref(self, &mut buf)?;
+        Ok(buf.freeze())
+    }
+}
+
+impl VoicePacket<Serverbound> {
+    /// Turns audio received from a client into the packet relaying it to other clients.
+    ///
+    /// The speaker's session id is added and the target replaced by the clientbound one, which
+    /// tells the receivers how the audio reaches them (see
+    /// [TalkMode](crate::state::TalkMode), which converts into it). The sequence number,
+    /// payload and positional data are kept, sharing their bytes. Pings are passed through.
+    pub fn into_client_bound(
+        self,
+        session_id: u32,
+        target: VoiceTargetId,
+    ) -> VoicePacket<Clientbound> {
+        match self {
+            VoicePacket::Ping { timestamp } => VoicePacket::Ping { timestamp },
+            VoicePacket::Audio {
+                session_id: (),
+                seq_num,
+                payload,
+                position_info,
+                ..
+            } => VoicePacket::Audio {
+                _dst: PhantomData,
+                target: target.into(),
+                session_id,
+                seq_num,
+                payload,
+                position_info,
+            },
+        }
+    }
+}
+
+impl VoicePacket<Clientbound> {
+    /// Turns relayed audio back into the packet a client sends, e.g. for retransmitting it.
+    ///
+    /// The session id is dropped and the target replaced, everything else is kept like in
+    /// [into_client_bound](VoicePacket::into_client_bound).
+    pub fn into_server_bound(self, target: VoiceTargetId) -> VoicePacket<Serverbound> {
+        match self {
+            VoicePacket::Ping { timestamp } => VoicePacket::Ping { timestamp },
+            VoicePacket::Audio {
+                seq_num,
+                payload,
+                position_info,
+                ..
+            } => VoicePacket::Audio {
+                _dst: PhantomData,
+                target: target.into(),
+                session_id: (),
+                seq_num,
+                payload,
+                position_info,
+            },
+        }
+    }
+}
+
+/// Returns the ping timestamp for `now`: the microseconds since this was first called.
+///
+/// Instants before the first call are mapped to 0.
+pub fn monotonic_timestamp(now: Instant) -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    let epoch = *EPOCH.get_or_init(Instant::now);
+    now.saturating_duration_since(epoch).as_micros() as u64
+}
+
+/// Prints the packet type along with its target, session, sequence number and payload length.
+impl<Dst: VoicePacketDst> fmt::Display for VoicePacket<Dst> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoicePacket::Ping { timestamp } => write!(f, "Ping{{timestamp={}}}", timestamp),
+            VoicePacket::Audio {
+                target,
+                session_id,
+                seq_num,
+                payload,
+                position_info,
+                ..
+            } => {
+                let (name, len) = match payload {
+                    VoicePacketPayload::CeltAlpha(frames) => ("CeltAlpha", frames_len(frames)),
+                    VoicePacketPayload::CeltBeta(frames) => ("CeltBeta", frames_len(frames)),
+                    VoicePacketPayload::Speex(frames) => ("Speex", frames_len(frames)),
+                    VoicePacketPayload::Opus(frame, _) => ("Opus", frame.len()),
+                };
+                write!(f, "{}{{target={}", name, target)?;
+                if Dst::DIRECTION == Direction::Clientbound {
+                    write!(f, ", session={:?}", session_id)?;
+                }
+                write!(f, ", seq={}, len={}", seq_num, len)?;
+                if let VoicePacketPayload::Opus(_, true) = payload {
+                    f.write_str(", end")?;
+                }
+                if position_info.is_some() {
+                    f.write_str(", positional")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn frames_len(frames: &[Bytes]) -> usize {
+    frames.iter().map(|frame| frame.len()).sum()
+}
+
+/// The codec of the audio in a [VoicePacketPayload].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AudioCodec {
+    /// CELT Alpha (0.7.0).
+    CeltAlpha,
+    /// CELT Beta (0.11.0).
+    CeltBeta,
+    /// Speex.
+    Speex,
+    /// Opus.
+    Opus,
+}
+
 /// Audio data payload of [VoicePacket]s.
+///
+/// This is only the container format: the audio data is neither encoded nor decoded by this
+/// crate, so frames are relayed exactly as received.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
 pub enum VoicePacketPayload {
     /// CELT Alpha (0.7.0) encoded audio frames.
     CeltAlpha(Vec<Bytes>),
     /// CELT Beta (0.11.0) encoded audio frames.
     CeltBeta(Vec<Bytes>),
-    /// Speex encoded audio frames.
+    /// Speex encoded audio frames, voice packet type 2.
+    ///
+    /// Only used by clients before 1.2.0 and some embedded ones.
     Speex(Vec<Bytes>),
     /// Opus encoded audio frame with end-of-transmission bit.
     Opus(Bytes, bool),
 }
 
+/// The maximum length of a CELT or Speex frame, limited by its 7-bit length header.
+pub const MAX_FRAME_LEN: usize = 0x7f;
+
+/// The maximum length of an Opus frame, limited by its 13-bit length header.
+pub const MAX_OPUS_FRAME_LEN: usize = 0x1fff;
+
+/// The bit in the Opus length header marking the last frame of a transmission.
+const OPUS_TERMINATOR_BIT: u64 = 0x2000;
+
+impl VoicePacketPayload {
+    /// Creates an Opus payload, `last` marking the end of the transmission.
+    ///
+    /// Fails if the frame is longer than [MAX_OPUS_FRAME_LEN].
+    pub fn opus(data: impl Into<Bytes>, last: bool) -> Result<Self, VoiceError> {
+        let data = data.into();
+        check_frame_len(&data, MAX_OPUS_FRAME_LEN)?;
+        Ok(VoicePacketPayload::Opus(data, last))
+    }
+
+    /// Creates a CELT Alpha payload from the frames of a packet.
+    ///
+    /// Fails if there are no frames or one is longer than [MAX_FRAME_LEN].
+    pub fn celt_alpha<I: IntoIterator<Item = impl Into<Bytes>>>(
+        frames: I,
+    ) -> Result<Self, VoiceError> {
+        Ok(VoicePacketPayload::CeltAlpha(legacy_frames(frames)?))
+    }
+
+    /// Creates a CELT Beta payload from the frames of a packet, see
+    /// [celt_alpha](Self::celt_alpha).
+    pub fn celt_beta<I: IntoIterator<Item = impl Into<Bytes>>>(
+        frames: I,
+    ) -> Result<Self, VoiceError> {
+        Ok(VoicePacketPayload::CeltBeta(legacy_frames(frames)?))
+    }
+
+    /// Creates a Speex payload from the frames of a packet, see [celt_alpha](Self::celt_alpha).
+    pub fn speex<I: IntoIterator<Item = impl Into<Bytes>>>(frames: I) -> Result<Self, VoiceError> {
+        Ok(VoicePacketPayload::Speex(legacy_frames(frames)?))
+    }
+
+    /// Returns the codec of the audio.
+    pub fn codec(&self) -> AudioCodec {
+        match self {
+            VoicePacketPayload::CeltAlpha(_) => AudioCodec::CeltAlpha,
+            VoicePacketPayload::CeltBeta(_) => AudioCodec::CeltBeta,
+            VoicePacketPayload::Speex(_) => AudioCodec::Speex,
+            VoicePacketPayload::Opus(..) => AudioCodec::Opus,
+        }
+    }
+
+    /// Returns the length of the audio in bytes, without any frame headers.
+    pub fn len(&self) -> usize {
+        self.frames().map(|(frame, _)| frame.len()).sum()
+    }
+
+    /// Returns whether there is no audio at all, e.g. in an empty Opus terminator.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the frames of the payload along with whether each is the last one of the packet.
+    ///
+    /// On the wire, every CELT and Speex frame but the last has the continuation bit set in its
+    /// header. An Opus payload is a single frame.
+    pub fn frames(&self) -> impl Iterator<Item = (&Bytes, bool)> + '_ {
+        let frames = match self {
+            VoicePacketPayload::CeltAlpha(frames)
+            | VoicePacketPayload::Speex(frames)
+            | VoicePacketPayload::CeltBeta(frames) => frames.as_slice(),
+            VoicePacketPayload::Opus(frame, _) => std::slice::from_ref(frame),
+        };
+        let last = frames.len().saturating_sub(1);
+        frames
+            .iter()
+            .enumerate()
+            .map(move |(i, frame)| (frame, i == last))
+    }
+
+    /// Returns the amount of 10 ms frames of audio, which is what the sequence number of the
+    /// next packet is ahead by.
+    ///
+    /// Opus packets carry no frame headers, so their duration is read from the TOC byte instead,
+    /// as Mumble does. Packets whose TOC is malformed (missing, a frame count of zero or longer
+    /// than the 120 ms Opus allows) count as a single frame. Arbitrary payloads therefore never
+    /// count as more than 12 frames, and no payload counts as less than one.
+    pub fn frame_count(&self) -> u64 {
+        match self {
+            VoicePacketPayload::CeltAlpha(frames)
+            | VoicePacketPayload::Speex(frames)
+            | VoicePacketPayload::CeltBeta(frames) => frames.len().max(1) as u64,
+            VoicePacketPayload::Opus(frame, _) => {
+                let duration_us = opus_duration_us(frame).unwrap_or(0);
+                u64::from(duration_us / 10_000).max(1)
+            }
+        }
+    }
+
+    /// Returns whether this is the last Opus frame of a transmission.
+    ///
+    /// Always `false` for the legacy codecs, which have no such marker.
+    pub fn is_terminator(&self) -> bool {
+        matches!(self, VoicePacketPayload::Opus(_, true))
+    }
+
+    /// Sets whether this is the last Opus frame of a transmission. Does nothing for the legacy
+    /// codecs.
+    pub fn set_terminator(&mut self, last: bool) {
+        if let VoicePacketPayload::Opus(_, terminator) = self {
+            *terminator = last;
+        }
+    }
+
+    /// Checks that there are frames and all of them fit into their length headers.
+    fn check_frames(&self) -> Result<(), VoiceError> {
+        match self {
+            VoicePacketPayload::CeltAlpha(frames)
+            | VoicePacketPayload::Speex(frames)
+            | VoicePacketPayload::CeltBeta(frames) => {
+                if frames.is_empty() {
+                    return Err(VoiceError::NoFrames);
+                }
+                frames
+                    .iter()
+                    .try_for_each(|frame| check_frame_len(frame, MAX_FRAME_LEN))
+            }
+            VoicePacketPayload::Opus(frame, _) => check_frame_len(frame, MAX_OPUS_FRAME_LEN),
+        }
+    }
+
+    /// Returns the amount of bytes this payload occupies once encoded.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            VoicePacketPayload::CeltAlpha(frames)
+            | VoicePacketPayload::Speex(frames)
+            | VoicePacketPayload::CeltBeta(frames) => {
+                frames.iter().map(|frame| 1 + frame.len()).sum()
+            }
+            VoicePacketPayload::Opus(frame, termination_bit) => {
+                let term_bit = if *termination_bit {
+                    OPUS_TERMINATOR_BIT
+                } else {
+                    0
+                };
+                varint::encoded_len((term_bit | frame.len() as u64) as i64) + frame.len()
+            }
+        }
+    }
+}
+
+/// Longest audio an Opus packet may contain, in microseconds.
+const MAX_OPUS_DURATION_US: u32 = 120_000;
+
+/// Returns the duration of an Opus packet in microseconds as given by its TOC byte, see
+/// RFC 6716 section 3.1.
+///
+/// Returns `None` if the TOC is malformed, i.e. the packet is empty, lacks the frame count byte
+/// or describes no audio or more than 120 ms of it.
+fn opus_duration_us(packet: &[u8]) -> Option<u32> {
+    let toc = *packet.first()?;
+    let config = usize::from(toc >> 3);
+    let frame_us = match config {
+        0..=11 => [10_000, 20_000, 40_000, 60_000][config % 4],
+        12..=15 => [10_000, 20_000][config % 2],
+        _ => [2_500, 5_000, 10_000, 20_000][config % 4],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => u32::from(*packet.get(1)? & 0x3f),
+    };
+    Some(frame_us * frames).filter(|duration| (1..=MAX_OPUS_DURATION_US).contains(duration))
+}
+
+fn legacy_frames<I: IntoIterator<Item = impl Into<Bytes>>>(
+    frames: I,
+) -> Result<Vec<Bytes>, VoiceError> {
+    let frames: Vec<Bytes> = frames.into_iter().map(Into::into).collect();
+    if frames.is_empty() {
+        return Err(VoiceError::NoFrames);
+    }
+    for frame in &frames {
+        check_frame_len(frame, MAX_FRAME_LEN)?;
+    }
+    Ok(frames)
+}
+
+fn check_frame_len(frame: &[u8], limit: usize) -> Result<(), VoiceError> {
+    if frame.len() > limit {
+        return Err(VoiceError::FrameTooLong {
+            len: frame.len(),
+            limit,
+        });
+    }
+    Ok(())
+}
+
+/// Builder for [VoicePacket::Audio] packets.
+///
+/// Clientbound packets need the session of the speaker, so [build](Self::build) is only
+/// available for them once [session](AudioBuilder::session) was called. The second type
+/// parameter tracks that.
+///
+/// ```
+/// # use mumble_protocol_2x::voice::*;
+/// let packet: VoicePacket<Serverbound> = AudioBuilder::opus(vec![0; 60])
+///     .target(VoiceTargetId::Normal)
+///     .seq(4)
+///     .position(Position::new(1.0, 0.0, 2.0))
+///     .build()
+///     .unwrap();
+/// let relayed: VoicePacket<Clientbound> = AudioBuilder::opus(vec![0; 60])
+///     .session(3)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct AudioBuilder<Dst: VoicePacketDst, Session = ()> {
+    _dst: PhantomData<Dst>,
+    session_id: Session,
+    target: VoiceTargetId,
+    seq_num: u64,
+    payload: VoicePacketPayload,
+    position: Option<Position>,
+}
+
+impl<Dst: VoicePacketDst> AudioBuilder<Dst> {
+    /// Starts a packet with the given payload, to the [Normal](VoiceTargetId::Normal) target
+    /// with sequence number 0 and without position.
+    pub fn new(payload: VoicePacketPayload) -> Self {
+        AudioBuilder {
+            _dst: PhantomData,
+            session_id: (),
+            target: VoiceTargetId::Normal,
+            seq_num: 0,
+            payload,
+            position: None,
+        }
+    }
+
+    /// Starts a packet with a single Opus frame which does not end the transmission, see
+    /// [new](Self::new).
+    pub fn opus(data: impl Into<Bytes>) -> Self {
+        Self::new(VoicePacketPayload::Opus(data.into(), false))
+    }
+}
+
+impl AudioBuilder<Clientbound> {
+    /// Sets the session of the speaker.
+    pub fn session(self, session_id: u32) -> AudioBuilder<Clientbound, u32> {
+        AudioBuilder {
+            _dst: PhantomData,
+            session_id,
+            target: self.target,
+            seq_num: self.seq_num,
+            payload: self.payload,
+            position: self.position,
+        }
+    }
+}
+
+impl<Dst: VoicePacketDst, Session> AudioBuilder<Dst, Session> {
+    /// Sets the target.
+    ///
+    /// In clientbound packets, the server replaces it with how the audio reached the receiver,
+    /// see [TalkMode](crate::state::TalkMode).
+    pub fn target(mut self, target: VoiceTargetId) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Sets the sequence number, e.g. one returned by [SequenceCounter::advance].
+    pub fn seq(mut self, seq_num: u64) -> Self {
+        self.seq_num = seq_num;
+        self
+    }
+
+    /// Sets the position of the speaker.
+    pub fn position(mut self, position: Position) -> Self {
+        self.position = Some(position);
+        self
+    }
+
+    /// Marks an Opus frame as the last one of the transmission.
+    pub fn last(mut self, last: bool) -> Self {
+        self.payload.set_terminator(last);
+        self
+    }
+}
+
+impl<Dst: VoicePacketDst> AudioBuilder<Dst, Dst::SessionId> {
+    /// Builds the packet.
+    ///
+    /// Fails if the payload could not be encoded, e.g. because a frame is too long.
+    pub fn build(self) -> Result<VoicePacket<Dst>, VoiceError> {
+        self.payload.check_frames()?;
+        let mut packet = VoicePacket::Audio {
+            _dst: PhantomData,
+            target: self.target.into(),
+            session_id: self.session_id,
+            seq_num: self.seq_num,
+            payload: self.payload,
+            position_info: None,
+        };
+        packet.set_position(self.position);
+        Ok(packet)
+    }
+}
+
+/// The duration of audio a sequence number stands for.
+pub const SEQUENCE_FRAME_DURATION: Duration = Duration::from_millis(10);
+
+/// Counts the sequence numbers of the audio packets sent during a transmission.
+///
+/// Sequence numbers count [SEQUENCE_FRAME_DURATION]s of audio, so they need to advance by more
+/// than one if a packet contains more audio, e.g. by two for 20 ms Opus packets.
+#[derive(Clone, Debug)]
+pub struct SequenceCounter {
+    next: u64,
+    step: u64,
+}
+
+impl SequenceCounter {
+    /// Creates a counter starting at 0 for packets containing `packet_duration` of audio each.
+    ///
+    /// The duration is rounded down to the next multiple of [SEQUENCE_FRAME_DURATION], but
+    /// packets always advance the counter by at least one.
+    pub fn new(packet_duration: Duration) -> Self {
+        let step = packet_duration.as_micros() / SEQUENCE_FRAME_DURATION.as_micros();
+        SequenceCounter {
+            next: 0,
+            step: (step as u64).max(1),
+        }
+    }
+
+    /// Makes the counter start at `seq_num` instead.
+    pub fn with_start(mut self, seq_num: u64) -> Self {
+        self.next = seq_num;
+        self
+    }
+
+    /// Returns the amount the counter advances by per packet.
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    /// Returns the sequence number of the next packet without advancing the counter.
+    pub fn peek(&self) -> u64 {
+        self.next
+    }
+
+    /// Returns the sequence number of the next packet and advances the counter past it.
+    pub fn advance(&mut self) -> u64 {
+        let seq_num = self.next;
+        self.next = self.next.wrapping_add(self.step);
+        seq_num
+    }
+}
+
 /// A `Codec` implementation that parses a stream of data chunks into [VoicePacket]s.
 ///
 /// The encoding and decoding of voice packets depends on their destination.
 /// See [ServerVoiceCodec] and [ClientVoiceCodec] for the two most reasonable configurations.
 #[derive(Debug, Default)]
 pub struct VoiceCodec<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> {
+    bandwidth_limit: Option<BandwidthLimit>,
     _encode_dst: PhantomData<EncodeDst>,
     _decode_dst: PhantomData<DecodeDst>,
 }
@@ -81,6 +877,20 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> VoiceCodec<EncodeDst,
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// Returns the bandwidth limit enforced when encoding, if any.
+    pub fn bandwidth_limit(&self) -> Option<&BandwidthLimit> {
+        self.bandwidth_limit.as_ref()
+    }
+
+    /// Installs or removes a bandwidth limit, usually read from the server's `ServerConfig`.
+    ///
+    /// Encoding an audio packet which exceeds the limit fails with
+    /// [VoiceError::BandwidthExceeded] without writing anything. Decoded packets are not
+    /// checked, servers can do so with [BandwidthLimit::check].
+    pub fn set_bandwidth_limit(&mut self, limit: Option<BandwidthLimit>) {
+        self.bandwidth_limit = limit;
+    }
 }
 
 /// Zero-sized struct indicating server-bound packet direction.
@@ -90,6 +900,17 @@ pub struct Serverbound;
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Clientbound;
 
+/// Direction a packet travels in, as a value.
+///
+/// See [Serverbound] and [Clientbound] for the type-level equivalents.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// From a client to the server.
+    Serverbound,
+    /// From the server to a client.
+    Clientbound,
+}
+
 mod private {
     pub trait Sealed {}
     impl Sealed for super::Serverbound {}
@@ -99,68 +920,116 @@ mod private {
 /// Sealed trait for indicating voice packet direction.
 ///
 /// The only two implementations are [Serverbound] and [Clientbound].
-pub trait VoicePacketDst: private::Sealed + Default + PartialEq {
+pub trait VoicePacketDst: private::Sealed + Clone + Default + PartialEq {
+    /// The direction indicated by this type.
+    const DIRECTION: Direction;
     /// Type of [VoicePacket::Audio::session_id](enum.VoicePacket.html#variant.Audio.field.session_id).
     type SessionId: Debug + Clone + PartialEq;
     /// Reads session id of packets traveling in this direction.
-    fn read_session_id<T: Read + Sized>(buf: &mut T) -> Result<Self::SessionId, io::Error>;
+    fn read_session_id<T: Buf>(buf: &mut T) -> Result<Self::SessionId, VoiceError>;
     /// Writes session id to packets traveling in this direction.
     fn write_session_id(buf: &mut BytesMut, session_id: Self::SessionId);
+    /// Returns the amount of bytes [write_session_id](Self::write_session_id) would write.
+    fn session_id_len(session_id: &Self::SessionId) -> usize;
+    /// Converts the `sender_session` of protobuf voice packets to the session id.
+    fn session_id_from_u32(session: u32) -> Self::SessionId;
+    /// Converts the session id to the `sender_session` of protobuf voice packets.
+    fn session_id_to_u32(session_id: &Self::SessionId) -> u32;
 }
 
 impl VoicePacketDst for Serverbound {
+    const DIRECTION: Direction = Direction::Serverbound;
     type SessionId = ();
 
-    fn read_session_id<T: Read + Sized>(_buf: &mut T) -> Result<Self::SessionId, io::Error> {
+    fn read_session_id<T: Buf>(_buf: &mut T) -> Result<Self::SessionId, VoiceError> {
         Ok(())
     }
 
     fn write_session_id(_buf: &mut BytesMut, _session_id: Self::SessionId) {}
+
+    fn session_id_len(_session_id: &Self::SessionId) -> usize {
+        0
+    }
+
+    fn session_id_from_u32(_session: u32) -> Self::SessionId {}
+
+    fn session_id_to_u32(_session_id: &Self::SessionId) -> u32 {
+        0
+    }
 }
 
 impl VoicePacketDst for Clientbound {
+    const DIRECTION: Direction = Direction::Clientbound;
     type SessionId = u32;
 
-    fn read_session_id<T: Read + Sized>(buf: &mut T) -> Result<Self::SessionId, io::Error> {
-        Ok(buf.read_varint()? as u32)
+    fn read_session_id<T: Buf>(buf: &mut T) -> Result<Self::SessionId, VoiceError> {
+        Ok(read_varint(buf)? as u32)
     }
 
     fn write_session_id(buf: &mut BytesMut, session_id: Self::SessionId) {
         buf.put_varint(u64::from(session_id))
     }
+
+    fn session_id_len(session_id: &Self::SessionId) -> usize {
+        varint::encoded_len(i64::from(*session_id))
+    }
+
+    fn session_id_from_u32(session: u32) -> Self::SessionId {
+        session
+    }
+
+    fn session_id_to_u32(session_id: &Self::SessionId) -> u32 {
+        *session_id
+    }
+}
+
+/// Reads a varint with the bit pattern of negative values preserved, like Murmur does.
+fn read_varint<T: Buf>(buf: &mut T) -> Result<u64, VoiceError> {
+    Ok(varint::decode(buf)? as u64)
 }
 
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> VoiceCodec<EncodeDst, DecodeDst> {
     // Note: other code assumes this returns Ok(Some(_)) or Err(_) but never Ok(None)
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<VoicePacket<DecodeDst>>, io::Error> {
-        let mut buf = Cursor::new(&src);
-        let header = buf.read_u8()?;
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    pub(crate) fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<VoicePacket<DecodeDst>>, Error> {
+        self.decode_bytes(src.split().freeze()).map(Some)
+    }
+
+    /// Decodes a whole packet, the audio frames and positional data are slices of `src`.
+    pub(crate) fn decode_bytes(&mut self, mut src: Bytes) -> Result<VoicePacket<DecodeDst>, Error> {
+        if src.is_empty() {
+            return Err(VoiceError::Truncated.into());
+        }
+        let header = src.get_u8();
         let kind = header >> 5;
         let target = header & 0b11111;
         let result = if kind == 1 {
-            let timestamp = buf.read_varint()?;
-            src.advance(src.len());
+            let timestamp = read_varint(&mut src)?;
             VoicePacket::Ping { timestamp }
         } else {
-            let session_id = DecodeDst::read_session_id(&mut buf)?;
-            let seq_num = buf.read_varint()?;
+            let session_id = DecodeDst::read_session_id(&mut src)?;
+            let seq_num = read_varint(&mut src)?;
             let payload = match kind {
                 0 | 2 | 3 => {
                     let mut frames = Vec::new();
-                    let position = buf.position();
-                    src.advance(position as usize);
                     loop {
                         if src.is_empty() {
-                            return Err(io::ErrorKind::UnexpectedEof.into());
+                            return Err(VoiceError::Truncated.into());
                         }
                         let header = src[0];
                         src.advance(1);
 
                         let len = (header & !0x80) as usize;
                         if src.len() < len {
-                            return Err(io::ErrorKind::UnexpectedEof.into());
+                            return Err(VoiceError::Truncated.into());
                         }
-                        frames.push(src.split_to(len).freeze());
+                        frames.push(src.split_to(len));
                         if header & 0x80 != 0x80 {
                             break;
                         }
@@ -173,28 +1042,24 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> VoiceCodec<EncodeDst,
                     }
                 }
                 4 => {
-                    let header = buf.read_varint()?;
-                    let position = buf.position();
-                    src.advance(position as usize);
-                    let termination_bit = header & 0x2000 == 0x2000;
-                    let len = (header & !0x2000) as usize;
+                    let header = read_varint(&mut src)?;
+                    let termination_bit = header & OPUS_TERMINATOR_BIT != 0;
+                    // Like Murmur, ignore any bits above the length, whatever the varint form
+                    let len = (header & MAX_OPUS_FRAME_LEN as u64) as usize;
                     if src.len() < len {
-                        return Err(io::ErrorKind::UnexpectedEof.into());
+                        return Err(VoiceError::Truncated.into());
                     }
-                    let frame = src.split_to(len).freeze();
+                    let frame = src.split_to(len);
                     VoicePacketPayload::Opus(frame, termination_bit)
                 }
                 _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "unknown voice packet type",
-                    ));
+                    return Err(VoiceError::UnknownType(kind).into());
                 }
             };
-            let position_info = if src.is_empty() {
-                None
-            } else {
-                Some(src.split().freeze())
+            let position_info = match src.len() {
+                0 => None,
+                len @ 1..POSITION_LEN => return Err(VoiceError::MalformedPosition(len).into()),
+                _ => Some(src),
             };
             VoicePacket::Audio {
                 _dst: PhantomData,
@@ -205,7 +1070,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> VoiceCodec<EncodeDst,
                 position_info,
             }
         };
-        Ok(Some(result))
+        Ok(result)
     }
 }
 
@@ -214,7 +1079,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> tokio_util::codec::De
     for VoiceCodec<EncodeDst, DecodeDst>
 {
     type Item = VoicePacket<DecodeDst>;
-    type Error = io::Error; // never
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         self.decode(src)
@@ -226,7 +1091,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::D
     for VoiceCodec<EncodeDst, DecodeDst>
 {
     type Item = VoicePacket<DecodeDst>;
-    type Error = io::Error; // never
+    type Error = Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         self.decode(src)
@@ -234,14 +1099,26 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::D
 }
 
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> VoiceCodec<EncodeDst, DecodeDst> {
-    fn encode(
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    pub(crate) fn encode(
         &mut self,
         item: VoicePacket<EncodeDst>,
         dst: &mut BytesMut,
-    ) -> Result<(), io::Error> {
+    ) -> Result<(), Error> {
+        self.encode_ref(&item, dst)
+    }
+
+    pub(crate) fn encode_ref(
+        &mut self,
+        item: &VoicePacket<EncodeDst>,
+        dst: &mut BytesMut,
+    ) -> Result<(), Error> {
         match item {
-            VoicePacket::Ping { timestamp } => {
-                dst.reserve(11);
+            &VoicePacket::Ping { timestamp } => {
+                dst.reserve(item.encoded_len());
                 dst.put_u8(0x20);
                 dst.put_varint(timestamp);
             }
@@ -259,15 +1136,18 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> VoiceCodec<EncodeDst,
                     VoicePacketPayload::CeltBeta(_) => 3,
                     VoicePacketPayload::Opus(_, _) => 4,
                 };
-                dst.reserve(1 /*header*/ + 10 /*session_id*/ + 10 /*seq_num*/);
-                dst.put_u8(kind << 5 | target & 0b11111);
-                EncodeDst::write_session_id(dst, session_id);
-                dst.put_varint(seq_num);
+                payload.check_frames()?;
+                if let Some(limit) = &self.bandwidth_limit {
+                    limit.check(item).map_err(VoiceError::BandwidthExceeded)?;
+                }
+                dst.reserve(item.encoded_len());
+                dst.put_u8(kind << 5 | *target & 0b11111);
+                EncodeDst::write_session_id(dst, session_id.clone());
+                dst.put_varint(*seq_num);
                 match payload {
                     VoicePacketPayload::CeltAlpha(frames)
                     | VoicePacketPayload::Speex(frames)
                     | VoicePacketPayload::CeltBeta(frames) => {
-                        dst.reserve(frames.iter().map(|frame| 1 + frame.len()).sum());
                         let mut iter = frames.iter().peekable();
                         while let Some(frame) = iter.next() {
                             let continuation = iter.peek().map(|_| 0x80).unwrap_or(0);
@@ -276,14 +1156,17 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> VoiceCodec<EncodeDst,
                         }
                     }
                     VoicePacketPayload::Opus(frame, termination_bit) => {
-                        dst.reserve(10 + frame.len());
-                        let term_bit = if termination_bit { 0x2000 } else { 0 };
+                        let term_bit = if *termination_bit {
+                            OPUS_TERMINATOR_BIT
+                        } else {
+                            0
+                        };
                         dst.put_varint(term_bit | (frame.len() as u64));
-                        dst.put(frame);
+                        dst.put_slice(frame);
                     }
                 };
                 if let Some(bytes) = position_info {
-                    dst.extend_from_slice(&bytes);
+                    dst.extend_from_slice(bytes);
                 }
             }
         }
@@ -295,7 +1178,7 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> VoiceCodec<EncodeDst,
 impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>
     tokio_util::codec::Encoder<VoicePacket<EncodeDst>> for VoiceCodec<EncodeDst, DecodeDst>
 {
-    type Error = io::Error; // never
+    type Error = Error;
 
     fn encode(
         &mut self,
@@ -311,9 +1194,569 @@ impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::E
     for VoiceCodec<EncodeDst, DecodeDst>
 {
     type Item = VoicePacket<EncodeDst>;
-    type Error = io::Error; // never
+    type Error = Error;
 
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
         self.encode(item, dst)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::TalkMode;
+    use crate::test_util::XorShift;
+
+    #[test]
+    fn decodes_opus_headers_in_any_varint_form() {
+        let mut codec = ClientVoiceCodec::new();
+        // Opus to target 0 from session 5 with sequence number 2^32, the frame header having
+        // bits set above the terminator in the 64-bit form
+        let mut src = BytesMut::from(
+            &[
+                0x80, 0x05, 0xf4, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xf4, 0x80, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x20, 0x03, 1, 2, 3,
+            ][..],
+        );
+        let packet = codec.decode(&mut src).unwrap().unwrap();
+        let VoicePacket::Audio {
+            session_id,
+            seq_num,
+            payload,
+            position_info,
+            ..
+        } = packet
+        else {
+            panic!("expected audio, got {:?}", packet);
+        };
+        assert_eq!((5, 1 << 32), (session_id, seq_num));
+        assert_eq!(
+            VoicePacketPayload::Opus(vec![1, 2, 3].into(), true),
+            payload
+        );
+        assert_eq!(None, position_info);
+    }
+
+    fn audio_with_trailer(trailer: &[u8]) -> BytesMut {
+        // Speex to target 0 from session 5 with sequence number 1 and a single frame
+        let mut src = BytesMut::from(&[0x40, 0x05, 0x01, 0x02, 1, 2][..]);
+        src.extend_from_slice(trailer);
+        src
+    }
+
+    #[test]
+    fn decodes_without_copying() {
+        let src = audio_with_trailer(&Position::new(1.0, 2.0, 3.0).to_bytes()).freeze();
+        let within_src = |bytes: &Bytes| src.as_ptr_range().contains(&bytes.as_ptr());
+        // As done for tunneled packets
+        let packet = VoicePacket::<Clientbound>::try_from(src.clone()).unwrap();
+        let VoicePacket::Audio {
+            payload: VoicePacketPayload::Speex(frames),
+            position_info: Some(position_info),
+            ..
+        } = &packet
+        else {
+            panic!("expected Speex audio with position");
+        };
+        assert!(within_src(&frames[0]));
+        assert!(within_src(position_info));
+    }
+
+    #[test]
+    fn decodes_positions() {
+        let mut codec = ClientVoiceCodec::new();
+        let position = Position::new(1.0, -2.5, 3.0);
+        let packet = codec
+            .decode(&mut audio_with_trailer(&position.to_bytes()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(position), packet.position());
+        assert_eq!(
+            Some(position),
+            Position::from_bytes(&[
+                0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x20, 0xc0, 0x00, 0x00, 0x40, 0x40
+            ])
+        );
+
+        let packet = codec.decode(&mut audio_with_trailer(&[])).unwrap().unwrap();
+        assert_eq!(None, packet.position());
+
+        // Additional data is kept but not part of the position
+        let mut trailer = position.to_bytes().to_vec();
+        trailer.push(42);
+        let packet = codec.decode(&mut audio_with_trailer(&trailer)).unwrap();
+        assert_eq!(Some(position), packet.as_ref().unwrap().position());
+
+        for len in 1..POSITION_LEN {
+            assert!(matches!(
+                codec.decode(&mut audio_with_trailer(&trailer[..len])),
+                Err(Error::MalformedVoice(VoiceError::MalformedPosition(it))) if it == len
+            ));
+        }
+    }
+
+    #[test]
+    fn encodes_positions() {
+        let mut codec = ServerVoiceCodec::new();
+        let mut packet = VoicePacket::<Clientbound>::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: 5,
+            seq_num: 1,
+            payload: VoicePacketPayload::Speex(vec![Bytes::from_static(&[1, 2])]),
+            position_info: None,
+        };
+        let mut dst = BytesMut::new();
+        codec.encode_ref(&packet, &mut dst).unwrap();
+        assert_eq!(audio_with_trailer(&[]), dst);
+
+        let position = Position::from([0.0, 0.0, -1.0]);
+        packet.set_position(Some(position));
+        assert_eq!(Some(position), packet.position());
+        assert!(!position.is_zero());
+        assert!(Position::zero().is_zero());
+        let mut dst = BytesMut::new();
+        codec.encode_ref(&packet, &mut dst).unwrap();
+        assert_eq!(audio_with_trailer(&position.to_bytes()), dst);
+        assert_eq!(packet.encoded_len(), dst.len());
+
+        packet.set_position(None);
+        assert_eq!(None, packet.position());
+    }
+
+    #[test]
+    fn limits_frame_lengths() {
+        let mut payload = VoicePacketPayload::opus(vec![0; MAX_OPUS_FRAME_LEN], false).unwrap();
+        assert!(!payload.is_terminator());
+        payload.set_terminator(true);
+        assert!(payload.is_terminator());
+        assert_eq!(
+            Err(VoiceError::FrameTooLong {
+                len: 0x2000,
+                limit: MAX_OPUS_FRAME_LEN
+            }),
+            VoicePacketPayload::opus(vec![0; 0x2000], true)
+        );
+
+        let mut codec = ServerVoiceCodec::new();
+        let mut packet = VoicePacket::<Clientbound>::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: 5,
+            seq_num: 1,
+            payload,
+            position_info: None,
+        };
+        let mut dst = BytesMut::new();
+        codec.encode_ref(&packet, &mut dst).unwrap();
+        let decoded = ClientVoiceCodec::new().decode(&mut dst).unwrap();
+        assert_eq!(Some(&packet), decoded.as_ref());
+
+        for payload in [
+            VoicePacketPayload::Opus(vec![0; 0x2000].into(), false),
+            VoicePacketPayload::Speex(vec![Bytes::new(), vec![0; 0x80].into()]),
+        ] {
+            if let VoicePacket::Audio { payload: it, .. } = &mut packet {
+                *it = payload;
+            }
+            let mut dst = BytesMut::new();
+            assert!(matches!(
+                codec.encode_ref(&packet, &mut dst),
+                Err(Error::MalformedVoice(VoiceError::FrameTooLong { .. }))
+            ));
+            assert!(dst.is_empty());
+        }
+    }
+
+    #[test]
+    fn iterates_legacy_frames() {
+        // As sent by a 0.7.0 client: CELT Alpha to target 0 with sequence number 0x1234, two
+        // frames, one of them as long as possible. Then the end of the transmission, marked by
+        // an empty frame following the last audio frame.
+        let mut packet = vec![0x00, 0x92, 0x34, 0x83, 0xa1, 0xa2, 0xa3, 0x7f];
+        packet.extend_from_slice(&[0xbb; MAX_FRAME_LEN]);
+        let end = [0x00, 0x92, 0x36, 0x81, 0xcc, 0x00];
+
+        let mut codec = ServerVoiceCodec::new();
+        for (bytes, lens) in [(&packet[..], &[3, MAX_FRAME_LEN][..]), (&end, &[1, 0])] {
+            let mut src = BytesMut::from(bytes);
+            let decoded = codec.decode(&mut src).unwrap().unwrap();
+            let VoicePacket::Audio {
+                payload,
+                position_info,
+                ..
+            } = &decoded
+            else {
+                panic!("expected audio, got {:?}", decoded);
+            };
+            assert_eq!(&None, position_info);
+            let frames: Vec<_> = payload.frames().collect();
+            assert_eq!(
+                lens,
+                frames.iter().map(|(it, _)| it.len()).collect::<Vec<_>>()
+            );
+            assert_eq!(
+                (0..lens.len())
+                    .map(|i| i == lens.len() - 1)
+                    .collect::<Vec<_>>(),
+                frames.iter().map(|(_, last)| *last).collect::<Vec<_>>()
+            );
+
+            let mut dst = BytesMut::new();
+            ClientVoiceCodec::new()
+                .encode_ref(&decoded, &mut dst)
+                .unwrap();
+            assert_eq!(bytes, &dst[..]);
+        }
+
+        // A frame may not extend past the end of the packet
+        let mut src = BytesMut::from(&[0x00, 0x01, 0x82, 0xa1, 0x03, 0xa2, 0xa3][..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::MalformedVoice(VoiceError::Truncated))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_varints() {
+        let mut codec = ServerVoiceCodec::new();
+        // A ping whose timestamp is a negated varint which is negated again
+        let mut src = BytesMut::from(&[0x20, 0xf8, 0xf8, 0x01][..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::MalformedVoice(VoiceError::MalformedVarint(
+                varint::VarintError::ReservedPrefix(0xf8)
+            )))
+        ));
+        // An Opus packet whose sequence number ends early
+        let mut src = BytesMut::from(&[0x80, 0x01, 0xf0, 0x00][..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(Error::MalformedVoice(VoiceError::Truncated))
+        ));
+    }
+
+    #[test]
+    fn builds_legacy_payloads() {
+        let payload = VoicePacketPayload::speex([&b"ab"[..], b"c"]).unwrap();
+        assert_eq!(
+            vec![
+                (&Bytes::from_static(b"ab"), false),
+                (&Bytes::from_static(b"c"), true)
+            ],
+            payload.frames().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Err(VoiceError::NoFrames),
+            VoicePacketPayload::celt_beta(Vec::<Bytes>::new())
+        );
+        assert_eq!(
+            Err(VoiceError::FrameTooLong {
+                len: 0x80,
+                limit: MAX_FRAME_LEN
+            }),
+            VoicePacketPayload::celt_alpha([vec![0; 0x80]])
+        );
+
+        let opus = VoicePacketPayload::opus(Bytes::from_static(b"x"), false).unwrap();
+        assert_eq!(
+            vec![(&Bytes::from_static(b"x"), true)],
+            opus.frames().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn relays_speex_unchanged() {
+        // As sent by a 1.1 client: Speex to target 0 with sequence number 200 and two frames,
+        // followed by its position
+        let mut received = vec![0x40, 0x80, 0xc8, 0x86];
+        received.extend_from_slice(&[0x1e, 0x9d, 0x20, 0x0b, 0x5c, 0x33]);
+        received.push(0x04);
+        received.extend_from_slice(&[0x1e, 0x9d, 0x21, 0x00]);
+        received.extend_from_slice(&Position::new(1.0, 0.0, -1.0).to_bytes());
+
+        let mut codec = ServerVoiceCodec::new();
+        let packet = codec
+            .decode(&mut BytesMut::from(&received[..]))
+            .unwrap()
+            .unwrap();
+        let VoicePacket::Audio {
+            target,
+            seq_num,
+            payload,
+            position_info,
+            ..
+        } = packet
+        else {
+            panic!("expected audio, got {:?}", packet);
+        };
+        assert!(matches!(payload, VoicePacketPayload::Speex(_)));
+        assert_eq!(2, payload.frames().count());
+
+        // Relayed with the sender's session, which is all that changes
+        let relayed = VoicePacket::<Clientbound>::Audio {
+            _dst: PhantomData,
+            target,
+            session_id: 7,
+            seq_num,
+            payload,
+            position_info,
+        };
+        let mut dst = BytesMut::new();
+        codec.encode_ref(&relayed, &mut dst).unwrap();
+        let mut expected = vec![received[0], 0x07];
+        expected.extend_from_slice(&received[1..]);
+        assert_eq!(expected, dst);
+    }
+
+    #[test]
+    fn classifies_targets() {
+        assert_eq!(Ok(VoiceTargetId::Normal), VoiceTargetId::try_from(0));
+        assert_eq!(
+            Ok(VoiceTargetId::ServerLoopback),
+            VoiceTargetId::try_from(31)
+        );
+        assert_eq!(Err(InvalidVoiceTarget(32)), VoiceTargetId::try_from(32));
+        for id in 1..=30 {
+            let target = VoiceTargetId::try_from(id).unwrap();
+            assert_eq!(VoiceTargetId::whisper(id), Some(target));
+            assert_eq!(id, u8::from(target));
+        }
+        assert_eq!(None, WhisperTarget::new(0));
+        assert_eq!(None, WhisperTarget::new(31));
+        assert_eq!(Some(30), WhisperTarget::new(30).map(u32::from));
+
+        let mut src = audio_with_trailer(&[]);
+        src[0] |= 31;
+        let mut packet = ClientVoiceCodec::new().decode(&mut src).unwrap().unwrap();
+        assert_eq!(Some(VoiceTargetId::ServerLoopback), packet.target());
+        packet.set_target(VoiceTargetId::whisper(2).unwrap());
+        assert_eq!(Some(2), packet.raw_target());
+        let mut dst = BytesMut::new();
+        ServerVoiceCodec::new()
+            .encode_ref(&packet, &mut dst)
+            .unwrap();
+        assert_eq!(0x42, dst[0]);
+        assert_eq!(
+            None,
+            VoicePacket::<Clientbound>::Ping { timestamp: 0 }.target()
+        );
+    }
+
+    #[test]
+    fn retags_like_hand_built_packets() {
+        // Drives the payload type, frame lengths, sequence number and position
+        let mut rng = XorShift::new();
+        let mut next = move || rng.next_u64();
+        let modes = [
+            TalkMode::Normal,
+            TalkMode::Shout,
+            TalkMode::Whisper,
+            TalkMode::Loopback,
+        ];
+        for _ in 0..2_000 {
+            let frame = |len: u64| Bytes::from(vec![0xab; len as usize]);
+            let payload = match next() % 4 {
+                0 => VoicePacketPayload::Opus(frame(next() % 0x2000), next() % 2 == 0),
+                kind => {
+                    let frames = (0..1 + next() % 4).map(|_| frame(next() % 0x80)).collect();
+                    match kind {
+                        1 => VoicePacketPayload::CeltAlpha(frames),
+                        2 => VoicePacketPayload::CeltBeta(frames),
+                        _ => VoicePacketPayload::Speex(frames),
+                    }
+                }
+            };
+            let position_info = match next() % 3 {
+                0 => None,
+                extra => Some(Bytes::from(vec![1; POSITION_LEN + extra as usize - 1])),
+            };
+            let seq_num = next() >> (next() % 64);
+            let session_id = next() as u32;
+            let mode = modes[next() as usize % modes.len()];
+            let target =
+                VoiceTargetId::try_from(next() as u8 % 32).unwrap_or(VoiceTargetId::Normal);
+
+            let received = VoicePacket::<Serverbound>::Audio {
+                _dst: PhantomData,
+                target: target.into(),
+                session_id: (),
+                seq_num,
+                payload: payload.clone(),
+                position_info: position_info.clone(),
+            };
+            let expected = VoicePacket::<Clientbound>::Audio {
+                _dst: PhantomData,
+                target: mode.target(),
+                session_id,
+                seq_num,
+                payload,
+                position_info,
+            };
+            let retagged = received.clone().into_client_bound(session_id, mode.into());
+
+            let mut codec = ServerVoiceCodec::new();
+            let mut retagged_bytes = BytesMut::new();
+            codec.encode_ref(&retagged, &mut retagged_bytes).unwrap();
+            let mut expected_bytes = BytesMut::new();
+            codec.encode_ref(&expected, &mut expected_bytes).unwrap();
+            assert_eq!(expected_bytes, retagged_bytes);
+            assert_eq!(received, retagged.into_server_bound(target));
+        }
+    }
+
+    #[test]
+    fn retags_without_copying() {
+        let frame = Bytes::from_static(b"frame");
+        let packet = AudioBuilder::opus(frame.clone()).build().unwrap();
+        let VoicePacket::Audio {
+            target, payload, ..
+        } = packet.into_client_bound(7, TalkMode::Whisper.into())
+        else {
+            panic!("expected audio");
+        };
+        assert_eq!(2, target);
+        let VoicePacketPayload::Opus(retagged, _) = payload else {
+            panic!("expected Opus");
+        };
+        assert_eq!(frame.as_ptr(), retagged.as_ptr());
+        assert_eq!(
+            VoicePacket::<Clientbound>::Ping { timestamp: 3 },
+            VoicePacket::<Serverbound>::Ping { timestamp: 3 }
+                .into_client_bound(7, VoiceTargetId::Normal)
+        );
+    }
+
+    #[test]
+    fn builds_audio_packets() {
+        let position = Position::new(1.0, 2.0, 3.0);
+        let packet: VoicePacket<Serverbound> = AudioBuilder::opus(vec![1, 2, 3])
+            .target(VoiceTargetId::ServerLoopback)
+            .seq(9)
+            .position(position)
+            .last(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            VoicePacket::Audio {
+                _dst: PhantomData,
+                target: 31,
+                session_id: (),
+                seq_num: 9,
+                payload: VoicePacketPayload::Opus(vec![1, 2, 3].into(), true),
+                position_info: Some(Bytes::copy_from_slice(&position.to_bytes())),
+            },
+            packet
+        );
+
+        let packet = AudioBuilder::<Clientbound>::new(
+            VoicePacketPayload::speex([Bytes::from_static(&[1, 2])]).unwrap(),
+        )
+        .session(5)
+        .seq(1)
+        .build()
+        .unwrap();
+        let mut dst = BytesMut::new();
+        ServerVoiceCodec::new()
+            .encode_ref(&packet, &mut dst)
+            .unwrap();
+        assert_eq!(audio_with_trailer(&[]), dst);
+
+        assert_eq!(
+            Err(VoiceError::FrameTooLong {
+                len: 0x2000,
+                limit: MAX_OPUS_FRAME_LEN
+            }),
+            AudioBuilder::<Serverbound>::opus(vec![0; 0x2000]).build()
+        );
+    }
+
+    #[test]
+    fn counts_sequence_numbers() {
+        let mut counter = SequenceCounter::new(Duration::from_millis(20)).with_start(3);
+        assert_eq!(2, counter.step());
+        assert_eq!(
+            (3, 5, 7),
+            (counter.advance(), counter.advance(), counter.peek())
+        );
+        assert_eq!(6, SequenceCounter::new(Duration::from_millis(60)).step());
+        assert_eq!(1, SequenceCounter::new(Duration::from_millis(10)).step());
+        assert_eq!(1, SequenceCounter::new(Duration::from_millis(5)).step());
+    }
+
+    #[test]
+    fn counts_frames() {
+        let opus = |toc: &[u8]| VoicePacketPayload::Opus(Bytes::copy_from_slice(toc), false);
+        // CELT-only 20 ms, SILK-only 60 ms, hybrid 10 ms twice, CELT-only 2.5 ms times 8
+        assert_eq!(2, opus(&[0xf8, 0xff]).frame_count());
+        assert_eq!(6, opus(&[0x18]).frame_count());
+        assert_eq!(2, opus(&[0x61]).frame_count());
+        assert_eq!(2, opus(&[0x83, 0x08]).frame_count());
+        assert_eq!(1, opus(&[]).frame_count());
+        // Code 3 packets lacking the frame count, with no frames or with 48 frames of 20 ms
+        assert_eq!(1, opus(&[0x1b]).frame_count());
+        assert_eq!(1, opus(&[0x1b, 0x00]).frame_count());
+        assert_eq!(1, opus(&[0x0b, 0x30]).frame_count());
+        // The longest valid packet, 120 ms, and the most any payload counts as
+        assert_eq!(12, opus(&[0x1b, 0x02]).frame_count());
+        for toc in 0..=u8::MAX {
+            for count in 0..=u8::MAX {
+                assert!((1..=12).contains(&opus(&[toc, count]).frame_count()));
+            }
+        }
+        let speex = VoicePacketPayload::speex([&b"a"[..], b"b", b"c"]).unwrap();
+        assert_eq!(3, speex.frame_count());
+    }
+
+    #[test]
+    fn describes_payloads() {
+        let opus: VoicePacket<Serverbound> = AudioBuilder::opus(vec![0x18, 1, 2])
+            .last(true)
+            .build()
+            .unwrap();
+        assert_eq!(Some(AudioCodec::Opus), opus.codec());
+        assert_eq!((3, 6), (opus.payload_len(), opus.frame_count()));
+        assert!(opus.is_end_of_transmission());
+
+        let celt = |frames: &[&[u8]]| {
+            AudioBuilder::<Serverbound>::new(
+                VoicePacketPayload::celt_beta(frames.iter().copied().map(Bytes::copy_from_slice))
+                    .unwrap(),
+            )
+            .build()
+            .unwrap()
+        };
+        let packet = celt(&[b"ab", b"c"]);
+        assert_eq!(Some(AudioCodec::CeltBeta), packet.codec());
+        assert_eq!((3, 2), (packet.payload_len(), packet.frame_count()));
+        assert!(!packet.is_end_of_transmission());
+        assert!(celt(&[b"ab", b""]).is_end_of_transmission());
+
+        let ping = VoicePacket::<Serverbound>::Ping { timestamp: 1 };
+        assert_eq!(None, ping.codec());
+        assert_eq!((0, 0), (ping.payload_len(), ping.frame_count()));
+        assert!(!ping.is_end_of_transmission());
+    }
+
+    #[test]
+    fn round_trips_ping_timestamps() {
+        let mut codec = ClientVoiceCodec::new();
+        let mut server = ServerVoiceCodec::new();
+        for timestamp in [0, 0x7f, 0xffff_ffff, 0x1_0000_0000, u64::MAX - 4, u64::MAX] {
+            let mut dst = BytesMut::new();
+            server
+                .encode_ref(&VoicePacket::Ping { timestamp }, &mut dst)
+                .unwrap();
+            assert_eq!(
+                1 + varint::encoded_len(timestamp as i64),
+                dst.len(),
+                "{}",
+                timestamp
+            );
+            assert_eq!(
+                Some(VoicePacket::Ping { timestamp }),
+                codec.decode(&mut dst).unwrap()
+            );
+        }
+    }
+}
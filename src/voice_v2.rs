@@ -0,0 +1,854 @@
+//! Protobuf based voice packets introduced with Mumble 1.5
+//!
+//! Each packet consists of a single byte giving its type, followed by a protobuf encoded
+//! [msgs::Audio] or [msgs::Ping]. Peers only switch to this format once both sides announced
+//! support for it, until then the legacy format of [crate::voice] is used. Packets can be
+//! converted between the two formats with `TryFrom` as far as their contents overlap.
+
+use std::marker::PhantomData;
+
+use bytes::Buf;
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
+
+use crate::control::backend;
+use crate::control::backend::message;
+use crate::error::Error;
+use crate::error::VoiceError;
+use crate::voice::Clientbound;
+use crate::voice::Direction;
+use crate::voice::Position;
+use crate::voice::Serverbound;
+use crate::voice::VoiceCodec;
+use crate::voice::VoicePacket;
+use crate::voice::VoicePacketDst;
+use crate::voice::VoicePacketPayload;
+
+/// ProtoBuf message types of the voice channel.
+#[allow(renamed_and_removed_lints)] // protobuf is missing `clippy::` prefix
+#[allow(missing_docs)] // these would have to be auto-generated by protobuf
+pub mod msgs {
+    #[cfg(any(feature = "protobuf", feature = "prost"))]
+    include!(concat!(env!("OUT_DIR"), "/proto_udp/mod.rs"));
+}
+
+/// The protobuf definitions the messages in [msgs] were generated from.
+pub const MUMBLE_UDP_PROTO: &str = include_str!("../protos/MumbleUDP.proto");
+
+/// Type byte of [msgs::Audio] packets.
+const AUDIO: u8 = 0;
+/// Type byte of [msgs::Ping] packets.
+const PING: u8 = 1;
+
+/// A packet transmitted via Mumble's voice channel in the protobuf format.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "Dst::SessionId: serde::Serialize",
+        deserialize = "Dst::SessionId: serde::Deserialize<'de>"
+    ))
+)]
+pub enum VoicePacketV2<Dst: VoicePacketDst> {
+    /// Ping packets, which may also ask the server for the information it shows to unconnected
+    /// clients.
+    Ping {
+        /// Opaque timestamp-like value.
+        /// Unless this is the echo, no assumptions about it should be made.
+        timestamp: u64,
+        /// Whether the server should fill in the remaining fields in its response.
+        request_extended_information: bool,
+        /// The server's version in the `version_v2` format of the `Version` message.
+        server_version_v2: u64,
+        /// Amount of users currently connected.
+        user_count: u32,
+        /// Maximum amount of users allowed.
+        max_user_count: u32,
+        /// Maximum bandwidth per user in bits per second.
+        max_bandwidth_per_user: u32,
+    },
+    /// Packet containing Opus audio.
+    Audio {
+        /// Destination. Required due to encoding differences depending on packet flow direction.
+        _dst: PhantomData<Dst>,
+        /// The target of [Serverbound] audio or the context of [Clientbound] audio.
+        ///
+        /// The values match those of [VoicePacket::Audio::target](VoicePacket#variant.Audio)
+        /// but are not limited to 5 bits.
+        target: u32,
+        /// Session ID. Absent when packet is [Serverbound].
+        session_id: Dst::SessionId,
+        /// Sequence number of the first 10 ms frame in this packet.
+        frame_number: u64,
+        /// A single Opus packet.
+        opus_data: Bytes,
+        /// Positional audio information.
+        position: Option<Position>,
+        /// Factor the receiver should scale the volume by, set by the server.
+        ///
+        /// It is 1.0 unless the server adjusts the volume.
+        volume_adjustment: f32,
+        /// Whether this is the last packet of the transmission.
+        is_terminator: bool,
+    },
+}
+
+impl<Dst: VoicePacketDst> VoicePacketV2<Dst> {
+    /// Returns a ping packet with only the timestamp set.
+    pub fn ping(timestamp: u64) -> Self {
+        VoicePacketV2::Ping {
+            timestamp,
+            request_extended_information: false,
+            server_version_v2: 0,
+            user_count: 0,
+            max_user_count: 0,
+            max_bandwidth_per_user: 0,
+        }
+    }
+
+    fn from_msg(kind: u8, bytes: &[u8]) -> Result<Self, Error> {
+        match kind {
+            AUDIO => {
+                let msg: msgs::Audio = backend::parse(bytes)?;
+                #[cfg(feature = "protobuf")]
+                let header = msg.Header;
+                #[cfg(feature = "prost")]
+                let header = msg.header;
+                let target = match header {
+                    Some(msgs::audio::Header::Target(target))
+                    | Some(msgs::audio::Header::Context(target)) => target,
+                    None => 0,
+                };
+                let position = match *msg.positional_data {
+                    [] => None,
+                    [x, y, z, ..] => Some(Position::new(x, y, z)),
+                    ref short => return Err(VoiceError::MalformedPosition(short.len() * 4).into()),
+                };
+                let volume_adjustment = match msg.volume_adjustment {
+                    0.0 => 1.0,
+                    factor => factor,
+                };
+                Ok(VoicePacketV2::Audio {
+                    _dst: PhantomData,
+                    target,
+                    session_id: Dst::session_id_from_u32(msg.sender_session),
+                    frame_number: msg.frame_number,
+                    opus_data: msg.opus_data.into(),
+                    position,
+                    volume_adjustment,
+                    is_terminator: msg.is_terminator,
+                })
+            }
+            PING => {
+                let msg: msgs::Ping = backend::parse(bytes)?;
+                Ok(VoicePacketV2::Ping {
+                    timestamp: msg.timestamp,
+                    request_extended_information: msg.request_extended_information,
+                    server_version_v2: msg.server_version_v2,
+                    user_count: msg.user_count,
+                    max_user_count: msg.max_user_count,
+                    max_bandwidth_per_user: msg.max_bandwidth_per_user,
+                })
+            }
+            _ => Err(VoiceError::UnknownType(kind).into()),
+        }
+    }
+
+    fn write(&self, dst: &mut BytesMut) -> Result<(), Error> {
+        match self {
+            &VoicePacketV2::Ping {
+                timestamp,
+                request_extended_information,
+                server_version_v2,
+                user_count,
+                max_user_count,
+                max_bandwidth_per_user,
+            } => {
+                let msg = message!(msgs::Ping {
+                    timestamp,
+                    request_extended_information,
+                    server_version_v2,
+                    user_count,
+                    max_user_count,
+                    max_bandwidth_per_user,
+                });
+                dst.reserve(1 + backend::encoded_len(&msg));
+                dst.put_u8(PING);
+                backend::write(&msg, dst)
+            }
+            VoicePacketV2::Audio {
+                target,
+                session_id,
+                frame_number,
+                opus_data,
+                position,
+                volume_adjustment,
+                is_terminator,
+                ..
+            } => {
+                let header = match Dst::DIRECTION {
+                    Direction::Serverbound => msgs::audio::Header::Target(*target),
+                    Direction::Clientbound => msgs::audio::Header::Context(*target),
+                };
+                let msg = message!(msgs::Audio {
+                    #[cfg(feature = "protobuf")]
+                    Header: Some(header),
+                    #[cfg(feature = "prost")]
+                    header: Some(header),
+                    sender_session: Dst::session_id_to_u32(session_id),
+                    frame_number: *frame_number,
+                    opus_data: opus_data.to_vec(),
+                    positional_data: position
+                        .map(|it| vec![it.x, it.y, it.z])
+                        .unwrap_or_default(),
+                    volume_adjustment: match *volume_adjustment {
+                        1.0 => 0.0,
+                        factor => factor,
+                    },
+                    is_terminator: *is_terminator,
+                });
+                dst.reserve(1 + backend::encoded_len(&msg));
+                dst.put_u8(AUDIO);
+                backend::write(&msg, dst)
+            }
+        }
+    }
+}
+
+/// Packets are converted as far as the legacy format allows: the extended information of pings
+/// is dropped and a volume adjustment other than 1.0 is lost.
+impl<Dst: VoicePacketDst> TryFrom<VoicePacketV2<Dst>> for VoicePacket<Dst> {
+    type Error = VoiceError;
+
+    fn try_from(packet: VoicePacketV2<Dst>) -> Result<Self, Self::Error> {
+        match packet {
+            VoicePacketV2::Ping { timestamp, .. } => Ok(VoicePacket::Ping { timestamp }),
+            VoicePacketV2::Audio {
+                target,
+                session_id,
+                frame_number,
+                opus_data,
+                position,
+                is_terminator,
+                ..
+            } => {
+                let target = match u8::try_from(target) {
+                    Ok(target @ 0..=31) => target,
+                    _ => return Err(VoiceError::TargetOutOfRange(target)),
+                };
+                let mut packet = VoicePacket::Audio {
+                    _dst: PhantomData,
+                    target,
+                    session_id,
+                    seq_num: frame_number,
+                    payload: VoicePacketPayload::opus(opus_data, is_terminator)?,
+                    position_info: None,
+                };
+                packet.set_position(position);
+                Ok(packet)
+            }
+        }
+    }
+}
+
+/// Only Opus audio can be converted, positional data beyond the [Position] is dropped.
+impl<Dst: VoicePacketDst> TryFrom<VoicePacket<Dst>> for VoicePacketV2<Dst> {
+    type Error = VoiceError;
+
+    fn try_from(packet: VoicePacket<Dst>) -> Result<Self, Self::Error> {
+        let position = packet.position();
+        match packet {
+            VoicePacket::Ping { timestamp } => Ok(VoicePacketV2::ping(timestamp)),
+            VoicePacket::Audio {
+                target,
+                session_id,
+                seq_num,
+                payload: VoicePacketPayload::Opus(opus_data, is_terminator),
+                ..
+            } => Ok(VoicePacketV2::Audio {
+                _dst: PhantomData,
+                target: target.into(),
+                session_id,
+                frame_number: seq_num,
+                opus_data,
+                position,
+                volume_adjustment: 1.0,
+                is_terminator,
+            }),
+            VoicePacket::Audio { .. } => Err(VoiceError::UnsupportedCodec),
+        }
+    }
+}
+
+/// Parses a whole protobuf voice packet, e.g. the payload of a `UDPTunnel` message.
+pub(crate) fn parse<Dst: VoicePacketDst>(bytes: &[u8]) -> Result<VoicePacketV2<Dst>, Error> {
+    match bytes.split_first() {
+        Some((&kind, msg)) => VoicePacketV2::from_msg(kind, msg),
+        None => Err(VoiceError::Truncated.into()),
+    }
+}
+
+/// A `Codec` implementation that parses a stream of data chunks into [VoicePacketV2]s.
+///
+/// Like [VoiceCodec](crate::voice::VoiceCodec), each chunk passed to it has to be a whole
+/// datagram. See [ServerVoiceCodecV2] and [ClientVoiceCodecV2] for the two most reasonable
+/// configurations.
+#[derive(Debug, Default)]
+pub struct VoiceCodecV2<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> {
+    _encode_dst: PhantomData<EncodeDst>,
+    _decode_dst: PhantomData<DecodeDst>,
+}
+/// The [VoiceCodecV2] used on the server side.
+pub type ServerVoiceCodecV2 = VoiceCodecV2<Clientbound, Serverbound>;
+/// The [VoiceCodecV2] used on the client side.
+pub type ClientVoiceCodecV2 = VoiceCodecV2<Serverbound, Clientbound>;
+
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> VoiceCodecV2<EncodeDst, DecodeDst> {
+    /// Creates a new voice codec.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Note: like VoiceCodec::decode, this returns Ok(Some(_)) or Err(_) but never Ok(None)
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    pub(crate) fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<VoicePacketV2<DecodeDst>>, Error> {
+        let result = parse(src);
+        src.advance(src.len());
+        result.map(Some)
+    }
+
+    pub(crate) fn encode_ref(
+        &mut self,
+        item: &VoicePacketV2<EncodeDst>,
+        dst: &mut BytesMut,
+    ) -> Result<(), Error> {
+        let start = dst.len();
+        item.write(dst).inspect_err(|_| dst.truncate(start))
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> tokio_util::codec::Decoder
+    for VoiceCodecV2<EncodeDst, DecodeDst>
+{
+    type Item = VoicePacketV2<DecodeDst>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode(src)
+    }
+}
+
+#[cfg(feature = "asynchronous-codec")]
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::Decoder
+    for VoiceCodecV2<EncodeDst, DecodeDst>
+{
+    type Item = VoicePacketV2<DecodeDst>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode(src)
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>
+    tokio_util::codec::Encoder<VoicePacketV2<EncodeDst>> for VoiceCodecV2<EncodeDst, DecodeDst>
+{
+    type Error = Error;
+
+    fn encode(
+        &mut self,
+        item: VoicePacketV2<EncodeDst>,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        self.encode_ref(&item, dst)
+    }
+}
+
+#[cfg(feature = "asynchronous-codec")]
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::Encoder
+    for VoiceCodecV2<EncodeDst, DecodeDst>
+{
+    type Item = VoicePacketV2<EncodeDst>;
+    type Error = Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_ref(&item, dst)
+    }
+}
+
+/// The framing of voice packets, see [NegotiatingVoiceCodec].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// The format of [VoicePacket], understood by all versions.
+    Legacy,
+    /// The format of [VoicePacketV2], understood since Mumble 1.5.
+    Protobuf,
+}
+
+impl Format {
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    fn other(self) -> Self {
+        match self {
+            Format::Legacy => Format::Protobuf,
+            Format::Protobuf => Format::Legacy,
+        }
+    }
+}
+
+/// A voice packet in either format, as handled by [NegotiatingVoiceCodec].
+#[derive(Clone, Debug, PartialEq)]
+pub enum NegotiatedPacket<Dst: VoicePacketDst> {
+    /// A packet in the legacy format.
+    Legacy(VoicePacket<Dst>),
+    /// A packet in the protobuf format.
+    Protobuf(VoicePacketV2<Dst>),
+    /// A packet which failed to decode in the selected format but decoded in the other one.
+    ///
+    /// Getting these hints at a mis-detected format, which can be corrected with
+    /// [force](NegotiatingVoiceCodec::force). Only returned by the decoder, it is never nested.
+    Mismatched(Box<NegotiatedPacket<Dst>>),
+}
+
+impl<Dst: VoicePacketDst> NegotiatedPacket<Dst> {
+    /// Returns the format the packet is in.
+    pub fn format(&self) -> Format {
+        match self {
+            NegotiatedPacket::Legacy(_) => Format::Legacy,
+            NegotiatedPacket::Protobuf(_) => Format::Protobuf,
+            NegotiatedPacket::Mismatched(packet) => packet.format(),
+        }
+    }
+}
+
+impl<Dst: VoicePacketDst> From<VoicePacket<Dst>> for NegotiatedPacket<Dst> {
+    fn from(packet: VoicePacket<Dst>) -> Self {
+        NegotiatedPacket::Legacy(packet)
+    }
+}
+
+impl<Dst: VoicePacketDst> From<VoicePacketV2<Dst>> for NegotiatedPacket<Dst> {
+    fn from(packet: VoicePacketV2<Dst>) -> Self {
+        NegotiatedPacket::Protobuf(packet)
+    }
+}
+
+/// A `Codec` implementation which detects the voice packet format spoken by the peer.
+///
+/// Servers since Mumble 1.5 answer pings in the format they were sent in, older ones only
+/// answer legacy pings. Until a format is selected, packets of both formats are decoded and
+/// the first ping received selects the format of its sender. Packets to be encoded are
+/// converted into the selected format, or sent as they are while none is selected, which allows
+/// probing with pings in both formats.
+///
+/// Once a format is selected, packets are only expected in it. Packets failing to decode in it
+/// but decoding in the other format are returned as [NegotiatedPacket::Mismatched] instead of
+/// being dropped, so a mis-detection can be noticed and corrected with [force](Self::force).
+#[derive(Debug, Default)]
+pub struct NegotiatingVoiceCodec<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> {
+    format: Option<Format>,
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    legacy: VoiceCodec<EncodeDst, DecodeDst>,
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    protobuf: VoiceCodecV2<EncodeDst, DecodeDst>,
+}
+/// The [NegotiatingVoiceCodec] used on the server side.
+pub type ServerNegotiatingVoiceCodec = NegotiatingVoiceCodec<Clientbound, Serverbound>;
+/// The [NegotiatingVoiceCodec] used on the client side.
+pub type ClientNegotiatingVoiceCodec = NegotiatingVoiceCodec<Serverbound, Clientbound>;
+
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst>
+    NegotiatingVoiceCodec<EncodeDst, DecodeDst>
+{
+    /// Creates a new voice codec which has yet to detect the format.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the selected format, `None` until it was detected or forced.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+
+    /// Selects the format, regardless of what was detected.
+    pub fn force(&mut self, format: Format) {
+        self.format = Some(format);
+    }
+
+    /// Decodes a whole datagram in the given format, leaving `src` untouched.
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    fn decode_as(
+        &mut self,
+        format: Format,
+        src: &BytesMut,
+    ) -> Result<NegotiatedPacket<DecodeDst>, Error> {
+        let mut src = src.clone();
+        Ok(match format {
+            Format::Legacy => NegotiatedPacket::Legacy(self.legacy.decode(&mut src)?.unwrap()),
+            Format::Protobuf => {
+                NegotiatedPacket::Protobuf(self.protobuf.decode(&mut src)?.unwrap())
+            }
+        })
+    }
+
+    // Note: like VoiceCodec::decode, this returns Ok(Some(_)) or Err(_) but never Ok(None)
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    pub(crate) fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<NegotiatedPacket<DecodeDst>>, Error> {
+        let datagram = src.split();
+        let result = match (self.format, datagram.first()) {
+            (Some(format), _) => self.decode_as(format, &datagram).or_else(|err| {
+                match self.decode_as(format.other(), &datagram) {
+                    Ok(packet) => Ok(NegotiatedPacket::Mismatched(Box::new(packet))),
+                    Err(_) => Err(err),
+                }
+            }),
+            (None, Some(&PING)) => self.decode_as(Format::Protobuf, &datagram).inspect(|_| {
+                self.format = Some(Format::Protobuf);
+            }),
+            (None, Some(header)) if header >> 5 == 1 => {
+                self.decode_as(Format::Legacy, &datagram).inspect(|_| {
+                    self.format = Some(Format::Legacy);
+                })
+            }
+            // Protobuf audio shares its header with the long obsolete CELT Alpha audio
+            (None, Some(&AUDIO)) => self
+                .decode_as(Format::Protobuf, &datagram)
+                .or_else(|err| self.decode_as(Format::Legacy, &datagram).or(Err(err))),
+            (None, _) => self.decode_as(Format::Legacy, &datagram),
+        };
+        result.map(Some)
+    }
+
+    #[cfg_attr(
+        not(any(feature = "tokio-codec", feature = "asynchronous-codec")),
+        allow(dead_code)
+    )]
+    pub(crate) fn encode_ref(
+        &mut self,
+        item: &NegotiatedPacket<EncodeDst>,
+        dst: &mut BytesMut,
+    ) -> Result<(), Error> {
+        match (self.format, item) {
+            (_, NegotiatedPacket::Mismatched(packet)) => self.encode_ref(packet, dst),
+            (None | Some(Format::Legacy), NegotiatedPacket::Legacy(packet)) => {
+                self.legacy.encode_ref(packet, dst)
+            }
+            (None | Some(Format::Protobuf), NegotiatedPacket::Protobuf(packet)) => {
+                self.protobuf.encode_ref(packet, dst)
+            }
+            (Some(Format::Protobuf), NegotiatedPacket::Legacy(packet)) => self
+                .protobuf
+                .encode_ref(&VoicePacketV2::try_from(packet.clone())?, dst),
+            (Some(Format::Legacy), NegotiatedPacket::Protobuf(packet)) => self
+                .legacy
+                .encode_ref(&VoicePacket::try_from(packet.clone())?, dst),
+        }
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> tokio_util::codec::Decoder
+    for NegotiatingVoiceCodec<EncodeDst, DecodeDst>
+{
+    type Item = NegotiatedPacket<DecodeDst>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode(src)
+    }
+}
+
+#[cfg(feature = "asynchronous-codec")]
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::Decoder
+    for NegotiatingVoiceCodec<EncodeDst, DecodeDst>
+{
+    type Item = NegotiatedPacket<DecodeDst>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decode(src)
+    }
+}
+
+#[cfg(feature = "tokio-codec")]
+impl<
+        EncodeDst: VoicePacketDst,
+        DecodeDst: VoicePacketDst,
+        T: Into<NegotiatedPacket<EncodeDst>>,
+    > tokio_util::codec::Encoder<T> for NegotiatingVoiceCodec<EncodeDst, DecodeDst>
+{
+    type Error = Error;
+
+    fn encode(&mut self, item: T, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_ref(&item.into(), dst)
+    }
+}
+
+#[cfg(feature = "asynchronous-codec")]
+impl<EncodeDst: VoicePacketDst, DecodeDst: VoicePacketDst> asynchronous_codec::Encoder
+    for NegotiatingVoiceCodec<EncodeDst, DecodeDst>
+{
+    type Item = NegotiatedPacket<EncodeDst>;
+    type Error = Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.encode_ref(&item, dst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn audio<Dst: VoicePacketDst>(session_id: Dst::SessionId) -> VoicePacketV2<Dst> {
+        VoicePacketV2::Audio {
+            _dst: PhantomData,
+            target: 2,
+            session_id,
+            frame_number: 1234,
+            opus_data: Bytes::from_static(&[0xf8, 1, 2, 3]),
+            position: Some(Position::new(1.0, 2.5, -3.0)),
+            volume_adjustment: 0.5,
+            is_terminator: true,
+        }
+    }
+
+    #[test]
+    fn round_trips_packets() {
+        let packets = vec![
+            audio::<Clientbound>(42),
+            VoicePacketV2::ping(7),
+            VoicePacketV2::Ping {
+                timestamp: 1,
+                request_extended_information: true,
+                server_version_v2: 1 << 48 | 5 << 32,
+                user_count: 3,
+                max_user_count: 100,
+                max_bandwidth_per_user: 72000,
+            },
+        ];
+        for packet in packets {
+            let mut buf = BytesMut::new();
+            ServerVoiceCodecV2::new()
+                .encode_ref(&packet, &mut buf)
+                .unwrap();
+            let decoded = ClientVoiceCodecV2::new().decode(&mut buf).unwrap();
+            assert_eq!(Some(packet), decoded);
+            assert!(buf.is_empty());
+        }
+
+        let packet = audio::<Serverbound>(());
+        let mut buf = BytesMut::new();
+        ClientVoiceCodecV2::new()
+            .encode_ref(&packet, &mut buf)
+            .unwrap();
+        assert_eq!(AUDIO, buf[0]);
+        assert_eq!(
+            Some(packet),
+            ServerVoiceCodecV2::new().decode(&mut buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn decodes_defaults_and_rejects_garbage() {
+        let mut buf = BytesMut::from(&[AUDIO][..]);
+        let packet = ClientVoiceCodecV2::new().decode(&mut buf).unwrap();
+        assert!(matches!(
+            packet,
+            Some(VoicePacketV2::Audio {
+                target: 0,
+                session_id: 0,
+                position: None,
+                volume_adjustment,
+                is_terminator: false,
+                ..
+            }) if volume_adjustment == 1.0
+        ));
+
+        let mut codec = ClientVoiceCodecV2::new();
+        assert!(matches!(
+            codec.decode(&mut BytesMut::new()),
+            Err(Error::MalformedVoice(VoiceError::Truncated))
+        ));
+        assert!(matches!(
+            codec.decode(&mut BytesMut::from(&[2][..])),
+            Err(Error::MalformedVoice(VoiceError::UnknownType(2)))
+        ));
+        assert!(matches!(
+            codec.decode(&mut BytesMut::from(&[AUDIO, 0xff][..])),
+            Err(Error::Protobuf(_))
+        ));
+        // positional_data with only two floats
+        let mut short_position = BytesMut::from(&[AUDIO, 0x32, 8][..]);
+        short_position.put_slice(&[0; 8]);
+        assert!(matches!(
+            codec.decode(&mut short_position),
+            Err(Error::MalformedVoice(VoiceError::MalformedPosition(8)))
+        ));
+    }
+
+    #[test]
+    fn converts_from_and_to_legacy_packets() {
+        let packet = audio::<Clientbound>(42);
+        let legacy = VoicePacket::try_from(packet.clone()).unwrap();
+        assert!(matches!(
+            &legacy,
+            VoicePacket::Audio {
+                target: 2,
+                session_id: 42,
+                seq_num: 1234,
+                payload: VoicePacketPayload::Opus(_, true),
+                ..
+            }
+        ));
+        assert_eq!(Some(Position::new(1.0, 2.5, -3.0)), legacy.position());
+        // The volume adjustment is lost
+        let VoicePacketV2::Audio {
+            volume_adjustment, ..
+        } = VoicePacketV2::try_from(legacy).unwrap()
+        else {
+            panic!("expected audio");
+        };
+        assert_eq!(1.0, volume_adjustment);
+
+        assert_eq!(
+            Ok(VoicePacket::<Serverbound>::Ping { timestamp: 5 }),
+            VoicePacket::try_from(VoicePacketV2::ping(5))
+        );
+
+        let mut far_target = audio::<Clientbound>(1);
+        if let VoicePacketV2::Audio { target, .. } = &mut far_target {
+            *target = 32;
+        }
+        assert_eq!(
+            Err(VoiceError::TargetOutOfRange(32)),
+            VoicePacket::try_from(far_target)
+        );
+
+        let speex = VoicePacket::<Serverbound>::Audio {
+            _dst: PhantomData,
+            target: 0,
+            session_id: (),
+            seq_num: 0,
+            payload: VoicePacketPayload::speex(vec![Bytes::from_static(&[1, 2])]).unwrap(),
+            position_info: None,
+        };
+        assert_eq!(
+            Err(VoiceError::UnsupportedCodec),
+            VoicePacketV2::try_from(speex)
+        );
+    }
+
+    #[test]
+    fn negotiation_detects_protobuf_servers() {
+        let mut codec = ClientNegotiatingVoiceCodec::new();
+        let mut buf = BytesMut::new();
+        codec
+            .encode_ref(&VoicePacket::Ping { timestamp: 1 }.into(), &mut buf)
+            .unwrap();
+        codec
+            .encode_ref(&VoicePacketV2::ping(2).into(), &mut buf)
+            .unwrap();
+        assert_eq!(0x20, buf[0]);
+        assert_eq!(PING, buf[2]);
+        assert_eq!(None, codec.format());
+
+        let mut buf = BytesMut::new();
+        ServerVoiceCodecV2::new()
+            .encode_ref(&VoicePacketV2::ping(2), &mut buf)
+            .unwrap();
+        assert_eq!(
+            Some(NegotiatedPacket::Protobuf(VoicePacketV2::ping(2))),
+            codec.decode(&mut buf).unwrap()
+        );
+        assert_eq!(Some(Format::Protobuf), codec.format());
+
+        // Legacy packets are converted from now on
+        codec
+            .encode_ref(&VoicePacket::Ping { timestamp: 3 }.into(), &mut buf)
+            .unwrap();
+        assert_eq!(
+            Some(VoicePacketV2::ping(3)),
+            ServerVoiceCodecV2::new().decode(&mut buf).unwrap()
+        );
+    }
+
+    #[test]
+    fn negotiation_detects_legacy_servers() {
+        let mut codec = ClientNegotiatingVoiceCodec::new();
+        let mut buf = BytesMut::new();
+        crate::voice::ServerVoiceCodec::new()
+            .encode_ref(&VoicePacket::Ping { timestamp: 4 }, &mut buf)
+            .unwrap();
+        assert_eq!(
+            Some(NegotiatedPacket::Legacy(VoicePacket::Ping { timestamp: 4 })),
+            codec.decode(&mut buf).unwrap()
+        );
+        assert_eq!(Some(Format::Legacy), codec.format());
+
+        codec
+            .encode_ref(&VoicePacketV2::ping(5).into(), &mut buf)
+            .unwrap();
+        assert_eq!(0x20, buf[0]);
+        let mut buf = BytesMut::new();
+        codec
+            .encode_ref(&audio::<Serverbound>(()).into(), &mut buf)
+            .unwrap();
+        assert!(matches!(
+            crate::voice::ServerVoiceCodec::new().decode(&mut buf),
+            Ok(Some(VoicePacket::Audio { seq_num: 1234, .. }))
+        ));
+    }
+
+    #[test]
+    fn negotiation_reports_mismatched_packets() {
+        let mut codec = ClientNegotiatingVoiceCodec::new();
+        codec.force(Format::Legacy);
+        let mut buf = BytesMut::new();
+        ServerVoiceCodecV2::new()
+            .encode_ref(&VoicePacketV2::ping(5), &mut buf)
+            .unwrap();
+        let packet = codec.decode(&mut buf.clone()).unwrap().unwrap();
+        assert_eq!(
+            NegotiatedPacket::Mismatched(Box::new(VoicePacketV2::ping(5).into())),
+            packet
+        );
+        assert_eq!(Format::Protobuf, packet.format());
+        assert_eq!(Some(Format::Legacy), codec.format());
+
+        codec.force(Format::Protobuf);
+        assert_eq!(
+            Some(NegotiatedPacket::Protobuf(VoicePacketV2::ping(5))),
+            codec.decode(&mut buf).unwrap()
+        );
+        assert!(buf.is_empty());
+        assert!(codec.decode(&mut BytesMut::from(&[7][..])).is_err());
+    }
+}